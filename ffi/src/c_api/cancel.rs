@@ -0,0 +1,94 @@
+use crate::c_api::client::write_error;
+use crate::cancel::CancelToken;
+use crate::cancel::Cancellation;
+use std::ffi::c_void;
+use std::panic;
+use std::time::Duration;
+
+pub type CCancelToken = c_void;
+
+/// Constructs a cancellation token that isn't triggered
+///
+/// Tokens are given to `netbase_cache_lookup`, `netbase_cache_lookup_batch` and
+/// `netbase_cache_submit` to stop them from another thread.
+#[no_mangle]
+pub extern "C" fn netbase_cancel_token_new(_class: *const i8) -> *mut CCancelToken {
+    Box::into_raw(Box::new(CancelToken::new())) as *mut CCancelToken
+}
+
+/// Triggers a cancellation token
+///
+/// Lookups using the token give up on their queries in flight and return promptly. The token
+/// stays triggered, so later lookups using it don't make any queries.
+///
+/// This takes a lock, so it must not be called from a signal handler. Use
+/// `netbase_cancel_token_cancel_on_signal` to cancel on a signal.
+#[no_mangle]
+pub extern "C" fn netbase_cancel_token_cancel(this: *const CCancelToken) {
+    let this = unsafe { &*(this as *const CancelToken) };
+    this.cancel();
+}
+
+/// Checks whether a cancellation token has been triggered
+///
+/// Returns 1 if it has and 0 otherwise.
+#[no_mangle]
+pub extern "C" fn netbase_cancel_token_is_cancelled(this: *const CCancelToken) -> u8 {
+    let this = unsafe { &*(this as *const CancelToken) };
+    this.is_cancelled() as u8
+}
+
+/// Triggers a cancellation token whenever the process receives a signal
+///
+/// The default action of the signal, e.g. terminating the process on `SIGINT`, is replaced for
+/// the rest of the life of the process. Calling this again for the same signal only adds the
+/// token to those it triggers.
+///
+/// # Arguments
+/// * `signum` - The number of the signal
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer. This happens for signals that can't be handled, like `SIGKILL`.
+/// * If the callback is not called and a zero value is returned, this means that a panic was
+///   caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cancel_token_cancel_on_signal(
+    this: *const CCancelToken,
+    signum: i32,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let this = unsafe { &*(this as *const CancelToken) };
+        match this.cancel_on_signal(signum) {
+            Ok(()) => 1,
+            Err(err) => {
+                write_error(&err, get_buffer);
+                0
+            }
+        }
+    })
+    .unwrap_or(0)
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn netbase_cancel_token_DESTROY(p: *mut CCancelToken) {
+    unsafe { drop(Box::from_raw(p as *mut CancelToken)) };
+}
+
+/// Decodes the cancellation arguments of the lookup functions.
+///
+/// `token` may be null and a zero `deadline` means there is none.
+pub fn cancellation(token: *const CCancelToken, deadline: u64) -> Cancellation {
+    let token = if token.is_null() {
+        None
+    } else {
+        Some(unsafe { &*(token as *const CancelToken) }.clone())
+    };
+    Cancellation {
+        token,
+        deadline: (deadline > 0).then(|| Duration::from_millis(deadline)),
+    }
+}
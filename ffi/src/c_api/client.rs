@@ -1,15 +1,45 @@
+use crate::c_api::cancel::cancellation;
+use crate::c_api::cancel::CCancelToken;
+use crate::c_api::filter::CFilter;
 use crate::c_api::ip::CIpAddr;
 use crate::c_api::message::CMessage;
-use crate::c_api::net::CNet;
+use crate::c_api::name::CName;
 use crate::c_api::question::CQuestion;
+use crate::c_api::queue::CQueue;
+use crate::c_api::transport;
+use crate::c_api::transport::CTransport;
+use crate::c_api::zones::CZones;
 use crate::client::Cache;
-use crate::client::Net;
+use crate::client::MergePolicy;
+use crate::client::MissStrategy;
 use crate::client::Question;
+use crate::client::RequestOrder;
+use crate::client::SingleResponse;
+use crate::diff;
+use crate::diff::Change;
+use crate::diff::DiffOptions;
+use crate::dnstap;
+use crate::dnstap::DnstapType;
+use crate::json;
+use crate::pcap;
+use crate::queue::LookupQueue;
+use crate::server;
+use crate::server::Listener;
+use crate::stats;
+use crate::stats::Latency;
+use crate::store::Filter;
+use crate::zone::Zones;
+use std::ffi::c_char;
 use std::ffi::c_void;
+use std::ffi::CStr;
+use std::ffi::CString;
 use std::net::IpAddr;
+use std::net::SocketAddr;
 use std::panic;
 use std::ptr;
-use std::rc::Rc;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use trust_dns_client::rr::Record;
 
 pub type CCache = c_void;
 
@@ -51,11 +81,82 @@ pub extern "C" fn netbase_cache_from_bytes(
         match Cache::from_bytes(bytes) {
             Ok(cache) => Box::into_raw(Box::new(cache)) as *mut CCache,
             Err(err) => {
-                let err = err.to_string();
-                let buffer = get_buffer(err.len());
-                let buffer = ptr::slice_from_raw_parts_mut(buffer, err.len());
-                let buffer = unsafe { &mut *buffer };
-                buffer.copy_from_slice(err.as_bytes());
+                write_error(&err, get_buffer);
+                ptr::null_mut()
+            }
+        }
+    });
+    match result {
+        Ok(this) => this,
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Constructs a new cache instance from its JSON representation
+///
+/// # Arguments
+/// * `json` - A pointer to the UTF-8 encoded JSON
+/// * `size` - The length of the JSON in bytes
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the callback is called this means an error occurred and that details are found in the
+///   buffer.
+/// * If the callback is not called and the returned value is a null pointer, this means that a
+///   panic was caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_from_json(
+    json: *const u8,
+    size: usize,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> *mut CCache {
+    let result = panic::catch_unwind(|| {
+        let json = ptr::slice_from_raw_parts(json, size);
+        let json = unsafe { &*json };
+        let cache = std::str::from_utf8(json)
+            .map_err(|err| err.to_string())
+            .and_then(|json| json::from_json(json).map_err(|err| err.to_string()));
+        match cache {
+            Ok(cache) => Box::into_raw(Box::new(cache)) as *mut CCache,
+            Err(err) => {
+                write_error(&err, get_buffer);
+                ptr::null_mut()
+            }
+        }
+    });
+    match result {
+        Ok(this) => this,
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Constructs a new cache instance from the DNS exchanges in a pcap or pcapng capture
+///
+/// # Arguments
+/// * `capture` - A pointer to the capture file contents
+/// * `size` - The length of the capture in bytes
+/// * `port` - Only UDP and TCP traffic to or from this port is imported
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the callback is called this means an error occurred and that details are found in the
+///   buffer.
+/// * If the callback is not called and the returned value is a null pointer, this means that a
+///   panic was caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_from_pcap(
+    capture: *const u8,
+    size: usize,
+    port: u16,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> *mut CCache {
+    let result = panic::catch_unwind(|| {
+        let capture = ptr::slice_from_raw_parts(capture, size);
+        let capture = unsafe { &*capture };
+        match pcap::from_pcap(capture, port) {
+            Ok(cache) => Box::into_raw(Box::new(cache)) as *mut CCache,
+            Err(err) => {
+                write_error(&err, get_buffer);
                 ptr::null_mut()
             }
         }
@@ -66,155 +167,1248 @@ pub extern "C" fn netbase_cache_from_bytes(
     }
 }
 
-/// Serializes the cache into a byte string
+/// Constructs a new cache instance from the DNS exchanges in a dnstap log
+///
+/// # Arguments
+/// * `log` - A pointer to the Frame Streams file contents
+/// * `size` - The length of the log in bytes
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the callback is called this means an error occurred and that details are found in the
+///   buffer.
+/// * If the callback is not called and the returned value is a null pointer, this means that a
+///   panic was caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_from_dnstap(
+    log: *const u8,
+    size: usize,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> *mut CCache {
+    let result = panic::catch_unwind(|| {
+        let log = ptr::slice_from_raw_parts(log, size);
+        let log = unsafe { &*log };
+        match dnstap::from_dnstap(log) {
+            Ok(cache) => Box::into_raw(Box::new(cache)) as *mut CCache,
+            Err(err) => {
+                write_error(&err, get_buffer);
+                ptr::null_mut()
+            }
+        }
+    });
+    match result {
+        Ok(this) => this,
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Opens a cache backed by an SQLite database file
+///
+/// The file is created if it doesn't exist. Entries are written to the file as they are added to
+/// the cache.
+///
+/// # Arguments
+/// * `path` - A null terminated path to the database file
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the callback is called this means an error occurred and that details are found in the
+///   buffer.
+/// * If the callback is not called and the returned value is a null pointer, this means that a
+///   panic was caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_open_sqlite(
+    path: *const c_char,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> *mut CCache {
+    let result = panic::catch_unwind(|| {
+        let path = unsafe { CStr::from_ptr(path) };
+        let path = path.to_string_lossy();
+        match Cache::open_sqlite(path.as_ref()) {
+            Ok(cache) => Box::into_raw(Box::new(cache)) as *mut CCache,
+            Err(err) => {
+                write_error(&err, get_buffer);
+                ptr::null_mut()
+            }
+        }
+    });
+    match result {
+        Ok(this) => this,
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Copies all entries into another cache
+///
+/// Entries in `other` with the same question and server are replaced.
+///
+/// # Arguments
+/// * `other` - The cache to copy entries into
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the callback is called this means an error occurred and that details are found in the
+///   buffer.
+/// * If the callback is not called and a zero value is returned, this means that a panic was
+///   caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_copy_to(
+    cache: *const CCache,
+    other: *mut CCache,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let cache = unsafe { &*(cache as *const Cache) };
+        let other = unsafe { &*(other as *const Cache) };
+        match cache.copy_to(other) {
+            Ok(()) => 1,
+            Err(err) => {
+                write_error(&err, get_buffer);
+                0
+            }
+        }
+    })
+    .unwrap_or(0)
+}
+
+/// Serializes the cache into a byte string
+///
+/// # Arguments
+/// * `get_bytes_buffer` - A callback for getting a buffer of required size for the byte string.
+///   Called at most once.
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer.
+/// * If a zero value is returned and `get_buffer` is not called, this means that a panic was caught
+///   and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_to_bytes(
+    cache: *const CCache,
+    get_bytes_buffer: extern "C" fn(usize) -> *mut u8,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let cache = unsafe { &*(cache as *const Cache) };
+        match cache.to_bytes() {
+            Ok(bytes) => {
+                let size = bytes.len();
+                let buffer = get_bytes_buffer(size);
+                let buffer = ptr::slice_from_raw_parts_mut(buffer, size);
+                let buffer = unsafe { &mut *buffer };
+                buffer.copy_from_slice(&bytes);
+                1
+            }
+            Err(err) => {
+                write_error(&err, get_buffer);
+                0
+            }
+        }
+    })
+    .unwrap_or(0)
+}
+
+/// Serializes the cache into its JSON representation
+///
+/// Entries are ordered by qname, qtype and server.
+///
+/// # Arguments
+/// * `get_json_buffer` - A callback for getting a buffer of required size for the UTF-8 encoded
+///   JSON. Called at most once.
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer.
+/// * If a zero value is returned and `get_buffer` is not called, this means that a panic was caught
+///   and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_to_json(
+    cache: *const CCache,
+    get_json_buffer: extern "C" fn(usize) -> *mut u8,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let cache = unsafe { &*(cache as *const Cache) };
+        match json::to_json(cache) {
+            Ok(json) => {
+                let size = json.len();
+                let buffer = get_json_buffer(size);
+                let buffer = ptr::slice_from_raw_parts_mut(buffer, size);
+                let buffer = unsafe { &mut *buffer };
+                buffer.copy_from_slice(json.as_bytes());
+                1
+            }
+            Err(err) => {
+                write_error(&err, get_buffer);
+                0
+            }
+        }
+    })
+    .unwrap_or(0)
+}
+
+/// Exports all recorded exchanges as a pcap capture
+///
+/// # Arguments
+/// * `client` - The source address of the queries
+/// * `get_pcap_buffer` - A callback for getting a buffer of required size for the capture.
+///   Called at most once.
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer.
+/// * If a zero value is returned and `get_buffer` is not called, this means that a panic was caught
+///   and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_to_pcap(
+    cache: *const CCache,
+    client: *const CIpAddr,
+    get_pcap_buffer: extern "C" fn(usize) -> *mut u8,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let cache = unsafe { &*(cache as *const Cache) };
+        let client = unsafe { *(client as *const IpAddr) };
+        match pcap::to_pcap(cache, client) {
+            Ok(bytes) => {
+                let size = bytes.len();
+                let buffer = get_pcap_buffer(size);
+                let buffer = ptr::slice_from_raw_parts_mut(buffer, size);
+                let buffer = unsafe { &mut *buffer };
+                buffer.copy_from_slice(&bytes);
+                1
+            }
+            Err(err) => {
+                write_error(&err, get_buffer);
+                0
+            }
+        }
+    })
+    .unwrap_or(0)
+}
+
+/// Exports all recorded exchanges as a dnstap log
+///
+/// # Arguments
+/// * `client` - The source address of the queries
+/// * `dnstap_type` - The message types to log:
+///   * 1 - CLIENT_QUERY and CLIENT_RESPONSE
+///   * 2 - RESOLVER_QUERY and RESOLVER_RESPONSE
+/// * `get_dnstap_buffer` - A callback for getting a buffer of required size for the log.
+///   Called at most once.
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer.
+/// * If a zero value is returned and `get_buffer` is not called, this means that a panic was caught
+///   and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_to_dnstap(
+    cache: *const CCache,
+    client: *const CIpAddr,
+    dnstap_type: u8,
+    get_dnstap_buffer: extern "C" fn(usize) -> *mut u8,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let cache = unsafe { &*(cache as *const Cache) };
+        let client = unsafe { *(client as *const IpAddr) };
+        let Ok(dnstap_type) = DnstapType::try_from(dnstap_type) else {
+            write_error(&"invalid dnstap type", get_buffer);
+            return 0;
+        };
+        match dnstap::to_dnstap(cache, client, dnstap_type) {
+            Ok(bytes) => {
+                let size = bytes.len();
+                let buffer = get_dnstap_buffer(size);
+                let buffer = ptr::slice_from_raw_parts_mut(buffer, size);
+                let buffer = unsafe { &mut *buffer };
+                buffer.copy_from_slice(&bytes);
+                1
+            }
+            Err(err) => {
+                write_error(&err, get_buffer);
+                0
+            }
+        }
+    })
+    .unwrap_or(0)
+}
+
+/// Inserts the responses that servers of a set of zones give to a question
+///
+/// The responses replace any earlier entries for the question and the servers.
+///
+/// # Arguments
+/// * `zones` - The zones served by the servers
+/// * `question` - The question to send to all the servers
+/// * `servers` - A pointer to an array of IpAddr pointers
+/// * `servers_len` - The length of the array
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer.
+/// * If a zero value is returned and `get_buffer` is not called, this means that a panic was caught
+///   and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_synthesize(
+    cache: *mut CCache,
+    zones: *const CZones,
+    question: *const CQuestion,
+    servers: *const *const CIpAddr,
+    servers_len: usize,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let cache = unsafe { &*(cache as *const Cache) };
+        let zones = unsafe { &*(zones as *const Zones) };
+        let question = unsafe { &*(question as *const Question) };
+        let servers = ptr::slice_from_raw_parts(servers as *const &IpAddr, servers_len);
+        let servers: Vec<IpAddr> = unsafe { &*servers }.iter().map(|server| **server).collect();
+        match zones.synthesize(cache, question, &servers) {
+            Ok(()) => 1,
+            Err(err) => {
+                write_error(&err, get_buffer);
+                0
+            }
+        }
+    })
+    .unwrap_or(0)
+}
+
+/// Answers DNS queries with the responses recorded in the cache
+///
+/// Listens for UDP and TCP queries on the given local addresses, each playing the role of the
+/// recorded server at the same index. Blocks for as long as the listeners run.
+///
+/// # Arguments
+/// * `listen` - A pointer to an array of IpAddr pointers to listen on
+/// * `servers` - A pointer to an array of IpAddr pointers to the recorded servers
+/// * `len` - The length of both arrays
+/// * `port` - The port to listen on
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer.
+/// * If a zero value is returned and `get_buffer` is not called, this means that a panic was caught
+///   and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_replay(
+    cache: *const CCache,
+    listen: *const *const CIpAddr,
+    servers: *const *const CIpAddr,
+    len: usize,
+    port: u16,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let cache = unsafe { &*(cache as *const Cache) };
+        let listen = ptr::slice_from_raw_parts(listen as *const &IpAddr, len);
+        let listen = unsafe { &*listen };
+        let servers = ptr::slice_from_raw_parts(servers as *const &IpAddr, len);
+        let servers = unsafe { &*servers };
+
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            let Some(listeners) = bind_listeners(listen, servers, port, get_buffer).await else {
+                return 0;
+            };
+            server::replay(listeners, cache).await;
+            1
+        })
+    })
+    .unwrap_or(0)
+}
+
+/// Forwards DNS queries to their servers and records the exchanges in the cache
+///
+/// Listens for UDP and TCP queries on the given local addresses, each forwarding to the server at
+/// the same index. Blocks for as long as the listeners run.
+///
+/// # Arguments
+/// * `net` - The net instance used for forwarding the queries
+/// * `listen` - A pointer to an array of IpAddr pointers to listen on
+/// * `servers` - A pointer to an array of IpAddr pointers to the upstream servers
+/// * `len` - The length of both arrays
+/// * `port` - The port to listen on
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer.
+/// * If a zero value is returned and `get_buffer` is not called, this means that a panic was caught
+///   and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_proxy(
+    cache: *mut CCache,
+    net: *const CTransport,
+    listen: *const *const CIpAddr,
+    servers: *const *const CIpAddr,
+    len: usize,
+    port: u16,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let cache = unsafe { &*(cache as *const Cache) };
+        let net = unsafe { transport::from_handle(net) };
+        let listen = ptr::slice_from_raw_parts(listen as *const &IpAddr, len);
+        let listen = unsafe { &*listen };
+        let servers = ptr::slice_from_raw_parts(servers as *const &IpAddr, len);
+        let servers = unsafe { &*servers };
+
+        net.runtime().block_on(async {
+            let Some(listeners) = bind_listeners(listen, servers, port, get_buffer).await else {
+                return 0;
+            };
+            server::proxy(listeners, &**net, cache).await;
+            1
+        })
+    })
+    .unwrap_or(0)
+}
+
+/// Looks up responses to a question from a set of server addresses
+///
+/// # Arguments
+/// * `strategy` - What to do about servers without a cached response:
+///   * `1` - Report them as not in cache
+///   * `2` - Query them over `transport`
+///   * `3` - Query every server over `transport`, even those with a cached response
+///   * `4` - Query them over `transport`, as well as servers whose latest response started more
+///     than `max_age` milliseconds ago
+///   * `5` - Fail the lookup as a whole
+/// * `transport` - A net or scripted transport for strategies that make queries, or null for the
+///   others
+/// * `max_age` - The maximum age of cached responses for strategy `4`, otherwise ignored
+/// * `cancel_token` - A token that stops the lookup when triggered, or null
+/// * `deadline` - How long the queries may take in all (milliseconds of real time), or zero for
+///   no limit
+/// * `question` - The question to send to all the servers
+/// * `servers` - A pointer to an array of IpAddr pointers
+/// * `servers_len` - The length of the array
+/// * `handle_outcome` - A callback to be called with an outcome for each server. Its arguments
+///   are:
+///   * `server` - The subject of this call
+///   * `started` - The time the query was sent (or when the connection was attempted in case
+///     `error_kind` indicates a connection error) (milliseconds since the Unix epoch)
+///   * `duration` - How long before the request was completed or timed out
+///   * `connect_micros` - How long connecting to the server took (microseconds, measured
+///     monotonically)
+///   * `query_micros` - How long the final attempt took (microseconds, measured monotonically)
+///   * `error_kind` - The kind error that occurred, `6` if the request is not in the cache, `7`
+///     if the query was cancelled, or zero for no error
+///   * `packet_size` - The size in bytes of the received DNS packet or zero if no packet was
+///     received
+///   * `message` - The received response or null if no response was received
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// Responses of queries made over `transport` are appended to the histories in the cache. Queries that
+/// are stopped by `cancel_token` or `deadline` are recorded with error kind `7`, and such
+/// responses count as misses in later lookups.
+///
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer. This happens for invalid strategies, a null `transport` for a strategy
+///   that makes queries and misses with strategy `5`. `handle_outcome` is not called then.
+/// * If the callback is not called and a zero value is returned, this means that a panic was
+///   caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_lookup(
+    cache: *mut CCache,
+    strategy: u8,
+    transport: *const CTransport,
+    max_age: u64,
+    cancel_token: *const CCancelToken,
+    deadline: u64,
+    question: *const CQuestion,
+    servers: *const *const CIpAddr,
+    servers_len: usize,
+    handle_outcome: extern "C" fn(*mut CIpAddr, u64, u32, u32, u32, u16, u16, *mut CMessage),
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let cache = unsafe { &*(cache as *const Cache) };
+        let servers = ptr::slice_from_raw_parts(servers as *const &IpAddr, servers_len);
+        let servers = unsafe { &*servers };
+        let servers = servers.iter().map(|server| **server).collect();
+        let question = unsafe { &*(question as *const Question) };
+        let cancellation = cancellation(cancel_token, deadline);
+        let strategy = match miss_strategy(strategy, transport, max_age) {
+            Ok(strategy) => strategy,
+            Err(err) => {
+                write_error(&err, get_buffer);
+                return 0;
+            }
+        };
+
+        let results = match cache.lookup(&strategy, question.clone(), &servers, &cancellation) {
+            Ok(results) => results,
+            Err(err) => {
+                write_error(&err, get_buffer);
+                return 0;
+            }
+        };
+
+        for (server, response) in results {
+            let server = Box::into_raw(Box::new(server)) as *mut CIpAddr;
+            let (started, duration, connect, query, err_kind, size, message) =
+                outcome_args(response);
+            handle_outcome(
+                server, started, duration, connect, query, err_kind, size, message,
+            );
+        }
+        1
+    })
+    .unwrap_or(0)
+}
+
+/// Looks up responses to many questions at once, each from a set of servers of its own
+///
+/// Every request is handled just like by `netbase_cache_lookup`, but the queries of all the
+/// requests are made concurrently, and the outcomes of each request are handed over as soon as
+/// it is complete. The callbacks are called outside of the runtime of the transport, so they may
+/// do lookups of their own.
 ///
 /// # Arguments
-/// * `get_buffer` - A callback for getting a buffer of required size.
-///   Called exactly once.
+/// * `questions` - A pointer to an array of question pointers, one for each request
+/// * `questions_len` - The length of the array, i.e. the number of requests
+/// * `servers` - A pointer to an array of IpAddr pointers with the servers of every request, one
+///   request after the other
+/// * `servers_lens` - A pointer to an array of the number of servers of each request, with
+///   `questions_len` elements
+/// * `handle_outcome` - A callback to be called with an outcome for each server of a completed
+///   request. Its arguments are:
+///   * `index` - The index of the request in `questions`
+///   * the remaining arguments are the same as those of the `handle_outcome` callback of
+///     `netbase_cache_lookup`
+/// * `handle_completion` - A callback to be called once for each request, after the outcomes of
+///   its servers. Requests that need no queries complete first, in order, and the others as
+///   their queries finish. Its arguments are:
+///   * `index` - The index of the request
+///   * `error` - Why the request failed as a whole as a null terminated string, i.e. a miss with
+///     strategy `5`, or null if it didn't. Only valid during the call.
+/// * the remaining arguments are the same as those of `netbase_cache_lookup`. The deadline
+///   applies to the batch as a whole.
 ///
 /// # Errors
-/// * If a zero value is returned this means that a panic was caught and the function returned
-///   abnormally.
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer. This happens for invalid strategies and a null `transport` for a
+///   strategy that makes queries. No other callback is called then.
+/// * If the callback is not called and a zero value is returned, this means that a panic was
+///   caught and the function returned abnormally.
 #[no_mangle]
-pub extern "C" fn netbase_cache_to_bytes(
+pub extern "C" fn netbase_cache_lookup_batch(
     cache: *const CCache,
+    strategy: u8,
+    transport: *const CTransport,
+    max_age: u64,
+    cancel_token: *const CCancelToken,
+    deadline: u64,
+    questions: *const *const CQuestion,
+    questions_len: usize,
+    servers: *const *const CIpAddr,
+    servers_lens: *const usize,
+    handle_outcome: extern "C" fn(usize, *mut CIpAddr, u64, u32, u32, u32, u16, u16, *mut CMessage),
+    handle_completion: extern "C" fn(usize, *const c_char),
     get_buffer: extern "C" fn(usize) -> *mut u8,
 ) -> u8 {
     panic::catch_unwind(|| {
         let cache = unsafe { &*(cache as *const Cache) };
-        let bytes = cache.to_bytes().unwrap();
-        let size = bytes.len();
-        let buffer = get_buffer(size);
-        let buffer = ptr::slice_from_raw_parts_mut(buffer, size);
-        let buffer = unsafe { &mut *buffer };
-        buffer.copy_from_slice(&bytes);
+        let questions = ptr::slice_from_raw_parts(questions as *const &Question, questions_len);
+        let questions = unsafe { &*questions };
+        let servers_lens = unsafe { &*ptr::slice_from_raw_parts(servers_lens, questions_len) };
+        let servers_len = servers_lens.iter().sum();
+        let servers = ptr::slice_from_raw_parts(servers as *const &IpAddr, servers_len);
+        let mut servers = unsafe { &*servers }.iter();
+        let requests = questions
+            .iter()
+            .zip(servers_lens)
+            .map(|(question, len)| {
+                let servers = servers.by_ref().take(*len).map(|server| **server);
+                ((*question).clone(), servers.collect())
+            })
+            .collect();
+        let cancellation = cancellation(cancel_token, deadline);
+        let strategy = match miss_strategy(strategy, transport, max_age) {
+            Ok(strategy) => strategy,
+            Err(err) => {
+                write_error(&err, get_buffer);
+                return 0;
+            }
+        };
+
+        cache.lookup_batch(
+            &strategy,
+            requests,
+            &cancellation,
+            |index, result| match result {
+                Ok(results) => {
+                    for (server, response) in results {
+                        let server = Box::into_raw(Box::new(server)) as *mut CIpAddr;
+                        let (started, duration, connect, query, err_kind, size, message) =
+                            outcome_args(response);
+                        handle_outcome(
+                            index, server, started, duration, connect, query, err_kind, size,
+                            message,
+                        );
+                    }
+                    handle_completion(index, ptr::null());
+                }
+                Err(miss) => {
+                    let miss = CString::new(miss.to_string()).unwrap_or_default();
+                    handle_completion(index, miss.as_ptr());
+                }
+            },
+        );
+        1
     })
-    .is_ok() as u8
+    .unwrap_or(0)
 }
 
-/// Looks up responses to a question from a set of server addresses
+/// Starts a lookup in the background and returns right away
+///
+/// The lookup runs on the runtime of `transport` and is otherwise the same as one made by
+/// `netbase_cache_lookup`. Its outcomes are collected with `netbase_queue_drain` once the file
+/// descriptor of the queue becomes readable.
 ///
 /// # Arguments
-/// * `net` - An optional net instance to be used as a fallback for cache misses
-/// * `question` - The question to send to all the servers
-/// * `servers` - A pointer to an array of IpAddr pointers
-/// * `servers_len` - The length of the array
-/// * `handle_outcome` - A callback to be called with an outcome for each server. It's arguments
-///   are:
-///   * `server` - The subject of this call
-///   * `started` - The time the query was sent (or when the connection was attempted in case
-///     `error_kind` indicates a connection error) (milliseconds since the Unix epoch)
-///   * `duration` - How long before the request was completed or timed out
-///   * `error_kind` - The kind error that occurred or zero for no error
-///   * `packet_size` - The size in bytes of the received DNS packet or zero if no packet was
-///     received
-///   * `message` - The received response or null if no response was received
+/// * `queue` - The queue to collect the outcomes from
+/// * the remaining arguments are the same as those of `netbase_cache_lookup`, except that there
+///   is no `handle_outcome`
 ///
-/// # Errors
-/// * If a zero value is returned this means that a panic was caught and the function returned
-///   abnormally.
+/// # Returns
+/// The ID of the lookup, which is never zero.
 ///
-/// A call to `handle_outcome` with a `error_kind` set to zero and `message` set to null means the
-/// request is not in the cache and no net instance was provided as a fallback.
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer. This happens for invalid strategies and a null `transport` for a
+///   strategy that makes queries. Misses with strategy `5` are reported when draining instead.
+/// * If the callback is not called and a zero value is returned, this means that a panic was
+///   caught and the function returned abnormally.
 #[no_mangle]
-pub extern "C" fn netbase_cache_lookup(
-    cache: *mut CCache,
-    net: *const CNet,
+pub extern "C" fn netbase_cache_submit(
+    cache: *const CCache,
+    queue: *const CQueue,
+    strategy: u8,
+    transport: *const CTransport,
+    max_age: u64,
+    cancel_token: *const CCancelToken,
+    deadline: u64,
     question: *const CQuestion,
     servers: *const *const CIpAddr,
     servers_len: usize,
-    handle_outcome: extern "C" fn(*mut CIpAddr, u64, u32, u16, u16, *mut CMessage),
-) -> u8 {
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u64 {
     panic::catch_unwind(|| {
-        let cache = unsafe { &mut *(cache as *mut Cache) };
+        let cache = unsafe { &*(cache as *const Cache) };
+        let queue = unsafe { &*(queue as *const LookupQueue) };
         let servers = ptr::slice_from_raw_parts(servers as *const &IpAddr, servers_len);
         let servers = unsafe { &*servers };
-        let mut servers = servers.iter().map(|server| **server).collect();
+        let servers = servers.iter().map(|server| **server).collect();
         let question = unsafe { &*(question as *const Question) };
-        let net = if net.is_null() {
-            None
-        } else {
-            let net = unsafe { &*(net as *const Net) };
-            unsafe {
-                Rc::increment_strong_count(net);
-            }
-            let net = unsafe { Rc::from_raw(net) };
-            Some(net)
+        let cancellation = cancellation(cancel_token, deadline);
+        let strategy = match miss_strategy(strategy, transport, max_age) {
+            Ok(strategy) => strategy,
+            Err(err) => {
+                write_error(&err, get_buffer);
+                return 0;
+            }
         };
+        queue.submit(cache, strategy, question.clone(), servers, cancellation)
+    })
+    .unwrap_or(0)
+}
 
-        let results = cache.lookup(net, question.clone(), &servers);
+/// Lists the responses for a question and server address, oldest first
+///
+/// # Arguments
+/// * `question` - The question of the entry
+/// * `server` - The server of the entry
+/// * `handle_response` - A callback to be called for each response. Its arguments are the same
+///   as those of the `handle_outcome` callback of `netbase_cache_lookup`, except for `server`.
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer.
+/// * If `get_buffer` is not called and a zero value is returned, this means that a panic was
+///   caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_history(
+    cache: *const CCache,
+    question: *const CQuestion,
+    server: *const CIpAddr,
+    handle_response: extern "C" fn(u64, u32, u32, u32, u16, u16, *mut CMessage),
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let cache = unsafe { &*(cache as *const Cache) };
+        let question = unsafe { &*(question as *const Question) };
+        let server = unsafe { &*(server as *const IpAddr) };
+        match cache.history(question, server) {
+            Ok(history) => {
+                for response in history {
+                    let (started, duration, connect, query, err_kind, size, message) =
+                        outcome_args(SingleResponse::from(&*response));
+                    handle_response(started, duration, connect, query, err_kind, size, message);
+                }
+                1
+            }
+            Err(err) => {
+                write_error(&err, get_buffer);
+                0
+            }
+        }
+    })
+    .unwrap_or(0)
+}
 
-        for (server, response) in results {
-            servers.remove(&server);
-            let server = Box::into_raw(Box::new(server)) as *mut CIpAddr;
-            match response.outcome {
-                Ok((message, packet_size)) => {
-                    let err_kind = 0;
-                    let message = Rc::into_raw(message) as *mut CMessage;
-                    handle_outcome(
-                        server,
-                        response.started,
-                        response.duration,
-                        err_kind,
-                        packet_size,
-                        message,
-                    );
+/// Gets the response for a question and server address that started closest to a given time
+///
+/// # Arguments
+/// * `question` - The question of the entry
+/// * `server` - The server of the entry
+/// * `time` - The time to compare start times to (milliseconds since the Unix epoch)
+/// * `handle_response` - A callback to be called with the response, unless there is none. Its
+///   arguments are the same as those of the `handle_outcome` callback of `netbase_cache_lookup`,
+///   except for `server`.
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer.
+/// * If `get_buffer` is not called and a zero value is returned, this means that a panic was
+///   caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_closest(
+    cache: *const CCache,
+    question: *const CQuestion,
+    server: *const CIpAddr,
+    time: u64,
+    handle_response: extern "C" fn(u64, u32, u32, u32, u16, u16, *mut CMessage),
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let cache = unsafe { &*(cache as *const Cache) };
+        let question = unsafe { &*(question as *const Question) };
+        let server = unsafe { &*(server as *const IpAddr) };
+        match cache.closest(question, server, time) {
+            Ok(response) => {
+                if let Some(response) = response {
+                    let (started, duration, connect, query, err_kind, size, message) =
+                        outcome_args(SingleResponse::from(&*response));
+                    handle_response(started, duration, connect, query, err_kind, size, message);
                 }
-                Err(err_kind) => {
-                    let packet_size = 0;
-                    let err_kind: u16 = err_kind.into();
-                    let message = ptr::null_mut();
-                    handle_outcome(
-                        server,
-                        response.started,
-                        response.duration,
-                        err_kind,
-                        packet_size,
+                1
+            }
+            Err(err) => {
+                write_error(&err, get_buffer);
+                0
+            }
+        }
+    })
+    .unwrap_or(0)
+}
+
+/// Decodes the strategy arguments of `netbase_cache_lookup` and `netbase_cache_submit`.
+fn miss_strategy(
+    strategy: u8,
+    transport: *const CTransport,
+    max_age: u64,
+) -> Result<MissStrategy, String> {
+    let net = (!transport.is_null()).then(|| unsafe { transport::from_handle(transport) }.clone());
+    match (strategy, net) {
+        (1, _) => Ok(MissStrategy::CacheOnly),
+        (2, Some(net)) => Ok(MissStrategy::FillOnMiss(net)),
+        (3, Some(net)) => Ok(MissStrategy::RefreshAlways(net)),
+        (4, Some(net)) => Ok(MissStrategy::RefreshIfOlderThan(net, max_age)),
+        (5, _) => Ok(MissStrategy::FailOnMiss),
+        (2..=4, None) => Err("lookup strategy requires a transport".to_string()),
+        (strategy, _) => Err(format!("unrecognized lookup strategy: {}", strategy)),
+    }
+}
+
+/// The arguments of the `handle_outcome` callback of `netbase_cache_lookup` following `server`.
+pub type OutcomeArgs = (u64, u32, u32, u32, u16, u16, *mut CMessage);
+
+pub fn outcome_args(response: SingleResponse) -> OutcomeArgs {
+    let (err_kind, packet_size, message) = match response.outcome {
+        Ok((message, packet_size)) => (0, packet_size, Arc::into_raw(message) as *mut CMessage),
+        Err(err_kind) => (err_kind.into(), 0, ptr::null_mut()),
+    };
+    (
+        response.started,
+        response.duration,
+        response.connect_micros,
+        response.query_micros,
+        err_kind,
+        packet_size,
+        message,
+    )
+}
+
+/// Merges the entries of another cache into this one
+///
+/// # Arguments
+/// * `other` - The cache to merge entries from
+/// * `policy` - How to resolve entries for the same question and server:
+///   * `1` - Keep the entry whose latest response started first
+///   * `2` - Keep the entry whose latest response started last
+///   * `3` - Keep the responses from both entries in the history
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the callback is called this means an error occurred and that details are found in the
+///   buffer.
+/// * If the callback is not called and a zero value is returned, this means that a panic was
+///   caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_merge(
+    cache: *mut CCache,
+    other: *const CCache,
+    policy: u8,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let cache = unsafe { &*(cache as *const Cache) };
+        let other = unsafe { &*(other as *const Cache) };
+        let Ok(policy) = MergePolicy::try_from(policy) else {
+            write_error(&"invalid merge policy", get_buffer);
+            return 0;
+        };
+        match cache.merge(other, policy) {
+            Ok(()) => 1,
+            Err(err) => {
+                write_error(&err, get_buffer);
+                0
+            }
+        }
+    })
+    .unwrap_or(0)
+}
+
+/// Layers this cache over another one
+///
+/// Entries this cache doesn't have are looked up in its bases, in the order they were added. The
+/// base is read through rather than copied and is never written to. New responses only go into
+/// this cache, and only its own entries are serialized.
+///
+/// # Arguments
+/// * `base` - The cache to consult after this one and its earlier bases
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the callback is called this means an error occurred and that details are found in the
+///   buffer. This happens if the cache would become a base of itself.
+/// * If the callback is not called and a zero value is returned, this means that a panic was
+///   caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_add_base(
+    cache: *mut CCache,
+    base: *const CCache,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let cache = unsafe { &*(cache as *const Cache) };
+        let base = unsafe { &*(base as *const Cache) };
+        if !cache.add_base(base) {
+            write_error(&"cache can't be a base of itself", get_buffer);
+            return 0;
+        }
+        1
+    })
+    .unwrap_or(0)
+}
+
+/// Lists the requests matching a filter along with their latest outcomes
+///
+/// The requests are ordered by qname in canonical DNS order, qtype and server.
+///
+/// # Arguments
+/// * `filter` - Selects the requests
+/// * `handle_match` - A callback to be called for each matching request. Its arguments are:
+///   * `question` - The question of the request
+///   * `server` - The server of the request
+///   * the remaining arguments are the same as those of the `handle_outcome` callback of
+///     `netbase_cache_lookup`, except for `server`
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer.
+/// * If `get_buffer` is not called and a zero value is returned, this means that a panic was
+///   caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_query(
+    cache: *const CCache,
+    filter: *const CFilter,
+    handle_match: extern "C" fn(
+        *mut CQuestion,
+        *mut CIpAddr,
+        u64,
+        u32,
+        u32,
+        u32,
+        u16,
+        u16,
+        *mut CMessage,
+    ),
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let cache = unsafe { &*(cache as *const Cache) };
+        let filter = unsafe { &*(filter as *const Filter) };
+        match cache.query(filter) {
+            Ok(matches) => {
+                for (question, server, response) in matches {
+                    let question = Box::into_raw(Box::new(question)) as *mut CQuestion;
+                    let server = Box::into_raw(Box::new(server)) as *mut CIpAddr;
+                    let (started, duration, connect, query, err_kind, size, message) =
+                        outcome_args(SingleResponse::from(&*response));
+                    handle_match(
+                        question, server, started, duration, connect, query, err_kind, size,
                         message,
                     );
                 }
+                1
+            }
+            Err(err) => {
+                write_error(&err, get_buffer);
+                0
             }
         }
+    })
+    .unwrap_or(0)
+}
 
-        for server in servers {
-            let server = Box::into_raw(Box::new(server)) as *mut CIpAddr;
-            let started = 0;
-            let duration = 0;
-            let err_kind = 0;
-            let packet_size = 0;
-            let message = ptr::null_mut();
-            handle_outcome(server, started, duration, err_kind, packet_size, message);
+/// Collects statistics over every recorded response of this cache, including earlier ones in
+/// histories
+///
+/// # Arguments
+/// * `handle_count` - A callback to be called once for each count. Its arguments are:
+///   * `category` - What is counted:
+///     * `0` - The number of (question, server) pairs, `key` is zero
+///     * `1` - The number of responses per qtype
+///     * `2` - The number of responses per protocol (`1` for UDP and `2` for TCP)
+///     * `3` - The number of decodable response messages per rcode
+///     * `4` - The number of responses per error kind, as in `netbase_cache_lookup`
+///   * `key` - The qtype, protocol, rcode or error kind
+///   * `count` - The count
+/// * `handle_server` - A callback to be called once for each server, ordered by address. Its
+///   arguments are:
+///   * `server` - The server
+///   * `responses` - The number of responses from the server
+///   * `answered` - The number of those responses with a decodable response message
+///   * `errors` - The number of those responses that are errors
+///   * `retries` - The number of failed attempts that were retried
+///   * `bytes` - The total size of the received response messages
+///   * `min`, `p50`, `p90`, `p99`, `max` - Latency percentiles of the answered responses
+///     (milliseconds), or zero if there are none
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer.
+/// * If the `get_buffer` callback is not called and a zero value is returned, this means that a
+///   panic was caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_stats(
+    cache: *const CCache,
+    handle_count: extern "C" fn(u8, u16, u64),
+    handle_server: extern "C" fn(*mut CIpAddr, u64, u64, u64, u64, u64, u32, u32, u32, u32, u32),
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let cache = unsafe { &*(cache as *const Cache) };
+        let stats = match stats::stats(cache) {
+            Ok(stats) => stats,
+            Err(err) => {
+                write_error(&err, get_buffer);
+                return 0;
+            }
+        };
+        handle_count(0, 0, stats.entries);
+        for (qtype, count) in stats.qtypes {
+            handle_count(1, qtype, count);
+        }
+        for (proto, count) in stats.protocols {
+            handle_count(2, u8::from(proto).into(), count);
+        }
+        for (rcode, count) in stats.rcodes {
+            handle_count(3, rcode, count);
+        }
+        for (error_kind, count) in stats.errors {
+            handle_count(4, error_kind.into(), count);
         }
+        for (server, server_stats) in stats.servers {
+            let latency = server_stats.latency.unwrap_or(Latency {
+                min: 0,
+                p50: 0,
+                p90: 0,
+                p99: 0,
+                max: 0,
+            });
+            handle_server(
+                Box::into_raw(Box::new(server)) as *mut CIpAddr,
+                server_stats.responses,
+                server_stats.answered,
+                server_stats.errors,
+                server_stats.retries,
+                server_stats.bytes,
+                latency.min,
+                latency.p50,
+                latency.p90,
+                latency.p99,
+                latency.max,
+            );
+        }
+        1
     })
-    .is_ok() as u8
+    .unwrap_or(0)
+}
+
+/// Constructs a new in-memory cache with the entries matching a filter
+///
+/// # Arguments
+/// * `filter` - Selects the entries to copy
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the callback is called this means an error occurred and that details are found in the
+///   buffer.
+/// * If the callback is not called and the returned value is a null pointer, this means that a
+///   panic was caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_filter(
+    cache: *const CCache,
+    filter: *const CFilter,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> *mut CCache {
+    let result = panic::catch_unwind(|| {
+        let cache = unsafe { &*(cache as *const Cache) };
+        let filter = unsafe { &*(filter as *const Filter) };
+        match cache.filter(filter) {
+            Ok(cache) => Box::into_raw(Box::new(cache)) as *mut CCache,
+            Err(err) => {
+                write_error(&err, get_buffer);
+                ptr::null_mut()
+            }
+        }
+    });
+    match result {
+        Ok(this) => this,
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Removes the entries matching a filter
+///
+/// Only entries of the cache itself are removed, not those of its bases.
+///
+/// # Arguments
+/// * `filter` - Selects the entries to remove
+/// * `removed` - Set to the number of removed entries
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the callback is called this means an error occurred and that details are found in the
+///   buffer.
+/// * If the callback is not called and a zero value is returned, this means that a panic was
+///   caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_prune(
+    cache: *mut CCache,
+    filter: *const CFilter,
+    removed: *mut usize,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let cache = unsafe { &*(cache as *const Cache) };
+        let filter = unsafe { &*(filter as *const Filter) };
+        match cache.prune(filter) {
+            Ok(count) => {
+                unsafe {
+                    *removed = count;
+                }
+                1
+            }
+            Err(err) => {
+                write_error(&err, get_buffer);
+                0
+            }
+        }
+    })
+    .unwrap_or(0)
+}
+
+/// Compares the latest responses of this cache (old) with those of another cache (new)
+///
+/// Message IDs are never compared.
+///
+/// # Arguments
+/// * `other` - The new cache
+/// * `ignore_ttl` - Non-zero to ignore differences in record TTLs
+/// * `handle_difference` - A callback to be called once for each difference, ordered by qname,
+///   qtype and server. Its arguments are:
+///   * `question` - The question of the differing entry
+///   * `server` - The server of the differing entry
+///   * `kind` - The kind of difference:
+///     * `1` - The entry only exists in the new cache
+///     * `2` - The entry only exists in the old cache
+///     * `3` - The outcome kinds differ
+///     * `4` - The rcodes differ
+///     * `5` - The header flags differ
+///     * `6` - The records of an RRset differ
+///   * `section` - For RRset differences the section of the RRset (`1` for answer, `2` for
+///     authority and `3` for additional), otherwise zero
+///   * `owner` - For RRset differences the owner name of the RRset, otherwise null
+///   * `rrtype` - For RRset differences the type of the RRset, otherwise zero
+///   * `old` - The old value as a null terminated string, or null for added and removed entries.
+///     For RRset differences these are the records of the RRset, one per line, or the empty
+///     string if the RRset is missing. Only valid during the call.
+///   * `new` - The new value, like `old`
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer.
+/// * If the `get_buffer` callback is not called and a zero value is returned, this means that a
+///   panic was caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_cache_diff(
+    cache: *const CCache,
+    other: *const CCache,
+    ignore_ttl: u8,
+    handle_difference: extern "C" fn(
+        *mut CQuestion,
+        *mut CIpAddr,
+        u8,
+        u8,
+        *mut CName,
+        u16,
+        *const c_char,
+        *const c_char,
+    ),
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let cache = unsafe { &*(cache as *const Cache) };
+        let other = unsafe { &*(other as *const Cache) };
+        let options = DiffOptions {
+            ignore_ttl: ignore_ttl != 0,
+        };
+        let differences = match diff::diff(cache, other, options) {
+            Ok(differences) => differences,
+            Err(err) => {
+                write_error(&err, get_buffer);
+                return 0;
+            }
+        };
+        for difference in differences {
+            let kind = difference.change.kind();
+            let (section, owner, rrtype, values) = match difference.change {
+                Change::Added | Change::Removed => (0, ptr::null_mut(), 0, None),
+                Change::Outcome { old, new } => (
+                    0,
+                    ptr::null_mut(),
+                    0,
+                    Some((old.to_string(), new.to_string())),
+                ),
+                Change::Rcode { old, new } => (
+                    0,
+                    ptr::null_mut(),
+                    0,
+                    Some((format!("{:?}", old), format!("{:?}", new))),
+                ),
+                Change::Flags { old, new } => (
+                    0,
+                    ptr::null_mut(),
+                    0,
+                    Some((old.to_string(), new.to_string())),
+                ),
+                Change::Rrset {
+                    section,
+                    owner,
+                    rtype,
+                    old,
+                    new,
+                } => {
+                    let lines = |records: Vec<Record>| {
+                        records
+                            .iter()
+                            .map(|record| record.to_string())
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    };
+                    (
+                        section.into(),
+                        Box::into_raw(Box::new(owner)) as *mut CName,
+                        rtype.into(),
+                        Some((lines(old), lines(new))),
+                    )
+                }
+            };
+            let values = values.map(|(old, new)| {
+                (
+                    CString::new(old).unwrap_or_default(),
+                    CString::new(new).unwrap_or_default(),
+                )
+            });
+            let (old, new) = match &values {
+                Some((old, new)) => (old.as_ptr(), new.as_ptr()),
+                None => (ptr::null(), ptr::null()),
+            };
+            let question = Box::into_raw(Box::new(difference.question)) as *mut CQuestion;
+            let server = Box::into_raw(Box::new(difference.server)) as *mut CIpAddr;
+            handle_difference(question, server, kind, section, owner, rrtype, old, new);
+        }
+        1
+    })
+    .unwrap_or(0)
 }
 
 /// Traverse all cached requests.
 ///
 /// # Arguments
-/// * `callback` - A callback to be called with an outcome for each server. It's arguments
+/// * `order` - The order to traverse the requests in:
+///   * `1` - By qname in canonical DNS order, qtype and server
+///   * `2` - By the start of the first attempt of the oldest response of each request
+/// * `callback` - A callback to be called with an outcome for each server. Its arguments
 ///   are:
 ///   * `question` - A question with one or more cache records
 ///   * `server` - A server with a cache record for this `question`
 ///
+/// The requests are collected before the first call, so the callback may look up and insert
+/// entries in this cache.
+///
 /// # Errors
-/// * If a zero value is returned this means that a panic was caught and the function returned
-///   abnormally.
+/// * If a zero value is returned this means that a panic was caught or `order` is invalid.
 #[allow(non_snake_case)]
 #[no_mangle]
 pub extern "C" fn netbase_cache_for_each_request(
-    cache: *const CCache,
+    cache: *mut CCache,
+    order: u8,
     callback: extern "C" fn(*mut CQuestion, *mut CIpAddr) -> (),
 ) -> u8 {
     panic::catch_unwind(|| {
         let cache = unsafe { &*(cache as *const Cache) };
-        cache.for_each_request(|(question, server)| {
+        let Ok(order) = RequestOrder::try_from(order) else {
+            return 0;
+        };
+        cache.for_each_request(order, |_, question, server| {
             let server = Box::into_raw(Box::new(server)) as *mut CIpAddr;
             let question = Box::into_raw(Box::new(question)) as *mut CQuestion;
             callback(question, server);
         });
+        1
     })
-    .is_ok() as u8
+    .unwrap_or(0)
 }
 
 #[allow(non_snake_case)]
@@ -223,19 +1417,51 @@ pub extern "C" fn netbase_cache_for_each_retry(
     cache: *const CCache,
     question: *const CQuestion,
     server: *const CIpAddr,
-    callback: extern "C" fn(u64, u32, u16) -> (),
+    callback: extern "C" fn(u64, u32, u32, u16) -> (),
 ) -> u8 {
     panic::catch_unwind(|| {
         let cache = unsafe { &*(cache as *const Cache) };
         let server = unsafe { &*(server as *const IpAddr) };
         let question = unsafe { &*(question as *const Question) };
-        cache.for_each_retry(question, server, |start, duration, error| {
-            callback(start, duration, error.into());
+        cache.for_each_retry(question, server, |start, duration, micros, error| {
+            callback(start, duration, micros, error.into());
         });
     })
     .is_ok() as u8
 }
 
+/// Binds a listener on each local address for the server at the same index.
+async fn bind_listeners(
+    listen: &[&IpAddr],
+    servers: &[&IpAddr],
+    port: u16,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> Option<Vec<Listener>> {
+    let mut listeners = Vec::new();
+    for (addr, server) in listen.iter().zip(servers) {
+        let addr = SocketAddr::new(**addr, port);
+        match Listener::bind(addr, **server).await {
+            Ok(listener) => listeners.push(listener),
+            Err(err) => {
+                write_error(
+                    &format!("could not listen on {}: {}", addr, err),
+                    get_buffer,
+                );
+                return None;
+            }
+        }
+    }
+    Some(listeners)
+}
+
+pub fn write_error(err: &impl ToString, get_buffer: extern "C" fn(usize) -> *mut u8) {
+    let err = err.to_string();
+    let buffer = get_buffer(err.len());
+    let buffer = ptr::slice_from_raw_parts_mut(buffer, err.len());
+    let buffer = unsafe { &mut *buffer };
+    buffer.copy_from_slice(err.as_bytes());
+}
+
 #[allow(non_snake_case)]
 #[no_mangle]
 pub extern "C" fn netbase_cache_DESTROY(p: *mut CCache) -> u8 {
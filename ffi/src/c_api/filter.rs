@@ -0,0 +1,104 @@
+use crate::c_api::ip::CIpAddr;
+use crate::c_api::name::CName;
+use crate::client::OutcomeKind;
+use crate::client::Protocol;
+use crate::store::Filter;
+use std::ffi::c_void;
+use std::net::IpAddr;
+use trust_dns_client::rr::Name;
+use trust_dns_client::rr::RecordType;
+
+pub type CFilter = c_void;
+
+/// Constructs a filter matching every cache entry
+///
+/// Each setter narrows the filter down further.
+#[no_mangle]
+pub extern "C" fn netbase_filter_new(_class: *const i8) -> *mut CFilter {
+    Box::into_raw(Box::<Filter>::default()) as *mut CFilter
+}
+
+#[no_mangle]
+pub extern "C" fn netbase_filter_set_qname(this: *mut CFilter, qname: *const CName) {
+    let this = unsafe { &mut *(this as *mut Filter) };
+    let qname = unsafe { &*(qname as *const Name) };
+    this.qname = Some(qname.clone());
+}
+
+/// Matches the given name and all names below it
+#[no_mangle]
+pub extern "C" fn netbase_filter_set_qname_suffix(this: *mut CFilter, suffix: *const CName) {
+    let this = unsafe { &mut *(this as *mut Filter) };
+    let suffix = unsafe { &*(suffix as *const Name) };
+    this.qname_suffix = Some(suffix.clone());
+}
+
+#[no_mangle]
+pub extern "C" fn netbase_filter_set_qtype(this: *mut CFilter, qtype: u16) {
+    let this = unsafe { &mut *(this as *mut Filter) };
+    this.qtype = Some(RecordType::from(qtype));
+}
+
+/// Returns zero if `proto` is not a valid protocol
+#[no_mangle]
+pub extern "C" fn netbase_filter_set_proto(this: *mut CFilter, proto: u8) -> u8 {
+    let this = unsafe { &mut *(this as *mut Filter) };
+    match Protocol::try_from(proto) {
+        Ok(proto) => {
+            this.proto = Some(proto);
+            1
+        }
+        Err(()) => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn netbase_filter_set_server(this: *mut CFilter, server: *const CIpAddr) {
+    let this = unsafe { &mut *(this as *mut Filter) };
+    let server = unsafe { *(server as *const IpAddr) };
+    this.server = Some(server);
+}
+
+/// Matches entries whose latest outcome has the given error kind, or zero for a response
+///
+/// Returns zero if `outcome` is not a valid error kind
+#[no_mangle]
+pub extern "C" fn netbase_filter_set_outcome(this: *mut CFilter, outcome: u16) -> u8 {
+    let this = unsafe { &mut *(this as *mut Filter) };
+    match OutcomeKind::try_from(outcome) {
+        Ok(outcome) => {
+            this.outcome = Some(outcome);
+            1
+        }
+        Err(()) => 0,
+    }
+}
+
+/// Matches entries whose latest outcome is a response with the given response code
+#[no_mangle]
+pub extern "C" fn netbase_filter_set_rcode(this: *mut CFilter, rcode: u16) {
+    let this = unsafe { &mut *(this as *mut Filter) };
+    this.rcode = Some(rcode.into());
+}
+
+/// Matches entries whose latest request started at or after the given time (milliseconds since
+/// the Unix epoch)
+#[no_mangle]
+pub extern "C" fn netbase_filter_set_started_from(this: *mut CFilter, from: u64) {
+    let this = unsafe { &mut *(this as *mut Filter) };
+    this.started_from = Some(from);
+}
+
+/// Matches entries whose latest request started before the given time (milliseconds since the
+/// Unix epoch)
+#[no_mangle]
+pub extern "C" fn netbase_filter_set_started_until(this: *mut CFilter, until: u64) {
+    let this = unsafe { &mut *(this as *mut Filter) };
+    this.started_until = Some(until);
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn netbase_filter_DESTROY(p: *mut CFilter) {
+    unsafe { drop(Box::from_raw(p as *mut Filter)) };
+}
@@ -22,7 +22,7 @@ pub extern "C" fn netbase_ip_new(_class: *const i8, ip: *const i8) -> *mut CIpAd
 #[no_mangle]
 pub extern "C" fn netbase_ip_to_string(ip: *mut CIpAddr) -> *const i8 {
     thread_local!(
-        static KEEP: RefCell<Option<CString>> = RefCell::new(None);
+        static KEEP: RefCell<Option<CString>> = const { RefCell::new(None) };
     );
 
     let ip = unsafe { &*(ip as *mut IpAddr) };
@@ -2,20 +2,20 @@ use crate::trust_dns_ext::MessageExt;
 use std::cell::RefCell;
 use std::ffi::c_void;
 use std::ffi::CString;
-use std::rc::Rc;
+use std::sync::Arc;
 use trust_dns_client::op::Message;
 
 pub type CMessage = c_void;
 
 #[no_mangle]
 pub extern "C" fn netbase_message_new(_class: *const i8) -> *mut CMessage {
-    Rc::into_raw(Rc::new(Message::new())) as *mut CMessage
+    Arc::into_raw(Arc::new(Message::new())) as *mut CMessage
 }
 
 #[no_mangle]
 pub extern "C" fn netbase_message_to_string(this: *mut CMessage) -> *const i8 {
     thread_local!(
-        static KEEP: RefCell<Option<CString>> = RefCell::new(None);
+        static KEEP: RefCell<Option<CString>> = const { RefCell::new(None) };
     );
 
     let this = unsafe { &*(this as *mut Message) };
@@ -31,5 +31,5 @@ pub extern "C" fn netbase_message_to_string(this: *mut CMessage) -> *const i8 {
 #[allow(non_snake_case)]
 #[no_mangle]
 pub extern "C" fn netbase_message_DESTROY(p: *mut CMessage) {
-    unsafe { drop(Rc::from_raw(p as *mut Message)) };
+    unsafe { drop(Arc::from_raw(p as *mut Message)) };
 }
@@ -1,6 +1,47 @@
+//! The C API.
+//!
+//! # Thread safety
+//!
+//! Every object handed out by the API may be used from, and destroyed on, any thread. The rules
+//! for calling into the API from several threads at once are:
+//!
+//! * Caches are locked internally, so every `netbase_cache_*` function may be called
+//!   concurrently for the same cache, except for `netbase_cache_DESTROY`. Readers share the lock.
+//!   Lookups only hold it while reading and recording responses, never while waiting for the
+//!   network, so concurrent lookups don't wait for each other's requests. Functions that both
+//!   read and write a cache, like `netbase_cache_merge` and `netbase_cache_prune`, are not atomic
+//!   with regard to concurrent writers. The same goes for the base caches of
+//!   `netbase_cache_add_base`, which are shared with the caches layered over them.
+//! * Nets may be used by `netbase_net_lookup` and `netbase_cache_lookup` concurrently, and their
+//!   manual clocks advanced with `netbase_net_advance_clock`.
+//! * Scripted transports may be used by `netbase_cache_lookup` and
+//!   `netbase_scripted_transport_push` concurrently.
+//! * Queues may be used by `netbase_cache_submit`, `netbase_queue_drain` and the other
+//!   `netbase_queue_*` functions concurrently. A queue may be destroyed while its lookups are still
+//!   running; their outcomes are then discarded.
+//! * Cancellation tokens may be used concurrently. `netbase_cancel_token_cancel` must not be
+//!   called from a signal handler; `netbase_cancel_token_cancel_on_signal` is for that.
+//! * Filters, questions and zones are plain values. Their setters (`netbase_filter_set_*`,
+//!   `netbase_question_set_edns` and `netbase_zones_add`) need exclusive access, while any other
+//!   use may be concurrent.
+//! * IP addresses, names and messages are immutable.
+//! * The strings returned by the `*_to_string` functions are kept per thread and stay valid until
+//!   the next call to the same function on the same thread.
+//! * An object must not be destroyed with its `*_DESTROY` function while any other call is
+//!   using it. Objects that a cache or a lookup holds on to, like the net of a lookup strategy or
+//!   a base cache, are reference counted and outlive their handles as needed.
+//! * Callbacks are called on the calling thread, during the call that was given them. Lookups
+//!   submitted with `netbase_cache_submit` run as tasks on the runtime of their net, but their
+//!   outcomes are only handed over by `netbase_queue_drain`.
+mod cancel;
 mod client;
+mod filter;
 mod ip;
 mod message;
 mod name;
 mod net;
 mod question;
+mod queue;
+mod scripted;
+mod transport;
+mod zones;
@@ -29,7 +29,7 @@ pub extern "C" fn netbase_name_from_ascii(_class: *const i8, name: *mut i8) -> *
 #[no_mangle]
 pub extern "C" fn netbase_name_to_string(this: *mut CName) -> *const i8 {
     thread_local!(
-        static KEEP: RefCell<Option<CString>> = RefCell::new(None);
+        static KEEP: RefCell<Option<CString>> = const { RefCell::new(None) };
     );
 
     let this = unsafe { &*(this as *mut Name) };
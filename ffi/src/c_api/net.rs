@@ -1,17 +1,36 @@
 use crate::c_api::ip::CIpAddr;
 use crate::c_api::question::CQuestion;
+use crate::c_api::transport;
+use crate::c_api::transport::CTransport;
+use crate::cancel::Cancellation;
 use crate::client::ErrorKind;
 use crate::client::Net;
 use crate::client::Question;
-use std::ffi::c_void;
+use crate::clock::Clock;
 use std::net::IpAddr;
 use std::net::SocketAddr;
 use std::ptr;
-use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::runtime::Runtime;
+use tokio::sync::Semaphore;
 
-pub type CNet = c_void;
-
+/// Constructs a net that queries servers on port 53
+///
+/// The net is a transport for `netbase_cache_lookup` and its relatives.
+///
+/// # Arguments
+/// * `bind_addr` - The local address to send queries from
+/// * `timeout` - How long to wait for each attempt (milliseconds)
+/// * `retry` - How many attempts to make at most for each query
+/// * `retrans` - How long to wait between attempts (milliseconds)
+/// * `max_in_flight` - How many lookups may be in flight at once, or zero for no limit
+/// * `clock` - The clock used for timestamps and retry sleeps: `1` for the system clock, `2` for
+///   a clock fixed at `now` and `3` for a manual clock starting at `now`. Fixed and manual clocks
+///   don't actually sleep. A manual clock is moved up to the deadline of each query that times out.
+/// * `now` - When fixed and manual clocks start (milliseconds since the Unix epoch)
+///
+/// Returns a null pointer if `clock` is not a valid clock kind
 #[no_mangle]
 pub extern "C" fn netbase_net_new(
     _class: *const i8,
@@ -19,43 +38,56 @@ pub extern "C" fn netbase_net_new(
     timeout: u32,
     retry: u16,
     retrans: u32,
-) -> *mut CNet {
+    max_in_flight: u32,
+    clock: u8,
+    now: u64,
+) -> *mut CTransport {
+    let clock = match clock {
+        1 => Clock::Real,
+        2 => Clock::Fixed(now),
+        3 => Clock::manual(now),
+        _ => return ptr::null_mut(),
+    };
     let bind_addr = unsafe { *(bind_addr as *const IpAddr) };
     let bind_addr = SocketAddr::new(bind_addr, 0);
     let runtime = Runtime::new().unwrap();
-    let net = Rc::new(Net {
+    let net = Arc::new(Net {
         bind_addr,
+        port: 53,
         timeout,
         retry,
         retrans,
         runtime,
+        clock,
+        in_flight: (max_in_flight > 0).then(|| Semaphore::new(max_in_flight as usize)),
     });
-    Rc::into_raw(net) as *mut CNet
+    transport::into_handle(net)
 }
 
 #[no_mangle]
 pub extern "C" fn netbase_net_lookup(
-    net: *mut CNet,
+    net: *const CTransport,
     question: *const CQuestion,
     server: *const CIpAddr,
     query_start: *mut u64,
     query_duration: *mut u32,
     get_buffer: extern "C" fn(usize) -> *mut u8,
 ) -> u16 {
-    let net = unsafe { &mut *(net as *mut Net) };
+    let net = unsafe { transport::from_handle(net) };
     let server = unsafe { *(server as *const IpAddr) };
     let question = unsafe { &*(question as *const Question) };
 
     let runtime = Runtime::new().unwrap();
     let _guard = runtime.enter();
-    let (_, start, duration, res) = runtime.block_on(net.lookup(question.clone(), server));
+    let never = Cancellation::default();
+    let lookup = runtime.block_on(net.lookup(question.clone(), server, &never, None));
     unsafe {
-        *query_start = start;
+        *query_start = lookup.started;
     };
     unsafe {
-        *query_duration = duration;
+        *query_duration = lookup.duration;
     }
-    match res {
+    match lookup.outcome {
         Ok(bytes) => {
             let buf = get_buffer(bytes.len());
             let buf = ptr::slice_from_raw_parts_mut(buf, bytes.len());
@@ -67,9 +99,17 @@ pub extern "C" fn netbase_net_lookup(
     }
 }
 
+/// Moves a manual clock forward by the given number of milliseconds
+///
+/// Other clocks are left as they are.
+#[no_mangle]
+pub extern "C" fn netbase_net_advance_clock(net: *const CTransport, millis: u64) {
+    let net = unsafe { transport::from_handle(net) };
+    net.clock().advance(Duration::from_millis(millis));
+}
+
 #[allow(non_snake_case)]
 #[no_mangle]
-pub extern "C" fn netbase_net_DESTROY(net: *mut CNet) {
-    let net = unsafe { Rc::from_raw(net as *mut Net) };
-    drop(net);
+pub extern "C" fn netbase_net_DESTROY(net: *mut CTransport) {
+    unsafe { transport::drop_handle(net) };
 }
@@ -69,7 +69,7 @@ pub extern "C" fn netbase_question_set_edns(
 #[no_mangle]
 pub extern "C" fn netbase_question_to_string(this: *mut CQuestion) -> *const i8 {
     thread_local!(
-        static KEEP: RefCell<Option<CString>> = RefCell::new(None);
+        static KEEP: RefCell<Option<CString>> = const { RefCell::new(None) };
     );
 
     let this = unsafe { &*(this as *mut Question) };
@@ -0,0 +1,119 @@
+use crate::c_api::client::outcome_args;
+use crate::c_api::client::write_error;
+use crate::c_api::ip::CIpAddr;
+use crate::c_api::message::CMessage;
+use crate::queue::LookupQueue;
+use std::ffi::c_char;
+use std::ffi::c_void;
+use std::ffi::CString;
+use std::panic;
+use std::ptr;
+
+pub type CQueue = c_void;
+
+/// Constructs a queue for lookups running in the background
+///
+/// Lookups are submitted with `netbase_cache_submit`.
+///
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer.
+/// * If the callback is not called and the returned value is a null pointer, this means that a
+///   panic was caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_queue_new(
+    _class: *const i8,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> *mut CQueue {
+    let result = panic::catch_unwind(|| match LookupQueue::new() {
+        Ok(queue) => Box::into_raw(Box::new(queue)) as *mut CQueue,
+        Err(err) => {
+            write_error(&err, get_buffer);
+            ptr::null_mut()
+        }
+    });
+    result.unwrap_or(ptr::null_mut())
+}
+
+/// Gets a file descriptor that is readable while there are completed lookups to drain
+///
+/// The file descriptor belongs to the queue. It must only be polled, never read or closed.
+#[no_mangle]
+pub extern "C" fn netbase_queue_fd(this: *const CQueue) -> i32 {
+    let this = unsafe { &*(this as *const LookupQueue) };
+    this.fd()
+}
+
+/// Gets the number of lookups submitted and not yet drained
+#[no_mangle]
+pub extern "C" fn netbase_queue_pending(this: *const CQueue) -> u64 {
+    let this = unsafe { &*(this as *const LookupQueue) };
+    this.pending()
+}
+
+/// Collects the completed lookups without waiting for more
+///
+/// # Arguments
+/// * `handle_outcome` - A callback to be called with an outcome for each server of a completed
+///   lookup. Its arguments are:
+///   * `id` - The ID that `netbase_cache_submit` returned for the lookup
+///   * the remaining arguments are the same as those of the `handle_outcome` callback of
+///     `netbase_cache_lookup`
+/// * `handle_completion` - A callback to be called once for each completed lookup, after the
+///   outcomes of its servers, in the order the lookups completed. Its arguments are:
+///   * `id` - The ID of the lookup
+///   * `error` - Why the lookup failed as a whole as a null terminated string, e.g. a miss with
+///     strategy `5`, or null if it didn't. Only valid during the call.
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer.
+/// * If the callback is not called and a zero value is returned, this means that a panic was
+///   caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_queue_drain(
+    this: *const CQueue,
+    handle_outcome: extern "C" fn(u64, *mut CIpAddr, u64, u32, u32, u32, u16, u16, *mut CMessage),
+    handle_completion: extern "C" fn(u64, *const c_char),
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let this = unsafe { &*(this as *const LookupQueue) };
+        let completions = match this.drain() {
+            Ok(completions) => completions,
+            Err(err) => {
+                write_error(&err, get_buffer);
+                return 0;
+            }
+        };
+        for completion in completions {
+            let id = completion.id;
+            match completion.result {
+                Ok(responses) => {
+                    for (server, response) in responses {
+                        let server = Box::into_raw(Box::new(server)) as *mut CIpAddr;
+                        let (started, duration, connect, query, err_kind, size, message) =
+                            outcome_args(response);
+                        handle_outcome(
+                            id, server, started, duration, connect, query, err_kind, size, message,
+                        );
+                    }
+                    handle_completion(id, ptr::null());
+                }
+                Err(err) => {
+                    let err = CString::new(err).unwrap_or_default();
+                    handle_completion(id, err.as_ptr());
+                }
+            }
+        }
+        1
+    })
+    .unwrap_or(0)
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn netbase_queue_DESTROY(p: *mut CQueue) {
+    unsafe { drop(Box::from_raw(p as *mut LookupQueue)) };
+}
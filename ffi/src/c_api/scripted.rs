@@ -0,0 +1,115 @@
+use crate::c_api::client::write_error;
+use crate::c_api::ip::CIpAddr;
+use crate::c_api::question::CQuestion;
+use crate::c_api::transport;
+use crate::c_api::transport::CTransport;
+use crate::client::Question;
+use crate::clock::Clock;
+use crate::transport::scripted::Attempt;
+use crate::transport::scripted::ScriptedTransport;
+use std::any::Any;
+use std::io;
+use std::net::IpAddr;
+use std::panic;
+use std::ptr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Constructs a transport that plays out scripted attempts instead of sending anything
+///
+/// It is given to `netbase_cache_lookup` and its relatives in place of a net. Its clock is a
+/// manual clock starting at `now` (milliseconds since the Unix epoch), moved forward by the
+/// scripted delays, so lookups return right away.
+///
+/// # Arguments
+/// * `retry` - How many attempts to make at most for each query
+/// * `retrans` - How long to wait between attempts (milliseconds)
+/// * `now` - When the clock starts (milliseconds since the Unix epoch)
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer.
+/// * If the callback is not called and the returned value is a null pointer, this means that a
+///   panic was caught and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_scripted_transport_new(
+    _class: *const i8,
+    retry: u16,
+    retrans: u32,
+    now: u64,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> *mut CTransport {
+    let result = panic::catch_unwind(|| match ScriptedTransport::new(retry, retrans) {
+        Ok(mut scripted) => {
+            scripted.clock = Clock::manual(now);
+            transport::into_handle(Arc::new(scripted))
+        }
+        Err(err) => {
+            write_error(&err, get_buffer);
+            ptr::null_mut()
+        }
+    });
+    result.unwrap_or(ptr::null_mut())
+}
+
+/// Appends an attempt to the script for a question and server address
+///
+/// Attempts are used up in order. Once there are none left, attempts time out.
+///
+/// # Arguments
+/// * `question` - The question of the attempt
+/// * `server` - The server address of the attempt
+/// * `delay` - How long the attempt takes (milliseconds)
+/// * `kind` - What happens:
+///   * `1` - The server responds with `message`
+///   * `2` - The server responds with `message` with the TC flag set
+///   * `3` - The server responds with `message`, which isn't a valid message
+///   * `4` - The attempt times out
+///   * `5` - The server refuses the connection
+/// * `message` - A pointer to the response for kinds `1` to `3`, otherwise ignored
+/// * `message_len` - The length of the response
+///
+/// Returns zero if `kind` is not a valid kind of attempt or `this` is not a scripted transport
+#[no_mangle]
+pub extern "C" fn netbase_scripted_transport_push(
+    this: *const CTransport,
+    question: *const CQuestion,
+    server: *const CIpAddr,
+    delay: u64,
+    kind: u8,
+    message: *const u8,
+    message_len: usize,
+) -> u8 {
+    let this: &dyn Any = unsafe { &**transport::from_handle(this) };
+    let Some(this) = this.downcast_ref::<ScriptedTransport>() else {
+        return 0;
+    };
+    let question = unsafe { &*(question as *const Question) };
+    let server = unsafe { *(server as *const IpAddr) };
+    let message = || {
+        let message = ptr::slice_from_raw_parts(message, message_len);
+        unsafe { &*message }.to_vec()
+    };
+    let attempt = match kind {
+        1 => Attempt::Respond(message()),
+        2 => Attempt::Truncate(message()),
+        3 => Attempt::Malformed(message()),
+        4 => Attempt::Timeout,
+        5 => Attempt::Io(io::ErrorKind::ConnectionRefused),
+        _ => return 0,
+    };
+    this.push(
+        question.clone(),
+        server,
+        Duration::from_millis(delay),
+        attempt,
+    );
+    1
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn netbase_scripted_transport_DESTROY(p: *mut CTransport) {
+    unsafe { transport::drop_handle(p) };
+}
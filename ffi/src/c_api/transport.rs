@@ -0,0 +1,29 @@
+use crate::transport::Transport;
+use std::ffi::c_void;
+use std::sync::Arc;
+
+/// A transport of any kind, as returned by `netbase_net_new` and `netbase_scripted_transport_new`
+///
+/// It points to a boxed `Arc<dyn Transport>`, so that lookups take one transport argument
+/// whatever its kind.
+pub type CTransport = c_void;
+
+pub fn into_handle(transport: Arc<dyn Transport>) -> *mut CTransport {
+    Box::into_raw(Box::new(transport)) as *mut CTransport
+}
+
+/// Borrows the transport of a handle.
+///
+/// # Safety
+/// `handle` must have been returned by `into_handle` and not been destroyed.
+pub unsafe fn from_handle<'a>(handle: *const CTransport) -> &'a Arc<dyn Transport> {
+    &*(handle as *const Arc<dyn Transport>)
+}
+
+/// Destroys a handle, dropping its reference to the transport.
+///
+/// # Safety
+/// `handle` must have been returned by `into_handle` and not been destroyed.
+pub unsafe fn drop_handle(handle: *mut CTransport) {
+    drop(Box::from_raw(handle as *mut Arc<dyn Transport>));
+}
@@ -0,0 +1,74 @@
+use crate::c_api::client::write_error;
+use crate::c_api::ip::CIpAddr;
+use crate::c_api::name::CName;
+use crate::zone::Zone;
+use crate::zone::Zones;
+use std::ffi::c_void;
+use std::net::IpAddr;
+use std::panic;
+use std::ptr;
+use trust_dns_client::rr::Name;
+
+pub type CZones = c_void;
+
+/// Constructs a set of zones without any servers
+#[no_mangle]
+pub extern "C" fn netbase_zones_new(_class: *const i8) -> *mut CZones {
+    Box::into_raw(Box::<Zones>::default()) as *mut CZones
+}
+
+/// Adds a zone in master file format to those served by a server
+///
+/// # Arguments
+/// * `server` - The address of the server serving the zone
+/// * `text` - A pointer to the zone file contents
+/// * `size` - The length of the zone file in bytes
+/// * `origin` - The origin of the zone, or null to take it from an `$ORIGIN` directive
+/// * `get_buffer` - A callback for getting an error message buffer of required (non-zero) size.
+///
+/// # Errors
+/// * If the `get_buffer` callback is called this means an error occurred and that details are
+///   found in the buffer.
+/// * If a zero value is returned and `get_buffer` is not called, this means that a panic was caught
+///   and the function returned abnormally.
+#[no_mangle]
+pub extern "C" fn netbase_zones_add(
+    this: *mut CZones,
+    server: *const CIpAddr,
+    text: *const u8,
+    size: usize,
+    origin: *const CName,
+    get_buffer: extern "C" fn(usize) -> *mut u8,
+) -> u8 {
+    panic::catch_unwind(|| {
+        let this = unsafe { &mut *(this as *mut Zones) };
+        let server = unsafe { *(server as *const IpAddr) };
+        let text = ptr::slice_from_raw_parts(text, size);
+        let text = unsafe { &*text };
+        let origin = if origin.is_null() {
+            None
+        } else {
+            Some(unsafe { &*(origin as *const Name) }.clone())
+        };
+        let zone = std::str::from_utf8(text)
+            .map_err(|err| err.to_string())
+            .and_then(|text| Zone::parse(text, origin).map_err(|err| err.to_string()));
+        match zone {
+            Ok(zone) => {
+                this.add(server, zone);
+                1
+            }
+            Err(err) => {
+                write_error(&err, get_buffer);
+                0
+            }
+        }
+    })
+    .unwrap_or(0)
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn netbase_zones_DESTROY(p: *mut CZones) {
+    unsafe { drop(Box::from_raw(p as *mut Zones)) };
+}
@@ -0,0 +1,161 @@
+//! Giving up on lookups before their queries are done.
+//!
+//! A lookup can be stopped by a cancellation token, which may be triggered from any thread, and by
+//! a deadline for the lookup as a whole. Queries still in flight are then recorded as cancelled.
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::sync::Weak;
+use std::thread;
+use std::time::Duration;
+use tokio::runtime::Builder;
+use tokio::signal::unix::signal;
+use tokio::signal::unix::SignalKind;
+use tokio::sync::Notify;
+use tokio::time;
+use tokio::time::Instant;
+
+/// The tokens to trigger for each signal with a thread handling it.
+///
+/// A signal can't be handed back to its default action once it is handled, so each thread lives
+/// as long as the process. Tokens are held weakly and forgotten once they are dropped.
+static SIGNAL_TOKENS: Mutex<BTreeMap<i32, Vec<Weak<Flag>>>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Default)]
+struct Flag {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+/// A flag that stops the lookups it is given to once it is set.
+///
+/// Clones share the same flag. Lookups waiting on it are woken up as soon as it is set.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    flag: Arc<Flag>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// Sets the flag and wakes up the lookups waiting on it.
+    ///
+    /// This takes a lock, so it must not be called from a signal handler. Use `cancel_on_signal`
+    /// for that.
+    pub fn cancel(&self) {
+        self.flag.cancelled.store(true, Ordering::SeqCst);
+        self.flag.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Triggers the token whenever the process receives a signal.
+    ///
+    /// This replaces the default action of the signal, e.g. terminating the process on `SIGINT`,
+    /// for the rest of the life of the process. Each signal is handled by a single thread,
+    /// however many tokens it triggers and however many times this is called.
+    pub fn cancel_on_signal(&self, signum: i32) -> io::Result<()> {
+        let mut signal_tokens = SIGNAL_TOKENS.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(tokens) = signal_tokens.get_mut(&signum) {
+            tokens.retain(|token| token.strong_count() > 0);
+            if !tokens
+                .iter()
+                .any(|token| token.as_ptr() == Arc::as_ptr(&self.flag))
+            {
+                tokens.push(Arc::downgrade(&self.flag));
+            }
+            return Ok(());
+        }
+
+        let runtime = Builder::new_current_thread().enable_io().build()?;
+        let mut signals = {
+            let _guard = runtime.enter();
+            signal(SignalKind::from_raw(signum))?
+        };
+        thread::Builder::new()
+            .name(format!("netbase-signal-{}", signum))
+            .spawn(move || {
+                runtime.block_on(async {
+                    while signals.recv().await.is_some() {
+                        let signal_tokens =
+                            SIGNAL_TOKENS.lock().unwrap_or_else(PoisonError::into_inner);
+                        let tokens = signal_tokens.get(&signum).into_iter().flatten();
+                        for flag in tokens.filter_map(Weak::upgrade) {
+                            CancelToken { flag }.cancel();
+                        }
+                    }
+                })
+            })?;
+        signal_tokens.insert(signum, vec![Arc::downgrade(&self.flag)]);
+        Ok(())
+    }
+
+    /// Waits until the token is triggered.
+    async fn cancelled(&self) {
+        // Notified futures are woken by `notify_waiters` from the moment they are created, so
+        // setting the flag after it is checked still wakes this one up.
+        let notified = self.flag.notify.notified();
+        if self.is_cancelled() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// What stops a lookup before its queries are done, if anything.
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation {
+    pub token: Option<CancelToken>,
+    /// How long the queries of the lookup may take in all, in real time even if the transport
+    /// has a manual clock
+    pub deadline: Option<Duration>,
+}
+
+impl Cancellation {
+    /// When the deadline passes for a lookup starting now.
+    pub fn expiry(&self) -> Option<Instant> {
+        self.deadline.map(|deadline| Instant::now() + deadline)
+    }
+
+    /// Waits until the token is triggered or `expiry` passes, whichever comes first.
+    pub async fn stopped(&self, expiry: Option<Instant>) {
+        let cancelled = async {
+            match &self.token {
+                Some(token) => token.cancelled().await,
+                None => futures::future::pending().await,
+            }
+        };
+        let expired = async {
+            match expiry {
+                Some(expiry) => time::sleep_until(expiry).await,
+                None => futures::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = cancelled => {}
+            _ = expired => {}
+        }
+    }
+
+    /// Runs `future` to completion, unless the lookup is stopped first as by `stopped`.
+    pub async fn unless_stopped<F: Future>(
+        &self,
+        expiry: Option<Instant>,
+        future: F,
+    ) -> Option<F::Output> {
+        tokio::select! {
+            biased;
+            _ = self.stopped(expiry) => None,
+            output = future => Some(output),
+        }
+    }
+}
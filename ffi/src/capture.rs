@@ -0,0 +1,407 @@
+//! Conversion between caches and logs of DNS traffic.
+//!
+//! Exporting flattens a cache into the individual exchanges it records. Importing pairs logged
+//! queries and responses up into exchanges and groups them into requests.
+use crate::client::request_order;
+use crate::client::Cache;
+use crate::client::EdnsConfig;
+use crate::client::ErrorKind;
+use crate::client::Failure;
+use crate::client::Protocol;
+use crate::client::Question;
+use crate::client::RetriedResponse;
+use crate::store::MemoryStore;
+use crate::store::Store;
+use crate::store::StoreError;
+use crate::trust_dns_ext::MyMessage;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use trust_dns_client::op::Message;
+use trust_dns_client::op::MessageType;
+use trust_dns_proto::error::ProtoError;
+use trust_dns_proto::xfer::DnsRequest;
+
+pub const DNS_PORT: u16 = 53;
+/// Client ports are assigned from the dynamic port range in the order of the requests.
+const FIRST_CLIENT_PORT: u16 = 49152;
+/// How long after an unanswered attempt a retry is still taken for part of the same request.
+pub const RETRY_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum CaptureError {
+    Format(String),
+    Proto(ProtoError),
+    Store(StoreError),
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CaptureError::Format(reason) => write!(f, "invalid capture: {}", reason),
+            CaptureError::Proto(err) => write!(f, "dns error: {}", err),
+            CaptureError::Store(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {}
+
+impl From<ProtoError> for CaptureError {
+    fn from(err: ProtoError) -> Self {
+        CaptureError::Proto(err)
+    }
+}
+
+impl From<StoreError> for CaptureError {
+    fn from(err: StoreError) -> Self {
+        CaptureError::Store(err)
+    }
+}
+
+/// A recorded request with synthetic client and server socket addresses.
+pub struct Request {
+    pub question: Question,
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    pub response: Arc<RetriedResponse>,
+}
+
+/// A query sent by an attempt of a request.
+pub struct Sent {
+    /// Micros since epoch
+    pub started: u64,
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+    pub query: Vec<u8>,
+}
+
+impl Request {
+    /// The queries of all attempts, the last of which got the answer if there is one.
+    ///
+    /// Attempts recorded with their wire details keep the query as sent and the addresses it was
+    /// sent from and answered from, falling back to the synthetic addresses of the request for
+    /// what wasn't recorded. A client bound to the unspecified address keeps its recorded port.
+    /// Other attempts send the query re-encoded from the question using the ID of the response,
+    /// or zero if there is none.
+    pub fn attempts(&self) -> Result<Vec<Sent>, ProtoError> {
+        let response = &self.response;
+        let attempts = response
+            .failures
+            .iter()
+            .map(|failure| (failure.query_start, &failure.wire))
+            .chain([(response.started, &response.wire)]);
+        let mut sent = Vec::new();
+        for (started, wire) in attempts {
+            let started = started * 1000;
+            sent.push(match wire {
+                Some(wire) => Sent {
+                    started,
+                    client: if wire.local.ip().is_unspecified() {
+                        SocketAddr::new(self.client.ip(), wire.local.port())
+                    } else {
+                        wire.local
+                    },
+                    server: wire.remote.unwrap_or(self.server),
+                    query: wire.query.clone(),
+                },
+                None => Sent {
+                    started,
+                    client: self.client,
+                    server: self.server,
+                    query: self.encode_query()?,
+                },
+            });
+        }
+        Ok(sent)
+    }
+
+    /// The received response message and when it was received (micros since epoch), if any.
+    ///
+    /// The time is only precise to the millisecond for responses recorded without micros.
+    pub fn answer(&self) -> Option<(u64, &[u8])> {
+        let response = &self.response;
+        let answer = response.outcome.as_ref().ok()?;
+        let duration = match response.query_micros {
+            0 => response.duration as u64 * 1000,
+            micros => micros as u64,
+        };
+        Some((
+            response.started * 1000 + duration,
+            answer.encoded.as_slice(),
+        ))
+    }
+
+    /// The query re-encoded from the question using the ID of the response, or zero if there is
+    /// none.
+    fn encode_query(&self) -> Result<Vec<u8>, ProtoError> {
+        let id = match self.answer() {
+            Some((_, answer)) if answer.len() >= 2 => u16::from_be_bytes([answer[0], answer[1]]),
+            _ => 0,
+        };
+        let mut request = DnsRequest::from(self.question.clone());
+        request.set_id(id);
+        request.to_vec()
+    }
+}
+
+/// Lists every request in a cache, ordered by qname, qtype and server and then by time.
+///
+/// Requests are sent from `client` to port 53 of their servers, each from a port of its own. For
+/// servers of the other address family than `client`, the unspecified address of their family is
+/// used as the client address instead.
+pub fn requests(cache: &Cache, client: IpAddr) -> Result<Vec<Request>, StoreError> {
+    let mut entries = cache.entries()?;
+    entries.sort_by_cached_key(|(question, server, _)| request_order(question, server));
+
+    let mut requests = Vec::new();
+    for (question, server, history) in entries {
+        let client = match (client, server) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => client,
+            (_, IpAddr::V4(_)) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            (_, IpAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        for response in history {
+            let port = FIRST_CLIENT_PORT
+                + (requests.len() % (u16::MAX - FIRST_CLIENT_PORT + 1) as usize) as u16;
+            requests.push(Request {
+                question: question.clone(),
+                client: SocketAddr::new(client, port),
+                server: SocketAddr::new(server, DNS_PORT),
+                response,
+            });
+        }
+    }
+    Ok(requests)
+}
+
+/// A logged DNS message sent from `src` to `dst`.
+pub struct DnsPacket {
+    /// Micros since epoch
+    pub timestamp: u64,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub proto: Protocol,
+    pub bytes: Vec<u8>,
+    /// For a response, when the query was sent (micros since epoch) and the query message, if
+    /// they were logged along with it.
+    pub query: Option<(u64, Option<Vec<u8>>)>,
+}
+
+/// A query and its response, if any.
+struct Exchange {
+    question: Question,
+    server: IpAddr,
+    /// Micros since epoch
+    sent: u64,
+    /// Micros since epoch
+    response: Option<(u64, Vec<u8>)>,
+}
+
+/// The question asked by a query message, if it has one.
+pub fn question_of(query: &Message, proto: Protocol) -> Option<Question> {
+    let query_section = query.queries().first()?;
+    let edns_config = query.extensions().as_ref().map(|edns| {
+        let mut options: Vec<_> = edns
+            .options()
+            .as_ref()
+            .iter()
+            .filter_map(|(code, option)| {
+                Some((u16::from(*code), Vec::<u8>::try_from(option).ok()?))
+            })
+            .collect();
+        options.sort();
+        let (option_code, option_value) = options.into_iter().next().unwrap_or_default();
+        EdnsConfig {
+            version: edns.version(),
+            dnssec_ok: edns.dnssec_ok(),
+            max_payload: edns.max_payload(),
+            option_code,
+            option_value,
+        }
+    });
+    Some(Question {
+        qname: query_section.name().clone(),
+        qtype: query_section.query_type(),
+        proto,
+        recursion_desired: query.recursion_desired(),
+        edns_config,
+    })
+}
+
+/// Builds a cache from logged DNS messages.
+///
+/// Queries are paired with responses by addresses, ports and message ID. Each query becomes an
+/// attempt of a request to its destination address. A response without a logged query becomes an
+/// attempt of its own if the time of its query is known. Its question is then taken from the query
+/// logged along with it, or else from the response itself.
+///
+/// Queries that got no response are recorded as timed out attempts. Consecutive attempts for the
+/// same question and server make up a single request, ending at the first attempt with a response
+/// or at an unanswered attempt that isn't retried within [`RETRY_WINDOW`]. The duration of a timed
+/// out attempt is the time until the next attempt, or zero for the last one of a request.
+pub fn to_cache(mut packets: Vec<DnsPacket>) -> Result<Cache, StoreError> {
+    packets.sort_by_key(|packet| packet.timestamp);
+
+    let mut exchanges: Vec<Exchange> = Vec::new();
+    let mut outstanding: HashMap<(SocketAddr, SocketAddr, Protocol, u16), usize> = HashMap::new();
+    for packet in packets {
+        if packet.bytes.len() < 12 {
+            continue;
+        }
+        let id = u16::from_be_bytes([packet.bytes[0], packet.bytes[1]]);
+        let is_response = packet.bytes[2] & 0x80 != 0;
+        if is_response {
+            let key = (packet.dst, packet.src, packet.proto, id);
+            if let Some(index) = outstanding.remove(&key) {
+                exchanges[index].response = Some((packet.timestamp, packet.bytes));
+            } else if let Some((sent, query)) = packet.query {
+                let question = query
+                    .and_then(|query| Message::from_vec(&query).ok())
+                    .or_else(|| Message::from_vec(&packet.bytes).ok())
+                    .and_then(|message| question_of(&message, packet.proto));
+                let Some(question) = question else {
+                    continue;
+                };
+                exchanges.push(Exchange {
+                    question,
+                    server: packet.src.ip(),
+                    sent,
+                    response: Some((packet.timestamp, packet.bytes)),
+                });
+            }
+        } else {
+            let Ok(query) = Message::from_vec(&packet.bytes) else {
+                continue;
+            };
+            if query.message_type() != MessageType::Query {
+                continue;
+            }
+            let Some(question) = question_of(&query, packet.proto) else {
+                continue;
+            };
+            outstanding.insert((packet.src, packet.dst, packet.proto, id), exchanges.len());
+            exchanges.push(Exchange {
+                question,
+                server: packet.dst.ip(),
+                sent: packet.timestamp,
+                response: None,
+            });
+        }
+    }
+    exchanges.sort_by_key(|exchange| exchange.sent);
+
+    let mut attempts: HashMap<(Question, IpAddr), Vec<Exchange>> = HashMap::new();
+    let mut keys = Vec::new();
+    for exchange in exchanges {
+        let key = (exchange.question.clone(), exchange.server);
+        if !attempts.contains_key(&key) {
+            keys.push(key.clone());
+        }
+        attempts.entry(key).or_default().push(exchange);
+    }
+    let mut entries = Vec::new();
+    for key in keys {
+        let exchanges = attempts.remove(&key).unwrap_or_default();
+        let (question, server) = key;
+        entries.push((question, server, history(exchanges)));
+    }
+
+    let mut store = MemoryStore::new();
+    store.extend(entries)?;
+    Ok(Cache::with_store(Box::new(store)))
+}
+
+/// Groups the attempts for a question and server into requests.
+fn history(exchanges: Vec<Exchange>) -> Vec<Arc<RetriedResponse>> {
+    let millis = |micros: u64| micros / 1000;
+    let micros = |micros: u64| micros.min(u32::MAX as u64) as u32;
+    let mut history = Vec::new();
+    let mut failures = Vec::new();
+    let window = RETRY_WINDOW.as_micros() as u64;
+    let mut exchanges = exchanges.into_iter().peekable();
+    while let Some(exchange) = exchanges.next() {
+        match exchange.response {
+            Some((received, bytes)) => history.push(Arc::new(RetriedResponse {
+                failures: std::mem::take(&mut failures),
+                started: millis(exchange.sent),
+                duration: millis(received.saturating_sub(exchange.sent)) as u32,
+                outcome: Ok(MyMessage::from_vec(bytes).0),
+                connect_micros: 0,
+                query_micros: micros(received.saturating_sub(exchange.sent)),
+                wire: None,
+            })),
+            None => match exchanges.peek() {
+                Some(next) if next.sent - exchange.sent <= window => failures.push(Failure {
+                    query_start: millis(exchange.sent),
+                    query_duration: millis(next.sent - exchange.sent) as u32,
+                    kind: ErrorKind::Timeout,
+                    query_micros: micros(next.sent - exchange.sent),
+                    wire: None,
+                }),
+                _ => history.push(Arc::new(RetriedResponse {
+                    failures: std::mem::take(&mut failures),
+                    started: millis(exchange.sent),
+                    duration: 0,
+                    outcome: Err(ErrorKind::Timeout),
+                    connect_micros: 0,
+                    query_micros: 0,
+                    wire: None,
+                })),
+            },
+        }
+    }
+    history
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::question;
+
+    fn query(id: u16) -> Vec<u8> {
+        let mut request = DnsRequest::from(question("example.com."));
+        request.set_id(id);
+        request.to_vec().unwrap()
+    }
+
+    #[test]
+    fn retry_window() {
+        let client: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        let server: SocketAddr = "192.0.2.53:53".parse().unwrap();
+        let packet = |timestamp: u64, from_client: bool, bytes: Vec<u8>| DnsPacket {
+            timestamp,
+            src: if from_client { client } else { server },
+            dst: if from_client { server } else { client },
+            proto: Protocol::Udp,
+            bytes,
+            query: None,
+        };
+        let mut response = query(3);
+        response[2] |= 0x80;
+        let window = RETRY_WINDOW.as_micros() as u64;
+        let packets = vec![
+            packet(0, true, query(1)),
+            packet(1_000_000, true, query(2)),
+            // Not a retry of the abandoned request
+            packet(1_000_001 + window, true, query(3)),
+            packet(1_000_002 + window, false, response),
+        ];
+
+        let cache = to_cache(packets).unwrap();
+        let history = cache
+            .history(&question("example.com."), &server.ip())
+            .unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].failures.len(), 1);
+        assert_eq!(history[0].started, 1_000);
+        assert_eq!(history[0].outcome, Err(ErrorKind::Timeout));
+        assert!(history[1].failures.is_empty());
+        assert!(history[1].outcome.is_ok());
+    }
+}
@@ -1,39 +1,64 @@
+use crate::cancel::Cancellation;
+use crate::clock::micros;
+use crate::clock::Clock;
+use crate::clock::Deadline;
+use crate::store;
+use crate::store::Entry;
+use crate::store::Filter;
+use crate::store::LayeredStore;
+use crate::store::Match;
+use crate::store::MemoryStore;
+use crate::store::SharedStore;
+use crate::store::SqliteStore;
+use crate::store::Store;
+use crate::store::StoreError;
+use crate::transport;
+use crate::transport::Lookup;
+use crate::transport::Transport;
 use crate::trust_dns_ext;
 use crate::trust_dns_ext::MyMessage;
-use futures_util::stream::StreamExt;
+use futures::future::BoxFuture;
+use futures_util::future::FutureExt;
 use rmp_serde as rmps;
 use serde::Deserialize;
 use serde::Serialize;
-use std::cell::Cell;
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt;
+use std::io;
 use std::net::IpAddr;
 use std::net::SocketAddr;
-use std::rc::Rc;
+use std::panic;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::RwLock;
 use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpSocket;
 use tokio::net::TcpStream;
 use tokio::net::UdpSocket;
-use tokio::runtime::Handle;
 use tokio::runtime::Runtime;
-use trust_dns_client::client::AsyncClient;
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
 use trust_dns_client::op::Edns;
+use trust_dns_client::op::Header;
 use trust_dns_client::op::Message;
+use trust_dns_client::op::MessageType;
 use trust_dns_client::op::Query;
-use trust_dns_client::proto::rr::dnssec::SigSigner;
+use trust_dns_client::op::ResponseCode;
 use trust_dns_client::rr::Name;
 use trust_dns_client::rr::RecordType;
-use trust_dns_client::tcp::TcpClientStream;
-use trust_dns_client::udp::UdpClientStream;
 use trust_dns_proto::error::ProtoError;
 use trust_dns_proto::error::ProtoErrorKind;
-use trust_dns_proto::iocompat::AsyncIoTokioAsStd;
-use trust_dns_proto::xfer::DnsMultiplexer;
-use trust_dns_proto::xfer::DnsMultiplexerConnect;
+use trust_dns_proto::serialize::binary::BinDecodable;
+use trust_dns_proto::serialize::binary::BinDecoder;
 use trust_dns_proto::xfer::DnsRequest;
-use trust_dns_proto::xfer::DnsResponse;
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
 pub enum Protocol {
     Udp,
     Tcp,
@@ -59,7 +84,7 @@ impl From<Protocol> for u8 {
     }
 }
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
 pub struct EdnsConfig {
     pub version: u8,
     pub dnssec_ok: bool,
@@ -80,6 +105,25 @@ pub struct Question {
     pub edns_config: Option<EdnsConfig>,
 }
 
+/// Questions are ordered by qname in canonical DNS order, then by qtype, protocol, whether
+/// recursion is desired and EDNS configuration.
+impl Ord for Question {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.qname
+            .cmp(&other.qname)
+            .then_with(|| u16::from(self.qtype).cmp(&u16::from(other.qtype)))
+            .then_with(|| self.proto.cmp(&other.proto))
+            .then_with(|| self.recursion_desired.cmp(&other.recursion_desired))
+            .then_with(|| self.edns_config.cmp(&other.edns_config))
+    }
+}
+
+impl PartialOrd for Question {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl From<Question> for DnsRequest {
     fn from(question: Question) -> DnsRequest {
         use trust_dns_client::op::MessageType;
@@ -123,13 +167,15 @@ impl From<Question> for DnsRequest {
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
 pub enum ErrorKind {
     Io,
     Timeout,
     Protocol,
     Internal,
-    Lock,
+    NotInCache,
+    /// The lookup was cancelled or ran past its deadline before a response was received
+    Cancelled,
 }
 
 impl From<&ProtoError> for ErrorKind {
@@ -137,6 +183,7 @@ impl From<&ProtoError> for ErrorKind {
         match err.kind() {
             ProtoErrorKind::Io(_) => ErrorKind::Io,
             ProtoErrorKind::Timeout => ErrorKind::Timeout,
+            ProtoErrorKind::Canceled(_) => ErrorKind::Cancelled,
             ProtoErrorKind::CharacterDataTooLong { .. }
             | ProtoErrorKind::IncorrectRDataLengthRead { .. } => ErrorKind::Protocol,
             _ => ErrorKind::Internal,
@@ -151,7 +198,9 @@ impl From<ErrorKind> for u16 {
             ErrorKind::Io => 2,
             ErrorKind::Protocol => 3,
             ErrorKind::Timeout => 4,
-            ErrorKind::Lock => 5,
+            // 5 was used for an error that no longer exists
+            ErrorKind::NotInCache => 6,
+            ErrorKind::Cancelled => 7,
         }
     }
 }
@@ -163,176 +212,775 @@ impl fmt::Display for ErrorKind {
             ErrorKind::Io => write!(f, "IO_ERROR"),
             ErrorKind::Protocol => write!(f, "PROTOCOL_ERROR"),
             ErrorKind::Timeout => write!(f, "TIMEOUT_ERROR"),
-            ErrorKind::Lock => write!(f, "LOCK_ERROR"),
+            ErrorKind::NotInCache => write!(f, "NOT_IN_CACHE_ERROR"),
+            ErrorKind::Cancelled => write!(f, "CANCELLED_ERROR"),
+        }
+    }
+}
+
+impl FromStr for ErrorKind {
+    type Err = ();
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "INTERNAL_ERROR" => Ok(ErrorKind::Internal),
+            "IO_ERROR" => Ok(ErrorKind::Io),
+            "PROTOCOL_ERROR" => Ok(ErrorKind::Protocol),
+            "TIMEOUT_ERROR" => Ok(ErrorKind::Timeout),
+            "NOT_IN_CACHE_ERROR" => Ok(ErrorKind::NotInCache),
+            "CANCELLED_ERROR" => Ok(ErrorKind::Cancelled),
+            _ => Err(()),
+        }
+    }
+}
+
+impl TryFrom<u16> for ErrorKind {
+    type Error = ();
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(ErrorKind::Internal),
+            2 => Ok(ErrorKind::Io),
+            3 => Ok(ErrorKind::Protocol),
+            4 => Ok(ErrorKind::Timeout),
+            6 => Ok(ErrorKind::NotInCache),
+            7 => Ok(ErrorKind::Cancelled),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Whether a request got a response or what kind of error occurred.
+///
+/// A response that can't be decoded counts as a protocol error.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum OutcomeKind {
+    Response,
+    Error(ErrorKind),
+}
+
+impl TryFrom<u16> for OutcomeKind {
+    type Error = ();
+    fn try_from(value: u16) -> Result<Self, ()> {
+        match value {
+            0 => Ok(OutcomeKind::Response),
+            value => ErrorKind::try_from(value).map(OutcomeKind::Error),
+        }
+    }
+}
+
+impl From<OutcomeKind> for u16 {
+    fn from(kind: OutcomeKind) -> Self {
+        match kind {
+            OutcomeKind::Response => 0,
+            OutcomeKind::Error(error_kind) => error_kind.into(),
+        }
+    }
+}
+
+impl fmt::Display for OutcomeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutcomeKind::Response => write!(f, "RESPONSE"),
+            OutcomeKind::Error(error_kind) => write!(f, "{}", error_kind),
+        }
+    }
+}
+
+/// How to resolve entries for the same question and server when merging caches.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MergePolicy {
+    /// Keep the entry whose latest response started first
+    Older,
+    /// Keep the entry whose latest response started last
+    Newer,
+    /// Keep the responses from both entries in the history
+    Both,
+}
+
+impl TryFrom<u8> for MergePolicy {
+    type Error = ();
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(MergePolicy::Older),
+            2 => Ok(MergePolicy::Newer),
+            3 => Ok(MergePolicy::Both),
+            _ => Err(()),
         }
     }
 }
 
 #[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
-struct RetriedResponse {
-    failures: Vec<Failure>,
+pub struct RetriedResponse {
+    pub failures: Vec<Failure>,
     /// Millis since epoch
-    started: u64,
+    pub started: u64,
     /// Millis
-    duration: u32,
-    outcome: Result<MyMessage, ErrorKind>,
+    pub duration: u32,
+    pub outcome: Result<MyMessage, ErrorKind>,
+    /// Micros spent connecting to the server, measured monotonically
+    #[serde(default)]
+    pub connect_micros: u32,
+    /// Micros spent on the final attempt, measured monotonically
+    #[serde(default)]
+    pub query_micros: u32,
+    #[serde(default)]
+    pub wire: Option<Wire>,
 }
 
+impl RetriedResponse {
+    pub fn outcome_kind(&self) -> OutcomeKind {
+        match &self.outcome {
+            Ok(MyMessage {
+                decoded: Some(_), ..
+            }) => OutcomeKind::Response,
+            Ok(MyMessage { decoded: None, .. }) => OutcomeKind::Error(ErrorKind::Protocol),
+            Err(error_kind) => OutcomeKind::Error(*error_kind),
+        }
+    }
+
+    /// The decoded response message, if any.
+    pub fn message(&self) -> Option<&Message> {
+        match &self.outcome {
+            Ok(MyMessage {
+                decoded: Some(message),
+                ..
+            }) => Some(message),
+            _ => None,
+        }
+    }
+
+    /// The response code of the decoded response message, if any.
+    pub fn rcode(&self) -> Option<ResponseCode> {
+        self.message().map(Message::response_code)
+    }
+}
+
+/// A sort key ordering (question, server) pairs by qname in canonical DNS order, qtype and server,
+/// and then by the rest of the question.
+pub fn request_order(question: &Question, server: &IpAddr) -> (Name, u16, IpAddr, Question) {
+    (
+        question.qname.clone(),
+        u16::from(question.qtype),
+        *server,
+        question.clone(),
+    )
+}
+
+/// How `Cache::for_each_request` orders requests.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RequestOrder {
+    /// By `request_order`
+    Name,
+    /// By the start of the first attempt of their oldest response, and then by `request_order`
+    FirstAttempt,
+}
+
+impl TryFrom<u8> for RequestOrder {
+    type Error = ();
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(RequestOrder::Name),
+            2 => Ok(RequestOrder::FirstAttempt),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct SingleResponse {
     /// Millis since epoch
     pub started: u64,
     /// Millis
     pub duration: u32,
-    pub outcome: Result<(Rc<Message>, u16), ErrorKind>,
+    pub connect_micros: u32,
+    pub query_micros: u32,
+    pub outcome: Result<(Arc<Message>, u16), ErrorKind>,
+}
+
+impl SingleResponse {
+    fn not_in_cache() -> Self {
+        SingleResponse {
+            started: 0,
+            duration: 0,
+            connect_micros: 0,
+            query_micros: 0,
+            outcome: Err(ErrorKind::NotInCache),
+        }
+    }
+}
+
+impl From<&RetriedResponse> for SingleResponse {
+    fn from(response: &RetriedResponse) -> Self {
+        SingleResponse {
+            started: response.started,
+            duration: response.duration,
+            connect_micros: response.connect_micros,
+            query_micros: response.query_micros,
+            outcome: match &response.outcome {
+                Ok(mymessage) => match mymessage.decoded {
+                    Some(ref message) => Ok((message.clone(), mymessage.encoded.len() as u16)),
+                    None => Err(ErrorKind::Protocol),
+                },
+                Err(error_kind) => Err(*error_kind),
+            },
+        }
+    }
+}
+
+/// What `Cache::lookup` does about servers without a cached response.
+#[derive(Clone)]
+pub enum MissStrategy {
+    /// Report them as `ErrorKind::NotInCache`
+    CacheOnly,
+    /// Query them
+    FillOnMiss(Arc<dyn Transport>),
+    /// Query every server, even those with a cached response
+    RefreshAlways(Arc<dyn Transport>),
+    /// Query them, as well as servers whose latest response started more than the given number
+    /// of milliseconds ago
+    RefreshIfOlderThan(Arc<dyn Transport>, u64),
+    /// Fail the lookup as a whole
+    FailOnMiss,
 }
 
-#[derive(Default, Deserialize, Serialize)]
+impl MissStrategy {
+    pub fn net(&self) -> Option<&Arc<dyn Transport>> {
+        match self {
+            MissStrategy::CacheOnly | MissStrategy::FailOnMiss => None,
+            MissStrategy::FillOnMiss(net)
+            | MissStrategy::RefreshAlways(net)
+            | MissStrategy::RefreshIfOlderThan(net, _) => Some(net),
+        }
+    }
+
+    /// Whether a cached response can be returned without querying the server again.
+    ///
+    /// Cancelled queries never are, since they didn't get to find anything out.
+    fn is_fresh(&self, response: &RetriedResponse) -> bool {
+        if response.outcome == Err(ErrorKind::Cancelled) {
+            return false;
+        }
+        match self {
+            MissStrategy::RefreshAlways(_) => false,
+            MissStrategy::RefreshIfOlderThan(net, max_age) => {
+                net.clock().now().saturating_sub(response.started) <= *max_age
+            }
+            _ => true,
+        }
+    }
+}
+
+/// The servers without a cached response in a lookup with `MissStrategy::FailOnMiss`.
+#[derive(Debug)]
+pub struct CacheMiss {
+    pub servers: Vec<IpAddr>,
+}
+
+impl fmt::Display for CacheMiss {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let servers: Vec<_> = self.servers.iter().map(ToString::to_string).collect();
+        write!(f, "not in cache: @{}", servers.join(", @"))
+    }
+}
+
+impl std::error::Error for CacheMiss {}
+
+/// A cache of responses, optionally layered over base caches.
+///
+/// Entries of the cache itself are kept in its own store and shadow those of its bases. Bases are
+/// only ever read, and only the entries of the cache itself are serialized.
+///
+/// A cache can be shared between threads. Its store is locked only while reading or writing
+/// entries, not while lookups wait for the network.
 pub struct Cache {
-    cache: HashMap<Question, HashMap<IpAddr, Rc<RetriedResponse>>>,
-    #[serde(skip)]
-    is_reading: Cell<bool>,
+    store: SharedStore,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache::new()
+    }
 }
 
 impl Cache {
     pub fn new() -> Self {
+        Cache::with_store(Box::new(MemoryStore::new()))
+    }
+
+    pub fn with_store(store: Box<dyn Store>) -> Self {
+        Cache {
+            store: Arc::new(RwLock::new(LayeredStore::new(store))),
+        }
+    }
+
+    /// Another handle to this cache, sharing its entries and bases rather than copying them.
+    pub fn share(&self) -> Cache {
         Cache {
-            cache: HashMap::new(),
-            is_reading: Cell::new(false),
+            store: self.store.clone(),
         }
     }
 
+    /// Adds a cache to consult for entries this cache doesn't have, after any bases added before.
+    ///
+    /// The base is read through rather than copied, along with its own bases. Returns false
+    /// without adding it if that would make this cache a base of itself.
+    pub fn add_base(&self, base: &Cache) -> bool {
+        LayeredStore::add_base(&self.store, base.store.clone())
+    }
+
+    /// Opens a cache backed by an SQLite database file, creating the file if it doesn't exist.
+    pub fn open_sqlite<P: AsRef<Path>>(path: P) -> Result<Cache, StoreError> {
+        Ok(Cache::with_store(Box::new(SqliteStore::open(path)?)))
+    }
+
+    /// Looks up responses to a question from a set of servers.
+    ///
+    /// Cached responses are returned unless the strategy says to refresh them. What happens with
+    /// the other servers is also up to the strategy. Responses of queries made over the network
+    /// are appended to the histories of their entries.
+    ///
+    /// Queries that `cancellation` gives up on are recorded with their unfinished attempt
+    /// failing with `ErrorKind::Cancelled`, after the attempts that failed before it.
     pub fn lookup(
-        &mut self,
-        net: Option<Rc<Net>>,
+        &self,
+        strategy: &MissStrategy,
         question: Question,
         servers: &HashSet<IpAddr>,
-    ) -> HashMap<IpAddr, SingleResponse> {
-        use futures::future;
-        use futures_util::future::FutureExt;
+        cancellation: &Cancellation,
+    ) -> Result<HashMap<IpAddr, SingleResponse>, CacheMiss> {
+        let mut result = None;
+        let requests = vec![(question, servers.clone())];
+        self.lookup_batch(strategy, requests, cancellation, |_, lookup| {
+            result = Some(lookup)
+        });
+        result.expect("every request of a batch completes")
+    }
 
-        let mut results = Vec::new();
-        match net {
-            None => results.extend(servers.iter().filter_map(|server| {
-                self.cache
-                    .get(&question)
-                    .and_then(|inner| inner.get(server))
-                    .cloned()
-                    .map(|response| (server, response))
-            })),
-            Some(ref net) => {
-                let question_bucket = self
-                    .cache
-                    .entry(question.clone())
-                    .or_insert_with(HashMap::new);
-                let mut queries = Vec::new();
-                for server in servers {
-                    if self.is_reading.get() {
-                        results.push((
-                            server,
-                            Rc::new(RetriedResponse {
-                                started: 0,
-                                duration: 0,
-                                outcome: Err(ErrorKind::Lock),
-                                failures: Vec::new(),
-                            }),
-                        ));
-                        continue;
-                    }
-                    if let Some(response) = question_bucket.get(server) {
-                        results.push((server, response.clone()));
-                    } else {
-                        queries.push(net.lookup(question.clone(), *server).map(
-                            move |(failures, started, duration, bytes)| {
-                                let outcome = match bytes {
-                                    Ok(bytes) => {
-                                        let (message, parse_err) = MyMessage::from_vec(bytes);
-                                        if let Some(parse_err) = parse_err {
-                                            Self::perror(started, &parse_err);
-                                        }
-                                        Ok(message)
-                                    }
-                                    Err(lookup_err) => {
-                                        Self::perror(started, &lookup_err);
-                                        Err((&lookup_err).into())
-                                    }
-                                };
-                                let response = Rc::new(RetriedResponse {
-                                    failures,
-                                    started,
-                                    duration,
-                                    outcome,
-                                });
-                                (server, response)
-                            },
-                        ));
+    /// Looks up responses to many questions, each from a set of servers of its own.
+    ///
+    /// Every request is handled just like by `lookup`, but the queries of all the requests are
+    /// made concurrently, as tasks on the runtime of the transport of the strategy. Rather than
+    /// waiting for all of them, `on_complete` is called with the index and the result of each
+    /// request as soon as it is complete: first the requests that need no queries, in order, and
+    /// then the others as their last queries finish. It is called on the calling thread, outside
+    /// of the runtime, so it may do lookups of its own.
+    ///
+    /// The deadline of `cancellation` applies to the batch as a whole.
+    pub fn lookup_batch<F>(
+        &self,
+        strategy: &MissStrategy,
+        requests: Vec<(Question, HashSet<IpAddr>)>,
+        cancellation: &Cancellation,
+        mut on_complete: F,
+    ) where
+        F: FnMut(usize, Result<HashMap<IpAddr, SingleResponse>, CacheMiss>),
+    {
+        let expiry = cancellation.expiry();
+        let (sender, receiver) = mpsc::channel();
+        let mut tasks = Vec::new();
+        for (index, (question, servers)) in requests.into_iter().enumerate() {
+            match self.read_cached(strategy, &question, servers) {
+                Err(miss) => on_complete(index, Err(miss)),
+                Ok((results, misses)) => match strategy.net() {
+                    Some(net) if !misses.is_empty() => {
+                        let cache = self.share();
+                        let transport = net.clone();
+                        let cancellation = cancellation.clone();
+                        let sender = sender.clone();
+                        tasks.push(net.runtime().spawn(async move {
+                            let responses = cache
+                                .query_servers(&*transport, question, misses, &cancellation, expiry)
+                                .await;
+                            // The receiving side may drop the last reference to the transport
+                            // once it has every result, and a runtime can't be dropped on one
+                            // of its own threads.
+                            drop(transport);
+                            let _ = sender.send((index, results, responses));
+                        }));
                     }
+                    _ => on_complete(index, Ok(results)),
+                },
+            }
+        }
+        drop(sender);
+
+        for (index, mut results, responses) in receiver {
+            for (server, response) in responses {
+                results.insert(server, SingleResponse::from(&*response));
+            }
+            on_complete(index, Ok(results));
+        }
+        if let Some(net) = strategy.net() {
+            for task in tasks {
+                if let Err(err) = net.runtime().block_on(task) {
+                    panic::resume_unwind(err.into_panic());
                 }
-                let _guard = net.runtime.enter();
-                let responses = net.runtime.block_on(future::join_all(queries));
-                for (server, response) in responses {
-                    question_bucket.insert(*server, response.clone());
-                    results.push((server, response));
+            }
+        }
+    }
+
+    /// Same as `lookup`, but awaited rather than blocking.
+    ///
+    /// This must run on the runtime of the transport of the strategy, if it has one.
+    pub async fn lookup_async(
+        &self,
+        strategy: &MissStrategy,
+        question: Question,
+        servers: HashSet<IpAddr>,
+        cancellation: &Cancellation,
+    ) -> Result<HashMap<IpAddr, SingleResponse>, CacheMiss> {
+        let (mut results, misses) = self.read_cached(strategy, &question, servers)?;
+        if let Some(net) = strategy.net().filter(|_| !misses.is_empty()) {
+            let expiry = cancellation.expiry();
+            let responses = self
+                .query_servers(&**net, question, misses, cancellation, expiry)
+                .await;
+            for (server, response) in responses {
+                results.insert(server, SingleResponse::from(&*response));
+            }
+        }
+        Ok(results)
+    }
+
+    /// Reads the cached responses to a question from a set of servers.
+    ///
+    /// Returns them along with the servers that are left to query over the transport of the
+    /// strategy. Strategies without a transport deal with those servers right away instead.
+    fn read_cached(
+        &self,
+        strategy: &MissStrategy,
+        question: &Question,
+        servers: HashSet<IpAddr>,
+    ) -> Result<(HashMap<IpAddr, SingleResponse>, Vec<IpAddr>), CacheMiss> {
+        let net = strategy.net();
+        let clock = net.map_or(&Clock::Real, |net| net.clock());
+        let mut results = HashMap::new();
+        let mut misses = Vec::new();
+        for server in servers {
+            match store::read(&self.store).get(question, &server) {
+                Ok(Some(response)) if strategy.is_fresh(&response) => {
+                    results.insert(server, SingleResponse::from(&*response));
                 }
+                Ok(_) => misses.push(server),
+                Err(err) => {
+                    let response = Self::store_failure(&err, clock);
+                    results.insert(server, SingleResponse::from(&*response));
+                }
+            }
+        }
+
+        match net {
+            None if matches!(strategy, MissStrategy::FailOnMiss) && !misses.is_empty() => {
+                misses.sort();
+                Err(CacheMiss { servers: misses })
+            }
+            None => {
+                for server in misses.drain(..) {
+                    results.insert(server, SingleResponse::not_in_cache());
+                }
+                Ok((results, misses))
+            }
+            Some(_) => Ok((results, misses)),
+        }
+    }
+
+    /// Queries servers concurrently and records their responses.
+    ///
+    /// Queries still going on when `cancellation` stops them are recorded as cancelled.
+    async fn query_servers(
+        &self,
+        net: &dyn Transport,
+        question: Question,
+        servers: Vec<IpAddr>,
+        cancellation: &Cancellation,
+        expiry: Option<Instant>,
+    ) -> Vec<(IpAddr, Arc<RetriedResponse>)> {
+        let queries = servers.into_iter().map(|server| {
+            let lookup = net.lookup(question.clone(), server, cancellation, expiry);
+            lookup.map(move |lookup| (server, Self::retried_response(lookup)))
+        });
+        let responses = futures::future::join_all(queries).await;
+        for (server, response) in &responses {
+            self.record(question.clone(), *server, response.clone());
+        }
+        responses
+    }
+
+    /// Converts the outcome of `Transport::lookup` into a response to be cached.
+    pub fn retried_response(lookup: Lookup) -> Arc<RetriedResponse> {
+        let started = lookup.started;
+        let outcome = match lookup.outcome {
+            Ok(bytes) => {
+                let (message, parse_err) = MyMessage::from_vec(bytes);
+                if let Some(parse_err) = parse_err {
+                    Self::perror(started, &parse_err);
+                }
+                Ok(message)
+            }
+            Err(lookup_err) => {
+                Self::perror(started, &lookup_err);
+                Err((&lookup_err).into())
             }
         };
-        results
-            .into_iter()
-            .map(|(server, response)| {
-                (
-                    *server,
-                    SingleResponse {
-                        started: response.started,
-                        duration: response.duration,
-                        outcome: match &response.outcome {
-                            Ok(mymessage) => match mymessage.decoded {
-                                Some(ref message) => {
-                                    Ok((message.clone(), mymessage.encoded.len() as u16))
-                                }
-                                None => Err(ErrorKind::Protocol),
-                            },
-                            Err(error_kind) => Err(*error_kind),
-                        },
-                    },
-                )
-            })
-            .collect()
+        Arc::new(RetriedResponse {
+            failures: lookup.failures,
+            started,
+            duration: lookup.duration,
+            outcome,
+            connect_micros: lookup.connect_micros,
+            query_micros: lookup.query_micros,
+            wire: lookup.wire,
+        })
+    }
+
+    /// Appends a response to the history of a (question, server) pair.
+    ///
+    /// The store stays locked in between reading and writing the history, so responses recorded
+    /// concurrently for the same pair are all kept.
+    pub fn record(&self, question: Question, server: IpAddr, response: Arc<RetriedResponse>) {
+        let started = response.started;
+        let mut store = store::write(&self.store);
+        let result = store.history(&question, &server).and_then(|mut history| {
+            history.push(response);
+            store.set_history(question, server, history)
+        });
+        if let Err(err) = result {
+            Self::perror(started, &err);
+        }
     }
 
-    pub fn to_bytes(&self) -> Result<Vec<u8>, rmps::encode::Error> {
+    /// Replaces the history of a (question, server) pair with a single response.
+    pub fn insert(
+        &self,
+        question: Question,
+        server: IpAddr,
+        response: Arc<RetriedResponse>,
+    ) -> Result<(), StoreError> {
+        store::write(&self.store).insert(question, server, response)
+    }
+
+    /// Serializes the contents into the msgpack cache file format, regardless of backend.
+    ///
+    /// Entries only found in bases are left out.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, StoreError> {
+        let mut memory = MemoryStore::new();
+        memory.extend(self.own_entries()?)?;
         let mut buf = Vec::new();
-        self.serialize(&mut rmps::Serializer::new(&mut buf))?;
+        memory.serialize(&mut rmps::Serializer::new(&mut buf))?;
         Ok(buf)
     }
 
     pub fn from_bytes(buf: &[u8]) -> Result<Cache, rmps::decode::Error> {
-        Cache::deserialize(&mut rmps::Deserializer::new(buf))
+        let store = MemoryStore::deserialize(&mut rmps::Deserializer::new(buf))?;
+        Ok(Cache::with_store(Box::new(store)))
     }
 
-    pub fn for_each_request(&self, callback: impl FnMut((Question, IpAddr))) {
-        let old_val = self.is_reading.replace(true);
-        self.cache
-            .iter()
-            .flat_map(|(question, inner)| inner.keys().map(|server| (question.clone(), *server)))
-            .for_each(callback);
-        self.is_reading.set(old_val);
+    /// Copies all entries into another cache, replacing any entries there with the same keys.
+    ///
+    /// Together with `open_sqlite`, `from_bytes` and `to_bytes` this converts between the
+    /// storage formats.
+    pub fn copy_to(&self, other: &Cache) -> Result<(), StoreError> {
+        store::write(&other.store).extend(self.entries()?)
+    }
+
+    /// Lists all entries with their histories, ordered by `request_order`.
+    pub fn entries(&self) -> Result<Vec<Entry>, StoreError> {
+        let mut entries = store::read(&self.store).entries()?;
+        entries.sort_by_cached_key(|(question, server, _)| request_order(question, server));
+        Ok(entries)
+    }
+
+    /// Lists the entries of this cache itself with their histories, ordered by `request_order`,
+    /// leaving out entries only found in bases.
+    pub fn own_entries(&self) -> Result<Vec<Entry>, StoreError> {
+        let mut entries = store::read(&self.store).overlay.entries()?;
+        entries.sort_by_cached_key(|(question, server, _)| request_order(question, server));
+        Ok(entries)
+    }
+
+    /// Lists the (question, server) pairs of all entries, ordered by `request_order`.
+    pub fn requests(&self) -> Result<Vec<(Question, IpAddr)>, StoreError> {
+        let mut requests = store::read(&self.store).select(&Filter::default())?;
+        requests.sort_by_cached_key(|(question, server)| request_order(question, server));
+        Ok(requests)
+    }
+
+    /// Lists the (question, server) pairs of all entries in the given order.
+    pub fn requests_in(&self, order: RequestOrder) -> Result<Vec<(Question, IpAddr)>, StoreError> {
+        match order {
+            RequestOrder::Name => self.requests(),
+            RequestOrder::FirstAttempt => {
+                let mut entries = store::read(&self.store).entries()?;
+                entries.sort_by_cached_key(|(question, server, history)| {
+                    let first_attempt = history.first().map(|response| {
+                        response
+                            .failures
+                            .first()
+                            .map_or(response.started, |failure| failure.query_start)
+                    });
+                    (first_attempt, request_order(question, server))
+                });
+                Ok(entries
+                    .into_iter()
+                    .map(|(question, server, _)| (question, server))
+                    .collect())
+            }
+        }
+    }
+
+    /// Gets the latest response for a (question, server) pair without making any network
+    /// requests.
+    pub fn latest(
+        &self,
+        question: &Question,
+        server: &IpAddr,
+    ) -> Result<Option<Arc<RetriedResponse>>, StoreError> {
+        store::read(&self.store).get(question, server)
+    }
+
+    /// Gets all responses for a (question, server) pair, oldest first, without making any
+    /// network requests.
+    pub fn history(
+        &self,
+        question: &Question,
+        server: &IpAddr,
+    ) -> Result<Vec<Arc<RetriedResponse>>, StoreError> {
+        store::read(&self.store).history(question, server)
+    }
+
+    /// Gets the response for a (question, server) pair that started closest to a time (millis
+    /// since epoch), preferring the older one on ties.
+    pub fn closest(
+        &self,
+        question: &Question,
+        server: &IpAddr,
+        time: u64,
+    ) -> Result<Option<Arc<RetriedResponse>>, StoreError> {
+        Ok(store::read(&self.store)
+            .history(question, server)?
+            .into_iter()
+            .min_by_key(|response| (response.started.abs_diff(time), response.started)))
+    }
+
+    /// Merges the entries of another cache into this one.
+    pub fn merge(&self, other: &Cache, policy: MergePolicy) -> Result<(), StoreError> {
+        let mut merged = Vec::new();
+        let entries = store::read(&other.store).entries()?;
+        for (question, server, theirs) in entries {
+            let ours = store::read(&self.store).history(&question, &server)?;
+            let (Some(our_latest), Some(their_latest)) = (ours.last(), theirs.last()) else {
+                merged.push((question, server, theirs));
+                continue;
+            };
+            let history = match policy {
+                MergePolicy::Older if their_latest.started < our_latest.started => theirs,
+                MergePolicy::Newer if their_latest.started > our_latest.started => theirs,
+                MergePolicy::Older | MergePolicy::Newer => continue,
+                MergePolicy::Both => {
+                    let mut history = ours;
+                    for response in theirs {
+                        if !history.contains(&response) {
+                            history.push(response);
+                        }
+                    }
+                    history.sort_by_key(|response| response.started);
+                    history
+                }
+            };
+            merged.push((question, server, history));
+        }
+        store::write(&self.store).extend(merged)
+    }
+
+    /// Lists the keys and latest responses of the entries matching a filter, ordered by
+    /// `request_order`.
+    pub fn query(&self, filter: &Filter) -> Result<Vec<Match>, StoreError> {
+        let mut matches = store::read(&self.store).query(filter)?;
+        matches.sort_by_cached_key(|(question, server, _)| request_order(question, server));
+        Ok(matches)
+    }
+
+    /// Constructs a new in-memory cache with the entries matching a filter.
+    pub fn filter(&self, filter: &Filter) -> Result<Cache, StoreError> {
+        let mut entries = Vec::new();
+        let contents = store::read(&self.store);
+        for (question, server) in contents.select(filter)? {
+            let history = contents.history(&question, &server)?;
+            entries.push((question, server, history));
+        }
+        let mut store = MemoryStore::new();
+        store.extend(entries)?;
+        Ok(Cache::with_store(Box::new(store)))
+    }
+
+    /// Removes the entries matching a filter, returning how many were removed.
+    ///
+    /// Only entries of the cache itself are removed, uncovering those of its bases if they have
+    /// the same keys. Entries only found in bases are left alone and not counted.
+    pub fn prune(&self, filter: &Filter) -> Result<usize, StoreError> {
+        let mut store = store::write(&self.store);
+        let keys = store.overlay.select(filter)?;
+        let removed = keys.len();
+        store.overlay.extend(
+            keys.into_iter()
+                .map(|(question, server)| (question, server, vec![]))
+                .collect(),
+        )?;
+        Ok(removed)
+    }
+
+    /// Calls back with the (question, server) pair of every entry in the given order.
+    ///
+    /// The pairs are collected before the first call, so the callback is free to look up and
+    /// insert entries, e.g. to refresh every request. Entries it adds are not visited.
+    pub fn for_each_request(
+        &self,
+        order: RequestOrder,
+        mut callback: impl FnMut(&Cache, Question, IpAddr),
+    ) {
+        match self.requests_in(order) {
+            Ok(requests) => {
+                for (question, server) in requests {
+                    callback(self, question, server);
+                }
+            }
+            Err(err) => Self::perror(Clock::Real.now(), &err),
+        }
     }
 
     pub fn for_each_retry(
         &self,
         question: &Question,
         server: &IpAddr,
-        mut callback: impl FnMut(u64, u32, ErrorKind),
+        mut callback: impl FnMut(u64, u32, u32, ErrorKind),
     ) {
-        let old_val = self.is_reading.replace(true);
-        self.cache
-            .get(question)
-            .and_then(|inner| inner.get(server))
-            .iter()
-            .flat_map(|response| &response.failures)
-            .for_each(|failure| {
-                callback(failure.query_start, failure.query_duration, failure.kind)
-            });
-        self.is_reading.set(old_val);
+        match store::read(&self.store).get(question, server) {
+            Ok(response) => response
+                .iter()
+                .flat_map(|response| &response.failures)
+                .for_each(|failure| {
+                    callback(
+                        failure.query_start,
+                        failure.query_duration,
+                        failure.query_micros,
+                        failure.kind,
+                    )
+                }),
+            Err(err) => Self::perror(Clock::Real.now(), &err),
+        }
+    }
+
+    fn store_failure(err: &StoreError, clock: &Clock) -> Arc<RetriedResponse> {
+        let started = clock.now();
+        Self::perror(started, err);
+        Arc::new(RetriedResponse {
+            failures: Vec::new(),
+            started,
+            duration: 0,
+            outcome: Err(ErrorKind::Internal),
+            connect_micros: 0,
+            query_micros: 0,
+            wire: None,
+        })
     }
 
     fn perror<E: fmt::Debug>(started: u64, error: &E) {
@@ -348,146 +996,1145 @@ impl Cache {
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct Failure {
-    query_start: u64,
-    query_duration: u32,
-    kind: ErrorKind,
+    /// Millis since epoch
+    pub query_start: u64,
+    /// Millis
+    pub query_duration: u32,
+    pub kind: ErrorKind,
+    /// Measured monotonically
+    #[serde(default)]
+    pub query_micros: u32,
+    #[serde(default)]
+    pub wire: Option<Wire>,
+}
+
+/// What an attempt looked like on the local socket.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Wire {
+    /// The query message as sent, with the message ID it was sent with
+    #[serde(with = "serde_bytes")]
+    pub query: Vec<u8>,
+    pub local: SocketAddr,
+    /// The address the response came from, if there was one
+    pub remote: Option<SocketAddr>,
+    /// Bytes written to the socket, including the TCP length prefix
+    pub sent: u32,
+    /// Bytes read from the socket, including the TCP length prefix and any strays
+    pub received: u32,
+    /// Messages that arrived while waiting for the response but weren't taken for it, in the
+    /// order they arrived
+    #[serde(default)]
+    pub strays: Vec<Stray>,
+}
+
+/// A message that came from another address than the server's or that isn't a response to the
+/// query, e.g. one with another message ID or question section than the query's.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Stray {
+    #[serde(with = "serde_bytes")]
+    pub message: Vec<u8>,
+    pub remote: SocketAddr,
 }
 
 #[derive(Debug)]
 pub struct Net {
     pub bind_addr: SocketAddr,
+    /// The port servers are queried on
+    pub port: u16,
     pub timeout: u32,
     pub retry: u16,
     pub retrans: u32,
     pub runtime: Runtime,
+    /// The clock for timestamps and retry sleeps
+    pub clock: Clock,
+    /// Limits how many lookups are in flight at once, if set. The others wait for a permit
+    /// before connecting
+    pub in_flight: Option<Semaphore>,
 }
 
-impl Net {
-    pub async fn lookup(
-        &self,
+impl Transport for Net {
+    fn runtime(&self) -> &Runtime {
+        &self.runtime
+    }
+
+    fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    fn lookup<'a>(
+        &'a self,
         question: Question,
         server: IpAddr,
-    ) -> (Vec<Failure>, u64, u32, Result<Vec<u8>, ProtoError>) {
-        use chrono::Utc;
-
-        let server_addr = SocketAddr::new(server, 53);
-        let timeout = Duration::from_millis(self.timeout as u64);
-        let retrans = Duration::from_millis(self.retrans as u64);
-        let conn_start = Utc::now().timestamp_millis();
-        match Self::connect(question.proto, server_addr, self.bind_addr, timeout).await {
-            Ok(mut conn) => {
-                let (failures, outcome, query_start, query_duration) =
-                    Self::query_retry(&mut conn, &question, self.retry, retrans).await;
-                let bytes = outcome.map(|dns_response| dns_response.into_buffer());
-                (failures, query_start, query_duration, bytes)
-            }
-            Err(err) => {
-                let finished = Utc::now().timestamp_millis();
-                let duration = finished - conn_start;
-                (vec![], conn_start as u64, duration as u32, Err(err))
+        cancellation: &'a Cancellation,
+        expiry: Option<Instant>,
+    ) -> BoxFuture<'a, Lookup> {
+        async move {
+            let _permit = match &self.in_flight {
+                Some(in_flight) => {
+                    let permit = cancellation.unless_stopped(expiry, in_flight.acquire());
+                    match permit.await {
+                        Some(permit) => Some(permit.expect("never closed")),
+                        None => return Lookup::cancelled(self.clock.now()),
+                    }
+                }
+                None => None,
+            };
+            let server_addr = SocketAddr::new(server, self.port);
+            let timeout = Duration::from_millis(self.timeout as u64);
+            let conn_start = self.clock.now();
+            let connecting = self.clock.stopwatch();
+            let deadline = self.clock.deadline(timeout);
+            let conn = Connection::open(question.proto, server_addr, self.bind_addr, deadline);
+            let conn = cancellation
+                .unless_stopped(expiry, conn)
+                .await
+                .unwrap_or_else(|| Err(transport::cancelled()));
+            let connect_elapsed = connecting.elapsed();
+            match conn {
+                Ok(mut conn) => {
+                    let (failures, (outcome, query_start, query_elapsed, wire)) = self
+                        .query_retry(&mut conn, &question, timeout, cancellation, expiry)
+                        .await;
+                    Lookup {
+                        failures,
+                        started: query_start,
+                        duration: query_elapsed.as_millis() as u32,
+                        connect_micros: micros(connect_elapsed),
+                        query_micros: micros(query_elapsed),
+                        outcome,
+                        wire,
+                    }
+                }
+                Err(err) => Lookup {
+                    failures: vec![],
+                    started: conn_start,
+                    duration: connect_elapsed.as_millis() as u32,
+                    connect_micros: micros(connect_elapsed),
+                    query_micros: 0,
+                    outcome: Err(err),
+                    wire: None,
+                },
             }
         }
+        .boxed()
     }
+}
 
+/// The outcome of an attempt, when it started (millis since epoch) and how long it took.
+type Attempt = (Result<Vec<u8>, ProtoError>, u64, Duration, Option<Wire>);
+
+impl Net {
+    /// Makes attempts until one succeeds, the tries run out or the lookup is stopped, returning
+    /// the failed attempts and the final one.
     async fn query_retry(
-        client: &mut AsyncClient,
+        &self,
+        conn: &mut Connection,
         question: &Question,
-        tries: u16,
-        retrans: Duration,
-    ) -> (Vec<Failure>, Result<DnsResponse, ProtoError>, u64, u32) {
-        use tokio::time;
-
+        timeout: Duration,
+        cancellation: &Cancellation,
+        expiry: Option<Instant>,
+    ) -> (Vec<Failure>, Attempt) {
+        let retrans = Duration::from_millis(self.retrans as u64);
         let mut failures = Vec::new();
-        let mut final_outcome = None;
-        for tries_left in (0..tries.max(1)).rev() {
-            let (outcome, query_start, query_duration) =
-                Self::query(client, question.clone()).await;
+        for tries_left in (0..self.retry.max(1)).rev() {
+            let started = self.clock.now();
+            let stopwatch = self.clock.stopwatch();
+            let attempt = self.query(conn, question, timeout);
+            let (outcome, wire) = cancellation
+                .unless_stopped(expiry, attempt)
+                .await
+                .unwrap_or_else(|| (Err(transport::cancelled()), None));
+            let (query_start, elapsed) = (started, stopwatch.elapsed());
             match outcome {
-                Err(failure) if tries_left > 0 => {
+                Err(failure)
+                    if tries_left > 0 && ErrorKind::from(&failure) != ErrorKind::Cancelled =>
+                {
                     failures.push(Failure {
                         query_start,
-                        query_duration,
+                        query_duration: elapsed.as_millis() as u32,
                         kind: (&failure).into(),
+                        query_micros: micros(elapsed),
+                        wire,
                     });
-                    time::sleep(retrans).await;
-                }
-                outcome => {
-                    final_outcome = Some((outcome, query_start, query_duration));
-                    break;
+                    let sleep = self.clock.sleep(retrans);
+                    if cancellation.unless_stopped(expiry, sleep).await.is_none() {
+                        let stopped = (
+                            Err(transport::cancelled()),
+                            self.clock.now(),
+                            Duration::ZERO,
+                            None,
+                        );
+                        return (failures, stopped);
+                    }
                 }
+                outcome => return (failures, (outcome, query_start, elapsed, wire)),
             }
         }
-
-        let (outcome, query_start, query_duration) =
-            final_outcome.expect("at this point final_outcome is always Some");
-        (failures, outcome, query_start, query_duration)
+        unreachable!("the final attempt always returns")
     }
 
     async fn query(
-        client: &mut AsyncClient,
-        question: Question,
-    ) -> (Result<DnsResponse, ProtoError>, u64, u32) {
-        use chrono::Utc;
-        use trust_dns_proto::DnsHandle;
-
-        let mut query = client.send(question);
-        let started = Utc::now().timestamp_millis();
-        let outcome = query.next().await;
-        let finished = Utc::now().timestamp_millis();
-        let duration = finished - started;
-        (
-            outcome.unwrap_or_else(|| Err(ProtoErrorKind::Message("no response").into())),
-            started as u64,
-            duration as u32,
-        )
+        &self,
+        conn: &mut Connection,
+        question: &Question,
+        timeout: Duration,
+    ) -> (Result<Vec<u8>, ProtoError>, Option<Wire>) {
+        let mut request = DnsRequest::from(question.clone());
+        request.set_id(rand::random());
+        match request.to_vec() {
+            Ok(query) => conn.exchange(query, self.clock.deadline(timeout)).await,
+            Err(err) => (Err(err), None),
+        }
     }
+}
 
-    async fn connect(
+/// A way of sending queries to a server.
+///
+/// UDP queries are sent from a new socket each, so that late responses to earlier attempts aren't
+/// taken for responses to later ones. TCP queries are sent over the same connection for as long as
+/// attempts succeed, and over a new one after an attempt fails.
+enum Connection {
+    Udp {
+        server_addr: SocketAddr,
+        bind_addr: SocketAddr,
+    },
+    Tcp {
+        server_addr: SocketAddr,
+        bind_addr: SocketAddr,
+        stream: Option<TcpStream>,
+    },
+}
+
+impl Connection {
+    async fn open(
         proto: Protocol,
         server_addr: SocketAddr,
         bind_addr: SocketAddr,
-        timeout: Duration,
-    ) -> Result<AsyncClient, ProtoError> {
+        deadline: Deadline,
+    ) -> Result<Connection, ProtoError> {
         match proto {
-            Protocol::Udp => Self::connect_udp(server_addr, bind_addr, timeout).await,
-            Protocol::Tcp => Self::connect_tcp(server_addr, bind_addr, timeout).await,
+            Protocol::Udp => Ok(Connection::Udp {
+                server_addr,
+                bind_addr,
+            }),
+            Protocol::Tcp => {
+                let stream = Self::connect(server_addr, bind_addr, &deadline).await?;
+                Ok(Connection::Tcp {
+                    server_addr,
+                    bind_addr,
+                    stream: Some(stream),
+                })
+            }
         }
     }
 
-    async fn connect_udp(
+    async fn connect(
         server_addr: SocketAddr,
         bind_addr: SocketAddr,
-        timeout: Duration,
-    ) -> Result<AsyncClient, ProtoError> {
-        let stream = UdpClientStream::<UdpSocket>::with_bind_addr_and_timeout(
-            server_addr,
-            Some(bind_addr),
-            timeout,
-        );
-        AsyncClient::connect(stream).await.map(|(conn, bg)| {
-            Handle::current().spawn(bg);
-            conn
-        })
+        deadline: &Deadline,
+    ) -> Result<TcpStream, ProtoError> {
+        let socket = match bind_addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.bind(bind_addr)?;
+        let stream = deadline
+            .timeout(socket.connect(server_addr))
+            .await
+            .ok_or_else(|| ProtoError::from(ProtoErrorKind::Timeout))??;
+        Ok(stream)
     }
 
-    async fn connect_tcp(
+    /// Sends a query and waits for the response.
+    ///
+    /// The response is the first response from the server with the message ID and the question
+    /// section of the query. Other messages are kept as strays, so that the recording shows what
+    /// the server actually sent. A TCP connection that was lost by an earlier attempt is reopened
+    /// before the deadline of this one.
+    async fn exchange(
+        &mut self,
+        query: Vec<u8>,
+        deadline: Deadline,
+    ) -> (Result<Vec<u8>, ProtoError>, Option<Wire>) {
+        match self {
+            Connection::Udp {
+                server_addr,
+                bind_addr,
+            } => Self::exchange_udp(*server_addr, *bind_addr, query, &deadline).await,
+            Connection::Tcp {
+                server_addr,
+                bind_addr,
+                stream,
+            } => {
+                let mut open = match stream.take() {
+                    Some(open) => open,
+                    None => match Self::connect(*server_addr, *bind_addr, &deadline).await {
+                        Ok(open) => open,
+                        Err(err) => return (Err(err), None),
+                    },
+                };
+                let exchanged = Self::exchange_tcp(&mut open, query, &deadline).await;
+                if exchanged.0.is_ok() {
+                    *stream = Some(open);
+                }
+                exchanged
+            }
+        }
+    }
+
+    async fn exchange_udp(
         server_addr: SocketAddr,
         bind_addr: SocketAddr,
-        timeout: Duration,
-    ) -> Result<AsyncClient, ProtoError> {
-        let (tcp_client_stream, handle) =
-            TcpClientStream::<AsyncIoTokioAsStd<TcpStream>>::with_bind_addr_and_timeout(
-                server_addr,
-                Some(bind_addr),
-                timeout,
-            );
-        let stream: DnsMultiplexerConnect<_, _, SigSigner> =
-            DnsMultiplexer::new(tcp_client_stream, handle, None);
-        AsyncClient::connect(stream).await.map(|(conn, bg)| {
-            Handle::current().spawn(bg);
-            conn
+        query: Vec<u8>,
+        deadline: &Deadline,
+    ) -> (Result<Vec<u8>, ProtoError>, Option<Wire>) {
+        let socket = match UdpSocket::bind(bind_addr).await {
+            Ok(socket) => socket,
+            Err(err) => return (Err(err.into()), None),
+        };
+        let mut wire = match socket.local_addr() {
+            Ok(local) => Wire::new(query, local),
+            Err(err) => return (Err(err.into()), None),
+        };
+        match socket.send_to(&wire.query, server_addr).await {
+            Ok(sent) => wire.sent = sent as u32,
+            Err(err) => return (Err(err.into()), Some(wire)),
+        }
+        let mut buf = vec![0; u16::MAX as usize];
+        loop {
+            let received = deadline.timeout(socket.recv_from(&mut buf)).await;
+            match received {
+                Some(Ok((len, remote))) => {
+                    wire.received += len as u32;
+                    let message = buf[..len].to_vec();
+                    if remote == server_addr && wire.matches(&message) {
+                        wire.remote = Some(remote);
+                        return (Ok(message), Some(wire));
+                    }
+                    wire.strays.push(Stray { message, remote });
+                }
+                Some(Err(err)) => return (Err(err.into()), Some(wire)),
+                None => return (Err(ProtoErrorKind::Timeout.into()), Some(wire)),
+            }
+        }
+    }
+
+    async fn exchange_tcp(
+        stream: &mut TcpStream,
+        query: Vec<u8>,
+        deadline: &Deadline,
+    ) -> (Result<Vec<u8>, ProtoError>, Option<Wire>) {
+        let mut wire = match stream.local_addr() {
+            Ok(local) => Wire::new(query, local),
+            Err(err) => return (Err(err.into()), None),
+        };
+        let remote = match stream.peer_addr() {
+            Ok(remote) => remote,
+            Err(err) => return (Err(err.into()), Some(wire)),
+        };
+        let mut framed = (wire.query.len() as u16).to_be_bytes().to_vec();
+        framed.extend(&wire.query);
+        if let Err(err) = stream.write_all(&framed).await {
+            return (Err(err.into()), Some(wire));
+        }
+        wire.sent = framed.len() as u32;
+        loop {
+            let received = deadline
+                .timeout(async {
+                    let len = stream.read_u16().await?;
+                    let mut buf = vec![0; len as usize];
+                    stream.read_exact(&mut buf).await?;
+                    Ok::<_, io::Error>(buf)
+                })
+                .await;
+            match received {
+                Some(Ok(message)) => {
+                    wire.received += message.len() as u32 + 2;
+                    if wire.matches(&message) {
+                        wire.remote = Some(remote);
+                        return (Ok(message), Some(wire));
+                    }
+                    wire.strays.push(Stray { message, remote });
+                }
+                Some(Err(err)) => return (Err(err.into()), Some(wire)),
+                None => return (Err(ProtoErrorKind::Timeout.into()), Some(wire)),
+            }
+        }
+    }
+}
+
+impl Wire {
+    fn new(query: Vec<u8>, local: SocketAddr) -> Self {
+        Wire {
+            query,
+            local,
+            remote: None,
+            sent: 0,
+            received: 0,
+            strays: vec![],
+        }
+    }
+
+    /// Whether a message is a response to the query, i.e. has its message ID and question
+    /// section.
+    fn matches(&self, message: &[u8]) -> bool {
+        match (header_and_queries(message), header_and_queries(&self.query)) {
+            (Some((header, queries)), Some((query_header, query_queries))) => {
+                header.id() == query_header.id()
+                    && header.message_type() == MessageType::Response
+                    && queries == query_queries
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The header and the question section of a message, without reading the rest of it.
+fn header_and_queries(message: &[u8]) -> Option<(Header, Vec<Query>)> {
+    let mut decoder = BinDecoder::new(message);
+    let header = Header::read(&mut decoder).ok()?;
+    let queries = (0..header.query_count())
+        .map(|_| Query::read(&mut decoder).ok())
+        .collect::<Option<_>>()?;
+    Some((header, queries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancel::CancelToken;
+    use crate::fixtures;
+    use crate::fixtures::answer;
+    use crate::fixtures::question;
+    use crate::transport::scripted::Attempt;
+    use crate::transport::scripted::ScriptedTransport;
+
+    fn failure(started: u64, kind: ErrorKind) -> Arc<RetriedResponse> {
+        Arc::new(RetriedResponse {
+            started,
+            ..fixtures::response(Err(kind))
         })
     }
+
+    fn cache(entries: &[(&str, u64, ErrorKind)]) -> Cache {
+        let server = "192.0.2.1".parse().unwrap();
+        let cache = Cache::new();
+        for (qname, started, kind) in entries {
+            store::write(&cache.store)
+                .insert(question(qname), server, failure(*started, *kind))
+                .unwrap();
+        }
+        cache
+    }
+
+    #[test]
+    fn merge() {
+        let server = "192.0.2.1".parse().unwrap();
+        let ours = [
+            ("a.example", 1, ErrorKind::Io),
+            ("b.example", 3, ErrorKind::Io),
+        ];
+        let theirs = [
+            ("b.example", 2, ErrorKind::Io),
+            ("c.example", 4, ErrorKind::Io),
+        ];
+
+        let older = cache(&ours);
+        older.merge(&cache(&theirs), MergePolicy::Older).unwrap();
+        let b = store::read(&older.store)
+            .history(&question("b.example"), &server)
+            .unwrap();
+        assert_eq!(b, vec![failure(2, ErrorKind::Io)]);
+        assert!(store::read(&older.store)
+            .get(&question("c.example"), &server)
+            .unwrap()
+            .is_some());
+
+        let newer = cache(&ours);
+        newer.merge(&cache(&theirs), MergePolicy::Newer).unwrap();
+        let b = store::read(&newer.store)
+            .history(&question("b.example"), &server)
+            .unwrap();
+        assert_eq!(b, vec![failure(3, ErrorKind::Io)]);
+
+        let both = cache(&ours);
+        both.merge(&cache(&theirs), MergePolicy::Both).unwrap();
+        let b = store::read(&both.store)
+            .history(&question("b.example"), &server)
+            .unwrap();
+        assert_eq!(
+            b,
+            vec![failure(2, ErrorKind::Io), failure(3, ErrorKind::Io)]
+        );
+
+        let bytes = both.to_bytes().unwrap();
+        let restored = Cache::from_bytes(&bytes).unwrap();
+        let b = store::read(&restored.store)
+            .history(&question("b.example"), &server)
+            .unwrap();
+        assert_eq!(
+            b,
+            vec![failure(2, ErrorKind::Io), failure(3, ErrorKind::Io)]
+        );
+    }
+
+    #[test]
+    fn filter_and_prune() {
+        let cache = cache(&[
+            ("a.example", 1, ErrorKind::Io),
+            ("www.b.example", 2, ErrorKind::Timeout),
+            ("b.example", 3, ErrorKind::Timeout),
+        ]);
+        let by_suffix = Filter {
+            qname_suffix: Some("b.example".parse().unwrap()),
+            ..Filter::default()
+        };
+        let subset = cache.filter(&by_suffix).unwrap();
+        assert_eq!(subset.requests().unwrap().len(), 2);
+
+        let by_outcome = Filter {
+            outcome: Some(OutcomeKind::Error(ErrorKind::Timeout)),
+            started_from: Some(3),
+            ..Filter::default()
+        };
+        assert_eq!(cache.prune(&by_outcome).unwrap(), 1);
+        assert_eq!(cache.requests().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn canonical_order() {
+        let entries = [
+            ("b.example.", 3000, ErrorKind::Io),
+            ("example.", 2000, ErrorKind::Timeout),
+            ("A.example.", 1000, ErrorKind::Io),
+            ("z.a.example.", 4000, ErrorKind::Protocol),
+        ];
+        let forward = cache(&entries);
+        let mut reversed = entries;
+        reversed.reverse();
+        let reversed = cache(&reversed);
+        assert_eq!(forward.to_bytes().unwrap(), reversed.to_bytes().unwrap());
+
+        let qnames = |order| {
+            forward
+                .requests_in(order)
+                .unwrap()
+                .into_iter()
+                .map(|(question, _)| question.qname.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            qnames(RequestOrder::Name),
+            vec!["example.", "a.example.", "z.a.example.", "b.example."]
+        );
+        assert_eq!(
+            qnames(RequestOrder::FirstAttempt),
+            vec!["a.example.", "example.", "b.example.", "z.a.example."]
+        );
+    }
+
+    #[test]
+    fn concurrent_add_base() {
+        for _ in 0..100 {
+            let (a, b) = (Cache::new(), Cache::new());
+            let added = std::thread::scope(|scope| {
+                let a_over_b = scope.spawn(|| a.add_base(&b));
+                let b_over_a = scope.spawn(|| b.add_base(&a));
+                [a_over_b.join().unwrap(), b_over_a.join().unwrap()]
+            });
+            assert_eq!(added.iter().filter(|added| **added).count(), 1);
+        }
+    }
+
+    #[test]
+    fn layered() {
+        let server = "192.0.2.1".parse().unwrap();
+        let started = |cache: &Cache, qname| {
+            let history = cache.history(&question(qname), &server).unwrap();
+            history
+                .iter()
+                .map(|response| response.started)
+                .collect::<Vec<_>>()
+        };
+        let base = cache(&[
+            ("a.example", 1, ErrorKind::Io),
+            ("b.example", 2, ErrorKind::Io),
+        ]);
+        let shared = base.to_bytes().unwrap();
+        let layered = cache(&[("b.example", 3, ErrorKind::Timeout)]);
+        assert!(layered.add_base(&base));
+        assert!(!base.add_base(&layered));
+
+        layered.record(question("a.example"), server, failure(4, ErrorKind::Io));
+        layered.record(question("c.example"), server, failure(5, ErrorKind::Io));
+        assert_eq!(started(&layered, "a.example"), vec![1, 4]);
+        assert_eq!(started(&layered, "b.example"), vec![3]);
+        assert_eq!(layered.requests().unwrap().len(), 3);
+        assert_eq!(base.to_bytes().unwrap(), shared);
+        assert_eq!(layered.own_entries().unwrap().len(), 3);
+
+        let by_qname = Filter {
+            qname: Some("b.example".parse().unwrap()),
+            ..Filter::default()
+        };
+        assert_eq!(layered.prune(&by_qname).unwrap(), 1);
+        assert_eq!(started(&layered, "b.example"), vec![2]);
+        assert_eq!(layered.prune(&by_qname).unwrap(), 0);
+        assert_eq!(started(&layered, "b.example"), vec![2]);
+        let from_base = Filter {
+            outcome: Some(OutcomeKind::Error(ErrorKind::Io)),
+            started_until: Some(3),
+            ..Filter::default()
+        };
+        assert_eq!(layered.query(&from_base).unwrap().len(), 1);
+        assert_eq!(
+            Cache::from_bytes(&layered.to_bytes().unwrap())
+                .unwrap()
+                .requests()
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn retry_timing() {
+        // A server that never answers
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = silent.local_addr().unwrap();
+        let net = Net {
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            port: server.port(),
+            timeout: 50,
+            retry: 3,
+            retrans: 5000,
+            runtime: Runtime::new().unwrap(),
+            clock: Clock::manual(1000),
+            in_flight: None,
+        };
+
+        let lookup = net.runtime.block_on(net.lookup(
+            question("example.com"),
+            server.ip(),
+            &Cancellation::default(),
+            None,
+        ));
+        let starts: Vec<_> = lookup
+            .failures
+            .iter()
+            .map(|failure| failure.query_start)
+            .collect();
+        // Timeouts move the manual clock as far as they waited
+        assert_eq!(starts, vec![1000, 6050]);
+        assert_eq!(lookup.failures[0].query_duration, 50);
+        assert_eq!((lookup.started, lookup.duration), (11100, 50));
+        assert!(lookup.outcome.is_err());
+        let wire = lookup.failures[0].wire.as_ref().unwrap();
+        assert_eq!(wire.remote, None);
+        assert_eq!(wire.sent as usize, wire.query.len());
+        assert_eq!(wire.received, 0);
+    }
+
+    #[test]
+    fn miss_strategies() {
+        let server: IpAddr = "192.0.2.1".parse().unwrap();
+        let servers = HashSet::from([server]);
+        let transport = ScriptedTransport::new(1, 100).unwrap();
+        let ms = Duration::from_millis;
+        for delay in [10, 20, 30] {
+            transport.push(
+                question("example"),
+                server,
+                ms(delay),
+                Attempt::Respond(answer()),
+            );
+        }
+        let transport: Arc<dyn Transport> = Arc::new(transport);
+        let cache = Cache::new();
+
+        let lookup = |strategy| {
+            cache
+                .lookup(
+                    &strategy,
+                    question("example"),
+                    &servers,
+                    &Cancellation::default(),
+                )
+                .map(|mut results| results.remove(&server).unwrap())
+        };
+        let miss = lookup(MissStrategy::CacheOnly).unwrap();
+        assert_eq!(miss.outcome.unwrap_err(), ErrorKind::NotInCache);
+        let miss = lookup(MissStrategy::FailOnMiss).unwrap_err();
+        assert_eq!(miss.servers, vec![server]);
+
+        let started = |strategy| lookup(strategy).unwrap().started;
+        let first = started(MissStrategy::FillOnMiss(transport.clone()));
+        assert_eq!(started(MissStrategy::FillOnMiss(transport.clone())), first);
+        let max_age = |max_age| MissStrategy::RefreshIfOlderThan(transport.clone(), max_age);
+        assert_eq!(started(max_age(1000)), first);
+        transport.clock().advance(ms(2000));
+        let second = started(max_age(1000));
+        assert!(second > first);
+        let third = started(MissStrategy::RefreshAlways(transport.clone()));
+        assert!(third > second);
+        assert_eq!(started(MissStrategy::CacheOnly), third);
+        assert_eq!(started(MissStrategy::FailOnMiss), third);
+
+        let history = cache.history(&question("example"), &server).unwrap();
+        let starts: Vec<_> = history.iter().map(|response| response.started).collect();
+        assert_eq!(starts, vec![first, second, third]);
+        let closest = |time| {
+            cache
+                .closest(&question("example"), &server, time)
+                .unwrap()
+                .unwrap()
+                .started
+        };
+        assert_eq!(closest(0), first);
+        assert_eq!(closest((first + second) / 2), first);
+        assert_eq!(closest(third + 1), third);
+    }
+
+    #[test]
+    fn refresh_while_iterating() {
+        let server: IpAddr = "192.0.2.1".parse().unwrap();
+        let servers = HashSet::from([server]);
+        let transport = ScriptedTransport::new(1, 100).unwrap();
+        for qname in ["a.example", "b.example"] {
+            for _ in 0..2 {
+                transport.push(
+                    question(qname),
+                    server,
+                    Duration::from_millis(10),
+                    Attempt::Respond(answer()),
+                );
+            }
+        }
+        let transport: Arc<dyn Transport> = Arc::new(transport);
+        let cache = Cache::new();
+        let fill = MissStrategy::FillOnMiss(transport.clone());
+        for qname in ["a.example", "b.example"] {
+            cache
+                .lookup(&fill, question(qname), &servers, &Cancellation::default())
+                .unwrap();
+        }
+
+        let refresh = MissStrategy::RefreshAlways(transport);
+        let mut visited = 0;
+        cache.for_each_request(RequestOrder::Name, |cache, question, _| {
+            let response = cache
+                .lookup(&refresh, question, &servers, &Cancellation::default())
+                .unwrap();
+            assert!(response[&server].outcome.is_ok());
+            visited += 1;
+        });
+        assert_eq!(visited, 2);
+        for qname in ["a.example", "b.example"] {
+            let history = cache.history(&question(qname), &server).unwrap();
+            assert_eq!(history.len(), 2);
+        }
+    }
+
+    #[test]
+    fn concurrent_lookups() {
+        fn assert_thread_safe<T: Send + Sync>() {}
+        assert_thread_safe::<Cache>();
+        assert_thread_safe::<Net>();
+
+        let server: IpAddr = "192.0.2.1".parse().unwrap();
+        let servers = HashSet::from([server]);
+        let qnames: Vec<_> = (0..8).map(|i| format!("{}.example", i)).collect();
+        let transport = ScriptedTransport::new(1, 100).unwrap();
+        for qname in &qnames {
+            for _ in 0..4 {
+                transport.push(
+                    question(qname),
+                    server,
+                    Duration::from_millis(10),
+                    Attempt::Respond(answer()),
+                );
+            }
+        }
+        let refresh = MissStrategy::RefreshAlways(Arc::new(transport));
+        let cache = Cache::new();
+
+        std::thread::scope(|scope| {
+            for qname in &qnames {
+                for _ in 0..4 {
+                    scope.spawn(|| {
+                        let response = cache
+                            .lookup(
+                                &refresh,
+                                question(qname),
+                                &servers,
+                                &Cancellation::default(),
+                            )
+                            .unwrap();
+                        assert!(response[&server].outcome.is_ok());
+                    });
+                }
+            }
+        });
+        for qname in &qnames {
+            let history = cache.history(&question(qname), &server).unwrap();
+            assert_eq!(history.len(), 4);
+        }
+    }
+
+    #[test]
+    fn batch_lookups() {
+        let server: IpAddr = "192.0.2.1".parse().unwrap();
+        let servers = HashSet::from([server]);
+        let ms = Duration::from_millis;
+        let mut transport = ScriptedTransport::new(1, 100).unwrap();
+        transport.clock = Clock::Real;
+        transport.push(question("slow.example"), server, ms(100), Attempt::Timeout);
+        for qname in ["cached.example", "fast.example"] {
+            transport.push(question(qname), server, ms(1), Attempt::Respond(answer()));
+        }
+        let fill = MissStrategy::FillOnMiss(Arc::new(transport));
+        let cache = Cache::new();
+        cache
+            .lookup(
+                &fill,
+                question("cached.example"),
+                &servers,
+                &Cancellation::default(),
+            )
+            .unwrap();
+
+        let requests = ["slow.example", "cached.example", "fast.example"]
+            .into_iter()
+            .map(|qname| (question(qname), servers.clone()))
+            .collect();
+        let mut completed = Vec::new();
+        cache.lookup_batch(
+            &fill,
+            requests,
+            &Cancellation::default(),
+            |index, result| {
+                let mut responses = result.unwrap();
+                completed.push((index, responses.remove(&server).unwrap().outcome.is_ok()));
+                // Callbacks run outside of the runtime, so they may do lookups of their own
+                let nested = question(&format!("nested-{}.example", index));
+                cache
+                    .lookup(&fill, nested, &servers, &Cancellation::default())
+                    .unwrap();
+            },
+        );
+        assert_eq!(completed, vec![(1, true), (2, true), (0, false)]);
+        for qname in ["nested-0.example", "nested-1.example", "nested-2.example"] {
+            let history = cache.history(&question(qname), &server).unwrap();
+            assert_eq!(history.len(), 1);
+        }
+        for qname in ["slow.example", "cached.example", "fast.example"] {
+            let history = cache.history(&question(qname), &server).unwrap();
+            assert_eq!(history.len(), 1);
+        }
+
+        let requests = vec![
+            (question("cached.example"), servers.clone()),
+            (question("missing.example"), servers.clone()),
+        ];
+        let mut completed = Vec::new();
+        let fail = MissStrategy::FailOnMiss;
+        cache.lookup_batch(
+            &fail,
+            requests,
+            &Cancellation::default(),
+            |index, result| {
+                completed.push((index, result.is_ok()));
+            },
+        );
+        assert_eq!(completed, vec![(0, true), (1, false)]);
+    }
+
+    #[test]
+    fn cancelled_lookups() {
+        let server: IpAddr = "192.0.2.1".parse().unwrap();
+        let servers = HashSet::from([server]);
+        let mut transport = ScriptedTransport::new(1, 100).unwrap();
+        transport.clock = Clock::Real;
+        for qname in ["deadline.example", "token.example"] {
+            transport.push(
+                question(qname),
+                server,
+                Duration::from_secs(60),
+                Attempt::Timeout,
+            );
+        }
+        let transport = Arc::new(transport);
+        let fill = MissStrategy::FillOnMiss(transport.clone());
+        let cache = Cache::new();
+        let lookup = |qname, cancellation: &Cancellation| {
+            let started = std::time::Instant::now();
+            let mut responses = cache
+                .lookup(&fill, question(qname), &servers, cancellation)
+                .unwrap();
+            assert!(started.elapsed() < Duration::from_secs(10));
+            responses.remove(&server).unwrap()
+        };
+
+        let deadline = Cancellation {
+            token: None,
+            deadline: Some(Duration::from_millis(20)),
+        };
+        let expired = lookup("deadline.example", &deadline);
+        assert_eq!(expired.outcome.unwrap_err(), ErrorKind::Cancelled);
+        assert!(expired.duration >= 20);
+
+        let token = CancelToken::new();
+        let cancellation = Cancellation {
+            token: Some(token.clone()),
+            deadline: None,
+        };
+        let cancelled = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(20));
+                token.cancel();
+            });
+            lookup("token.example", &cancellation)
+        });
+        assert_eq!(cancelled.outcome.unwrap_err(), ErrorKind::Cancelled);
+
+        let cached = cache.latest(&question("token.example"), &server).unwrap();
+        assert_eq!(cached.unwrap().outcome, Err(ErrorKind::Cancelled));
+        let unsent = lookup("unscripted.example", &cancellation);
+        assert_eq!(unsent.outcome.unwrap_err(), ErrorKind::Cancelled);
+        assert_eq!(unsent.duration, 0);
+
+        transport.push(
+            question("token.example"),
+            server,
+            Duration::ZERO,
+            Attempt::Respond(answer()),
+        );
+        let requeried = lookup("token.example", &Cancellation::default());
+        assert!(requeried.outcome.is_ok());
+        let history = cache.history(&question("token.example"), &server).unwrap();
+        assert_eq!(history.len(), 2);
+    }
+
+    #[test]
+    fn cancelled_after_failures() {
+        let server: IpAddr = "192.0.2.1".parse().unwrap();
+        let servers = HashSet::from([server]);
+        let ms = Duration::from_millis;
+        let mut transport = ScriptedTransport::new(3, 0).unwrap();
+        transport.clock = Clock::Real;
+        transport.push(question("example"), server, ms(10), Attempt::Timeout);
+        transport.push(question("example"), server, ms(60_000), Attempt::Timeout);
+        let fill = MissStrategy::FillOnMiss(Arc::new(transport));
+        let cache = Cache::new();
+
+        let deadline = Cancellation {
+            token: None,
+            deadline: Some(ms(100)),
+        };
+        let mut responses = cache
+            .lookup(&fill, question("example"), &servers, &deadline)
+            .unwrap();
+        let response = responses.remove(&server).unwrap();
+        assert_eq!(response.outcome.unwrap_err(), ErrorKind::Cancelled);
+        assert!(response.duration < 1000);
+
+        let recorded = cache
+            .latest(&question("example"), &server)
+            .unwrap()
+            .unwrap();
+        assert_eq!(recorded.outcome, Err(ErrorKind::Cancelled));
+        let kinds: Vec<_> = recorded
+            .failures
+            .iter()
+            .map(|failure| failure.kind)
+            .collect();
+        assert_eq!(kinds, vec![ErrorKind::Timeout]);
+        assert!(recorded.failures[0].query_duration >= 10);
+        assert!(recorded.started >= recorded.failures[0].query_start + 10);
+    }
+
+    #[test]
+    fn wire_details() {
+        // A server that echoes each query back as a response
+        let echo = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = echo.local_addr().unwrap();
+        let responder = std::thread::spawn(move || {
+            let mut buffer = [0; 512];
+            let (size, peer) = echo.recv_from(&mut buffer).unwrap();
+            buffer[2] |= 0x80;
+            echo.send_to(&buffer[..size], peer).unwrap();
+        });
+        let net = Net {
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            port: server.port(),
+            timeout: 1000,
+            retry: 1,
+            retrans: 0,
+            runtime: Runtime::new().unwrap(),
+            clock: Clock::manual(1000),
+            in_flight: None,
+        };
+
+        let lookup = net.runtime.block_on(net.lookup(
+            question("example.com"),
+            server.ip(),
+            &Cancellation::default(),
+            None,
+        ));
+        responder.join().unwrap();
+        let response = lookup.outcome.unwrap();
+        let wire = lookup.wire.unwrap();
+        assert_eq!(response[..2], wire.query[..2]);
+        assert_eq!(wire.local.ip(), server.ip());
+        assert_ne!(wire.local.port(), 0);
+        assert_eq!(wire.remote, Some(server));
+        assert_eq!(wire.sent as usize, wire.query.len());
+        assert_eq!(wire.received as usize, response.len());
+    }
+
+    #[test]
+    fn udp_strays() {
+        // A server that answers from the wrong address, with the wrong message ID, with the query
+        // itself and for the wrong question first
+        let server_socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let other_socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = server_socket.local_addr().unwrap();
+        let other = other_socket.local_addr().unwrap();
+        let responder = std::thread::spawn(move || {
+            let mut buffer = [0; 512];
+            let (size, peer) = server_socket.recv_from(&mut buffer).unwrap();
+            let query = buffer[..size].to_vec();
+            let mut response = query.clone();
+            response[2] |= 0x80;
+            other_socket.send_to(&response, peer).unwrap();
+            let mut wrong_id = response.clone();
+            wrong_id[0] ^= 0xff;
+            let mut wrong_question = response.clone();
+            wrong_question[size - 3] ^= 0x02;
+            for message in [wrong_id, query, wrong_question, response] {
+                server_socket.send_to(&message, peer).unwrap();
+            }
+        });
+        let net = Net {
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            port: server.port(),
+            timeout: 1000,
+            retry: 1,
+            retrans: 0,
+            runtime: Runtime::new().unwrap(),
+            clock: Clock::manual(1000),
+            in_flight: None,
+        };
+
+        let lookup = net.runtime.block_on(net.lookup(
+            question("example.com"),
+            server.ip(),
+            &Cancellation::default(),
+            None,
+        ));
+        responder.join().unwrap();
+        let response = lookup.outcome.unwrap();
+        let wire = lookup.wire.unwrap();
+        assert_eq!(response[..2], wire.query[..2]);
+        assert_eq!(wire.remote, Some(server));
+        let remotes: Vec<_> = wire.strays.iter().map(|stray| stray.remote).collect();
+        assert_eq!(remotes, vec![other, server, server, server]);
+        assert_eq!(wire.strays[0].message, response);
+        assert_ne!(wire.strays[1].message[..2], response[..2]);
+        assert_eq!(wire.strays[2].message, wire.query);
+        assert_ne!(wire.strays[3].message, response);
+        assert_eq!(wire.received as usize, response.len() * 5);
+    }
+
+    #[test]
+    fn tcp_reconnect() {
+        use std::io::Read;
+        use std::io::Write;
+
+        // A server that ignores the first connection and answers on the second one, with the
+        // wrong message ID first
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap();
+        let responder = std::thread::spawn(move || {
+            let read_query = |stream: &mut std::net::TcpStream| {
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).unwrap();
+                let mut query = vec![0; u16::from_be_bytes(len) as usize];
+                stream.read_exact(&mut query).unwrap();
+                query
+            };
+            let (mut ignored, _) = listener.accept().unwrap();
+            read_query(&mut ignored);
+            let (mut answered, _) = listener.accept().unwrap();
+            let mut response = read_query(&mut answered);
+            response[2] |= 0x80;
+            let mut wrong_id = response.clone();
+            wrong_id[0] ^= 0xff;
+            for message in [wrong_id, response] {
+                answered
+                    .write_all(&(message.len() as u16).to_be_bytes())
+                    .unwrap();
+                answered.write_all(&message).unwrap();
+            }
+            ignored
+        });
+        let net = Net {
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            port: server.port(),
+            timeout: 200,
+            retry: 2,
+            retrans: 0,
+            runtime: Runtime::new().unwrap(),
+            clock: Clock::manual(1000),
+            in_flight: None,
+        };
+
+        let tcp = Question {
+            proto: Protocol::Tcp,
+            ..question("example.com")
+        };
+        let lookup =
+            net.runtime
+                .block_on(net.lookup(tcp, server.ip(), &Cancellation::default(), None));
+        drop(responder.join().unwrap());
+        assert_eq!(lookup.failures.len(), 1);
+        assert_eq!(lookup.failures[0].kind, ErrorKind::Timeout);
+        let response = lookup.outcome.unwrap();
+        let wire = lookup.wire.unwrap();
+        assert_eq!(response[..2], wire.query[..2]);
+        assert_eq!(wire.remote, Some(server));
+        assert_eq!(wire.strays.len(), 1);
+        assert_eq!(wire.strays[0].remote, server);
+        assert_eq!(wire.received as usize, (response.len() + 2) * 2);
+    }
+
+    #[test]
+    fn in_flight_limit() {
+        // A server that never answers
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = silent.local_addr().unwrap();
+        let net = Net {
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            port: server.port(),
+            timeout: 50,
+            retry: 1,
+            retrans: 0,
+            runtime: Runtime::new().unwrap(),
+            clock: Clock::Real,
+            in_flight: Some(Semaphore::new(1)),
+        };
+
+        let never = Cancellation::default();
+        let (first, second) = net.runtime.block_on(async {
+            tokio::join!(
+                net.lookup(question("a.example"), server.ip(), &never, None),
+                net.lookup(question("b.example"), server.ip(), &never, None),
+            )
+        });
+        let mut starts = [first.started, second.started];
+        starts.sort();
+        assert!(starts[1] - starts[0] >= 50, "{:?}", starts);
+    }
 }
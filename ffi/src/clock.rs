@@ -0,0 +1,117 @@
+//! Sources of time for timestamps, retry sleeps and timeouts.
+use std::future::Future;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Tells the time in millis since epoch.
+///
+/// Recordings made with a fixed or manual clock have the same timestamps on every run, and their
+/// retries don't actually wait.
+#[derive(Clone, Debug, Default)]
+pub enum Clock {
+    /// The system clock
+    #[default]
+    Real,
+    /// Always the same time
+    Fixed(u64),
+    /// A time that only moves when advanced, which sleeping does
+    Manual(Arc<AtomicU64>),
+}
+
+impl Clock {
+    pub fn manual(start: u64) -> Self {
+        Clock::Manual(Arc::new(AtomicU64::new(start)))
+    }
+
+    pub fn now(&self) -> u64 {
+        match self {
+            Clock::Real => chrono::Utc::now().timestamp_millis() as u64,
+            Clock::Fixed(now) => *now,
+            Clock::Manual(now) => now.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Starts measuring a duration.
+    ///
+    /// The system clock measures durations monotonically, so they aren't thrown off by changes
+    /// to the time of day.
+    pub fn stopwatch(&self) -> Stopwatch {
+        match self {
+            Clock::Real => Stopwatch::Monotonic(Instant::now()),
+            Clock::Fixed(_) | Clock::Manual(_) => Stopwatch::Simulated(self.clone(), self.now()),
+        }
+    }
+
+    /// Moves a manual clock forward. Other clocks are left as they are.
+    pub fn advance(&self, duration: Duration) {
+        if let Clock::Manual(now) = self {
+            now.fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Waits for a while, or just advances the clock unless it's the real one.
+    pub async fn sleep(&self, duration: Duration) {
+        match self {
+            Clock::Real => tokio::time::sleep(duration).await,
+            Clock::Fixed(_) | Clock::Manual(_) => self.advance(duration),
+        }
+    }
+
+    /// Sets a deadline a timeout from now.
+    pub fn deadline(&self, timeout: Duration) -> Deadline {
+        Deadline {
+            clock: self.clone(),
+            at: tokio::time::Instant::now() + timeout,
+            time: self.now() + timeout.as_millis() as u64,
+        }
+    }
+}
+
+/// When waiting on a socket gives up.
+///
+/// Sockets only ever wait in real time, but a manual clock is moved up to the deadline when it
+/// passes, so that recordings show timed out attempts taking their timeout on every run.
+pub struct Deadline {
+    clock: Clock,
+    at: tokio::time::Instant,
+    /// The time of the clock at the deadline (millis since epoch)
+    time: u64,
+}
+
+impl Deadline {
+    /// Waits for a future until the deadline, returning `None` if it passed first.
+    pub async fn timeout<F: Future>(&self, future: F) -> Option<F::Output> {
+        let output = tokio::time::timeout_at(self.at, future).await.ok();
+        if output.is_none() {
+            if let Clock::Manual(now) = &self.clock {
+                now.fetch_max(self.time, Ordering::Relaxed);
+            }
+        }
+        output
+    }
+}
+
+pub enum Stopwatch {
+    Monotonic(Instant),
+    /// A clock and its time when the stopwatch was started
+    Simulated(Clock, u64),
+}
+
+impl Stopwatch {
+    pub fn elapsed(&self) -> Duration {
+        match self {
+            Stopwatch::Monotonic(started) => started.elapsed(),
+            Stopwatch::Simulated(clock, started) => {
+                Duration::from_millis(clock.now().saturating_sub(*started))
+            }
+        }
+    }
+}
+
+/// Whole micros, saturating at `u32::MAX` (a bit over an hour).
+pub fn micros(duration: Duration) -> u32 {
+    duration.as_micros().min(u32::MAX as u128) as u32
+}
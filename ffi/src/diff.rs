@@ -0,0 +1,310 @@
+use crate::client::request_order;
+use crate::client::Cache;
+use crate::client::OutcomeKind;
+use crate::client::Question;
+use crate::client::RetriedResponse;
+use crate::store::StoreError;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::net::IpAddr;
+use trust_dns_client::op::Header;
+use trust_dns_client::op::Message;
+use trust_dns_client::op::ResponseCode;
+use trust_dns_client::rr::DNSClass;
+use trust_dns_client::rr::Name;
+use trust_dns_client::rr::Record;
+use trust_dns_client::rr::RecordType;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Section {
+    Answer,
+    Authority,
+    Additional,
+}
+
+impl From<Section> for u8 {
+    fn from(section: Section) -> Self {
+        match section {
+            Section::Answer => 1,
+            Section::Authority => 2,
+            Section::Additional => 3,
+        }
+    }
+}
+
+/// Header flags of a response, ignoring the message ID.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Flags(Header);
+
+impl Flags {
+    fn of(message: &Message) -> Self {
+        let mut header = Header::new();
+        header
+            .set_message_type(message.message_type())
+            .set_authoritative(message.authoritative())
+            .set_truncated(message.truncated())
+            .set_recursion_desired(message.recursion_desired())
+            .set_recursion_available(message.recursion_available())
+            .set_authentic_data(message.authentic_data())
+            .set_checking_disabled(message.checking_disabled());
+        Flags(header)
+    }
+}
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use trust_dns_client::op::MessageType;
+
+        let header = &self.0;
+        let flags = [
+            (header.message_type() == MessageType::Response, "qr"),
+            (header.authoritative(), "aa"),
+            (header.truncated(), "tc"),
+            (header.recursion_desired(), "rd"),
+            (header.recursion_available(), "ra"),
+            (header.authentic_data(), "ad"),
+            (header.checking_disabled(), "cd"),
+        ];
+        let flags: Vec<_> = flags
+            .iter()
+            .filter(|(is_set, _)| *is_set)
+            .map(|(_, flag)| *flag)
+            .collect();
+        write!(f, "{}", flags.join(" "))
+    }
+}
+
+/// A single difference between the entries for a (question, server) pair in two caches.
+///
+/// Only the latest response of each entry is compared.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Change {
+    /// The pair only exists in the new cache
+    Added,
+    /// The pair only exists in the old cache
+    Removed,
+    Outcome {
+        old: OutcomeKind,
+        new: OutcomeKind,
+    },
+    Rcode {
+        old: ResponseCode,
+        new: ResponseCode,
+    },
+    Flags {
+        old: Flags,
+        new: Flags,
+    },
+    /// The records of an RRset differ. An empty side means the RRset is missing on that side.
+    Rrset {
+        section: Section,
+        owner: Name,
+        rtype: RecordType,
+        old: Vec<Record>,
+        new: Vec<Record>,
+    },
+}
+
+impl Change {
+    pub fn kind(&self) -> u8 {
+        match self {
+            Change::Added => 1,
+            Change::Removed => 2,
+            Change::Outcome { .. } => 3,
+            Change::Rcode { .. } => 4,
+            Change::Flags { .. } => 5,
+            Change::Rrset { .. } => 6,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Difference {
+    pub question: Question,
+    pub server: IpAddr,
+    pub change: Change,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DiffOptions {
+    pub ignore_ttl: bool,
+}
+
+/// Compares the latest responses of two caches.
+///
+/// Differences are ordered by qname, qtype and server.
+pub fn diff(old: &Cache, new: &Cache, options: DiffOptions) -> Result<Vec<Difference>, StoreError> {
+    let old_keys: HashSet<_> = old.requests()?.into_iter().collect();
+    let new_keys: HashSet<_> = new.requests()?.into_iter().collect();
+    let mut keys: Vec<_> = old_keys.union(&new_keys).collect();
+    keys.sort_by_cached_key(|(question, server)| request_order(question, server));
+
+    let mut differences = Vec::new();
+    for (question, server) in keys {
+        let changes = match (old.latest(question, server)?, new.latest(question, server)?) {
+            (Some(old), Some(new)) => diff_responses(&old, &new, options),
+            (Some(_), None) => vec![Change::Removed],
+            (None, Some(_)) => vec![Change::Added],
+            (None, None) => vec![],
+        };
+        differences.extend(changes.into_iter().map(|change| Difference {
+            question: question.clone(),
+            server: *server,
+            change,
+        }));
+    }
+    Ok(differences)
+}
+
+fn diff_responses(
+    old: &RetriedResponse,
+    new: &RetriedResponse,
+    options: DiffOptions,
+) -> Vec<Change> {
+    let (old_kind, new_kind) = (old.outcome_kind(), new.outcome_kind());
+    if old_kind != new_kind {
+        return vec![Change::Outcome {
+            old: old_kind,
+            new: new_kind,
+        }];
+    }
+    let (Some(old), Some(new)) = (old.message(), new.message()) else {
+        return vec![];
+    };
+
+    let mut changes = Vec::new();
+    if old.response_code() != new.response_code() {
+        changes.push(Change::Rcode {
+            old: old.response_code(),
+            new: new.response_code(),
+        });
+    }
+    let (old_flags, new_flags) = (Flags::of(old), Flags::of(new));
+    if old_flags != new_flags {
+        changes.push(Change::Flags {
+            old: old_flags,
+            new: new_flags,
+        });
+    }
+    let sections = [
+        (Section::Answer, old.answers(), new.answers()),
+        (Section::Authority, old.name_servers(), new.name_servers()),
+        (Section::Additional, old.additionals(), new.additionals()),
+    ];
+    for (section, old, new) in sections {
+        let mut old = rrsets(old, options);
+        let mut new = rrsets(new, options);
+        let mut keys: Vec<_> = old.keys().chain(new.keys()).cloned().collect();
+        keys.sort();
+        keys.dedup();
+        for key in keys {
+            let old = old.remove(&key).unwrap_or_default();
+            let new = new.remove(&key).unwrap_or_default();
+            let same = old.len() == new.len()
+                && old
+                    .iter()
+                    .zip(&new)
+                    .all(|(a, b)| a == b && a.ttl() == b.ttl());
+            if !same {
+                let (owner, rtype, _) = key;
+                changes.push(Change::Rrset {
+                    section,
+                    owner,
+                    rtype,
+                    old,
+                    new,
+                });
+            }
+        }
+    }
+    changes
+}
+
+type RrsetKey = (Name, RecordType, DNSClass);
+
+fn rrsets(records: &[Record], options: DiffOptions) -> BTreeMap<RrsetKey, Vec<Record>> {
+    let mut rrsets: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for record in records {
+        let mut record = record.clone();
+        if options.ignore_ttl {
+            record.set_ttl(0);
+        }
+        let key = (
+            record.name().to_lowercase(),
+            record.record_type(),
+            record.dns_class(),
+        );
+        rrsets.entry(key).or_default().push(record);
+    }
+    for rrset in rrsets.values_mut() {
+        rrset.sort();
+    }
+    rrsets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::fixtures::question;
+    use crate::store::MemoryStore;
+    use crate::store::Store;
+    use std::sync::Arc;
+    use trust_dns_client::rr::RData;
+
+    fn response(id: u16, authoritative: bool, ttl: u32) -> Arc<RetriedResponse> {
+        let mut message = Message::new();
+        message
+            .set_id(id)
+            .set_authoritative(authoritative)
+            .add_answer(Record::from_rdata(
+                "example.com.".parse().unwrap(),
+                ttl,
+                RData::A("192.0.2.1".parse().unwrap()),
+            ));
+        Arc::new(fixtures::response(Ok(message)))
+    }
+
+    fn cache(entries: Vec<(&str, Arc<RetriedResponse>)>) -> Cache {
+        let server = "192.0.2.53".parse().unwrap();
+        let mut store = MemoryStore::new();
+        for (qname, response) in entries {
+            store.insert(question(qname), server, response).unwrap();
+        }
+        Cache::with_store(Box::new(store))
+    }
+
+    #[test]
+    fn diff_caches() {
+        let old = cache(vec![("example.com", response(1, true, 300))]);
+        let new = cache(vec![
+            ("example.com", response(2, false, 600)),
+            ("example.net", response(3, true, 300)),
+        ]);
+
+        let changes: Vec<_> = diff(&old, &new, DiffOptions { ignore_ttl: true })
+            .unwrap()
+            .into_iter()
+            .map(|difference| difference.change.kind())
+            .collect();
+        assert_eq!(changes, vec![5, 1]);
+
+        let changes: Vec<_> = diff(&old, &new, DiffOptions { ignore_ttl: false })
+            .unwrap()
+            .into_iter()
+            .map(|difference| difference.change)
+            .collect();
+        assert_eq!(changes.len(), 3);
+        match &changes[1] {
+            Change::Rrset {
+                section, old, new, ..
+            } => {
+                assert_eq!(*section, Section::Answer);
+                assert_eq!(old[0].ttl(), 300);
+                assert_eq!(new[0].ttl(), 600);
+            }
+            change => panic!("unexpected change {:?}", change),
+        }
+    }
+}
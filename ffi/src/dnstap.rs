@@ -0,0 +1,603 @@
+//! Recorded traffic as dnstap logs.
+//!
+//! Logs are Frame Streams files with the content type `protobuf:dnstap.Dnstap`. Only the subset
+//! of the protobuf encoding that dnstap uses is implemented here.
+use crate::capture;
+use crate::capture::CaptureError;
+use crate::capture::DnsPacket;
+use crate::capture::Request;
+use crate::capture::Sent;
+use crate::client::Cache;
+use crate::client::Protocol;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+const CONTROL_START: u32 = 2;
+const CONTROL_STOP: u32 = 3;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 1;
+
+// Fields of the Dnstap message
+const DNSTAP_VERSION: u32 = 2;
+const DNSTAP_MESSAGE: u32 = 14;
+const DNSTAP_TYPE: u32 = 15;
+const DNSTAP_TYPE_MESSAGE: u64 = 1;
+
+// Fields of the Message message
+const MESSAGE_TYPE: u32 = 1;
+const SOCKET_FAMILY: u32 = 2;
+const SOCKET_PROTOCOL: u32 = 3;
+const QUERY_ADDRESS: u32 = 4;
+const RESPONSE_ADDRESS: u32 = 5;
+const QUERY_PORT: u32 = 6;
+const RESPONSE_PORT: u32 = 7;
+const QUERY_TIME_SEC: u32 = 8;
+const QUERY_TIME_NSEC: u32 = 9;
+const QUERY_MESSAGE: u32 = 10;
+const RESPONSE_TIME_SEC: u32 = 12;
+const RESPONSE_TIME_NSEC: u32 = 13;
+const RESPONSE_MESSAGE: u32 = 14;
+
+const FAMILY_INET: u64 = 1;
+const FAMILY_INET6: u64 = 2;
+const PROTOCOL_UDP: u64 = 1;
+const PROTOCOL_TCP: u64 = 2;
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_FIXED64: u32 = 1;
+const WIRE_BYTES: u32 = 2;
+const WIRE_FIXED32: u32 = 5;
+
+/// The role of the logging client in the exported messages.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DnstapType {
+    /// CLIENT_QUERY and CLIENT_RESPONSE messages
+    Client,
+    /// RESOLVER_QUERY and RESOLVER_RESPONSE messages
+    Resolver,
+}
+
+impl DnstapType {
+    /// The query and response message types.
+    fn message_types(self) -> (u64, u64) {
+        match self {
+            DnstapType::Client => (5, 6),
+            DnstapType::Resolver => (3, 4),
+        }
+    }
+}
+
+impl TryFrom<u8> for DnstapType {
+    type Error = ();
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(DnstapType::Client),
+            2 => Ok(DnstapType::Resolver),
+            _ => Err(()),
+        }
+    }
+}
+
+fn invalid(reason: &str) -> CaptureError {
+    CaptureError::Format(reason.to_string())
+}
+
+#[derive(Default)]
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u32) {
+        self.varint(((field << 3) | wire_type) as u64);
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        self.key(field, WIRE_VARINT);
+        self.varint(value);
+    }
+
+    fn fixed32(&mut self, field: u32, value: u32) {
+        self.key(field, WIRE_FIXED32);
+        self.buf.extend(value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, WIRE_BYTES);
+        self.varint(value.len() as u64);
+        self.buf.extend(value);
+    }
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Fixed(u64),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Value<'a> {
+    fn uint(&self) -> Option<u64> {
+        match self {
+            Value::Varint(value) | Value::Fixed(value) => Some(*value),
+            Value::Bytes(_) => None,
+        }
+    }
+
+    fn bytes(&self) -> Option<&'a [u8]> {
+        match *self {
+            Value::Bytes(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// Splits an encoded protobuf message into its fields.
+fn fields(mut buf: &[u8]) -> Result<Vec<(u32, Value<'_>)>, CaptureError> {
+    fn varint(buf: &mut &[u8]) -> Result<u64, CaptureError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = buf
+                .split_first()
+                .ok_or_else(|| invalid("truncated field"))?;
+            *buf = rest;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("overlong varint"))
+    }
+    fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8], CaptureError> {
+        if buf.len() < len {
+            return Err(invalid("truncated field"));
+        }
+        let (value, rest) = buf.split_at(len);
+        *buf = rest;
+        Ok(value)
+    }
+
+    let mut fields = Vec::new();
+    while !buf.is_empty() {
+        let key = varint(&mut buf)?;
+        let value = match (key & 7) as u32 {
+            WIRE_VARINT => Value::Varint(varint(&mut buf)?),
+            WIRE_FIXED64 => {
+                Value::Fixed(u64::from_le_bytes(take(&mut buf, 8)?.try_into().unwrap()))
+            }
+            WIRE_BYTES => {
+                let len = varint(&mut buf)? as usize;
+                Value::Bytes(take(&mut buf, len)?)
+            }
+            WIRE_FIXED32 => {
+                Value::Fixed(u32::from_le_bytes(take(&mut buf, 4)?.try_into().unwrap()) as u64)
+            }
+            _ => return Err(invalid("unsupported protobuf wire type")),
+        };
+        fields.push(((key >> 3) as u32, value));
+    }
+    Ok(fields)
+}
+
+fn address_bytes(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    }
+}
+
+/// A logged message, as (time in micros since epoch, encoded Dnstap message).
+fn message(
+    request: &Request,
+    message_type: u64,
+    sent: &Sent,
+    response: Option<(u64, &[u8])>,
+) -> (u64, Vec<u8>) {
+    let mut message = Encoder::default();
+    message.uint(MESSAGE_TYPE, message_type);
+    let family = match sent.server.ip() {
+        IpAddr::V4(_) => FAMILY_INET,
+        IpAddr::V6(_) => FAMILY_INET6,
+    };
+    message.uint(SOCKET_FAMILY, family);
+    let protocol = match request.question.proto {
+        Protocol::Udp => PROTOCOL_UDP,
+        Protocol::Tcp => PROTOCOL_TCP,
+    };
+    message.uint(SOCKET_PROTOCOL, protocol);
+    message.bytes(QUERY_ADDRESS, &address_bytes(sent.client.ip()));
+    message.bytes(RESPONSE_ADDRESS, &address_bytes(sent.server.ip()));
+    message.uint(QUERY_PORT, sent.client.port() as u64);
+    message.uint(RESPONSE_PORT, sent.server.port() as u64);
+    message.uint(QUERY_TIME_SEC, sent.started / 1_000_000);
+    message.fixed32(QUERY_TIME_NSEC, (sent.started % 1_000_000) as u32 * 1000);
+    message.bytes(QUERY_MESSAGE, &sent.query);
+    let mut timestamp = sent.started;
+    if let Some((response_time, response)) = response {
+        message.uint(RESPONSE_TIME_SEC, response_time / 1_000_000);
+        message.fixed32(
+            RESPONSE_TIME_NSEC,
+            (response_time % 1_000_000) as u32 * 1000,
+        );
+        message.bytes(RESPONSE_MESSAGE, response);
+        timestamp = response_time;
+    }
+
+    let mut dnstap = Encoder::default();
+    let version = concat!("netbase ", env!("CARGO_PKG_VERSION"));
+    dnstap.bytes(DNSTAP_VERSION, version.as_bytes());
+    dnstap.bytes(DNSTAP_MESSAGE, &message.buf);
+    dnstap.uint(DNSTAP_TYPE, DNSTAP_TYPE_MESSAGE);
+    (timestamp, dnstap.buf)
+}
+
+fn control_frame(control_type: u32, content_type: Option<&[u8]>) -> Vec<u8> {
+    let mut control = control_type.to_be_bytes().to_vec();
+    if let Some(content_type) = content_type {
+        control.extend(CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+        control.extend((content_type.len() as u32).to_be_bytes());
+        control.extend(content_type);
+    }
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    frame.extend((control.len() as u32).to_be_bytes());
+    frame.extend(control);
+    frame
+}
+
+/// Exports every recorded exchange in a cache as a dnstap log.
+///
+/// Each attempt becomes a query message to the server. Requests that got a response are also
+/// logged with a response message that includes both the final query and the response. As with
+/// [`to_pcap`](crate::pcap::to_pcap), attempts are logged as recorded on the wire when they were,
+/// and otherwise with the query re-encoded from its question and sent from `client` to port 53 of
+/// the server. Servers of the other address family than `client` get the unspecified address of
+/// their family as client address.
+pub fn to_dnstap(
+    cache: &Cache,
+    client: IpAddr,
+    dnstap_type: DnstapType,
+) -> Result<Vec<u8>, CaptureError> {
+    let (query_type, response_type) = dnstap_type.message_types();
+    let mut messages = Vec::new();
+    for request in capture::requests(cache, client)? {
+        let attempts = request.attempts()?;
+        for sent in &attempts {
+            messages.push(message(&request, query_type, sent, None));
+        }
+        if let (Some(answer), Some(last)) = (request.answer(), attempts.last()) {
+            messages.push(message(&request, response_type, last, Some(answer)));
+        }
+    }
+    messages.sort_by_key(|(timestamp, _)| *timestamp);
+
+    let mut buf = control_frame(CONTROL_START, Some(CONTENT_TYPE));
+    for (_, message) in messages {
+        buf.extend((message.len() as u32).to_be_bytes());
+        buf.extend(message);
+    }
+    buf.extend(control_frame(CONTROL_STOP, None));
+    Ok(buf)
+}
+
+/// Splits a Frame Streams file into its data frames.
+fn data_frames(log: &[u8]) -> Result<Vec<&[u8]>, CaptureError> {
+    let u32_at = |offset: usize| -> Result<u32, CaptureError> {
+        let bytes = log
+            .get(offset..offset + 4)
+            .ok_or_else(|| invalid("truncated frame"))?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+    };
+    let frame_at = |offset: usize, len: usize| {
+        log.get(offset..offset + len)
+            .ok_or_else(|| invalid("truncated frame"))
+    };
+
+    let mut frames = Vec::new();
+    let mut offset = 0;
+    let mut started = false;
+    while offset < log.len() {
+        let len = u32_at(offset)? as usize;
+        if len > 0 {
+            if !started {
+                return Err(invalid("not a dnstap log"));
+            }
+            frames.push(frame_at(offset + 4, len)?);
+            offset += 4 + len;
+            continue;
+        }
+        let len = u32_at(offset + 4)? as usize;
+        let control = frame_at(offset + 8, len)?;
+        offset += 8 + len;
+        match u32::from_be_bytes(
+            control
+                .get(..4)
+                .ok_or_else(|| invalid("truncated frame"))?
+                .try_into()
+                .unwrap(),
+        ) {
+            CONTROL_START => {
+                let mut fields = &control[4..];
+                while fields.len() >= 8 {
+                    let field = u32::from_be_bytes(fields[..4].try_into().unwrap());
+                    let len = u32::from_be_bytes(fields[4..8].try_into().unwrap()) as usize;
+                    let value = fields
+                        .get(8..8 + len)
+                        .ok_or_else(|| invalid("truncated frame"))?;
+                    if field == CONTROL_FIELD_CONTENT_TYPE && value != CONTENT_TYPE {
+                        return Err(invalid("not a dnstap log"));
+                    }
+                    fields = &fields[8 + len..];
+                }
+                started = true;
+            }
+            CONTROL_STOP => break,
+            _ => {}
+        }
+    }
+    if !started {
+        return Err(invalid("not a dnstap log"));
+    }
+    Ok(frames)
+}
+
+/// The logged query or response of a dnstap message, if any.
+fn packet(message: &[u8]) -> Result<Option<DnsPacket>, CaptureError> {
+    let mut message_type = None;
+    let mut family = None;
+    let mut protocol = None;
+    let mut addresses = [None, None];
+    let mut ports = [0, 0];
+    let mut times = [None, None];
+    let mut nanos = [0, 0];
+    let mut messages = [None, None];
+    for (field, value) in fields(message)? {
+        match field {
+            MESSAGE_TYPE => message_type = value.uint(),
+            SOCKET_FAMILY => family = value.uint(),
+            SOCKET_PROTOCOL => protocol = value.uint(),
+            QUERY_ADDRESS => addresses[0] = value.bytes().map(<[u8]>::to_vec),
+            RESPONSE_ADDRESS => addresses[1] = value.bytes().map(<[u8]>::to_vec),
+            QUERY_PORT => ports[0] = value.uint().unwrap_or_default() as u16,
+            RESPONSE_PORT => ports[1] = value.uint().unwrap_or_default() as u16,
+            QUERY_TIME_SEC => times[0] = value.uint(),
+            QUERY_TIME_NSEC => nanos[0] = value.uint().unwrap_or_default(),
+            QUERY_MESSAGE => messages[0] = value.bytes().map(<[u8]>::to_vec),
+            RESPONSE_TIME_SEC => times[1] = value.uint(),
+            RESPONSE_TIME_NSEC => nanos[1] = value.uint().unwrap_or_default(),
+            RESPONSE_MESSAGE => messages[1] = value.bytes().map(<[u8]>::to_vec),
+            _ => {}
+        }
+    }
+    let proto = match protocol {
+        Some(PROTOCOL_UDP) => Protocol::Udp,
+        Some(PROTOCOL_TCP) => Protocol::Tcp,
+        _ => return Ok(None),
+    };
+    let addresses = addresses.map(|address| -> Option<IpAddr> {
+        match (family, address?.as_slice()) {
+            (Some(FAMILY_INET), &[a, b, c, d]) => Some(Ipv4Addr::new(a, b, c, d).into()),
+            (Some(FAMILY_INET6), address) => {
+                let octets: [u8; 16] = address.try_into().ok()?;
+                Some(Ipv6Addr::from(octets).into())
+            }
+            _ => None,
+        }
+    });
+    let unspecified = match family {
+        Some(FAMILY_INET6) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+    let client = SocketAddr::new(addresses[0].unwrap_or(unspecified), ports[0]);
+    let server = SocketAddr::new(addresses[1].unwrap_or(unspecified), ports[1]);
+    let micros = |index: usize| -> Result<Option<u64>, CaptureError> {
+        let Some(seconds) = times[index] else {
+            return Ok(None);
+        };
+        seconds
+            .checked_mul(1_000_000)
+            .and_then(|micros| micros.checked_add(nanos[index] / 1000))
+            .map(Some)
+            .ok_or_else(|| invalid("timestamp out of range"))
+    };
+    let micros = [micros(0)?, micros(1)?];
+
+    let Some(message_type) = message_type else {
+        return Ok(None);
+    };
+    let [query, response] = messages;
+    let packet = if message_type % 2 == 1 {
+        let (Some(timestamp), Some(bytes)) = (micros[0], query) else {
+            return Ok(None);
+        };
+        DnsPacket {
+            timestamp,
+            src: client,
+            dst: server,
+            proto,
+            bytes,
+            query: None,
+        }
+    } else {
+        let (Some(timestamp), Some(bytes)) = (micros[1], response) else {
+            return Ok(None);
+        };
+        DnsPacket {
+            timestamp,
+            src: server,
+            dst: client,
+            proto,
+            bytes,
+            query: micros[0].map(|sent| (sent, query)),
+        }
+    };
+    Ok(Some(packet))
+}
+
+/// Imports the DNS exchanges in a dnstap log.
+///
+/// Query and response messages of any type are considered. A response message that also carries
+/// the time of its query is recorded even if the query itself was not logged. The messages are
+/// paired up into requests as described for [`capture::to_cache`].
+pub fn from_dnstap(log: &[u8]) -> Result<Cache, CaptureError> {
+    let mut packets = Vec::new();
+    for frame in data_frames(log)? {
+        let mut is_message = false;
+        let mut message = None;
+        for (field, value) in fields(frame)? {
+            match field {
+                DNSTAP_TYPE => is_message = value.uint() == Some(DNSTAP_TYPE_MESSAGE),
+                DNSTAP_MESSAGE => message = value.bytes(),
+                _ => {}
+            }
+        }
+        if let (true, Some(message)) = (is_message, message) {
+            packets.extend(packet(message)?);
+        }
+    }
+    Ok(capture::to_cache(packets)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::request_order;
+    use crate::client::ErrorKind;
+    use crate::client::Failure;
+    use crate::client::Question;
+    use crate::client::RetriedResponse;
+    use crate::fixtures;
+    use crate::store::MemoryStore;
+    use crate::store::Store;
+    use std::sync::Arc;
+    use trust_dns_client::op::Message;
+    use trust_dns_client::op::MessageType;
+    use trust_dns_client::op::Query;
+    use trust_dns_client::rr::RecordType;
+
+    fn question(proto: Protocol) -> Question {
+        Question {
+            proto,
+            recursion_desired: true,
+            ..fixtures::question("example.com.")
+        }
+    }
+
+    fn answered(started: u64) -> Arc<RetriedResponse> {
+        let mut response = Message::new();
+        response
+            .set_id(4711)
+            .set_message_type(MessageType::Response)
+            .add_query(Query::query("example.com.".parse().unwrap(), RecordType::A));
+        Arc::new(RetriedResponse {
+            failures: vec![Failure {
+                query_micros: 1_000_000,
+                ..fixtures::timeout(started, 1000)
+            }],
+            started: started + 1000,
+            duration: 12,
+            query_micros: 12_345,
+            ..fixtures::response(Ok(response))
+        })
+    }
+
+    fn sorted(cache: &Cache) -> Vec<crate::store::Entry> {
+        let mut entries = cache.entries().unwrap();
+        entries.sort_by_cached_key(|(question, server, _)| {
+            (
+                request_order(question, server),
+                question.proto == Protocol::Tcp,
+            )
+        });
+        entries
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut store = MemoryStore::new();
+        let server: IpAddr = "192.0.2.53".parse().unwrap();
+        store
+            .insert(question(Protocol::Udp), server, answered(1_600_000_000_000))
+            .unwrap();
+        store
+            .insert(question(Protocol::Tcp), server, answered(1_600_000_010_000))
+            .unwrap();
+        store
+            .insert(
+                question(Protocol::Udp),
+                "2001:db8::53".parse().unwrap(),
+                Arc::new(RetriedResponse {
+                    started: 1_600_000_020_000,
+                    ..fixtures::response(Err(ErrorKind::Timeout))
+                }),
+            )
+            .unwrap();
+        let cache = Cache::with_store(Box::new(store));
+
+        let client = "192.0.2.1".parse().unwrap();
+        for dnstap_type in [DnstapType::Client, DnstapType::Resolver] {
+            let log = to_dnstap(&cache, client, dnstap_type).unwrap();
+            let imported = from_dnstap(&log).unwrap();
+            assert_eq!(sorted(&imported), sorted(&cache));
+        }
+    }
+
+    #[test]
+    fn response_only() {
+        let mut store = MemoryStore::new();
+        let server: IpAddr = "192.0.2.53".parse().unwrap();
+        store
+            .insert(question(Protocol::Udp), server, answered(1_600_000_000_000))
+            .unwrap();
+        let cache = Cache::with_store(Box::new(store));
+        let log = to_dnstap(&cache, "192.0.2.1".parse().unwrap(), DnstapType::Client).unwrap();
+
+        // Drop the query messages, leaving the start frame, the response and the stop frame
+        let frames = data_frames(&log).unwrap();
+        assert_eq!(frames.len(), 3);
+        let mut response_only = control_frame(CONTROL_START, Some(CONTENT_TYPE));
+        response_only.extend((frames[2].len() as u32).to_be_bytes());
+        response_only.extend(frames[2]);
+
+        let imported = from_dnstap(&response_only).unwrap();
+        let (_, _, history) = imported.entries().unwrap().remove(0);
+        assert_eq!(history.len(), 1);
+        assert!(history[0].failures.is_empty());
+        assert_eq!(history[0].started, 1_600_000_001_000);
+        assert_eq!(history[0].outcome, answered(0).outcome);
+    }
+
+    #[test]
+    fn not_dnstap() {
+        assert!(from_dnstap(b"\xa1\xb2\xc3\xd4").is_err());
+        let mut other = control_frame(CONTROL_START, Some(b"protobuf:other"));
+        other.extend(control_frame(CONTROL_STOP, None));
+        assert!(from_dnstap(&other).is_err());
+    }
+
+    #[test]
+    fn timestamp_out_of_range() {
+        let mut message = Encoder::default();
+        message.uint(MESSAGE_TYPE, 5);
+        message.uint(SOCKET_PROTOCOL, PROTOCOL_UDP);
+        message.uint(QUERY_TIME_SEC, u64::MAX / 1000);
+        message.bytes(QUERY_MESSAGE, &[0; 12]);
+        let mut dnstap = Encoder::default();
+        dnstap.bytes(DNSTAP_MESSAGE, &message.buf);
+        dnstap.uint(DNSTAP_TYPE, DNSTAP_TYPE_MESSAGE);
+
+        let mut log = control_frame(CONTROL_START, Some(CONTENT_TYPE));
+        log.extend((dnstap.buf.len() as u32).to_be_bytes());
+        log.extend(&dnstap.buf);
+        log.extend(control_frame(CONTROL_STOP, None));
+        assert!(from_dnstap(&log).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+//! Questions and responses shared by the tests of different modules.
+//!
+//! Tests needing something else update these with struct update syntax, e.g.
+//! `Question { proto: Protocol::Tcp, ..question("example.com.") }`.
+use crate::client::ErrorKind;
+use crate::client::Failure;
+use crate::client::Protocol;
+use crate::client::Question;
+use crate::client::RetriedResponse;
+use crate::trust_dns_ext::MyMessage;
+use trust_dns_client::op::Message;
+use trust_dns_client::op::MessageType;
+use trust_dns_client::rr::RecordType;
+
+/// An A question over UDP without recursion or EDNS.
+pub fn question(qname: &str) -> Question {
+    Question {
+        qname: qname.parse().unwrap(),
+        qtype: RecordType::A,
+        proto: Protocol::Udp,
+        recursion_desired: false,
+        edns_config: None,
+    }
+}
+
+/// A response with the given outcome and no failed attempts, started at the epoch and taking no
+/// time.
+pub fn response(outcome: Result<Message, ErrorKind>) -> RetriedResponse {
+    RetriedResponse {
+        failures: vec![],
+        started: 0,
+        duration: 0,
+        outcome: outcome.map(|message| MyMessage::from_vec(message.to_vec().unwrap()).0),
+        connect_micros: 0,
+        query_micros: 0,
+        wire: None,
+    }
+}
+
+/// An empty authoritative response as sent over the wire, e.g. by a scripted transport.
+pub fn answer() -> Vec<u8> {
+    let mut message = Message::new();
+    message
+        .set_message_type(MessageType::Response)
+        .set_authoritative(true);
+    message.to_vec().unwrap()
+}
+
+/// A timed out attempt.
+pub fn timeout(query_start: u64, query_duration: u32) -> Failure {
+    Failure {
+        query_start,
+        query_duration,
+        kind: ErrorKind::Timeout,
+        query_micros: 0,
+        wire: None,
+    }
+}
@@ -0,0 +1,801 @@
+//! JSON representation of caches.
+//!
+//! Response messages are represented as in RFC 8427, along with their wire format bytes in base64
+//! for exact round-trips. When a message has been edited by hand so that it no longer matches its
+//! wire format, the message is re-encoded from the edited representation on import.
+use crate::client::Cache;
+use crate::client::EdnsConfig;
+use crate::client::ErrorKind;
+use crate::client::Failure;
+use crate::client::Protocol;
+use crate::client::Question;
+use crate::client::RetriedResponse;
+use crate::client::Stray;
+use crate::client::Wire;
+use crate::store::MemoryStore;
+use crate::store::Store;
+use crate::store::StoreError;
+use crate::trust_dns_ext::MyMessage;
+use data_encoding::BASE64;
+use data_encoding::HEXUPPER;
+use data_encoding::HEXUPPER_PERMISSIVE;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use trust_dns_client::op::Edns;
+use trust_dns_client::op::Message;
+use trust_dns_client::op::MessageType;
+use trust_dns_client::op::OpCode;
+use trust_dns_client::op::Query;
+use trust_dns_client::rr::DNSClass;
+use trust_dns_client::rr::Name;
+use trust_dns_client::rr::RData;
+use trust_dns_client::rr::Record;
+use trust_dns_client::rr::RecordType;
+use trust_dns_proto::error::ProtoError;
+use trust_dns_proto::serialize::binary::BinDecoder;
+use trust_dns_proto::serialize::binary::BinEncodable;
+use trust_dns_proto::serialize::binary::Restrict;
+use trust_dns_proto::serialize::txt::RDataParser;
+
+#[derive(Debug)]
+pub enum JsonError {
+    Syntax(serde_json::Error),
+    Invalid(String),
+    Proto(ProtoError),
+    Store(StoreError),
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonError::Syntax(err) => write!(f, "json error: {}", err),
+            JsonError::Invalid(reason) => write!(f, "invalid cache: {}", reason),
+            JsonError::Proto(err) => write!(f, "dns error: {}", err),
+            JsonError::Store(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+impl From<serde_json::Error> for JsonError {
+    fn from(err: serde_json::Error) -> Self {
+        JsonError::Syntax(err)
+    }
+}
+
+impl From<ProtoError> for JsonError {
+    fn from(err: ProtoError) -> Self {
+        JsonError::Proto(err)
+    }
+}
+
+impl From<StoreError> for JsonError {
+    fn from(err: StoreError) -> Self {
+        JsonError::Store(err)
+    }
+}
+
+fn invalid<T: fmt::Display>(what: &str, value: T) -> JsonError {
+    JsonError::Invalid(format!("{}: {}", what, value))
+}
+
+#[derive(Deserialize, Serialize)]
+struct JsonCache {
+    entries: Vec<JsonEntry>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct JsonEntry {
+    question: JsonQuestion,
+    server: IpAddr,
+    /// Oldest first
+    history: Vec<JsonResponse>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct JsonQuestion {
+    qname: String,
+    qtype: String,
+    /// "udp" or "tcp"
+    proto: String,
+    recursion_desired: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    edns: Option<JsonEdns>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct JsonEdns {
+    version: u8,
+    dnssec_ok: bool,
+    max_payload: u16,
+    #[serde(default)]
+    option_code: u16,
+    /// Base64
+    #[serde(default)]
+    option_value: String,
+}
+
+/// A response and the failed attempts before it.
+///
+/// Exactly one of `error`, `message` and `wire` is required. A `wire` without a `message` is a
+/// response that couldn't be decoded.
+#[derive(Deserialize, Serialize)]
+struct JsonResponse {
+    /// Millis since epoch
+    started: u64,
+    /// Millis
+    duration: u32,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    connect_micros: u32,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    query_micros: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    socket: Option<JsonSocket>,
+    #[serde(default)]
+    failures: Vec<JsonFailure>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message: Option<JsonMessage>,
+    /// Base64
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    wire: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct JsonFailure {
+    started: u64,
+    duration: u32,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    query_micros: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    socket: Option<JsonSocket>,
+    error: String,
+}
+
+/// An attempt as seen from the local socket.
+#[derive(Deserialize, Serialize)]
+struct JsonSocket {
+    /// The query message as sent, in base64
+    query: String,
+    local: SocketAddr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    remote: Option<SocketAddr>,
+    sent: u32,
+    received: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    strays: Vec<JsonStray>,
+}
+
+/// A message that wasn't taken for the response.
+#[derive(Deserialize, Serialize)]
+struct JsonStray {
+    /// The message as received, in base64
+    message: String,
+    remote: SocketAddr,
+}
+
+/// Micros are left out when unknown.
+fn is_zero(micros: &u32) -> bool {
+    *micros == 0
+}
+
+/// An RFC 8427 message object.
+///
+/// Flags are written as 0 or 1 and may be given as booleans as well. The counts are ignored on
+/// import.
+#[derive(Deserialize, Serialize)]
+struct JsonMessage {
+    #[serde(rename = "ID")]
+    id: u16,
+    #[serde(rename = "QR", deserialize_with = "flag")]
+    qr: u8,
+    #[serde(rename = "Opcode")]
+    opcode: u8,
+    #[serde(rename = "AA", deserialize_with = "flag")]
+    aa: u8,
+    #[serde(rename = "TC", deserialize_with = "flag")]
+    tc: u8,
+    #[serde(rename = "RD", deserialize_with = "flag")]
+    rd: u8,
+    #[serde(rename = "RA", deserialize_with = "flag")]
+    ra: u8,
+    #[serde(rename = "AD", deserialize_with = "flag")]
+    ad: u8,
+    #[serde(rename = "CD", deserialize_with = "flag")]
+    cd: u8,
+    #[serde(rename = "RCODE")]
+    rcode: u16,
+    #[serde(rename = "QDCOUNT", default)]
+    qdcount: u16,
+    #[serde(rename = "ANCOUNT", default)]
+    ancount: u16,
+    #[serde(rename = "NSCOUNT", default)]
+    nscount: u16,
+    #[serde(rename = "ARCOUNT", default)]
+    arcount: u16,
+    #[serde(rename = "questionRRs", default)]
+    questions: Vec<JsonQuery>,
+    #[serde(rename = "answerRRs", default)]
+    answers: Vec<JsonRecord>,
+    #[serde(rename = "authorityRRs", default)]
+    authorities: Vec<JsonRecord>,
+    #[serde(rename = "additionalRRs", default)]
+    additionals: Vec<JsonRecord>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct JsonQuery {
+    #[serde(rename = "NAME")]
+    name: String,
+    #[serde(rename = "TYPE")]
+    rtype: u16,
+    #[serde(rename = "TYPEname", default, skip_serializing_if = "Option::is_none")]
+    type_name: Option<String>,
+    #[serde(rename = "CLASS")]
+    class: u16,
+}
+
+/// An RFC 8427 resource record object.
+///
+/// The RDATA is given in hex and, for types whose presentation format can be parsed back, in
+/// presentation format under the key `rdata` followed by the type name, e.g. `rdataA`. The
+/// presentation format takes precedence on import.
+#[derive(Deserialize, Serialize)]
+struct JsonRecord {
+    #[serde(rename = "NAME")]
+    name: String,
+    #[serde(rename = "TYPE")]
+    rtype: u16,
+    #[serde(rename = "TYPEname", default, skip_serializing_if = "Option::is_none")]
+    type_name: Option<String>,
+    #[serde(rename = "CLASS")]
+    class: u16,
+    #[serde(rename = "TTL")]
+    ttl: u32,
+    #[serde(rename = "RDLENGTH", default, skip_serializing_if = "Option::is_none")]
+    rdlength: Option<u16>,
+    #[serde(rename = "RDATAHEX", default, skip_serializing_if = "Option::is_none")]
+    rdata_hex: Option<String>,
+    #[serde(flatten)]
+    rest: BTreeMap<String, Value>,
+}
+
+fn flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    use serde::de::Error;
+
+    match Value::deserialize(deserializer)? {
+        Value::Bool(value) => Ok(value as u8),
+        Value::Number(n) if n.as_u64() == Some(0) => Ok(0),
+        Value::Number(n) if n.as_u64() == Some(1) => Ok(1),
+        value => Err(D::Error::custom(format!("invalid flag: {}", value))),
+    }
+}
+
+/// The RFC 3597 name of unknown types.
+fn type_name(rtype: RecordType) -> String {
+    match rtype {
+        RecordType::Unknown(code) => format!("TYPE{}", code),
+        rtype => rtype.to_string(),
+    }
+}
+
+fn parse_type(name: &str) -> Result<RecordType, JsonError> {
+    let name = name.to_ascii_uppercase();
+    match name.strip_prefix("TYPE").map(u16::from_str) {
+        Some(Ok(code)) => Ok(RecordType::from(code)),
+        _ => RecordType::from_str(&name).map_err(|_| invalid("unknown type", name)),
+    }
+}
+
+fn parse_name(name: &str) -> Result<Name, JsonError> {
+    Name::from_str(name).map_err(|err| invalid("invalid name", err))
+}
+
+fn parse_error_kind(name: &str) -> Result<ErrorKind, JsonError> {
+    ErrorKind::from_str(name).map_err(|_| invalid("unknown error", name))
+}
+
+fn decode_base64(value: &str) -> Result<Vec<u8>, JsonError> {
+    BASE64
+        .decode(value.as_bytes())
+        .map_err(|err| invalid("invalid base64", err))
+}
+
+/// Serializes the entries of a cache, ordered by qname, qtype and server.
+///
+/// Entries only found in bases of the cache are left out.
+pub fn to_json(cache: &Cache) -> Result<String, JsonError> {
+    let entries = cache.own_entries()?;
+    let entries = entries
+        .into_iter()
+        .map(|(question, server, history)| {
+            Ok(JsonEntry {
+                question: question_to_json(&question),
+                server,
+                history: history
+                    .iter()
+                    .map(|response| response_to_json(response))
+                    .collect::<Result<_, JsonError>>()?,
+            })
+        })
+        .collect::<Result<_, JsonError>>()?;
+    Ok(serde_json::to_string_pretty(&JsonCache { entries })?)
+}
+
+/// Constructs a new in-memory cache from its JSON representation.
+pub fn from_json(json: &str) -> Result<Cache, JsonError> {
+    let cache: JsonCache = serde_json::from_str(json)?;
+    let mut entries = Vec::new();
+    for entry in cache.entries {
+        let history = entry
+            .history
+            .into_iter()
+            .map(response_from_json)
+            .collect::<Result<_, _>>()?;
+        entries.push((question_from_json(entry.question)?, entry.server, history));
+    }
+    let mut store = MemoryStore::new();
+    store.extend(entries)?;
+    Ok(Cache::with_store(Box::new(store)))
+}
+
+fn question_to_json(question: &Question) -> JsonQuestion {
+    JsonQuestion {
+        qname: question.qname.to_string(),
+        qtype: type_name(question.qtype),
+        proto: match question.proto {
+            Protocol::Udp => "udp".to_string(),
+            Protocol::Tcp => "tcp".to_string(),
+        },
+        recursion_desired: question.recursion_desired,
+        edns: question.edns_config.as_ref().map(|edns| JsonEdns {
+            version: edns.version,
+            dnssec_ok: edns.dnssec_ok,
+            max_payload: edns.max_payload,
+            option_code: edns.option_code,
+            option_value: BASE64.encode(&edns.option_value),
+        }),
+    }
+}
+
+fn question_from_json(question: JsonQuestion) -> Result<Question, JsonError> {
+    let edns_config = match question.edns {
+        Some(edns) => Some(EdnsConfig {
+            version: edns.version,
+            dnssec_ok: edns.dnssec_ok,
+            max_payload: edns.max_payload,
+            option_code: edns.option_code,
+            option_value: decode_base64(&edns.option_value)?,
+        }),
+        None => None,
+    };
+    Ok(Question {
+        qname: parse_name(&question.qname)?,
+        qtype: parse_type(&question.qtype)?,
+        proto: match question.proto.as_str() {
+            "udp" => Protocol::Udp,
+            "tcp" => Protocol::Tcp,
+            proto => return Err(invalid("unknown protocol", proto)),
+        },
+        recursion_desired: question.recursion_desired,
+        edns_config,
+    })
+}
+
+fn response_to_json(response: &RetriedResponse) -> Result<JsonResponse, JsonError> {
+    let (error, message, wire) = match &response.outcome {
+        Err(error_kind) => (Some(error_kind.to_string()), None, None),
+        Ok(MyMessage { encoded, decoded }) => {
+            let message = match decoded {
+                Some(message) => Some(message_to_json(message)?),
+                None => None,
+            };
+            (None, message, Some(BASE64.encode(encoded)))
+        }
+    };
+    Ok(JsonResponse {
+        started: response.started,
+        duration: response.duration,
+        connect_micros: response.connect_micros,
+        query_micros: response.query_micros,
+        socket: response.wire.as_ref().map(socket_to_json),
+        failures: response
+            .failures
+            .iter()
+            .map(|failure| JsonFailure {
+                started: failure.query_start,
+                duration: failure.query_duration,
+                query_micros: failure.query_micros,
+                socket: failure.wire.as_ref().map(socket_to_json),
+                error: failure.kind.to_string(),
+            })
+            .collect(),
+        error,
+        message,
+        wire,
+    })
+}
+
+fn response_from_json(response: JsonResponse) -> Result<Arc<RetriedResponse>, JsonError> {
+    let failures = response
+        .failures
+        .into_iter()
+        .map(|failure| {
+            Ok(Failure {
+                query_start: failure.started,
+                query_duration: failure.duration,
+                kind: parse_error_kind(&failure.error)?,
+                query_micros: failure.query_micros,
+                wire: failure.socket.map(socket_from_json).transpose()?,
+            })
+        })
+        .collect::<Result<_, JsonError>>()?;
+    let wire = match &response.wire {
+        Some(wire) => Some(decode_base64(wire)?),
+        None => None,
+    };
+    let outcome = match (response.error, response.message, wire) {
+        (Some(error), None, None) => Err(parse_error_kind(&error)?),
+        (None, Some(message), wire) => {
+            let encoded = message_from_json(message)?.to_vec()?;
+            // Keep the original bytes unless the message was edited
+            let encoded = match wire {
+                Some(wire) if reencode(&wire).as_ref() == Some(&encoded) => wire,
+                _ => encoded,
+            };
+            Ok(MyMessage::from_vec(encoded).0)
+        }
+        (None, None, Some(wire)) => Ok(MyMessage::from_vec(wire).0),
+        _ => {
+            return Err(JsonError::Invalid(
+                "a response needs exactly one of error, message and wire".to_string(),
+            ))
+        }
+    };
+    Ok(Arc::new(RetriedResponse {
+        failures,
+        started: response.started,
+        duration: response.duration,
+        outcome,
+        connect_micros: response.connect_micros,
+        query_micros: response.query_micros,
+        wire: response.socket.map(socket_from_json).transpose()?,
+    }))
+}
+
+fn socket_to_json(wire: &Wire) -> JsonSocket {
+    JsonSocket {
+        query: BASE64.encode(&wire.query),
+        local: wire.local,
+        remote: wire.remote,
+        sent: wire.sent,
+        received: wire.received,
+        strays: wire
+            .strays
+            .iter()
+            .map(|stray| JsonStray {
+                message: BASE64.encode(&stray.message),
+                remote: stray.remote,
+            })
+            .collect(),
+    }
+}
+
+fn socket_from_json(socket: JsonSocket) -> Result<Wire, JsonError> {
+    Ok(Wire {
+        query: decode_base64(&socket.query)?,
+        local: socket.local,
+        remote: socket.remote,
+        sent: socket.sent,
+        received: socket.received,
+        strays: socket
+            .strays
+            .into_iter()
+            .map(|stray| {
+                Ok(Stray {
+                    message: decode_base64(&stray.message)?,
+                    remote: stray.remote,
+                })
+            })
+            .collect::<Result<_, JsonError>>()?,
+    })
+}
+
+fn reencode(wire: &[u8]) -> Option<Vec<u8>> {
+    Message::from_vec(wire).ok()?.to_vec().ok()
+}
+
+fn message_to_json(message: &Message) -> Result<JsonMessage, JsonError> {
+    let header = message.header();
+    let records = |records: &[Record]| -> Result<Vec<_>, JsonError> {
+        records.iter().map(record_to_json).collect()
+    };
+    let mut additionals: Vec<_> = records(message.additionals())?;
+    if let Some(edns) = message.extensions() {
+        additionals.push(record_to_json(&Record::from(edns))?);
+    }
+    additionals.extend(records(message.sig0())?);
+    Ok(JsonMessage {
+        id: header.id(),
+        qr: (header.message_type() == MessageType::Response) as u8,
+        opcode: header.op_code().into(),
+        aa: header.authoritative() as u8,
+        tc: header.truncated() as u8,
+        rd: header.recursion_desired() as u8,
+        ra: header.recursion_available() as u8,
+        ad: header.authentic_data() as u8,
+        cd: header.checking_disabled() as u8,
+        rcode: message.response_code().into(),
+        qdcount: header.query_count(),
+        ancount: header.answer_count(),
+        nscount: header.name_server_count(),
+        arcount: header.additional_count(),
+        questions: message
+            .queries()
+            .iter()
+            .map(|query| JsonQuery {
+                name: query.name().to_string(),
+                rtype: query.query_type().into(),
+                type_name: Some(type_name(query.query_type())),
+                class: query.query_class().into(),
+            })
+            .collect(),
+        answers: records(message.answers())?,
+        authorities: records(message.name_servers())?,
+        additionals,
+    })
+}
+
+fn message_from_json(json: JsonMessage) -> Result<Message, JsonError> {
+    let mut message = Message::new();
+    message
+        .set_id(json.id)
+        .set_message_type(match json.qr {
+            0 => MessageType::Query,
+            _ => MessageType::Response,
+        })
+        .set_op_code(
+            OpCode::from_u8(json.opcode).map_err(|_| invalid("unknown opcode", json.opcode))?,
+        )
+        .set_authoritative(json.aa != 0)
+        .set_truncated(json.tc != 0)
+        .set_recursion_desired(json.rd != 0)
+        .set_recursion_available(json.ra != 0)
+        .set_authentic_data(json.ad != 0)
+        .set_checking_disabled(json.cd != 0)
+        .set_response_code(json.rcode.into());
+    for json in json.questions {
+        let mut query = Query::query(parse_name(&json.name)?, RecordType::from(json.rtype));
+        query.set_query_class(DNSClass::from_u16(json.class)?);
+        message.add_query(query);
+    }
+    for record in json.answers {
+        message.add_answer(record_from_json(record)?);
+    }
+    for record in json.authorities {
+        message.add_name_server(record_from_json(record)?);
+    }
+    for record in json.additionals {
+        let record = record_from_json(record)?;
+        match record.record_type() {
+            RecordType::OPT => {
+                message.set_edns(Edns::from(&record));
+            }
+            RecordType::SIG => {
+                message.add_sig0(record);
+            }
+            _ => {
+                message.add_additional(record);
+            }
+        }
+    }
+    Ok(message)
+}
+
+fn record_to_json(record: &Record) -> Result<JsonRecord, JsonError> {
+    let rtype = record.record_type();
+    let type_name = type_name(rtype);
+    let rdata = match record.data() {
+        Some(rdata) => rdata.to_bytes()?,
+        None => vec![],
+    };
+    let mut rest = BTreeMap::new();
+    if let Some(text) = record.data().and_then(|rdata| presentation(rtype, rdata)) {
+        rest.insert(format!("rdata{}", type_name), Value::String(text));
+    }
+    Ok(JsonRecord {
+        name: record.name().to_string(),
+        rtype: rtype.into(),
+        type_name: Some(type_name),
+        class: record.dns_class().into(),
+        ttl: record.ttl(),
+        rdlength: Some(rdata.len() as u16),
+        rdata_hex: Some(HEXUPPER.encode(&rdata)),
+        rest,
+    })
+}
+
+/// The presentation format of the RDATA, if it can be parsed back into the same RDATA.
+fn presentation(rtype: RecordType, rdata: &RData) -> Option<String> {
+    if rtype == RecordType::OPT {
+        return None;
+    }
+    let mut text = String::new();
+    write!(text, "{}", rdata).ok()?;
+    match RData::try_from_str(rtype, &text) {
+        Ok(parsed) if &parsed == rdata => Some(text),
+        _ => None,
+    }
+}
+
+fn record_from_json(json: JsonRecord) -> Result<Record, JsonError> {
+    let rtype = RecordType::from(json.rtype);
+    let class = match rtype {
+        RecordType::OPT => DNSClass::for_opt(json.class),
+        _ => DNSClass::from_u16(json.class)?,
+    };
+    let key = format!("rdata{}", type_name(rtype));
+    let rdata = match (json.rest.get(&key), json.rdata_hex) {
+        (Some(Value::String(text)), _) => Some(
+            RData::try_from_str(rtype, text)
+                .map_err(|err| invalid(&format!("invalid {}", key), err))?,
+        ),
+        (Some(value), _) => return Err(invalid(&format!("invalid {}", key), value)),
+        (None, Some(hex)) => {
+            let bytes = HEXUPPER_PERMISSIVE
+                .decode(hex.as_bytes())
+                .map_err(|err| invalid("invalid RDATAHEX", err))?;
+            if bytes.is_empty() {
+                None
+            } else {
+                let mut decoder = BinDecoder::new(&bytes);
+                Some(RData::read(
+                    &mut decoder,
+                    rtype,
+                    Restrict::new(bytes.len() as u16),
+                )?)
+            }
+        }
+        (None, None) => return Err(invalid("record without RDATA", json.name)),
+    };
+    let mut record = Record::with(parse_name(&json.name)?, rtype, json.ttl);
+    record.set_dns_class(class).set_data(rdata);
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use trust_dns_client::op::ResponseCode;
+
+    fn question(qname: &str, qtype: RecordType) -> Question {
+        Question {
+            qtype,
+            proto: Protocol::Tcp,
+            recursion_desired: true,
+            edns_config: Some(EdnsConfig {
+                version: 0,
+                dnssec_ok: true,
+                max_payload: 1232,
+                option_code: 10,
+                option_value: vec![1, 2, 3],
+            }),
+            ..fixtures::question(qname)
+        }
+    }
+
+    /// A response with an outcome that doesn't need to decode.
+    fn response(message: Result<Vec<u8>, ErrorKind>) -> Arc<RetriedResponse> {
+        Arc::new(RetriedResponse {
+            failures: vec![fixtures::timeout(1000, 2000)],
+            started: 3000,
+            duration: 12,
+            outcome: message.map(|bytes| MyMessage::from_vec(bytes).0),
+            wire: Some(Wire {
+                query: vec![0x12, 0x67, 1, 0],
+                local: "192.0.2.1:40000".parse().unwrap(),
+                remote: Some("192.0.2.53:53".parse().unwrap()),
+                sent: 4,
+                received: 16,
+                strays: vec![Stray {
+                    message: vec![0x12, 0x68, 0x80, 0],
+                    remote: "192.0.2.53:53".parse().unwrap(),
+                }],
+            }),
+            ..fixtures::response(Err(ErrorKind::Io))
+        })
+    }
+
+    fn message() -> Vec<u8> {
+        let mut message = Message::new();
+        message
+            .set_id(4711)
+            .set_message_type(MessageType::Response)
+            .set_authoritative(true)
+            .set_response_code(ResponseCode::BADKEY)
+            .add_query(Query::query("example.com.".parse().unwrap(), RecordType::A))
+            .add_answer(Record::from_rdata(
+                "example.com.".parse().unwrap(),
+                300,
+                RData::A("192.0.2.1".parse().unwrap()),
+            ))
+            .add_name_server(Record::from_rdata(
+                "example.com.".parse().unwrap(),
+                300,
+                RData::Unknown {
+                    code: 65280,
+                    rdata: trust_dns_client::rr::rdata::NULL::with(vec![0xde, 0xad]),
+                },
+            ))
+            .set_edns(Edns::new());
+        message.to_vec().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let server: IpAddr = "192.0.2.53".parse().unwrap();
+        let mut store = MemoryStore::new();
+        store
+            .set_history(
+                question("example.com.", RecordType::A),
+                server,
+                vec![response(Err(ErrorKind::Io)), response(Ok(message()))],
+            )
+            .unwrap();
+        store
+            .insert(
+                question("example.net.", RecordType::Unknown(65280)),
+                server,
+                response(Ok(vec![1, 2, 3])),
+            )
+            .unwrap();
+        let cache = Cache::with_store(Box::new(store));
+
+        let json = to_json(&cache).unwrap();
+        assert!(json.contains("\"rdataA\": \"192.0.2.1\""));
+        assert!(json.contains("\"qtype\": \"TYPE65280\""));
+        let restored = from_json(&json).unwrap();
+        assert_eq!(restored.entries().unwrap(), cache.entries().unwrap());
+        assert_eq!(to_json(&restored).unwrap(), json);
+    }
+
+    #[test]
+    fn edited_message() {
+        let server: IpAddr = "192.0.2.53".parse().unwrap();
+        let mut store = MemoryStore::new();
+        store
+            .insert(
+                question("example.com.", RecordType::A),
+                server,
+                response(Ok(message())),
+            )
+            .unwrap();
+        let cache = Cache::with_store(Box::new(store));
+        let json = to_json(&cache)
+            .unwrap()
+            .replace("\"rdataA\": \"192.0.2.1\"", "\"rdataA\": \"192.0.2.2\"")
+            .replace("\"AA\": 1", "\"AA\": false");
+
+        let restored = from_json(&json).unwrap();
+        let entries = restored.entries().unwrap();
+        let message = entries[0].2[0].message().unwrap();
+        assert!(!message.authoritative());
+        assert_eq!(
+            message.answers()[0].data(),
+            Some(&RData::A("192.0.2.2".parse().unwrap()))
+        );
+        assert_eq!(message.response_code(), ResponseCode::BADKEY);
+        assert!(from_json(&json.replace("\"TIMEOUT_ERROR\"", "\"NO_ERROR\"")).is_err());
+    }
+}
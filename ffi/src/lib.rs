@@ -2,5 +2,20 @@
 extern crate serde_derive;
 
 mod c_api;
+mod cancel;
+mod capture;
 mod client;
+mod clock;
+mod diff;
+mod dnstap;
+#[cfg(test)]
+mod fixtures;
+mod json;
+mod pcap;
+mod queue;
+mod server;
+mod stats;
+mod store;
+mod transport;
 mod trust_dns_ext;
+mod zone;
@@ -0,0 +1,538 @@
+use crate::capture;
+use crate::capture::CaptureError;
+use crate::capture::DnsPacket;
+use crate::client::Cache;
+use crate::client::Protocol;
+use crate::pcap::IPPROTO_TCP;
+use crate::pcap::IPPROTO_UDP;
+use crate::pcap::LINKTYPE_RAW;
+use crate::pcap::TCP_SYN;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const PCAPNG_IF_TSRESOL: u16 = 9;
+
+fn invalid(reason: &str) -> CaptureError {
+    CaptureError::Format(reason.to_string())
+}
+
+/// A captured link layer frame.
+struct Frame<'a> {
+    /// Micros since epoch
+    timestamp: u64,
+    linktype: u32,
+    data: &'a [u8],
+}
+
+/// Reads the fields of a capture file in its byte order.
+#[derive(Clone, Copy)]
+struct Endian {
+    big: bool,
+}
+
+impl Endian {
+    fn u16(self, buf: &[u8], offset: usize) -> Result<u16, CaptureError> {
+        let bytes = buf
+            .get(offset..offset + 2)
+            .ok_or_else(|| invalid("truncated capture"))?;
+        let bytes = [bytes[0], bytes[1]];
+        Ok(if self.big {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(self, buf: &[u8], offset: usize) -> Result<u32, CaptureError> {
+        let bytes = buf
+            .get(offset..offset + 4)
+            .ok_or_else(|| invalid("truncated capture"))?;
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        Ok(if self.big {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+}
+
+fn slice(buf: &[u8], offset: usize, len: usize) -> Result<&[u8], CaptureError> {
+    buf.get(offset..offset + len)
+        .ok_or_else(|| invalid("truncated capture"))
+}
+
+/// Splits a classic pcap or a pcapng capture into frames.
+fn frames(capture: &[u8]) -> Result<Vec<Frame<'_>>, CaptureError> {
+    let magic = capture
+        .get(..4)
+        .ok_or_else(|| invalid("truncated capture"))?;
+    let magic = u32::from_le_bytes([magic[0], magic[1], magic[2], magic[3]]);
+    match magic {
+        PCAPNG_SECTION_HEADER => pcapng_frames(capture),
+        0xa1b2_c3d4 => pcap_frames(capture, Endian { big: false }, 1),
+        0xa1b2_3c4d => pcap_frames(capture, Endian { big: false }, 1000),
+        0xd4c3_b2a1 => pcap_frames(capture, Endian { big: true }, 1),
+        0x4d3c_b2a1 => pcap_frames(capture, Endian { big: true }, 1000),
+        _ => Err(invalid("not a pcap or pcapng capture")),
+    }
+}
+
+/// `nanos_per_unit` is 1 for microsecond and 1000 for nanosecond timestamps.
+fn pcap_frames(
+    capture: &[u8],
+    endian: Endian,
+    nanos_per_unit: u64,
+) -> Result<Vec<Frame<'_>>, CaptureError> {
+    let linktype = endian.u32(capture, 20)? & 0x0fff_ffff;
+    let mut frames = Vec::new();
+    let mut offset = 24;
+    while offset < capture.len() {
+        let seconds = endian.u32(capture, offset)? as u64;
+        let fraction = endian.u32(capture, offset + 4)? as u64;
+        let len = endian.u32(capture, offset + 8)? as usize;
+        frames.push(Frame {
+            timestamp: seconds * 1_000_000 + fraction / nanos_per_unit,
+            linktype,
+            data: slice(capture, offset + 16, len)?,
+        });
+        offset += 16 + len;
+    }
+    Ok(frames)
+}
+
+fn pcapng_frames(capture: &[u8]) -> Result<Vec<Frame<'_>>, CaptureError> {
+    // (linktype, units per second) of each interface in the current section
+    let mut interfaces: Vec<(u32, u64)> = Vec::new();
+    let mut endian = Endian { big: false };
+    let mut frames = Vec::new();
+    let mut offset = 0;
+    while offset < capture.len() {
+        let block_type = endian.u32(capture, offset)?;
+        if block_type == PCAPNG_SECTION_HEADER {
+            endian = Endian { big: false };
+            if endian.u32(capture, offset + 8)? != PCAPNG_BYTE_ORDER_MAGIC {
+                endian = Endian { big: true };
+            }
+            interfaces.clear();
+        }
+        let len = endian.u32(capture, offset + 4)? as usize;
+        if len < 12 || !len.is_multiple_of(4) {
+            return Err(invalid("invalid pcapng block length"));
+        }
+        let body = slice(capture, offset + 8, len - 12)?;
+        match block_type {
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                let linktype = endian.u16(body, 0)? as u32;
+                let mut units_per_second = 1_000_000;
+                let mut option = 8;
+                while option + 4 <= body.len() {
+                    let code = endian.u16(body, option)?;
+                    let option_len = endian.u16(body, option + 2)? as usize;
+                    if code == PCAPNG_IF_TSRESOL && option_len == 1 {
+                        let resolution = slice(body, option + 4, 1)?[0];
+                        let exponent = (resolution & 0x7f) as u32;
+                        units_per_second = if resolution & 0x80 == 0 {
+                            10u64.checked_pow(exponent)
+                        } else {
+                            2u64.checked_pow(exponent)
+                        }
+                        .ok_or_else(|| invalid("invalid timestamp resolution"))?;
+                    }
+                    if code == 0 {
+                        break;
+                    }
+                    option += 4 + option_len.next_multiple_of(4);
+                }
+                interfaces.push((linktype, units_per_second));
+            }
+            PCAPNG_ENHANCED_PACKET => {
+                let interface = endian.u32(body, 0)? as usize;
+                let (linktype, units_per_second) = *interfaces
+                    .get(interface)
+                    .ok_or_else(|| invalid("packet from unknown interface"))?;
+                let timestamp = (endian.u32(body, 4)? as u64) << 32 | endian.u32(body, 8)? as u64;
+                let captured = endian.u32(body, 12)? as usize;
+                frames.push(Frame {
+                    timestamp: (timestamp as u128 * 1_000_000 / units_per_second as u128) as u64,
+                    linktype,
+                    data: slice(body, 20, captured)?,
+                });
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+    Ok(frames)
+}
+
+/// The IP packet in a link layer frame, if any.
+fn ip_packet<'a>(linktype: u32, frame: &'a [u8]) -> Result<Option<&'a [u8]>, CaptureError> {
+    let ethertype_payload = |ethertype: u16, payload: &'a [u8]| match ethertype {
+        0x0800 | 0x86dd => Some(payload),
+        _ => None,
+    };
+    Ok(match linktype {
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => Some(frame),
+        LINKTYPE_NULL => frame.get(4..),
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            loop {
+                let Some(ethertype) = frame.get(offset..offset + 2) else {
+                    break None;
+                };
+                match u16::from_be_bytes([ethertype[0], ethertype[1]]) {
+                    // 802.1Q and 802.1ad VLAN tags
+                    0x8100 | 0x88a8 => offset += 4,
+                    ethertype => break ethertype_payload(ethertype, &frame[offset + 2..]),
+                }
+            }
+        }
+        LINKTYPE_LINUX_SLL => match frame.get(14..16) {
+            Some(ethertype) => ethertype_payload(
+                u16::from_be_bytes([ethertype[0], ethertype[1]]),
+                &frame[16..],
+            ),
+            None => None,
+        },
+        LINKTYPE_LINUX_SLL2 => match frame.get(..2) {
+            Some(ethertype) if frame.len() >= 20 => ethertype_payload(
+                u16::from_be_bytes([ethertype[0], ethertype[1]]),
+                &frame[20..],
+            ),
+            _ => None,
+        },
+        linktype => {
+            return Err(CaptureError::Format(format!(
+                "unsupported link type {}",
+                linktype
+            )))
+        }
+    })
+}
+
+/// A transport layer segment.
+struct Segment<'a> {
+    src: SocketAddr,
+    dst: SocketAddr,
+    /// TCP sequence number and flags
+    tcp: Option<(u32, u8)>,
+    payload: &'a [u8],
+}
+
+/// Parses the UDP or TCP segment in an unfragmented IP packet.
+fn segment(packet: &[u8]) -> Option<Segment<'_>> {
+    let (src, dst, mut protocol, mut payload): (IpAddr, IpAddr, u8, &[u8]) =
+        match packet.first()? >> 4 {
+            4 => {
+                let header_len = ((packet[0] & 0x0f) as usize) * 4;
+                let total_len = u16::from_be_bytes([*packet.get(2)?, *packet.get(3)?]) as usize;
+                let fragment = u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]);
+                // More fragments or a non-zero fragment offset
+                if fragment & 0x3fff != 0 {
+                    return None;
+                }
+                let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+                let dst: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+                (
+                    Ipv4Addr::from(src).into(),
+                    Ipv4Addr::from(dst).into(),
+                    packet[9],
+                    packet.get(header_len..total_len.min(packet.len()))?,
+                )
+            }
+            6 => {
+                let payload_len = u16::from_be_bytes([*packet.get(4)?, *packet.get(5)?]) as usize;
+                let src: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+                let dst: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+                (
+                    Ipv6Addr::from(src).into(),
+                    Ipv6Addr::from(dst).into(),
+                    packet[6],
+                    packet.get(40..(40 + payload_len).min(packet.len()))?,
+                )
+            }
+            _ => return None,
+        };
+    // IPv6 extension headers
+    while let 0 | 43 | 60 = protocol {
+        if !src.is_ipv6() {
+            return None;
+        }
+        let len = (*payload.get(1)? as usize + 1) * 8;
+        protocol = *payload.first()?;
+        payload = payload.get(len..)?;
+    }
+    match protocol {
+        IPPROTO_UDP => {
+            let len = u16::from_be_bytes([*payload.get(4)?, *payload.get(5)?]) as usize;
+            Some(Segment {
+                src: SocketAddr::new(src, u16::from_be_bytes([payload[0], payload[1]])),
+                dst: SocketAddr::new(dst, u16::from_be_bytes([payload[2], payload[3]])),
+                tcp: None,
+                payload: payload.get(8..len.min(payload.len()))?,
+            })
+        }
+        IPPROTO_TCP => {
+            let header_len = ((*payload.get(12)? >> 4) as usize) * 4;
+            let seq = u32::from_be_bytes(payload.get(4..8)?.try_into().ok()?);
+            Some(Segment {
+                src: SocketAddr::new(src, u16::from_be_bytes([payload[0], payload[1]])),
+                dst: SocketAddr::new(dst, u16::from_be_bytes([payload[2], payload[3]])),
+                tcp: Some((seq, *payload.get(13)?)),
+                payload: payload.get(header_len..)?,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// One direction of a TCP connection, reassembled into length prefixed DNS messages.
+#[derive(Default)]
+struct TcpStream {
+    next_seq: Option<u32>,
+    /// Segments after a gap, by sequence number
+    pending: HashMap<u32, Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl TcpStream {
+    fn receive(&mut self, seq: u32, flags: u8, payload: &[u8]) -> Vec<Vec<u8>> {
+        if flags & TCP_SYN != 0 {
+            *self = TcpStream::default();
+            self.next_seq = Some(seq.wrapping_add(1));
+        }
+        if payload.is_empty() {
+            return vec![];
+        }
+        let next_seq = *self.next_seq.get_or_insert(seq);
+        let offset = seq.wrapping_sub(next_seq) as i32;
+        if offset > 0 {
+            self.pending.insert(seq, payload.to_vec());
+            return vec![];
+        }
+        // Skip what has already been received from a retransmission
+        let Some(payload) = payload.get(offset.unsigned_abs() as usize..) else {
+            return vec![];
+        };
+        self.append(payload);
+        while let Some(payload) = self.pending.remove(&self.next_seq.unwrap()) {
+            self.append(&payload);
+        }
+
+        let mut messages = Vec::new();
+        while self.buf.len() >= 2 {
+            let len = u16::from_be_bytes([self.buf[0], self.buf[1]]) as usize;
+            if self.buf.len() < 2 + len {
+                break;
+            }
+            messages.push(self.buf[2..2 + len].to_vec());
+            self.buf.drain(..2 + len);
+        }
+        messages
+    }
+
+    fn append(&mut self, payload: &[u8]) {
+        self.buf.extend(payload);
+        self.next_seq = self
+            .next_seq
+            .map(|seq| seq.wrapping_add(payload.len() as u32));
+    }
+}
+
+/// Imports the DNS exchanges in a pcap or pcapng capture.
+///
+/// Only UDP and TCP traffic to or from `port` is considered. TCP streams are reassembled before
+/// the messages are paired up into requests as described for [`capture::to_cache`].
+pub fn from_pcap(pcap: &[u8], port: u16) -> Result<Cache, CaptureError> {
+    let mut packets = Vec::new();
+    let mut streams: HashMap<(SocketAddr, SocketAddr), TcpStream> = HashMap::new();
+    for frame in frames(pcap)? {
+        let Some(segment) = ip_packet(frame.linktype, frame.data)?.and_then(segment) else {
+            continue;
+        };
+        if segment.src.port() != port && segment.dst.port() != port {
+            continue;
+        }
+        let (proto, messages) = match segment.tcp {
+            None => (Protocol::Udp, vec![segment.payload.to_vec()]),
+            Some((seq, flags)) => {
+                let stream = streams.entry((segment.src, segment.dst)).or_default();
+                (Protocol::Tcp, stream.receive(seq, flags, segment.payload))
+            }
+        };
+        packets.extend(messages.into_iter().map(|bytes| DnsPacket {
+            timestamp: frame.timestamp,
+            src: segment.src,
+            dst: segment.dst,
+            proto,
+            bytes,
+            query: None,
+        }));
+    }
+    Ok(capture::to_cache(packets)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::EdnsConfig;
+    use crate::client::ErrorKind;
+    use crate::client::Failure;
+    use crate::client::Question;
+    use crate::client::RetriedResponse;
+    use crate::fixtures;
+    use crate::pcap::to_pcap;
+    use crate::store::MemoryStore;
+    use crate::store::Store;
+    use crate::trust_dns_ext::MyMessage;
+    use std::sync::Arc;
+    use trust_dns_client::op::MessageType;
+    use trust_dns_proto::xfer::DnsRequest;
+
+    fn question(proto: Protocol) -> Question {
+        Question {
+            proto,
+            recursion_desired: true,
+            edns_config: Some(EdnsConfig {
+                version: 0,
+                dnssec_ok: true,
+                max_payload: 512,
+                option_code: 10,
+                option_value: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            }),
+            ..fixtures::question("example.com.")
+        }
+    }
+
+    fn history() -> Vec<Arc<RetriedResponse>> {
+        let mut response = DnsRequest::from(question(Protocol::Udp));
+        response
+            .set_id(4711)
+            .set_message_type(MessageType::Response);
+        let answered = Arc::new(RetriedResponse {
+            failures: vec![Failure {
+                query_micros: 500_000,
+                ..fixtures::timeout(1_000, 500)
+            }],
+            started: 1_500,
+            duration: 20,
+            outcome: Ok(MyMessage::from_vec(response.to_vec().unwrap()).0),
+            query_micros: 20_250,
+            ..fixtures::response(Err(ErrorKind::Timeout))
+        });
+        let unanswered = Arc::new(RetriedResponse {
+            started: 2_000,
+            ..fixtures::response(Err(ErrorKind::Timeout))
+        });
+        vec![answered, unanswered]
+    }
+
+    fn cache() -> Cache {
+        let mut store = MemoryStore::new();
+        let entries = vec![
+            (
+                question(Protocol::Udp),
+                "192.0.2.53".parse().unwrap(),
+                history(),
+            ),
+            (
+                question(Protocol::Tcp),
+                "2001:db8::53".parse().unwrap(),
+                history(),
+            ),
+        ];
+        store.extend(entries.clone()).unwrap();
+        Cache::with_store(Box::new(store))
+    }
+
+    fn sorted(cache: &Cache) -> Vec<crate::store::Entry> {
+        let mut entries = cache.entries().unwrap();
+        entries.sort_by_key(|(_, server, _)| *server);
+        entries
+    }
+
+    /// Rewraps the packets of a classic pcap capture as a big-endian pcapng capture with
+    /// nanosecond timestamps.
+    fn to_pcapng(pcap: &[u8]) -> Vec<u8> {
+        let block = |block_type: u32, body: Vec<u8>| {
+            let len = (12 + body.len()) as u32;
+            let mut block = block_type.to_be_bytes().to_vec();
+            block.extend(len.to_be_bytes());
+            block.extend(body);
+            block.extend(len.to_be_bytes());
+            block
+        };
+        let mut pcapng = block(
+            PCAPNG_SECTION_HEADER,
+            [
+                &PCAPNG_BYTE_ORDER_MAGIC.to_be_bytes()[..],
+                &[0, 1, 0, 0],
+                &[0xff; 8],
+            ]
+            .concat(),
+        );
+        let mut idb = (LINKTYPE_RAW as u16).to_be_bytes().to_vec();
+        idb.extend([0, 0, 0, 0, 0, 0]);
+        idb.extend(PCAPNG_IF_TSRESOL.to_be_bytes());
+        idb.extend([0, 1, 9, 0, 0, 0]);
+        idb.extend([0, 0, 0, 0]);
+        pcapng.extend(block(PCAPNG_INTERFACE_DESCRIPTION, idb));
+        for frame in frames(pcap).unwrap() {
+            let nanos = frame.timestamp * 1000;
+            let mut epb = 0u32.to_be_bytes().to_vec();
+            epb.extend(((nanos >> 32) as u32).to_be_bytes());
+            epb.extend((nanos as u32).to_be_bytes());
+            epb.extend((frame.data.len() as u32).to_be_bytes());
+            epb.extend((frame.data.len() as u32).to_be_bytes());
+            epb.extend(frame.data);
+            epb.resize(epb.len().next_multiple_of(4), 0);
+            pcapng.extend(block(PCAPNG_ENHANCED_PACKET, epb));
+        }
+        pcapng
+    }
+
+    #[test]
+    fn round_trip() {
+        let cache = cache();
+        let pcap = to_pcap(&cache, "192.0.2.1".parse().unwrap()).unwrap();
+
+        let restored = from_pcap(&pcap, 53).unwrap();
+        assert_eq!(sorted(&restored), sorted(&cache));
+
+        let restored = from_pcap(&to_pcapng(&pcap), 53).unwrap();
+        assert_eq!(sorted(&restored), sorted(&cache));
+
+        assert!(from_pcap(&pcap, 5353)
+            .unwrap()
+            .entries()
+            .unwrap()
+            .is_empty());
+        assert!(from_pcap(&pcap[..30], 53).is_err());
+    }
+
+    #[test]
+    fn tcp_reassembly() {
+        let mut stream = TcpStream::default();
+        assert!(stream.receive(99, TCP_SYN, &[]).is_empty());
+        assert!(stream.receive(103, 0, &[8, 0, 1]).is_empty());
+        assert_eq!(stream.receive(100, 0, &[0, 2, 7]), vec![vec![7, 8]]);
+        // A retransmission overlapping what has already been received
+        assert_eq!(stream.receive(104, 0, &[0, 1, 9]), vec![vec![9]]);
+        assert!(stream.receive(108, 0, &[1, 6]).is_empty());
+        assert_eq!(stream.receive(107, 0, &[0]), vec![vec![6]]);
+    }
+}
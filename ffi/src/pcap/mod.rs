@@ -0,0 +1,444 @@
+//! Recorded traffic as pcap captures.
+//!
+//! Exported captures use the classic libpcap file format with raw IPv4/IPv6 packets
+//! (LINKTYPE_RAW) and microsecond timestamps. Imported captures may also be in the pcapng format
+//! and have Ethernet or Linux cooked capture framing.
+mod import;
+
+pub use import::from_pcap;
+
+use crate::capture;
+use crate::capture::CaptureError;
+use crate::capture::Request;
+use crate::capture::Sent;
+use crate::client::Cache;
+use crate::client::ErrorKind;
+use crate::client::Protocol;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+
+const MAGIC: u32 = 0xa1b2_c3d4;
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// A captured IP packet.
+struct Packet {
+    /// Micros since epoch
+    timestamp: u64,
+    data: Vec<u8>,
+}
+
+/// Exports every recorded exchange in a cache as a pcap capture.
+///
+/// Each attempt becomes a query packet to the server, followed by a response packet if a response
+/// was received. Failed attempts and requests that got no response appear as unanswered queries.
+/// Attempts are exported as recorded on the wire when they were (see
+/// [`Request::attempts`](crate::capture::Request::attempts)). Otherwise the query is re-encoded
+/// from its question and sent from `client` to port 53 of the server.
+///
+/// For servers of the other address family than `client`, the unspecified address of their family
+/// is used as the client address instead.
+pub fn to_pcap(cache: &Cache, client: IpAddr) -> Result<Vec<u8>, CaptureError> {
+    let mut packets = Vec::new();
+    for request in capture::requests(cache, client)? {
+        exchange_packets(&request, &mut packets)?;
+    }
+    packets.sort_by_key(|packet| packet.timestamp);
+
+    let mut buf = Vec::new();
+    buf.extend(MAGIC.to_le_bytes());
+    buf.extend(2u16.to_le_bytes());
+    buf.extend(4u16.to_le_bytes());
+    buf.extend(0i32.to_le_bytes());
+    buf.extend(0u32.to_le_bytes());
+    buf.extend(SNAPLEN.to_le_bytes());
+    buf.extend(LINKTYPE_RAW.to_le_bytes());
+    for packet in packets {
+        buf.extend(((packet.timestamp / 1_000_000) as u32).to_le_bytes());
+        buf.extend(((packet.timestamp % 1_000_000) as u32).to_le_bytes());
+        buf.extend((packet.data.len() as u32).to_le_bytes());
+        buf.extend((packet.data.len() as u32).to_le_bytes());
+        buf.extend(packet.data);
+    }
+    Ok(buf)
+}
+
+/// The packets of a single request: one query per attempt and the response, if any.
+///
+/// Over TCP, attempts are sent over one connection for as long as they are sent between the same
+/// addresses, and over a new one once they aren't.
+fn exchange_packets(request: &Request, packets: &mut Vec<Packet>) -> Result<(), CaptureError> {
+    let attempts = request.attempts()?;
+    let answered = request.answer();
+    let response = &request.response;
+    let last = attempts
+        .last()
+        .expect("the final attempt is always recorded");
+    let last_flow = &Flow::of(last);
+
+    match request.question.proto {
+        Protocol::Udp => {
+            for sent in &attempts {
+                packets.push(Packet {
+                    timestamp: sent.started,
+                    data: Flow::of(sent).udp(true, &sent.query),
+                });
+            }
+            if let Some((finished, answer)) = answered {
+                packets.push(Packet {
+                    timestamp: finished,
+                    data: last_flow.udp(false, answer),
+                });
+            }
+        }
+        Protocol::Tcp => {
+            // A connection error without any failed attempt before it
+            if response.failures.is_empty() && response.outcome == Err(ErrorKind::Io) {
+                let mut tcp = TcpState::default();
+                packets.push(Packet {
+                    timestamp: last.started,
+                    data: tcp.segment(last_flow, true, TCP_SYN, &[]),
+                });
+                return Ok(());
+            }
+            let mut connection: Option<(Flow, TcpState)> = None;
+            for sent in &attempts {
+                let flow = Flow::of(sent);
+                if !matches!(&connection, Some((open, _)) if *open == flow) {
+                    let mut tcp = TcpState::default();
+                    for (from_client, flags) in
+                        [(true, TCP_SYN), (false, TCP_SYN | TCP_ACK), (true, TCP_ACK)]
+                    {
+                        packets.push(Packet {
+                            timestamp: sent.started,
+                            data: tcp.segment(&flow, from_client, flags, &[]),
+                        });
+                    }
+                    connection = Some((flow, tcp));
+                }
+                let (flow, tcp) = connection.as_mut().unwrap();
+                packets.push(Packet {
+                    timestamp: sent.started,
+                    data: tcp.segment(flow, true, TCP_PSH | TCP_ACK, &framed(&sent.query)),
+                });
+            }
+            if let (Some((finished, answer)), Some((flow, tcp))) = (answered, &mut connection) {
+                packets.push(Packet {
+                    timestamp: finished,
+                    data: tcp.segment(flow, false, TCP_PSH | TCP_ACK, &framed(answer)),
+                });
+                packets.push(Packet {
+                    timestamp: finished,
+                    data: tcp.segment(flow, true, TCP_FIN | TCP_ACK, &[]),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A DNS message with the two byte length prefix used over TCP.
+fn framed(message: &[u8]) -> Vec<u8> {
+    let mut buf = (message.len() as u16).to_be_bytes().to_vec();
+    buf.extend(message);
+    buf
+}
+
+#[derive(PartialEq)]
+struct Flow {
+    client: SocketAddr,
+    server: SocketAddr,
+}
+
+impl Flow {
+    fn of(sent: &Sent) -> Self {
+        Flow {
+            client: sent.client,
+            server: sent.server,
+        }
+    }
+
+    fn endpoints(&self, from_client: bool) -> (SocketAddr, SocketAddr) {
+        if from_client {
+            (self.client, self.server)
+        } else {
+            (self.server, self.client)
+        }
+    }
+
+    fn udp(&self, from_client: bool, payload: &[u8]) -> Vec<u8> {
+        let (src, dst) = self.endpoints(from_client);
+        let mut udp = Vec::with_capacity(8 + payload.len());
+        udp.extend(src.port().to_be_bytes());
+        udp.extend(dst.port().to_be_bytes());
+        udp.extend(((8 + payload.len()) as u16).to_be_bytes());
+        udp.extend([0, 0]);
+        udp.extend(payload);
+        let checksum = match transport_checksum(&src.ip(), &dst.ip(), IPPROTO_UDP, &udp) {
+            0 => 0xffff,
+            checksum => checksum,
+        };
+        udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+        ip_packet(&src.ip(), &dst.ip(), IPPROTO_UDP, udp)
+    }
+}
+
+/// Sequence numbers of both directions of a synthetic TCP connection.
+#[derive(Default)]
+struct TcpState {
+    client_seq: u32,
+    server_seq: u32,
+}
+
+impl TcpState {
+    fn segment(&mut self, flow: &Flow, from_client: bool, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let (src, dst) = flow.endpoints(from_client);
+        let (seq, ack) = if from_client {
+            (&mut self.client_seq, self.server_seq)
+        } else {
+            (&mut self.server_seq, self.client_seq)
+        };
+        let mut tcp = Vec::with_capacity(20 + payload.len());
+        tcp.extend(src.port().to_be_bytes());
+        tcp.extend(dst.port().to_be_bytes());
+        tcp.extend(seq.to_be_bytes());
+        tcp.extend(if flags & TCP_ACK != 0 { ack } else { 0 }.to_be_bytes());
+        tcp.extend([5 << 4, flags]);
+        // Window, checksum and urgent pointer
+        tcp.extend([0xff, 0xff, 0, 0, 0, 0]);
+        tcp.extend(payload);
+        let checksum = transport_checksum(&src.ip(), &dst.ip(), IPPROTO_TCP, &tcp);
+        tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+        // SYN and FIN each take up one sequence number
+        let len = payload.len() as u32 + (flags & (TCP_SYN | TCP_FIN) != 0) as u32;
+        *seq = seq.wrapping_add(len);
+        ip_packet(&src.ip(), &dst.ip(), IPPROTO_TCP, tcp)
+    }
+}
+
+fn ip_packet(src: &IpAddr, dst: &IpAddr, protocol: u8, payload: Vec<u8>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(40 + payload.len());
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            buf.extend([0x45, 0]);
+            buf.extend(((20 + payload.len()) as u16).to_be_bytes());
+            buf.extend([0, 0, 0x40, 0, 64, protocol, 0, 0]);
+            buf.extend(src.octets());
+            buf.extend(dst.octets());
+            let checksum = checksum(0, &buf);
+            buf[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            buf.extend([0x60, 0, 0, 0]);
+            buf.extend((payload.len() as u16).to_be_bytes());
+            buf.extend([protocol, 64]);
+            buf.extend(src.octets());
+            buf.extend(dst.octets());
+        }
+        _ => unreachable!("mixed address families"),
+    }
+    buf.extend(payload);
+    buf
+}
+
+/// The UDP or TCP checksum including the pseudo header.
+fn transport_checksum(src: &IpAddr, dst: &IpAddr, protocol: u8, segment: &[u8]) -> u16 {
+    let mut pseudo = Vec::with_capacity(40);
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            pseudo.extend(src.octets());
+            pseudo.extend(dst.octets());
+            pseudo.extend([0, protocol]);
+            pseudo.extend((segment.len() as u16).to_be_bytes());
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            pseudo.extend(src.octets());
+            pseudo.extend(dst.octets());
+            pseudo.extend((segment.len() as u32).to_be_bytes());
+            pseudo.extend([0, 0, 0, protocol]);
+        }
+        _ => unreachable!("mixed address families"),
+    }
+    checksum(sum(0, &pseudo), segment)
+}
+
+fn sum(mut acc: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        acc += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        acc += (*last as u32) << 8;
+    }
+    acc
+}
+
+/// The internet checksum (RFC 1071) of some data, continuing from a partial sum.
+fn checksum(acc: u32, data: &[u8]) -> u16 {
+    let mut acc = sum(acc, data);
+    while acc > 0xffff {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    !(acc as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Failure;
+    use crate::client::Question;
+    use crate::client::RetriedResponse;
+    use crate::client::Wire;
+    use crate::fixtures;
+    use crate::store::MemoryStore;
+    use crate::store::Store;
+    use std::sync::Arc;
+    use trust_dns_client::op::Message;
+
+    fn question(proto: Protocol) -> Question {
+        Question {
+            proto,
+            ..fixtures::question("example.com.")
+        }
+    }
+
+    fn answered() -> Arc<RetriedResponse> {
+        let mut message = Message::new();
+        message.set_id(4711);
+        Arc::new(RetriedResponse {
+            failures: vec![fixtures::timeout(1_000, 400)],
+            started: 1_500,
+            duration: 20,
+            ..fixtures::response(Ok(message))
+        })
+    }
+
+    /// Timestamps and lengths of all records
+    fn records(pcap: &[u8]) -> Vec<(u64, usize)> {
+        let mut records = Vec::new();
+        let mut rest = &pcap[24..];
+        while !rest.is_empty() {
+            let field = |i: usize| u32::from_le_bytes(rest[i..i + 4].try_into().unwrap());
+            let timestamp = field(0) as u64 * 1_000_000 + field(4) as u64;
+            let len = field(8) as usize;
+            records.push((timestamp, len));
+            rest = &rest[16 + len..];
+        }
+        records
+    }
+
+    #[test]
+    fn export() {
+        let server = "192.0.2.53".parse().unwrap();
+        let mut store = MemoryStore::new();
+        store
+            .insert(question(Protocol::Udp), server, answered())
+            .unwrap();
+        store
+            .insert(
+                question(Protocol::Tcp),
+                "2001:db8::53".parse().unwrap(),
+                answered(),
+            )
+            .unwrap();
+        let cache = Cache::with_store(Box::new(store));
+
+        let pcap = to_pcap(&cache, "192.0.2.1".parse().unwrap()).unwrap();
+        assert_eq!(&pcap[..4], &MAGIC.to_le_bytes());
+        let records = records(&pcap);
+        let timestamps: Vec<_> = records.iter().map(|(timestamp, _)| *timestamp).collect();
+        // UDP: 2 queries, 1 response. TCP: 3 handshake segments, 2 queries, 1 response, 1 FIN.
+        assert_eq!(records.len(), 10);
+        assert!(timestamps.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(timestamps[0], 1_000_000);
+        assert_eq!(timestamps[9], 1_520_000);
+
+        // The IPv4 header checksums verify
+        let mut rest = &pcap[24..];
+        for (_, len) in &records {
+            let packet = &rest[16..16 + len];
+            if packet[0] >> 4 == 4 {
+                assert_eq!(checksum(0, &packet[..20]), 0);
+                assert_eq!(
+                    transport_checksum(
+                        &IpAddr::from(<[u8; 4]>::try_from(&packet[12..16]).unwrap()),
+                        &IpAddr::from(<[u8; 4]>::try_from(&packet[16..20]).unwrap()),
+                        packet[9],
+                        &packet[20..],
+                    ),
+                    0
+                );
+            }
+            rest = &rest[16 + len..];
+        }
+    }
+
+    #[test]
+    fn recorded_addresses() {
+        let wire = |query: &[u8], local: &str, remote: Option<&str>| Wire {
+            query: query.to_vec(),
+            local: local.parse().unwrap(),
+            remote: remote.map(|remote| remote.parse().unwrap()),
+            sent: query.len() as u32,
+            received: 0,
+            strays: vec![],
+        };
+        let response = Arc::new(RetriedResponse {
+            failures: vec![Failure {
+                wire: Some(wire(b"first query!", "0.0.0.0:40001", None)),
+                ..fixtures::timeout(1_000, 400)
+            }],
+            started: 1_500,
+            duration: 20,
+            wire: Some(wire(
+                b"second query",
+                "192.0.2.1:40002",
+                Some("192.0.2.53:5353"),
+            )),
+            ..fixtures::response(Ok(Message::new()))
+        });
+        let mut store = MemoryStore::new();
+        store
+            .insert(
+                question(Protocol::Udp),
+                "192.0.2.53".parse().unwrap(),
+                response,
+            )
+            .unwrap();
+        let cache = Cache::with_store(Box::new(store));
+
+        let pcap = to_pcap(&cache, "192.0.2.1".parse().unwrap()).unwrap();
+        let mut rest = &pcap[24..];
+        let mut udp = Vec::new();
+        for (_, len) in records(&pcap) {
+            let packet = &rest[16..16 + len];
+            let port = |i: usize| u16::from_be_bytes([packet[i], packet[i + 1]]);
+            udp.push((
+                packet[12..16].to_vec(),
+                port(20),
+                port(22),
+                packet[28..].to_vec(),
+            ));
+            rest = &rest[16 + len..];
+        }
+        let client = vec![192, 0, 2, 1];
+        let server = vec![192, 0, 2, 53];
+        assert_eq!(
+            udp[0],
+            (client.clone(), 40001, 53, b"first query!".to_vec())
+        );
+        assert_eq!(udp[1], (client, 40002, 5353, b"second query".to_vec()));
+        assert_eq!(
+            (udp[2].0.clone(), udp[2].1, udp[2].2),
+            (server, 5353, 40002)
+        );
+    }
+}
@@ -0,0 +1,261 @@
+//! Lookups running in the background, for callers that mustn't block.
+//!
+//! Submitted lookups run as tasks on the runtime of their transport. A pipe signals completed
+//! lookups, so that an event loop can wait for them along with its other file descriptors.
+use crate::cancel::Cancellation;
+use crate::client::Cache;
+use crate::client::MissStrategy;
+use crate::client::Question;
+use crate::client::SingleResponse;
+use crate::transport::Transport;
+use futures_util::future::FutureExt;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io;
+use std::io::PipeReader;
+use std::io::PipeWriter;
+use std::io::Read;
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::fd::AsRawFd;
+use std::os::fd::RawFd;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::SendError;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::OnceLock;
+use std::sync::PoisonError;
+use std::thread;
+
+/// A completed lookup.
+pub struct Completion {
+    /// The ID that `LookupQueue::submit` returned for the lookup
+    pub id: u64,
+    /// The responses per server, or why the lookup failed as a whole
+    pub result: Result<HashMap<IpAddr, SingleResponse>, String>,
+}
+
+/// Completions not yet drained, and the end of the pipe that signals them.
+struct Completed {
+    completions: Vec<Completion>,
+    writer: PipeWriter,
+    /// Whether the pipe holds the signal byte
+    signalled: bool,
+    /// Why signalling failed, until the next drain reports it
+    signal_error: Option<io::Error>,
+}
+
+/// The state shared with the lookup tasks.
+///
+/// The tasks keep the read end of the pipe open until they are done, so that writing never raises
+/// `SIGPIPE` after the queue is dropped.
+struct Shared {
+    /// Lookups submitted and not yet drained
+    pending: AtomicU64,
+    completed: Mutex<Completed>,
+    reader: PipeReader,
+}
+
+/// Lookups running in the background.
+///
+/// The pipe holds a single byte while there are completions to drain, and nothing otherwise,
+/// unless writing the byte failed. It is only written and read while the completions are locked,
+/// so the two always agree.
+pub struct LookupQueue {
+    next_id: AtomicU64,
+    shared: Arc<Shared>,
+}
+
+impl LookupQueue {
+    pub fn new() -> io::Result<Self> {
+        let (reader, writer) = io::pipe()?;
+        Ok(LookupQueue {
+            next_id: AtomicU64::new(1),
+            shared: Arc::new(Shared {
+                pending: AtomicU64::new(0),
+                completed: Mutex::new(Completed {
+                    completions: Vec::new(),
+                    writer,
+                    signalled: false,
+                    signal_error: None,
+                }),
+                reader,
+            }),
+        })
+    }
+
+    /// The file descriptor that is readable while there are completions to drain.
+    pub fn fd(&self) -> RawFd {
+        self.shared.reader.as_raw_fd()
+    }
+
+    /// The number of lookups submitted and not yet drained.
+    pub fn pending(&self) -> u64 {
+        self.shared.pending.load(Ordering::SeqCst)
+    }
+
+    /// Starts a lookup in the background, returning its ID. IDs start at 1.
+    ///
+    /// The lookup works on the same entries as `cache`, just like `Cache::lookup` would. Lookups
+    /// that make queries are spawned on the runtime of the transport of the strategy, while the
+    /// others complete right away.
+    pub fn submit(
+        &self,
+        cache: &Cache,
+        strategy: MissStrategy,
+        question: Question,
+        servers: HashSet<IpAddr>,
+        cancellation: Cancellation,
+    ) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let cache = cache.share();
+        let shared = self.shared.clone();
+        self.shared.pending.fetch_add(1, Ordering::SeqCst);
+        let Some(net) = strategy.net().cloned() else {
+            let result = cache.lookup(&strategy, question, &servers, &cancellation);
+            shared.complete(id, result.map_err(|miss| miss.to_string()));
+            return id;
+        };
+        let runtime = net.runtime().handle().clone();
+        runtime.spawn(async move {
+            let lookup = cache.lookup_async(&strategy, question, servers, &cancellation);
+            let result = match AssertUnwindSafe(lookup).catch_unwind().await {
+                Ok(Ok(responses)) => Ok(responses),
+                Ok(Err(miss)) => Err(miss.to_string()),
+                Err(_) => Err("panic in lookup".to_string()),
+            };
+            shared.complete(id, result);
+            drop(strategy);
+            reap(net);
+        });
+        id
+    }
+
+    /// Takes the completed lookups, in the order they completed, without waiting for more.
+    ///
+    /// Fails if signalling a completion failed since the last drain. The completions are then
+    /// left for the next drain.
+    pub fn drain(&self) -> io::Result<Vec<Completion>> {
+        let mut completed = self.shared.lock();
+        if let Some(err) = completed.signal_error.take() {
+            return Err(err);
+        }
+        if completed.completions.is_empty() {
+            return Ok(Vec::new());
+        }
+        if completed.signalled {
+            let mut signal = [0];
+            (&self.shared.reader).read_exact(&mut signal)?;
+            completed.signalled = false;
+        }
+        let completions = std::mem::take(&mut completed.completions);
+        self.shared
+            .pending
+            .fetch_sub(completions.len() as u64, Ordering::SeqCst);
+        Ok(completions)
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Completed> {
+        self.completed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Adds a completion and signals it unless that has been done already.
+    fn complete(&self, id: u64, result: Result<HashMap<IpAddr, SingleResponse>, String>) {
+        let mut completed = self.lock();
+        if !completed.signalled {
+            match completed.writer.write_all(&[0]) {
+                Ok(()) => completed.signalled = true,
+                Err(err) => completed.signal_error = Some(err),
+            }
+        }
+        completed.completions.push(Completion { id, result });
+    }
+}
+
+/// Drops a transport on a thread of its own.
+///
+/// The transport owns its runtime, which can't be dropped on one of its own threads. That would
+/// happen if a lookup task held the last reference to its transport, and no task can tell
+/// whether it does, since the others may be dropping theirs at the same time.
+fn reap(transport: Arc<dyn Transport>) {
+    static REAPER: OnceLock<Sender<Arc<dyn Transport>>> = OnceLock::new();
+    let reaper = REAPER.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || receiver.into_iter().for_each(drop));
+        sender
+    });
+    if let Err(SendError(transport)) = reaper.send(transport) {
+        thread::spawn(move || drop(transport));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::question;
+    use crate::transport::scripted::Attempt;
+    use crate::transport::scripted::ScriptedTransport;
+    use std::time::Duration;
+    use trust_dns_client::op::Message;
+    use trust_dns_client::op::MessageType;
+
+    #[test]
+    fn submit_and_drain() {
+        let server: IpAddr = "192.0.2.1".parse().unwrap();
+        let transport = ScriptedTransport::new(1, 100).unwrap();
+        let mut message = Message::new();
+        message.set_message_type(MessageType::Response);
+        transport.push(
+            question("a.example"),
+            server,
+            Duration::from_millis(10),
+            Attempt::Respond(message.to_vec().unwrap()),
+        );
+        let fill = MissStrategy::FillOnMiss(Arc::new(transport));
+        let cache = Cache::new();
+        let queue = LookupQueue::new().unwrap();
+
+        let servers = HashSet::from([server]);
+        let a = queue.submit(
+            &cache,
+            fill,
+            question("a.example"),
+            servers.clone(),
+            Cancellation::default(),
+        );
+        let b = queue.submit(
+            &cache,
+            MissStrategy::FailOnMiss,
+            question("b.example"),
+            servers,
+            Cancellation::default(),
+        );
+        assert_eq!(queue.pending(), 2);
+
+        let mut completions = Vec::new();
+        while completions.len() < 2 {
+            completions.extend(queue.drain().unwrap());
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(queue.pending(), 0);
+        completions.sort_by_key(|completion| completion.id);
+        assert_eq!((completions[0].id, completions[1].id), (a, b));
+        let responses = completions[0].result.as_ref().unwrap();
+        assert!(responses[&server].outcome.is_ok());
+        assert_eq!(
+            completions[1].result.as_ref().unwrap_err(),
+            "not in cache: @192.0.2.1"
+        );
+        let cached = cache.latest(&question("a.example"), &server).unwrap();
+        assert!(cached.unwrap().outcome.is_ok());
+    }
+}
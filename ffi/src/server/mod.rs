@@ -0,0 +1,163 @@
+//! Local DNS servers standing in for other servers.
+//!
+//! Each listener serves UDP and TCP on the same local address and port, and plays the role of a
+//! single (virtual) server. Received queries are handed to a single consumer, which decides on the
+//! replies while the listener tasks take care of the sockets.
+mod proxy;
+mod replay;
+
+pub use proxy::proxy;
+pub use replay::replay;
+
+use crate::client::Protocol;
+use std::io;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::time;
+
+pub struct Listener {
+    udp: Arc<UdpSocket>,
+    tcp: TcpListener,
+    server: IpAddr,
+}
+
+impl Listener {
+    /// Listens on `addr` on behalf of `server`.
+    ///
+    /// If the port of `addr` is zero, the TCP listener uses the port assigned to the UDP socket.
+    pub async fn bind(addr: SocketAddr, server: IpAddr) -> io::Result<Self> {
+        let udp = UdpSocket::bind(addr).await?;
+        let tcp = TcpListener::bind(udp.local_addr()?).await?;
+        Ok(Listener {
+            udp: Arc::new(udp),
+            tcp,
+            server,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.udp.local_addr()
+    }
+}
+
+/// A received query.
+pub struct Query {
+    pub proto: Protocol,
+    pub client: SocketAddr,
+    /// The server whose role is played by the listener that received the query
+    pub server: IpAddr,
+    pub bytes: Vec<u8>,
+    reply: oneshot::Sender<Option<Reply>>,
+}
+
+impl Query {
+    /// Sends a reply to the client, or leaves the query unanswered if `reply` is `None`.
+    pub fn respond(self, reply: Option<Reply>) {
+        // The client is gone if the listener task has dropped the receiver
+        let _ = self.reply.send(reply);
+    }
+}
+
+pub struct Reply {
+    /// How long to wait before sending the reply
+    pub delay: Duration,
+    pub bytes: Vec<u8>,
+}
+
+/// Starts receiving queries on the listeners.
+///
+/// Must be called from within a tokio runtime. The listener tasks stop once the receiver is
+/// dropped.
+pub fn receive(listeners: Vec<Listener>) -> mpsc::UnboundedReceiver<Query> {
+    let (queries, receiver) = mpsc::unbounded_channel();
+    for listener in listeners {
+        tokio::spawn(receive_udp(listener.udp, listener.server, queries.clone()));
+        tokio::spawn(accept_tcp(listener.tcp, listener.server, queries.clone()));
+    }
+    receiver
+}
+
+async fn receive_udp(
+    socket: Arc<UdpSocket>,
+    server: IpAddr,
+    queries: mpsc::UnboundedSender<Query>,
+) {
+    let mut buf = vec![0; u16::MAX as usize];
+    loop {
+        // Errors are about earlier datagrams, e.g. ICMP port unreachable for a reply
+        let Ok((len, client)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let (reply, replied) = oneshot::channel();
+        let query = Query {
+            proto: Protocol::Udp,
+            client,
+            server,
+            bytes: buf[..len].to_vec(),
+            reply,
+        };
+        if queries.send(query).is_err() {
+            return;
+        }
+        let socket = socket.clone();
+        tokio::spawn(async move {
+            if let Ok(Some(reply)) = replied.await {
+                time::sleep(reply.delay).await;
+                let _ = socket.send_to(&reply.bytes, client).await;
+            }
+        });
+    }
+}
+
+async fn accept_tcp(listener: TcpListener, server: IpAddr, queries: mpsc::UnboundedSender<Query>) {
+    loop {
+        let Ok((stream, client)) = listener.accept().await else {
+            continue;
+        };
+        if queries.is_closed() {
+            return;
+        }
+        tokio::spawn(serve_tcp(stream, client, server, queries.clone()));
+    }
+}
+
+/// Answers the queries on a TCP connection one at a time, until the client closes it.
+async fn serve_tcp(
+    mut stream: TcpStream,
+    client: SocketAddr,
+    server: IpAddr,
+    queries: mpsc::UnboundedSender<Query>,
+) -> io::Result<()> {
+    loop {
+        let len = stream.read_u16().await?;
+        let mut bytes = vec![0; len as usize];
+        stream.read_exact(&mut bytes).await?;
+        let (reply, replied) = oneshot::channel();
+        let query = Query {
+            proto: Protocol::Tcp,
+            client,
+            server,
+            bytes,
+            reply,
+        };
+        if queries.send(query).is_err() {
+            return Ok(());
+        }
+        if let Ok(Some(reply)) = replied.await {
+            time::sleep(reply.delay).await;
+            let mut framed = (reply.bytes.len() as u16).to_be_bytes().to_vec();
+            framed.extend(reply.bytes);
+            stream.write_all(&framed).await?;
+        }
+    }
+}
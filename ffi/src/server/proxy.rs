@@ -0,0 +1,152 @@
+use crate::cancel::Cancellation;
+use crate::capture::question_of;
+use crate::client::Cache;
+use crate::server;
+use crate::server::Listener;
+use crate::server::Reply;
+use crate::transport::Transport;
+use futures::stream::FuturesUnordered;
+use futures_util::stream::StreamExt;
+use std::time::Duration;
+use trust_dns_client::op::Message;
+use trust_dns_client::op::MessageType;
+
+/// Forwards queries to the servers played by the listeners and records the exchanges.
+///
+/// Each query is turned into a question and looked up using `net`, just like `Cache::lookup`
+/// would on a cache miss, and the response is appended to the history of the question in `cache`.
+/// Lookups that fail are recorded as such and leave the query unanswered.
+///
+/// Must be called from within the runtime of `net`. Runs for as long as the listeners do.
+pub async fn proxy(listeners: Vec<Listener>, net: &dyn Transport, cache: &Cache) {
+    let mut queries = server::receive(listeners);
+    let mut lookups = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            query = queries.recv() => {
+                let Some(query) = query else {
+                    break;
+                };
+                let question = Message::from_vec(&query.bytes)
+                    .ok()
+                    .filter(|message| message.message_type() == MessageType::Query)
+                    .and_then(|message| Some((message.id(), question_of(&message, query.proto)?)));
+                let Some((id, question)) = question else {
+                    query.respond(None);
+                    continue;
+                };
+                lookups.push(async move {
+                    let never = Cancellation::default();
+                    let lookup = net.lookup(question.clone(), query.server, &never, None).await;
+                    (query, id, question, Cache::retried_response(lookup))
+                });
+            }
+            Some((query, id, question, response)) = lookups.next() => {
+                let reply = response.outcome.as_ref().ok().and_then(|answer| {
+                    let mut bytes = answer.encoded.clone();
+                    bytes.get_mut(..2)?.copy_from_slice(&id.to_be_bytes());
+                    Some(Reply {
+                        delay: Duration::ZERO,
+                        bytes,
+                    })
+                });
+                cache.record(question, query.server, response);
+                query.respond(reply);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Net;
+    use crate::clock::Clock;
+    use crate::fixtures;
+    use crate::fixtures::question;
+    use crate::server::replay;
+    use crate::store::MemoryStore;
+    use crate::store::Store;
+    use std::net::IpAddr;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::net::UdpSocket;
+    use tokio::runtime::Runtime;
+    use trust_dns_client::op::Query;
+    use trust_dns_client::rr::RecordType;
+    use trust_dns_proto::xfer::DnsRequest;
+
+    #[test]
+    fn proxy_and_record() {
+        let upstream: IpAddr = "127.0.0.1".parse().unwrap();
+        let mut message = Message::new();
+        message
+            .set_id(4711)
+            .set_message_type(MessageType::Response)
+            .set_authoritative(true)
+            .add_query(Query::query("example.com.".parse().unwrap(), RecordType::A));
+        let mut store = MemoryStore::new();
+        store
+            .insert(
+                question("example.com."),
+                upstream,
+                Arc::new(fixtures::response(Ok(message))),
+            )
+            .unwrap();
+        let recording = Cache::with_store(Box::new(store));
+
+        let runtime = Runtime::new().unwrap();
+        let stub = runtime
+            .block_on(Listener::bind("127.0.0.1:0".parse().unwrap(), upstream))
+            .unwrap();
+        let net = Net {
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            port: stub.local_addr().unwrap().port(),
+            timeout: 1000,
+            retry: 1,
+            retrans: 0,
+            runtime,
+            clock: Clock::Real,
+            in_flight: None,
+        };
+        let cache = Cache::new();
+
+        net.runtime.block_on(async {
+            let listener = Listener::bind("127.0.0.1:0".parse().unwrap(), upstream)
+                .await
+                .unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = async {
+                let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                for id in [1, 2] {
+                    let mut request = DnsRequest::from(question("example.com."));
+                    request.set_id(id);
+                    socket
+                        .send_to(&request.to_vec().unwrap(), addr)
+                        .await
+                        .unwrap();
+                    let mut buf = vec![0; 512];
+                    let (len, from): (usize, SocketAddr) =
+                        socket.recv_from(&mut buf).await.unwrap();
+                    assert_eq!(from, addr);
+                    let answer = Message::from_vec(&buf[..len]).unwrap();
+                    assert_eq!(answer.id(), id);
+                    assert!(answer.authoritative());
+                }
+            };
+            tokio::select! {
+                _ = replay(vec![stub], &recording) => panic!("replay stopped"),
+                _ = proxy(vec![listener], &net, &cache) => panic!("proxy stopped"),
+                _ = client => {}
+            }
+        });
+
+        let entries = cache.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        let (recorded, server, history) = &entries[0];
+        assert_eq!(*recorded, question("example.com."));
+        assert_eq!(*server, upstream);
+        assert_eq!(history.len(), 2);
+        assert!(history[1].message().unwrap().authoritative());
+    }
+}
@@ -0,0 +1,306 @@
+use crate::capture::question_of;
+use crate::capture::RETRY_WINDOW;
+use crate::client::Cache;
+use crate::client::Question;
+use crate::client::RetriedResponse;
+use crate::server;
+use crate::server::Listener;
+use crate::server::Query;
+use crate::server::Reply;
+use crate::store::Filter;
+use crate::store::StoreError;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use trust_dns_client::op::Message;
+use trust_dns_client::op::MessageType;
+use trust_dns_client::op::ResponseCode;
+
+/// Answers queries with the responses recorded in a cache.
+///
+/// A query is matched against the recorded questions for the server of the listener that received
+/// it. The latest response for the question is replayed with the message ID of the query, after
+/// waiting for as long as the recorded request took. Recorded failures are replayed as silence:
+/// a client retrying the same question gets no reply for as many attempts as failed in the
+/// recording, and none at all if the request failed altogether. Attempts that don't come within
+/// ten seconds of the previous one start over.
+///
+/// Queries for questions that aren't in the cache are refused.
+///
+/// Must be called from within a tokio runtime. Runs for as long as the listeners do.
+pub async fn replay(listeners: Vec<Listener>, cache: &Cache) {
+    let mut attempts = HashMap::new();
+    let mut queries = server::receive(listeners);
+    while let Some(query) = queries.recv().await {
+        let reply = answer(cache, &query, &mut attempts, Instant::now());
+        query.respond(reply);
+    }
+}
+
+/// Attempts made so far by a client for a recorded question and server, and when the latest one
+/// was made.
+type Attempts = HashMap<(IpAddr, Question, IpAddr), (usize, Instant)>;
+
+fn answer(cache: &Cache, query: &Query, attempts: &mut Attempts, now: Instant) -> Option<Reply> {
+    // Clients that gave up or got their answer elsewhere don't come back
+    attempts.retain(|_, (_, latest)| now.duration_since(*latest) < RETRY_WINDOW);
+
+    let message = Message::from_vec(&query.bytes).ok()?;
+    if message.message_type() != MessageType::Query {
+        return None;
+    }
+    let Some(question) = question_of(&message, query.proto) else {
+        return error_reply(message, ResponseCode::FormErr);
+    };
+    let (question, response) = match find(cache, &question, &query.server) {
+        Ok(Some(found)) => found,
+        Ok(None) => return error_reply(message, ResponseCode::Refused),
+        Err(_) => return error_reply(message, ResponseCode::ServFail),
+    };
+
+    let key = (query.client.ip(), question, query.server);
+    let (attempt, latest) = attempts.entry(key.clone()).or_insert((0, now));
+    if *attempt < response.failures.len() {
+        *attempt += 1;
+        *latest = now;
+        return None;
+    }
+    attempts.remove(&key);
+
+    let answer = response.outcome.as_ref().ok()?;
+    let mut bytes = answer.encoded.clone();
+    if bytes.len() < 2 {
+        return None;
+    }
+    bytes[..2].copy_from_slice(&message.id().to_be_bytes());
+    Some(Reply {
+        delay: Duration::from_millis(response.duration as u64),
+        bytes,
+    })
+}
+
+/// Finds the recorded question and latest response matching a query.
+///
+/// Other tools rarely agree with the recording on the advertised EDNS payload size or on EDNS
+/// options, so these are ignored if there is no exact match.
+fn find(
+    cache: &Cache,
+    question: &Question,
+    server: &IpAddr,
+) -> Result<Option<(Question, Arc<RetriedResponse>)>, StoreError> {
+    if let Some(response) = cache.latest(question, server)? {
+        return Ok(Some((question.clone(), response)));
+    }
+    let loosely = |question: &Question| {
+        (
+            question.qname.to_lowercase(),
+            question.qtype,
+            question.proto,
+            question.recursion_desired,
+            question
+                .edns_config
+                .as_ref()
+                .map(|edns| (edns.version, edns.dnssec_ok)),
+        )
+    };
+    let candidates = cache.query(&Filter {
+        qname: Some(question.qname.clone()),
+        qtype: Some(question.qtype),
+        proto: Some(question.proto),
+        server: Some(*server),
+        ..Filter::default()
+    })?;
+    let found = candidates
+        .into_iter()
+        .find(|(recorded, _, _)| loosely(recorded) == loosely(question))
+        .map(|(recorded, _, response)| (recorded, response));
+    Ok(found)
+}
+
+fn error_reply(mut query: Message, rcode: ResponseCode) -> Option<Reply> {
+    query
+        .set_message_type(MessageType::Response)
+        .set_response_code(rcode);
+    Some(Reply {
+        delay: Duration::ZERO,
+        bytes: query.to_vec().ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::EdnsConfig;
+    use crate::client::Protocol;
+    use crate::fixtures;
+    use crate::store::MemoryStore;
+    use crate::store::Store;
+    use std::net::SocketAddr;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+    use tokio::net::UdpSocket;
+    use tokio::runtime::Runtime;
+    use tokio::time;
+    use trust_dns_client::op::Edns;
+    use trust_dns_proto::xfer::DnsRequest;
+
+    fn question(qname: &str, proto: Protocol) -> Question {
+        Question {
+            proto,
+            ..fixtures::question(qname)
+        }
+    }
+
+    fn answered(failures: usize) -> Arc<RetriedResponse> {
+        let mut message = Message::new();
+        message
+            .set_id(4711)
+            .set_message_type(MessageType::Response)
+            .set_authoritative(true);
+        Arc::new(RetriedResponse {
+            failures: vec![fixtures::timeout(0, 0); failures],
+            duration: 10,
+            ..fixtures::response(Ok(message))
+        })
+    }
+
+    fn query(qname: &str, id: u16) -> Vec<u8> {
+        let mut request = DnsRequest::from(question(qname, Protocol::Udp));
+        request.set_id(id);
+        request.to_vec().unwrap()
+    }
+
+    async fn exchange_udp(addr: SocketAddr, query: &[u8]) -> Option<Message> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(query, addr).await.unwrap();
+        let mut buf = vec![0; 512];
+        let len = time::timeout(Duration::from_millis(200), socket.recv(&mut buf))
+            .await
+            .ok()?
+            .unwrap();
+        Some(Message::from_vec(&buf[..len]).unwrap())
+    }
+
+    async fn exchange_tcp(addr: SocketAddr, query: &[u8]) -> Message {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(&(query.len() as u16).to_be_bytes())
+            .await
+            .unwrap();
+        stream.write_all(query).await.unwrap();
+        let len = stream.read_u16().await.unwrap();
+        let mut buf = vec![0; len as usize];
+        stream.read_exact(&mut buf).await.unwrap();
+        Message::from_vec(&buf).unwrap()
+    }
+
+    #[test]
+    fn retry_window() {
+        let server = "192.0.2.53".parse().unwrap();
+        let mut store = MemoryStore::new();
+        store
+            .insert(
+                question("retry.example", Protocol::Udp),
+                server,
+                answered(2),
+            )
+            .unwrap();
+        let cache = Cache::with_store(Box::new(store));
+        let query = |id| Query {
+            proto: Protocol::Udp,
+            client: "127.0.0.1:40000".parse().unwrap(),
+            server,
+            bytes: query("retry.example", id),
+            reply: tokio::sync::oneshot::channel().0,
+        };
+
+        let mut attempts = Attempts::new();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert!(answer(&cache, &query(1), &mut attempts, at(0)).is_none());
+        // An abandoned sequence expires, and a later lookup starts over
+        assert!(answer(&cache, &query(2), &mut attempts, at(20)).is_none());
+        assert!(answer(&cache, &query(3), &mut attempts, at(25)).is_none());
+        assert!(answer(&cache, &query(4), &mut attempts, at(30)).is_some());
+        assert!(attempts.is_empty());
+        assert!(answer(&cache, &query(5), &mut attempts, at(40)).is_none());
+        assert!(answer(&cache, &query(6), &mut attempts, at(100)).is_none());
+        assert_eq!(attempts.len(), 1);
+    }
+
+    #[test]
+    fn replay_cache() {
+        let server = "192.0.2.53".parse().unwrap();
+        let mut store = MemoryStore::new();
+        store
+            .insert(question("example.com", Protocol::Udp), server, answered(0))
+            .unwrap();
+        store
+            .insert(question("example.com", Protocol::Tcp), server, answered(0))
+            .unwrap();
+        store
+            .insert(
+                question("retry.example", Protocol::Udp),
+                server,
+                answered(1),
+            )
+            .unwrap();
+        let mut edns_question = question("edns.example", Protocol::Udp);
+        edns_question.edns_config = Some(EdnsConfig {
+            version: 0,
+            dnssec_ok: true,
+            max_payload: 512,
+            option_code: 0,
+            option_value: vec![],
+        });
+        store.insert(edns_question, server, answered(0)).unwrap();
+        let cache = Cache::with_store(Box::new(store));
+
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            let listener = Listener::bind("127.0.0.1:0".parse().unwrap(), server)
+                .await
+                .unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = async {
+                let answer = exchange_udp(addr, &query("example.com", 1)).await.unwrap();
+                assert_eq!(answer.id(), 1);
+                assert!(answer.authoritative());
+
+                let answer = exchange_tcp(addr, &query("example.com", 2)).await;
+                assert_eq!(answer.id(), 2);
+                assert!(answer.authoritative());
+
+                // Recorded failure
+                assert!(exchange_udp(addr, &query("retry.example", 3))
+                    .await
+                    .is_none());
+                let answer = exchange_udp(addr, &query("retry.example", 4))
+                    .await
+                    .unwrap();
+                assert_eq!(answer.id(), 4);
+
+                // Another EDNS payload size than recorded
+                let mut message = Message::from_vec(&query("edns.example", 5)).unwrap();
+                let mut edns = Edns::new();
+                edns.set_max_payload(1232).set_dnssec_ok(true);
+                message.set_edns(edns);
+                let answer = exchange_udp(addr, &message.to_vec().unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(answer.id(), 5);
+                assert!(answer.authoritative());
+
+                let answer = exchange_udp(addr, &query("example.net", 6)).await.unwrap();
+                assert_eq!(answer.response_code(), ResponseCode::Refused);
+            };
+            tokio::select! {
+                _ = replay(vec![listener], &cache) => panic!("replay stopped"),
+                _ = client => {}
+            }
+        });
+    }
+}
@@ -0,0 +1,194 @@
+//! Counts and latency summaries over the responses recorded in a cache.
+use crate::client::Cache;
+use crate::client::ErrorKind;
+use crate::client::OutcomeKind;
+use crate::client::Protocol;
+use crate::store::StoreError;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+/// Latency percentiles of the responses from a server, in millis.
+///
+/// Percentiles are computed with the nearest-rank method.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Latency {
+    pub min: u32,
+    pub p50: u32,
+    pub p90: u32,
+    pub p99: u32,
+    pub max: u32,
+}
+
+impl Latency {
+    /// Summarizes a list of durations, or returns `None` if it is empty.
+    fn of(mut durations: Vec<u32>) -> Option<Self> {
+        durations.sort_unstable();
+        let percentile = |p: usize| {
+            let rank = (p * durations.len()).div_ceil(100).max(1);
+            durations[rank - 1]
+        };
+        Some(Latency {
+            min: *durations.first()?,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: *durations.last()?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ServerStats {
+    /// Number of requests, i.e. recorded responses including earlier ones in histories
+    pub responses: u64,
+    /// Number of requests that got a decodable response message
+    pub answered: u64,
+    /// Number of requests that ended in an error
+    pub errors: u64,
+    /// Number of failed attempts that were retried
+    pub retries: u64,
+    /// Total size of the received response messages
+    pub bytes: u64,
+    /// Latency of the requests that got a decodable response message
+    pub latency: Option<Latency>,
+}
+
+/// Statistics over every recorded response of a cache, including earlier ones in histories.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// Number of (question, server) pairs
+    pub entries: u64,
+    pub servers: BTreeMap<IpAddr, ServerStats>,
+    /// Number of responses per qtype
+    pub qtypes: BTreeMap<u16, u64>,
+    /// Number of responses per protocol
+    pub protocols: BTreeMap<Protocol, u64>,
+    /// Number of decodable response messages per rcode
+    pub rcodes: BTreeMap<u16, u64>,
+    /// Number of responses per error kind
+    pub errors: BTreeMap<ErrorKind, u64>,
+}
+
+/// Collects statistics over the responses recorded in a cache.
+///
+/// Errors are counted by the outcome of each request, so undecodable response messages count as
+/// protocol errors. Failed attempts that were retried only count as retries. Latencies are taken
+/// from the durations of requests that got a decodable response message.
+pub fn stats(cache: &Cache) -> Result<Stats, StoreError> {
+    let mut stats = Stats::default();
+    let mut durations: BTreeMap<IpAddr, Vec<u32>> = BTreeMap::new();
+    for (question, server, history) in cache.entries()? {
+        stats.entries += 1;
+        let server_stats = stats.servers.entry(server).or_default();
+        for response in history {
+            server_stats.responses += 1;
+            server_stats.retries += response.failures.len() as u64;
+            *stats.qtypes.entry(question.qtype.into()).or_default() += 1;
+            *stats.protocols.entry(question.proto).or_default() += 1;
+            if let Ok(message) = &response.outcome {
+                server_stats.bytes += message.encoded.len() as u64;
+            }
+            match response.outcome_kind() {
+                OutcomeKind::Response => {
+                    server_stats.answered += 1;
+                    durations.entry(server).or_default().push(response.duration);
+                }
+                OutcomeKind::Error(error_kind) => {
+                    server_stats.errors += 1;
+                    *stats.errors.entry(error_kind).or_default() += 1;
+                }
+            }
+            if let Some(rcode) = response.rcode() {
+                *stats.rcodes.entry(rcode.into()).or_default() += 1;
+            }
+        }
+    }
+    for (server, durations) in durations {
+        if let Some(server_stats) = stats.servers.get_mut(&server) {
+            server_stats.latency = Latency::of(durations);
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Question;
+    use crate::client::RetriedResponse;
+    use crate::fixtures;
+    use crate::fixtures::question;
+    use crate::store::MemoryStore;
+    use crate::store::Store;
+    use std::sync::Arc;
+    use trust_dns_client::op::Message;
+    use trust_dns_client::op::ResponseCode;
+    use trust_dns_client::rr::RecordType;
+
+    fn response(
+        duration: u32,
+        outcome: Result<ResponseCode, ErrorKind>,
+        retries: usize,
+    ) -> Arc<RetriedResponse> {
+        let outcome = outcome.map(|rcode| {
+            let mut message = Message::new();
+            message.set_response_code(rcode);
+            message
+        });
+        Arc::new(RetriedResponse {
+            failures: vec![fixtures::timeout(0, 1000); retries],
+            duration,
+            ..fixtures::response(outcome)
+        })
+    }
+
+    #[test]
+    fn summarize() {
+        let fast: IpAddr = "192.0.2.1".parse().unwrap();
+        let slow: IpAddr = "192.0.2.2".parse().unwrap();
+        let mut store = MemoryStore::new();
+        for duration in 1..=100 {
+            let qname = format!("{}.example", duration);
+            let response = response(duration, Ok(ResponseCode::NoError), 0);
+            store.insert(question(&qname), fast, response).unwrap();
+        }
+        let history = vec![
+            response(400, Ok(ResponseCode::NXDomain), 2),
+            response(0, Err(ErrorKind::Timeout), 3),
+        ];
+        store
+            .extend(vec![(
+                Question {
+                    qtype: RecordType::NS,
+                    ..question("example")
+                },
+                slow,
+                history,
+            )])
+            .unwrap();
+        let stats = stats(&Cache::with_store(Box::new(store))).unwrap();
+        let total = |field: fn(&ServerStats) -> u64| stats.servers.values().map(field).sum::<u64>();
+
+        assert_eq!(stats.entries, 101);
+        assert_eq!(total(|server| server.responses), 102);
+        assert_eq!(total(|server| server.retries), 5);
+        assert_eq!(stats.qtypes, BTreeMap::from([(1, 100), (2, 2)]));
+        assert_eq!(stats.protocols, BTreeMap::from([(Protocol::Udp, 102)]));
+        assert_eq!(stats.rcodes, BTreeMap::from([(0, 100), (3, 1)]));
+        assert_eq!(stats.errors, BTreeMap::from([(ErrorKind::Timeout, 1)]));
+        assert_eq!(
+            stats.servers[&fast].latency,
+            Some(Latency {
+                min: 1,
+                p50: 50,
+                p90: 90,
+                p99: 99,
+                max: 100,
+            })
+        );
+        let slow = &stats.servers[&slow];
+        assert_eq!((slow.responses, slow.answered, slow.errors), (2, 1, 1));
+        assert_eq!(slow.latency.unwrap().p50, 400);
+        assert_eq!(total(|server| server.bytes), 101 * 12);
+    }
+}
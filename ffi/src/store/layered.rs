@@ -0,0 +1,194 @@
+use crate::client::Question;
+use crate::client::RetriedResponse;
+use crate::store::Entry;
+use crate::store::Filter;
+use crate::store::Match;
+use crate::store::Store;
+use crate::store::StoreError;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::sync::RwLock;
+use std::sync::RwLockReadGuard;
+use std::sync::RwLockWriteGuard;
+
+/// A layered store that can be shared as the base of other layered stores, and across threads.
+///
+/// Readers share the lock, so lookups only wait for each other while one of them writes.
+pub type SharedStore = Arc<RwLock<LayeredStore>>;
+
+/// Locks a shared store for reading.
+///
+/// A panic while the lock was held leaves the store as consistent as the panicking operation
+/// left it, so poisoning is ignored.
+pub fn read(store: &SharedStore) -> RwLockReadGuard<'_, LayeredStore> {
+    store.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Locks a shared store for writing, ignoring poisoning like `read`.
+pub fn write(store: &SharedStore) -> RwLockWriteGuard<'_, LayeredStore> {
+    store.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A store layering the entries of its own overlay store over those of read-only bases.
+///
+/// Reads consult the overlay first and then each base in the order they were added, taking the
+/// whole entry from the first store that has one. Writes only ever go to the overlay, so removing
+/// an entry that a base also has uncovers the entry of the base.
+///
+/// Bases are read through rather than copied, so changes made to them later show up here too.
+pub struct LayeredStore {
+    pub overlay: Box<dyn Store>,
+    bases: Vec<SharedStore>,
+}
+
+impl LayeredStore {
+    pub fn new(overlay: Box<dyn Store>) -> Self {
+        LayeredStore {
+            overlay,
+            bases: Vec::new(),
+        }
+    }
+
+    /// Adds a base to consult after the overlay and the bases added before.
+    ///
+    /// Returns false without adding it if that would make the store a base of itself.
+    pub fn add_base(this: &SharedStore, base: SharedStore) -> bool {
+        // Bases are only ever added while this is held, so that no other base is added between
+        // checking for a cycle and adding this one. Holding the lock of `this` instead would
+        // deadlock on the check when `base` does reach `this`.
+        static TOPOLOGY: Mutex<()> = Mutex::new(());
+        let _topology = TOPOLOGY.lock().unwrap_or_else(PoisonError::into_inner);
+        if Arc::ptr_eq(this, &base) || read(&base).reaches(this) {
+            return false;
+        }
+        write(this).bases.push(base);
+        true
+    }
+
+    fn reaches(&self, store: &SharedStore) -> bool {
+        self.bases
+            .iter()
+            .any(|base| Arc::ptr_eq(base, store) || read(base).reaches(store))
+    }
+
+    /// Collects the items of all layers, leaving out those of entries that a layer above has.
+    ///
+    /// Whether a layer above has an entry is only checked for the items collected, so the layers
+    /// are never scanned as a whole.
+    fn collect<T>(
+        &self,
+        items: impl Fn(&dyn Store) -> Result<Vec<T>, StoreError>,
+        key: fn(&T) -> (&Question, &IpAddr),
+    ) -> Result<Vec<T>, StoreError> {
+        let mut collected = items(self.overlay.as_ref())?;
+        for (i, base) in self.bases.iter().enumerate() {
+            let base_items = items(&*read(base))?;
+            for item in base_items {
+                let (question, server) = key(&item);
+                if !self.is_covered(i, question, server)? {
+                    collected.push(item);
+                }
+            }
+        }
+        Ok(collected)
+    }
+
+    /// Whether the overlay or any of the first `bases` bases has an entry.
+    fn is_covered(
+        &self,
+        bases: usize,
+        question: &Question,
+        server: &IpAddr,
+    ) -> Result<bool, StoreError> {
+        if self.overlay.get(question, server)?.is_some() {
+            return Ok(true);
+        }
+        for base in &self.bases[..bases] {
+            if read(base).get(question, server)?.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl Store for LayeredStore {
+    fn get(
+        &self,
+        question: &Question,
+        server: &IpAddr,
+    ) -> Result<Option<Arc<RetriedResponse>>, StoreError> {
+        if let Some(response) = self.overlay.get(question, server)? {
+            return Ok(Some(response));
+        }
+        for base in &self.bases {
+            if let Some(response) = read(base).get(question, server)? {
+                return Ok(Some(response));
+            }
+        }
+        Ok(None)
+    }
+
+    fn history(
+        &self,
+        question: &Question,
+        server: &IpAddr,
+    ) -> Result<Vec<Arc<RetriedResponse>>, StoreError> {
+        let history = self.overlay.history(question, server)?;
+        if !history.is_empty() {
+            return Ok(history);
+        }
+        for base in &self.bases {
+            let history = read(base).history(question, server)?;
+            if !history.is_empty() {
+                return Ok(history);
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    fn set_history(
+        &mut self,
+        question: Question,
+        server: IpAddr,
+        history: Vec<Arc<RetriedResponse>>,
+    ) -> Result<(), StoreError> {
+        self.overlay.set_history(question, server, history)
+    }
+
+    fn insert(
+        &mut self,
+        question: Question,
+        server: IpAddr,
+        response: Arc<RetriedResponse>,
+    ) -> Result<(), StoreError> {
+        self.overlay.insert(question, server, response)
+    }
+
+    fn extend(&mut self, entries: Vec<Entry>) -> Result<(), StoreError> {
+        self.overlay.extend(entries)
+    }
+
+    fn select(&self, filter: &Filter) -> Result<Vec<(Question, IpAddr)>, StoreError> {
+        self.collect(
+            |store| store.select(filter),
+            |(question, server)| (question, server),
+        )
+    }
+
+    fn query(&self, filter: &Filter) -> Result<Vec<Match>, StoreError> {
+        self.collect(
+            |store| store.query(filter),
+            |(question, server, _)| (question, server),
+        )
+    }
+
+    fn entries(&self) -> Result<Vec<Entry>, StoreError> {
+        self.collect(
+            |store| store.entries(),
+            |(question, server, _)| (question, server),
+        )
+    }
+}
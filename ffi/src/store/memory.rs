@@ -0,0 +1,417 @@
+use crate::client::OutcomeKind;
+use crate::client::Question;
+use crate::client::RetriedResponse;
+use crate::store::reversed_name;
+use crate::store::Filter;
+use crate::store::Store;
+use crate::store::StoreError;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Arc;
+use trust_dns_client::op::ResponseCode;
+use trust_dns_client::rr::RecordType;
+
+type Key = (Question, IpAddr);
+
+/// A store keeping all entries in memory.
+///
+/// Its serialized form is the msgpack cache file format. The latest response of each entry is
+/// kept apart from the earlier ones so that caches without any history serialize the same way
+/// they always have. Entries are kept ordered by question and server, so equal stores serialize to
+/// identical bytes.
+///
+/// Selections by qname, qname suffix, qtype, server, outcome kind or response code are served by
+/// secondary indexes rather than by scanning all entries.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(from = "Contents")]
+pub struct MemoryStore {
+    cache: BTreeMap<Question, BTreeMap<IpAddr, Arc<RetriedResponse>>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    earlier: BTreeMap<Question, BTreeMap<IpAddr, Vec<Arc<RetriedResponse>>>>,
+    #[serde(skip)]
+    index: Index,
+}
+
+/// The serialized fields of a `MemoryStore`.
+#[derive(Deserialize)]
+struct Contents {
+    cache: BTreeMap<Question, BTreeMap<IpAddr, Arc<RetriedResponse>>>,
+    #[serde(default)]
+    earlier: BTreeMap<Question, BTreeMap<IpAddr, Vec<Arc<RetriedResponse>>>>,
+}
+
+impl From<Contents> for MemoryStore {
+    fn from(contents: Contents) -> Self {
+        let mut index = Index::default();
+        for (question, inner) in &contents.cache {
+            for (server, response) in inner {
+                index.add(&(question.clone(), *server), response);
+            }
+        }
+        MemoryStore {
+            cache: contents.cache,
+            earlier: contents.earlier,
+            index,
+        }
+    }
+}
+
+/// Secondary indexes from the attributes of entries to their keys.
+///
+/// The outcome kind and response code are those of the latest response of each entry.
+#[derive(Default)]
+struct Index {
+    /// By `reversed_name` of the qname
+    qname: BTreeMap<String, BTreeSet<Key>>,
+    qtype: HashMap<RecordType, BTreeSet<Key>>,
+    server: HashMap<IpAddr, BTreeSet<Key>>,
+    outcome: HashMap<OutcomeKind, BTreeSet<Key>>,
+    rcode: HashMap<ResponseCode, BTreeSet<Key>>,
+}
+
+impl Index {
+    fn add(&mut self, key: &Key, latest: &RetriedResponse) {
+        let (question, server) = key;
+        self.qname
+            .entry(reversed_name(&question.qname))
+            .or_default()
+            .insert(key.clone());
+        Self::add_to(&mut self.qtype, question.qtype, key);
+        Self::add_to(&mut self.server, *server, key);
+        Self::add_to(&mut self.outcome, latest.outcome_kind(), key);
+        if let Some(rcode) = latest.rcode() {
+            Self::add_to(&mut self.rcode, rcode, key);
+        }
+    }
+
+    fn remove(&mut self, key: &Key, latest: &RetriedResponse) {
+        let (question, server) = key;
+        let qname = reversed_name(&question.qname);
+        if let Some(keys) = self.qname.get_mut(&qname) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.qname.remove(&qname);
+            }
+        }
+        Self::remove_from(&mut self.qtype, question.qtype, key);
+        Self::remove_from(&mut self.server, *server, key);
+        Self::remove_from(&mut self.outcome, latest.outcome_kind(), key);
+        if let Some(rcode) = latest.rcode() {
+            Self::remove_from(&mut self.rcode, rcode, key);
+        }
+    }
+
+    fn add_to<V: Eq + Hash>(map: &mut HashMap<V, BTreeSet<Key>>, value: V, key: &Key) {
+        map.entry(value).or_default().insert(key.clone());
+    }
+
+    fn remove_from<V: Eq + Hash>(map: &mut HashMap<V, BTreeSet<Key>>, value: V, key: &Key) {
+        if let Some(keys) = map.get_mut(&value) {
+            keys.remove(key);
+            if keys.is_empty() {
+                map.remove(&value);
+            }
+        }
+    }
+
+    /// The keys of the entries matching the indexed criteria of a filter, or `None` if it has
+    /// none.
+    ///
+    /// Only the smallest of the sets selected by the criteria is returned, so the entries must
+    /// still be matched against the filter.
+    fn candidates(&self, filter: &Filter) -> Option<Vec<&Key>> {
+        fn lookup(keys: Option<&BTreeSet<Key>>) -> Vec<&Key> {
+            keys.into_iter().flatten().collect()
+        }
+        let mut sets: Vec<Vec<&Key>> = Vec::new();
+        if let Some(qname) = &filter.qname {
+            sets.push(lookup(self.qname.get(&reversed_name(qname))));
+        }
+        if let Some(suffix) = &filter.qname_suffix {
+            let suffix = reversed_name(suffix);
+            let below = self
+                .qname
+                .range(format!("{}.", suffix)..format!("{}/", suffix));
+            sets.push(
+                self.qname
+                    .get(&suffix)
+                    .into_iter()
+                    .chain(below.map(|(_, keys)| keys))
+                    .flatten()
+                    .collect(),
+            );
+        }
+        if let Some(qtype) = filter.qtype {
+            sets.push(lookup(self.qtype.get(&qtype)));
+        }
+        if let Some(server) = filter.server {
+            sets.push(lookup(self.server.get(&server)));
+        }
+        if let Some(outcome) = filter.outcome {
+            sets.push(lookup(self.outcome.get(&outcome)));
+        }
+        if let Some(rcode) = filter.rcode {
+            sets.push(lookup(self.rcode.get(&rcode)));
+        }
+        sets.into_iter().min_by_key(Vec::len)
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    fn remove_from<V>(
+        map: &mut BTreeMap<Question, BTreeMap<IpAddr, V>>,
+        question: &Question,
+        server: &IpAddr,
+    ) {
+        if let Some(inner) = map.get_mut(question) {
+            inner.remove(server);
+            if inner.is_empty() {
+                map.remove(question);
+            }
+        }
+    }
+}
+
+impl Store for MemoryStore {
+    fn get(
+        &self,
+        question: &Question,
+        server: &IpAddr,
+    ) -> Result<Option<Arc<RetriedResponse>>, StoreError> {
+        Ok(self
+            .cache
+            .get(question)
+            .and_then(|inner| inner.get(server))
+            .cloned())
+    }
+
+    fn history(
+        &self,
+        question: &Question,
+        server: &IpAddr,
+    ) -> Result<Vec<Arc<RetriedResponse>>, StoreError> {
+        let mut history = self
+            .earlier
+            .get(question)
+            .and_then(|inner| inner.get(server))
+            .cloned()
+            .unwrap_or_default();
+        history.extend(self.get(question, server)?);
+        Ok(history)
+    }
+
+    fn set_history(
+        &mut self,
+        question: Question,
+        server: IpAddr,
+        mut history: Vec<Arc<RetriedResponse>>,
+    ) -> Result<(), StoreError> {
+        let key = (question.clone(), server);
+        if let Some(old) = self.get(&question, &server)? {
+            self.index.remove(&key, &old);
+        }
+        match history.pop() {
+            Some(latest) => {
+                self.index.add(&key, &latest);
+                if history.is_empty() {
+                    Self::remove_from(&mut self.earlier, &question, &server);
+                } else {
+                    self.earlier
+                        .entry(question.clone())
+                        .or_default()
+                        .insert(server, history);
+                }
+                self.cache
+                    .entry(question)
+                    .or_default()
+                    .insert(server, latest);
+            }
+            None => {
+                Self::remove_from(&mut self.cache, &question, &server);
+                Self::remove_from(&mut self.earlier, &question, &server);
+            }
+        }
+        Ok(())
+    }
+
+    fn select(&self, filter: &Filter) -> Result<Vec<(Question, IpAddr)>, StoreError> {
+        let matches = |(question, server): &Key| {
+            self.cache
+                .get(question)
+                .and_then(|inner| inner.get(server))
+                .is_some_and(|response| filter.matches(question, server, response))
+        };
+        Ok(match self.index.candidates(filter) {
+            Some(candidates) => candidates
+                .into_iter()
+                .filter(|key| matches(key))
+                .cloned()
+                .collect(),
+            None => self
+                .cache
+                .iter()
+                .flat_map(|(question, inner)| {
+                    inner
+                        .iter()
+                        .filter(move |(server, response)| {
+                            filter.matches(question, server, response)
+                        })
+                        .map(move |(server, _)| (question.clone(), *server))
+                })
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ErrorKind;
+    use crate::fixtures;
+    use rmp_serde as rmps;
+    use serde::Deserialize;
+    use serde::Serialize;
+    use trust_dns_client::op::Message;
+
+    fn question(qname: &str, qtype: RecordType) -> Question {
+        Question {
+            qtype,
+            ..fixtures::question(qname)
+        }
+    }
+
+    fn response(started: u64, outcome: Result<ResponseCode, ErrorKind>) -> Arc<RetriedResponse> {
+        let outcome = outcome.map(|rcode| {
+            let mut message = Message::new();
+            message.set_response_code(rcode);
+            message
+        });
+        Arc::new(RetriedResponse {
+            started,
+            ..fixtures::response(outcome)
+        })
+    }
+
+    /// Checks indexed selections against full scans.
+    fn assert_indexed(store: &MemoryStore, filters: &[Filter]) {
+        for filter in filters {
+            let mut indexed = store.select(filter).unwrap();
+            indexed.sort();
+            let mut scanned: Vec<_> = store
+                .cache
+                .iter()
+                .flat_map(|(question, inner)| {
+                    inner
+                        .iter()
+                        .filter(|(server, response)| filter.matches(question, server, response))
+                        .map(|(server, _)| (question.clone(), *server))
+                })
+                .collect();
+            scanned.sort();
+            assert_eq!(indexed, scanned, "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn indexed_select() {
+        let server1: IpAddr = "192.0.2.1".parse().unwrap();
+        let server2: IpAddr = "192.0.2.2".parse().unwrap();
+        let mut store = MemoryStore::new();
+        let entries = [
+            (
+                "example.se",
+                RecordType::NS,
+                server1,
+                Ok(ResponseCode::NoError),
+            ),
+            (
+                "www.example.se",
+                RecordType::A,
+                server1,
+                Ok(ResponseCode::NXDomain),
+            ),
+            (
+                "www.Example.SE",
+                RecordType::A,
+                server2,
+                Err(ErrorKind::Timeout),
+            ),
+            (
+                "example.org",
+                RecordType::A,
+                server2,
+                Ok(ResponseCode::NoError),
+            ),
+            (
+                "ample.se",
+                RecordType::A,
+                server1,
+                Ok(ResponseCode::Refused),
+            ),
+        ];
+        for (started, (qname, qtype, server, outcome)) in entries.into_iter().enumerate() {
+            store
+                .insert(
+                    question(qname, qtype),
+                    server,
+                    response(started as u64, outcome),
+                )
+                .unwrap();
+        }
+
+        let by = |update: &dyn Fn(&mut Filter)| {
+            let mut filter = Filter::default();
+            update(&mut filter);
+            filter
+        };
+        let filters = [
+            Filter::default(),
+            by(&|f| f.qname = Some("WWW.example.se.".parse().unwrap())),
+            by(&|f| f.qname_suffix = Some("example.se.".parse().unwrap())),
+            by(&|f| f.qname_suffix = Some(".".parse().unwrap())),
+            by(&|f| f.qtype = Some(RecordType::A)),
+            by(&|f| f.server = Some(server2)),
+            by(&|f| f.outcome = Some(OutcomeKind::Error(ErrorKind::Timeout))),
+            by(&|f| f.rcode = Some(ResponseCode::NoError)),
+            by(&|f| {
+                f.qname_suffix = Some("se.".parse().unwrap());
+                f.server = Some(server1);
+                f.rcode = Some(ResponseCode::Refused);
+            }),
+        ];
+        assert_indexed(&store, &filters);
+        assert_eq!(store.select(&filters[2]).unwrap().len(), 3);
+
+        // Replacing and removing entries updates the indexes
+        let q = question("www.example.se", RecordType::A);
+        store
+            .set_history(
+                q.clone(),
+                server2,
+                vec![
+                    response(5, Err(ErrorKind::Timeout)),
+                    response(6, Ok(ResponseCode::NoError)),
+                ],
+            )
+            .unwrap();
+        store.set_history(q, server1, vec![]).unwrap();
+        assert_indexed(&store, &filters);
+        assert_eq!(store.select(&filters[6]).unwrap(), vec![]);
+
+        // Deserializing rebuilds the indexes
+        let mut buf = Vec::new();
+        store
+            .serialize(&mut rmps::Serializer::new(&mut buf))
+            .unwrap();
+        let store = MemoryStore::deserialize(&mut rmps::Deserializer::new(&buf[..])).unwrap();
+        assert_indexed(&store, &filters);
+        assert_eq!(store.select(&filters[7]).unwrap().len(), 3);
+    }
+}
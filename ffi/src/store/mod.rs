@@ -0,0 +1,212 @@
+mod layered;
+mod memory;
+mod sqlite;
+
+pub use layered::read;
+pub use layered::write;
+pub use layered::LayeredStore;
+pub use layered::SharedStore;
+pub use memory::MemoryStore;
+pub use sqlite::SqliteStore;
+
+use crate::client::OutcomeKind;
+use crate::client::Protocol;
+use crate::client::Question;
+use crate::client::RetriedResponse;
+use rmp_serde as rmps;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use trust_dns_client::op::ResponseCode;
+use trust_dns_client::rr::Name;
+use trust_dns_client::rr::RecordType;
+
+/// The key and history of a cache entry.
+pub type Entry = (Question, IpAddr, Vec<Arc<RetriedResponse>>);
+
+/// The key and latest response of a cache entry.
+pub type Match = (Question, IpAddr, Arc<RetriedResponse>);
+
+/// A storage backend for cache entries.
+///
+/// Stores are shared between threads behind a lock, which readers share.
+///
+/// Every entry is keyed by a (question, server) pair and holds a non-empty history of responses,
+/// oldest first.
+pub trait Store: Send + Sync {
+    /// Gets the latest response.
+    fn get(
+        &self,
+        question: &Question,
+        server: &IpAddr,
+    ) -> Result<Option<Arc<RetriedResponse>>, StoreError> {
+        Ok(self.history(question, server)?.pop())
+    }
+
+    /// Gets all responses, oldest first.
+    fn history(
+        &self,
+        question: &Question,
+        server: &IpAddr,
+    ) -> Result<Vec<Arc<RetriedResponse>>, StoreError>;
+
+    /// Replaces all responses.
+    ///
+    /// An empty history removes the entry.
+    fn set_history(
+        &mut self,
+        question: Question,
+        server: IpAddr,
+        history: Vec<Arc<RetriedResponse>>,
+    ) -> Result<(), StoreError>;
+
+    /// Replaces all responses with a single one.
+    fn insert(
+        &mut self,
+        question: Question,
+        server: IpAddr,
+        response: Arc<RetriedResponse>,
+    ) -> Result<(), StoreError> {
+        self.set_history(question, server, vec![response])
+    }
+
+    /// Replaces the histories of many entries at once.
+    ///
+    /// Backends may override this to batch the writes.
+    fn extend(&mut self, entries: Vec<Entry>) -> Result<(), StoreError> {
+        for (question, server, history) in entries {
+            self.set_history(question, server, history)?;
+        }
+        Ok(())
+    }
+
+    /// Lists the keys of all entries whose latest response matches the filter.
+    fn select(&self, filter: &Filter) -> Result<Vec<(Question, IpAddr)>, StoreError>;
+
+    /// Lists the keys and latest responses of all entries whose latest response matches the
+    /// filter.
+    ///
+    /// Backends may override this to fetch the responses along with the keys.
+    fn query(&self, filter: &Filter) -> Result<Vec<Match>, StoreError> {
+        let mut matches = Vec::new();
+        for (question, server) in self.select(filter)? {
+            if let Some(response) = self.get(&question, &server)? {
+                matches.push((question, server, response));
+            }
+        }
+        Ok(matches)
+    }
+
+    /// Lists all entries.
+    fn entries(&self) -> Result<Vec<Entry>, StoreError> {
+        let mut entries = Vec::new();
+        for (question, server) in self.select(&Filter::default())? {
+            let history = self.history(&question, &server)?;
+            entries.push((question, server, history));
+        }
+        Ok(entries)
+    }
+}
+
+/// A conjunction of criteria for selecting cache entries.
+///
+/// Unset criteria match every entry.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub qname: Option<Name>,
+    /// Matches the qname and all names below it
+    pub qname_suffix: Option<Name>,
+    pub qtype: Option<RecordType>,
+    pub proto: Option<Protocol>,
+    pub server: Option<IpAddr>,
+    pub outcome: Option<OutcomeKind>,
+    /// Matches only responses with a decoded message
+    pub rcode: Option<ResponseCode>,
+    /// Millis since epoch, inclusive
+    pub started_from: Option<u64>,
+    /// Millis since epoch, exclusive
+    pub started_until: Option<u64>,
+}
+
+impl Filter {
+    pub fn matches(
+        &self,
+        question: &Question,
+        server: &IpAddr,
+        response: &RetriedResponse,
+    ) -> bool {
+        self.qname
+            .as_ref()
+            .is_none_or(|qname| qname == &question.qname)
+            && self
+                .qname_suffix
+                .as_ref()
+                .is_none_or(|suffix| suffix.zone_of(&question.qname))
+            && self.qtype.is_none_or(|qtype| qtype == question.qtype)
+            && self.proto.is_none_or(|proto| proto == question.proto)
+            && self.server.is_none_or(|s| &s == server)
+            && self
+                .outcome
+                .is_none_or(|outcome| outcome == response.outcome_kind())
+            && self
+                .rcode
+                .is_none_or(|rcode| Some(rcode) == response.rcode())
+            && self
+                .started_from
+                .is_none_or(|from| response.started >= from)
+            && self
+                .started_until
+                .is_none_or(|until| response.started < until)
+    }
+}
+
+/// Lowercased labels in reverse order, each preceded by a dot. The root name is empty.
+///
+/// Names below a given name sort right after it, in a range that ends before the reversed name
+/// followed by a slash.
+fn reversed_name(name: &Name) -> String {
+    let name = name.to_lowercase();
+    let mut labels: Vec<_> = name
+        .iter()
+        .map(|label| String::from_utf8_lossy(label).replace('.', "\\."))
+        .collect();
+    labels.reverse();
+    labels.iter().map(|label| format!(".{}", label)).collect()
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Encode(rmps::encode::Error),
+    Decode(rmps::decode::Error),
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::Encode(err) => write!(f, "encode error: {}", err),
+            StoreError::Decode(err) => write!(f, "decode error: {}", err),
+            StoreError::Sqlite(err) => write!(f, "sqlite error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<rmps::encode::Error> for StoreError {
+    fn from(err: rmps::encode::Error) -> Self {
+        StoreError::Encode(err)
+    }
+}
+
+impl From<rmps::decode::Error> for StoreError {
+    fn from(err: rmps::decode::Error) -> Self {
+        StoreError::Decode(err)
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(err: rusqlite::Error) -> Self {
+        StoreError::Sqlite(err)
+    }
+}
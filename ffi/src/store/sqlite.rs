@@ -0,0 +1,554 @@
+use crate::client::Question;
+use crate::client::RetriedResponse;
+use crate::store::reversed_name;
+use crate::store::Entry;
+use crate::store::Filter;
+use crate::store::Match;
+use crate::store::Store;
+use crate::store::StoreError;
+use rmp_serde as rmps;
+use rusqlite::params;
+use rusqlite::types::ToSql;
+use rusqlite::Connection;
+use rusqlite::OptionalExtension;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::PoisonError;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS response (
+        question BLOB NOT NULL,
+        server   TEXT NOT NULL,
+        seq      INTEGER NOT NULL,
+        latest   INTEGER NOT NULL,
+        qname    TEXT NOT NULL,
+        qtype    INTEGER NOT NULL,
+        proto    INTEGER NOT NULL,
+        outcome  INTEGER NOT NULL,
+        rcode    INTEGER,
+        started  INTEGER NOT NULL,
+        response BLOB NOT NULL,
+        PRIMARY KEY (question, server, seq)
+    );
+";
+
+const INDEXES: &str = "
+    CREATE INDEX IF NOT EXISTS response_qname ON response (qname);
+    CREATE INDEX IF NOT EXISTS response_qtype ON response (qtype);
+    CREATE INDEX IF NOT EXISTS response_server ON response (server);
+    CREATE INDEX IF NOT EXISTS response_outcome ON response (outcome);
+    CREATE INDEX IF NOT EXISTS response_rcode ON response (rcode);
+    CREATE INDEX IF NOT EXISTS response_started ON response (started);
+";
+
+/// A selected key and, if it was asked for, the encoded latest response.
+type Selected = (Question, IpAddr, Option<Vec<u8>>);
+
+/// A store keeping all entries in an SQLite database.
+///
+/// Every write goes to the database immediately. Questions and responses are stored as msgpack
+/// blobs, one row per response in the history of an entry. Questions are stored with a lowercase
+/// qname, so spellings of the same name in different cases share an entry, and questions are
+/// given back that way. The qname, qtype, protocol, server,
+/// outcome kind, response code and start time of each response are stored in indexed columns.
+///
+/// Qnames are stored with their labels in reverse order so that names below a given suffix form a
+/// contiguous range in the index.
+///
+/// The connection is locked for the duration of each operation, so operations from different
+/// threads take turns.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn conn_mut(&mut self) -> &mut Connection {
+        self.conn.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Opens an SQLite database file, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        Self::init(Connection::open(path)?)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self, StoreError> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(mut conn: Connection) -> Result<Self, StoreError> {
+        conn.execute_batch(SCHEMA)?;
+        Self::add_rcode_column(&mut conn)?;
+        Self::lowercase_questions(&mut conn)?;
+        conn.execute_batch(INDEXES)?;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Adds and fills in the rcode column in databases created before it existed.
+    fn add_rcode_column(conn: &mut Connection) -> Result<(), StoreError> {
+        let exists: bool = conn.query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('response') WHERE name = 'rcode'",
+            [],
+            |row| row.get(0),
+        )?;
+        if exists {
+            return Ok(());
+        }
+        let tx = conn.transaction()?;
+        tx.execute("ALTER TABLE response ADD COLUMN rcode INTEGER", [])?;
+        {
+            let mut select = tx.prepare("SELECT rowid, response FROM response")?;
+            let mut update = tx.prepare("UPDATE response SET rcode = ?1 WHERE rowid = ?2")?;
+            let mut rows = select.query([])?;
+            while let Some(row) = rows.next()? {
+                let rowid: i64 = row.get(0)?;
+                let response: Vec<u8> = row.get(1)?;
+                let response: RetriedResponse = Self::decode(&response)?;
+                update.execute(params![response.rcode().map(u16::from), rowid])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Merges the entries of questions that only differ in the case of their qname, in databases
+    /// created before questions were stored with a lowercase qname.
+    ///
+    /// Histories are merged in order of start time. Databases that have been through this are
+    /// marked with a `user_version` of 1.
+    fn lowercase_questions(conn: &mut Connection) -> Result<(), StoreError> {
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version >= 1 {
+            return Ok(());
+        }
+        let tx = conn.transaction()?;
+        {
+            let mut histories: BTreeMap<(Vec<u8>, String), Vec<i64>> = BTreeMap::new();
+            let mut select = tx.prepare(
+                "SELECT rowid, question, server FROM response ORDER BY started, seq, rowid",
+            )?;
+            let mut rows = select.query([])?;
+            while let Some(row) = rows.next()? {
+                let rowid: i64 = row.get(0)?;
+                let question: Vec<u8> = row.get(1)?;
+                let server: String = row.get(2)?;
+                let key = Self::key(&Self::decode(&question)?)?;
+                histories.entry((key, server)).or_default().push(rowid);
+            }
+            // Move rows out of the way first so renumbering them can't collide
+            tx.execute("UPDATE response SET seq = -1 - seq", [])?;
+            let mut update = tx.prepare(
+                "UPDATE response SET question = ?1, seq = ?2, latest = ?3 WHERE rowid = ?4",
+            )?;
+            for ((key, _), rowids) in &histories {
+                for (seq, rowid) in rowids.iter().enumerate() {
+                    update.execute(params![key, seq as i64, seq + 1 == rowids.len(), rowid])?;
+                }
+            }
+        }
+        tx.execute_batch("PRAGMA user_version = 1")?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Encodes the question an entry is stored under, with a lowercase qname.
+    fn key(question: &Question) -> Result<Vec<u8>, StoreError> {
+        Self::encode(&Question {
+            qname: question.qname.to_lowercase(),
+            ..question.clone()
+        })
+    }
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, StoreError> {
+        let mut buf = Vec::new();
+        value.serialize(&mut rmps::Serializer::new(&mut buf))?;
+        Ok(buf)
+    }
+
+    fn decode<'a, T: Deserialize<'a>>(buf: &'a [u8]) -> Result<T, StoreError> {
+        Ok(T::deserialize(&mut rmps::Deserializer::new(buf))?)
+    }
+
+    fn set_history_with(
+        conn: &Connection,
+        question: &Question,
+        server: &IpAddr,
+        history: &[Arc<RetriedResponse>],
+    ) -> Result<(), StoreError> {
+        let question_blob = Self::key(question)?;
+        conn.prepare_cached("DELETE FROM response WHERE question = ?1 AND server = ?2")?
+            .execute(params![question_blob, server.to_string()])?;
+        let mut stmt = conn.prepare_cached(
+            "INSERT INTO response
+                (question, server, seq, latest, qname, qtype, proto, outcome, rcode, started,
+                 response)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        )?;
+        for (seq, response) in history.iter().enumerate() {
+            stmt.execute(params![
+                question_blob,
+                server.to_string(),
+                seq as i64,
+                seq + 1 == history.len(),
+                reversed_name(&question.qname),
+                u16::from(question.qtype),
+                u8::from(question.proto),
+                u16::from(response.outcome_kind()),
+                response.rcode().map(u16::from),
+                response.started as i64,
+                Self::encode(response)?,
+            ])?;
+        }
+        Ok(())
+    }
+    /// Selects the given columns of the latest responses matching a filter, where the columns are
+    /// the question and the server, optionally followed by the response.
+    fn query_with(&self, columns: &str, filter: &Filter) -> Result<Vec<Selected>, StoreError> {
+        let mut sql = format!("SELECT {} FROM response WHERE latest", columns);
+        let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+        if let Some(qname) = &filter.qname {
+            sql.push_str(" AND qname = ?");
+            values.push(Box::new(reversed_name(qname)));
+        }
+        if let Some(suffix) = &filter.qname_suffix {
+            let suffix = reversed_name(suffix);
+            sql.push_str(" AND (qname = ? OR (qname >= ? AND qname < ?))");
+            values.push(Box::new(suffix.clone()));
+            values.push(Box::new(format!("{}.", suffix)));
+            values.push(Box::new(format!("{}/", suffix)));
+        }
+        if let Some(qtype) = filter.qtype {
+            sql.push_str(" AND qtype = ?");
+            values.push(Box::new(u16::from(qtype)));
+        }
+        if let Some(proto) = filter.proto {
+            sql.push_str(" AND proto = ?");
+            values.push(Box::new(u8::from(proto)));
+        }
+        if let Some(server) = filter.server {
+            sql.push_str(" AND server = ?");
+            values.push(Box::new(server.to_string()));
+        }
+        if let Some(outcome) = filter.outcome {
+            sql.push_str(" AND outcome = ?");
+            values.push(Box::new(u16::from(outcome)));
+        }
+        if let Some(rcode) = filter.rcode {
+            sql.push_str(" AND rcode = ?");
+            values.push(Box::new(u16::from(rcode)));
+        }
+        if let Some(from) = filter.started_from {
+            sql.push_str(" AND started >= ?");
+            values.push(Box::new(from as i64));
+        }
+        if let Some(until) = filter.started_until {
+            sql.push_str(" AND started < ?");
+            values.push(Box::new(until as i64));
+        }
+
+        let conn = self.conn();
+        let mut stmt = conn.prepare(&sql)?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(values.iter()))?;
+        let mut keys = Vec::new();
+        while let Some(row) = rows.next()? {
+            let question: Vec<u8> = row.get(0)?;
+            let server: String = row.get(1)?;
+            let server = server.parse().map_err(|_| {
+                rusqlite::Error::InvalidColumnType(1, "server".into(), rusqlite::types::Type::Text)
+            })?;
+            let response = if row.as_ref().column_count() > 2 {
+                Some(row.get(2)?)
+            } else {
+                None
+            };
+            keys.push((Self::decode(&question)?, server, response));
+        }
+        Ok(keys)
+    }
+}
+
+impl Store for SqliteStore {
+    fn get(
+        &self,
+        question: &Question,
+        server: &IpAddr,
+    ) -> Result<Option<Arc<RetriedResponse>>, StoreError> {
+        let response: Option<Vec<u8>> = self
+            .conn()
+            .prepare_cached(
+                "SELECT response FROM response
+                 WHERE question = ?1 AND server = ?2 AND latest",
+            )?
+            .query_row(params![Self::key(question)?, server.to_string()], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        match response {
+            Some(response) => Ok(Some(Arc::new(Self::decode(&response)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn history(
+        &self,
+        question: &Question,
+        server: &IpAddr,
+    ) -> Result<Vec<Arc<RetriedResponse>>, StoreError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached(
+            "SELECT response FROM response
+             WHERE question = ?1 AND server = ?2
+             ORDER BY seq",
+        )?;
+        let mut rows = stmt.query(params![Self::key(question)?, server.to_string()])?;
+        let mut history = Vec::new();
+        while let Some(row) = rows.next()? {
+            let response: Vec<u8> = row.get(0)?;
+            history.push(Arc::new(Self::decode(&response)?));
+        }
+        Ok(history)
+    }
+
+    fn set_history(
+        &mut self,
+        question: Question,
+        server: IpAddr,
+        history: Vec<Arc<RetriedResponse>>,
+    ) -> Result<(), StoreError> {
+        let tx = self.conn_mut().transaction()?;
+        Self::set_history_with(&tx, &question, &server, &history)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn extend(&mut self, entries: Vec<Entry>) -> Result<(), StoreError> {
+        let tx = self.conn_mut().transaction()?;
+        for (question, server, history) in &entries {
+            Self::set_history_with(&tx, question, server, history)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn select(&self, filter: &Filter) -> Result<Vec<(Question, IpAddr)>, StoreError> {
+        Ok(self
+            .query_with("question, server", filter)?
+            .into_iter()
+            .map(|(question, server, _)| (question, server))
+            .collect())
+    }
+
+    fn query(&self, filter: &Filter) -> Result<Vec<Match>, StoreError> {
+        let mut matches = Vec::new();
+        for (question, server, response) in self.query_with("question, server, response", filter)? {
+            if let Some(response) = response {
+                matches.push((question, server, Arc::new(Self::decode(&response)?)));
+            }
+        }
+        Ok(matches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ErrorKind;
+    use crate::fixtures;
+    use crate::store::MemoryStore;
+    use trust_dns_client::op::Message;
+    use trust_dns_client::op::ResponseCode;
+    use trust_dns_client::rr::Name;
+    use trust_dns_client::rr::RecordType;
+
+    fn question(qname: &str, qtype: RecordType) -> Question {
+        Question {
+            qtype,
+            ..fixtures::question(qname)
+        }
+    }
+
+    fn timeout(started: u64) -> Arc<RetriedResponse> {
+        Arc::new(RetriedResponse {
+            started,
+            duration: 5000,
+            ..fixtures::response(Err(ErrorKind::Timeout))
+        })
+    }
+
+    #[test]
+    fn get_and_select() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        let server1: IpAddr = "192.0.2.1".parse().unwrap();
+        let server2: IpAddr = "192.0.2.2".parse().unwrap();
+        let q1 = question("example.com", RecordType::A);
+        let q2 = question("Example.COM.", RecordType::NS);
+        store.insert(q1.clone(), server1, timeout(1000)).unwrap();
+        store.insert(q1.clone(), server2, timeout(2000)).unwrap();
+        store.insert(q2.clone(), server1, timeout(3000)).unwrap();
+
+        assert_eq!(store.select(&Filter::default()).unwrap().len(), 3);
+        assert_eq!(store.get(&q1, &server2).unwrap(), Some(timeout(2000)));
+        assert_eq!(store.get(&q2, &server2).unwrap(), None);
+
+        let by_qname = Filter {
+            qname: Some("example.com.".parse().unwrap()),
+            ..Filter::default()
+        };
+        assert_eq!(store.select(&by_qname).unwrap().len(), 3);
+
+        let by_server_and_time = Filter {
+            server: Some(server1),
+            started_from: Some(2000),
+            ..Filter::default()
+        };
+        assert_eq!(
+            store.select(&by_server_and_time).unwrap(),
+            vec![(q2.clone(), server1)]
+        );
+
+        let mut memory = MemoryStore::new();
+        memory.extend(store.entries().unwrap()).unwrap();
+        assert_eq!(
+            memory.select(&by_server_and_time).unwrap(),
+            vec![(q2, server1)]
+        );
+    }
+
+    #[test]
+    fn history_and_suffix() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        let server: IpAddr = "192.0.2.1".parse().unwrap();
+        let q1 = question("www.example.com", RecordType::A);
+        let q2 = question("example.org", RecordType::A);
+        store
+            .set_history(q1.clone(), server, vec![timeout(1000), timeout(2000)])
+            .unwrap();
+        store.insert(q2.clone(), server, timeout(3000)).unwrap();
+
+        assert_eq!(
+            store.history(&q1, &server).unwrap(),
+            vec![timeout(1000), timeout(2000)]
+        );
+        assert_eq!(store.get(&q1, &server).unwrap(), Some(timeout(2000)));
+
+        let by_suffix = |suffix: &str| Filter {
+            qname_suffix: Some(suffix.parse().unwrap()),
+            ..Filter::default()
+        };
+        assert_eq!(
+            store.select(&by_suffix("EXAMPLE.com.")).unwrap(),
+            vec![(q1.clone(), server)]
+        );
+        assert_eq!(store.select(&by_suffix("ample.com.")).unwrap(), vec![]);
+        assert_eq!(store.select(&by_suffix(".")).unwrap().len(), 2);
+
+        let by_time = Filter {
+            started_until: Some(1500),
+            ..Filter::default()
+        };
+        assert_eq!(store.select(&by_time).unwrap(), vec![]);
+
+        store.set_history(q1.clone(), server, vec![]).unwrap();
+        assert_eq!(store.history(&q1, &server).unwrap(), vec![]);
+        assert_eq!(
+            store.select(&Filter::default()).unwrap(),
+            vec![(q2, server)]
+        );
+    }
+
+    #[test]
+    fn qname_case() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        let server: IpAddr = "192.0.2.1".parse().unwrap();
+        // Parsing lowercases names but names from the C API keep their case
+        let lower = question("example.com.", RecordType::A);
+        let mixed = Question {
+            qname: Name::from_ascii("Example.COM.").unwrap(),
+            ..lower.clone()
+        };
+        assert!(!mixed.qname.eq_case(&lower.qname));
+        store.insert(mixed.clone(), server, timeout(1000)).unwrap();
+        store.insert(lower.clone(), server, timeout(2000)).unwrap();
+
+        // The second insert replaces the first
+        assert_eq!(store.history(&mixed, &server).unwrap(), vec![timeout(2000)]);
+        assert_eq!(store.get(&mixed, &server).unwrap(), Some(timeout(2000)));
+        let selected = store.select(&Filter::default()).unwrap();
+        assert_eq!(selected, vec![(lower.clone(), server)]);
+        assert!(selected[0].0.qname.eq_case(&lower.qname));
+
+        // Databases from before lowercase qnames get their entries merged when opened
+        let blob = SqliteStore::encode(&mixed).unwrap();
+        store
+            .conn_mut()
+            .execute(
+                "INSERT INTO response
+                    SELECT ?1, server, seq, latest, qname, qtype, proto, outcome, rcode, 1000,
+                        ?2
+                    FROM response",
+                params![blob, SqliteStore::encode(&timeout(1000)).unwrap()],
+            )
+            .unwrap();
+        store
+            .conn_mut()
+            .execute_batch("PRAGMA user_version = 0")
+            .unwrap();
+        assert_eq!(store.select(&Filter::default()).unwrap().len(), 2);
+        let store = SqliteStore::init(store.conn.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            store.history(&lower, &server).unwrap(),
+            vec![timeout(1000), timeout(2000)]
+        );
+        assert_eq!(
+            store.select(&Filter::default()).unwrap(),
+            vec![(lower, server)]
+        );
+    }
+
+    #[test]
+    fn rcode_and_migration() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        let server: IpAddr = "192.0.2.1".parse().unwrap();
+        let q1 = question("example.com", RecordType::A);
+        let q2 = question("example.org", RecordType::A);
+        let mut message = Message::new();
+        message.set_response_code(ResponseCode::NXDomain);
+        let nxdomain = Arc::new(RetriedResponse {
+            started: 2000,
+            duration: 10,
+            ..fixtures::response(Ok(message))
+        });
+        store.insert(q1.clone(), server, nxdomain.clone()).unwrap();
+        store.insert(q2, server, timeout(1000)).unwrap();
+
+        let by_rcode = Filter {
+            rcode: Some(ResponseCode::NXDomain),
+            ..Filter::default()
+        };
+        let expected = vec![(q1, server, nxdomain)];
+        assert_eq!(store.query(&by_rcode).unwrap(), expected);
+
+        // Databases from before the rcode column get it filled in when opened
+        store
+            .conn_mut()
+            .execute_batch(
+                "DROP INDEX response_rcode;
+                 ALTER TABLE response DROP COLUMN rcode;",
+            )
+            .unwrap();
+        let store = SqliteStore::init(store.conn.into_inner().unwrap()).unwrap();
+        assert_eq!(store.query(&by_rcode).unwrap(), expected);
+    }
+}
@@ -0,0 +1,79 @@
+//! Ways of sending requests to servers.
+//!
+//! A cache looks up the questions it is missing through a transport. `Net` sends them over the
+//! network, while a scripted transport makes up the outcomes, for testing code that does lookups.
+pub mod scripted;
+
+use crate::cancel::Cancellation;
+use crate::client::Failure;
+use crate::client::Question;
+use crate::client::Wire;
+use crate::clock::Clock;
+use futures::channel::oneshot::Canceled;
+use futures::future::BoxFuture;
+use std::any::Any;
+use std::net::IpAddr;
+use tokio::runtime::Runtime;
+use tokio::time::Instant;
+use trust_dns_proto::error::ProtoError;
+use trust_dns_proto::error::ProtoErrorKind;
+
+/// The outcome of a request.
+pub struct Lookup {
+    pub failures: Vec<Failure>,
+    /// When the final attempt started, or when connecting started if that failed. Millis since
+    /// epoch
+    pub started: u64,
+    /// How long the final attempt, or connecting, took. Millis
+    pub duration: u32,
+    pub connect_micros: u32,
+    pub query_micros: u32,
+    /// The received response message or the final error
+    pub outcome: Result<Vec<u8>, ProtoError>,
+    /// The final attempt on the wire, if it got as far as a socket
+    pub wire: Option<Wire>,
+}
+
+impl Lookup {
+    /// A lookup stopped before its first attempt started.
+    pub fn cancelled(started: u64) -> Self {
+        Lookup {
+            failures: vec![],
+            started,
+            duration: 0,
+            connect_micros: 0,
+            query_micros: 0,
+            outcome: Err(cancelled()),
+            wire: None,
+        }
+    }
+}
+
+/// Transports are shared between threads, which may run lookups on them concurrently.
+///
+/// The C API hands out every kind of transport as a `dyn Transport`, and gets back at the kind
+/// through `Any`.
+pub trait Transport: Any + Send + Sync {
+    /// The runtime that lookups are run on.
+    fn runtime(&self) -> &Runtime;
+
+    /// The clock that timestamps the attempts.
+    fn clock(&self) -> &Clock;
+
+    /// Sends a question to a server, retrying failed attempts.
+    ///
+    /// Once `cancellation` stops the lookup, the attempt going on is given up on and becomes the
+    /// final one, with the error of `cancelled`, while the failed attempts before it are kept.
+    fn lookup<'a>(
+        &'a self,
+        question: Question,
+        server: IpAddr,
+        cancellation: &'a Cancellation,
+        expiry: Option<Instant>,
+    ) -> BoxFuture<'a, Lookup>;
+}
+
+/// The error of an attempt that was given up on because its lookup was stopped.
+pub fn cancelled() -> ProtoError {
+    ProtoErrorKind::Canceled(Canceled).into()
+}
@@ -0,0 +1,259 @@
+use crate::cancel::Cancellation;
+use crate::client::Failure;
+use crate::client::Question;
+use crate::clock::micros;
+use crate::clock::Clock;
+use crate::transport;
+use crate::transport::Lookup;
+use crate::transport::Transport;
+use futures::future::BoxFuture;
+use futures_util::future::FutureExt;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::PoisonError;
+use std::time::Duration;
+use tokio::runtime::Builder;
+use tokio::runtime::Runtime;
+use tokio::time::Instant;
+use trust_dns_proto::error::ProtoError;
+use trust_dns_proto::error::ProtoErrorKind;
+
+/// What happens when a question is sent to a server.
+#[derive(Clone, Debug)]
+pub enum Attempt {
+    /// The server responds with a message
+    Respond(Vec<u8>),
+    /// The server responds with a message that has the TC flag set
+    Truncate(Vec<u8>),
+    /// The server responds with bytes that aren't a valid message
+    Malformed(Vec<u8>),
+    Timeout,
+    Io(io::ErrorKind),
+}
+
+/// The attempts left for a (question, server) pair, with their delays.
+type Script = VecDeque<(Duration, Attempt)>;
+
+/// A transport that plays out scripted attempts instead of sending anything.
+///
+/// Attempts are scripted per (question, server) pair and used up in order, retrying failed ones
+/// just like `Net` does. A pair with no attempts left times out.
+///
+/// Each attempt lasts for its scripted delay, slept on the clock of the transport. That's a manual
+/// clock starting at the epoch unless replaced, so lookups return right away.
+pub struct ScriptedTransport {
+    pub retry: u16,
+    /// Millis
+    pub retrans: u32,
+    pub clock: Clock,
+    runtime: Runtime,
+    scripts: Mutex<HashMap<(Question, IpAddr), Script>>,
+}
+
+impl ScriptedTransport {
+    pub fn new(retry: u16, retrans: u32) -> io::Result<Self> {
+        Ok(ScriptedTransport {
+            retry,
+            retrans,
+            clock: Clock::manual(0),
+            runtime: Builder::new_multi_thread()
+                .worker_threads(1)
+                .enable_all()
+                .build()?,
+            scripts: Mutex::default(),
+        })
+    }
+
+    /// Locks the scripts. Each change to them is a single step, so poisoning is ignored.
+    fn scripts(&self) -> MutexGuard<'_, HashMap<(Question, IpAddr), Script>> {
+        self.scripts.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Appends an attempt to the script for a (question, server) pair.
+    pub fn push(&self, question: Question, server: IpAddr, delay: Duration, attempt: Attempt) {
+        self.scripts()
+            .entry((question, server))
+            .or_default()
+            .push_back((delay, attempt));
+    }
+
+    fn next(&self, question: &Question, server: IpAddr) -> (Duration, Attempt) {
+        self.scripts()
+            .get_mut(&(question.clone(), server))
+            .and_then(VecDeque::pop_front)
+            .unwrap_or((Duration::ZERO, Attempt::Timeout))
+    }
+}
+
+impl Transport for ScriptedTransport {
+    fn runtime(&self) -> &Runtime {
+        &self.runtime
+    }
+
+    fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    fn lookup<'a>(
+        &'a self,
+        question: Question,
+        server: IpAddr,
+        cancellation: &'a Cancellation,
+        expiry: Option<Instant>,
+    ) -> BoxFuture<'a, Lookup> {
+        async move {
+            let mut failures = Vec::new();
+            for tries_left in (0..self.retry.max(1)).rev() {
+                let (delay, attempt) = self.next(&question, server);
+                let started = self.clock.now();
+                let stopwatch = self.clock.stopwatch();
+                let slept = cancellation.unless_stopped(expiry, self.clock.sleep(delay));
+                let attempt = slept.await.map(|()| attempt);
+                let elapsed = stopwatch.elapsed();
+                let Some(attempt) = attempt else {
+                    return Lookup {
+                        failures,
+                        started,
+                        duration: elapsed.as_millis() as u32,
+                        connect_micros: 0,
+                        query_micros: micros(elapsed),
+                        outcome: Err(transport::cancelled()),
+                        wire: None,
+                    };
+                };
+                let outcome = match attempt {
+                    Attempt::Respond(bytes) | Attempt::Malformed(bytes) => Ok(bytes),
+                    Attempt::Truncate(mut bytes) => {
+                        if let Some(flags) = bytes.get_mut(2) {
+                            *flags |= 0x02;
+                        }
+                        Ok(bytes)
+                    }
+                    Attempt::Timeout => Err(ProtoError::from(ProtoErrorKind::Timeout)),
+                    Attempt::Io(kind) => Err(ProtoError::from(io::Error::from(kind))),
+                };
+                match outcome {
+                    Err(failure) if tries_left > 0 => {
+                        failures.push(Failure {
+                            query_start: started,
+                            query_duration: elapsed.as_millis() as u32,
+                            kind: (&failure).into(),
+                            query_micros: micros(elapsed),
+                            wire: None,
+                        });
+                        let retrans = Duration::from_millis(self.retrans as u64);
+                        let sleep = self.clock.sleep(retrans);
+                        if cancellation.unless_stopped(expiry, sleep).await.is_none() {
+                            let stopped = Lookup::cancelled(self.clock.now());
+                            return Lookup {
+                                failures,
+                                ..stopped
+                            };
+                        }
+                    }
+                    outcome => {
+                        return Lookup {
+                            failures,
+                            started,
+                            duration: elapsed.as_millis() as u32,
+                            connect_micros: 0,
+                            query_micros: micros(elapsed),
+                            outcome,
+                            wire: None,
+                        }
+                    }
+                }
+            }
+            unreachable!("the final attempt always returns")
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancel::Cancellation;
+    use crate::client::Cache;
+    use crate::client::ErrorKind;
+    use crate::client::MissStrategy;
+    use crate::fixtures::answer;
+    use crate::fixtures::question;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    #[test]
+    fn scripted_lookups() {
+        let server: IpAddr = "192.0.2.1".parse().unwrap();
+        let servers = HashSet::from([server]);
+        let transport = ScriptedTransport::new(3, 100).unwrap();
+        let ms = Duration::from_millis;
+        transport.push(question("retry.example"), server, ms(800), Attempt::Timeout);
+        transport.push(
+            question("retry.example"),
+            server,
+            ms(5),
+            Attempt::Io(io::ErrorKind::ConnectionRefused),
+        );
+        transport.push(
+            question("retry.example"),
+            server,
+            ms(20),
+            Attempt::Respond(answer()),
+        );
+        transport.push(
+            question("tc.example"),
+            server,
+            ms(0),
+            Attempt::Truncate(answer()),
+        );
+        transport.push(
+            question("garbage.example"),
+            server,
+            ms(0),
+            Attempt::Malformed(vec![0; 5]),
+        );
+        let transport: Arc<dyn Transport> = Arc::new(transport);
+        let cache = Cache::new();
+
+        let lookup = |cache: &Cache, qname| {
+            cache
+                .lookup(
+                    &MissStrategy::FillOnMiss(transport.clone()),
+                    question(qname),
+                    &servers,
+                    &Cancellation::default(),
+                )
+                .unwrap()
+                .remove(&server)
+                .unwrap()
+        };
+
+        let response = lookup(&cache, "retry.example");
+        assert_eq!(response.duration, 20);
+        assert_eq!(response.query_micros, 20000);
+        assert!(response.outcome.unwrap().0.authoritative());
+        let cached = cache
+            .latest(&question("retry.example"), &server)
+            .unwrap()
+            .unwrap();
+        let kinds: Vec<_> = cached.failures.iter().map(|failure| failure.kind).collect();
+        assert_eq!(kinds, vec![ErrorKind::Timeout, ErrorKind::Io]);
+        assert_eq!(cached.failures[0].query_start, 0);
+        assert_eq!(cached.failures[1].query_start, 900);
+        assert_eq!(cached.started, 1005);
+
+        let response = lookup(&cache, "tc.example");
+        assert!(response.outcome.unwrap().0.truncated());
+
+        let response = lookup(&cache, "garbage.example");
+        assert_eq!(response.outcome.unwrap_err(), ErrorKind::Protocol);
+
+        let response = lookup(&cache, "unscripted.example");
+        assert_eq!(response.outcome.unwrap_err(), ErrorKind::Timeout);
+    }
+}
@@ -4,7 +4,7 @@ use serde::Serialize;
 use serde::Serializer;
 use serde_bytes::ByteBuf;
 use std::fmt;
-use std::rc::Rc;
+use std::sync::Arc;
 use trust_dns_client::op::Message;
 use trust_dns_client::proto::error::ProtoError;
 
@@ -116,11 +116,11 @@ impl<'a> fmt::Display for DigMessage<'a> {
 }
 
 pub trait MessageExt {
-    fn as_dig(&self) -> DigMessage;
+    fn as_dig(&self) -> DigMessage<'_>;
 }
 
 impl MessageExt for Message {
-    fn as_dig(&self) -> DigMessage {
+    fn as_dig(&self) -> DigMessage<'_> {
         DigMessage(self)
     }
 }
@@ -128,7 +128,7 @@ impl MessageExt for Message {
 #[derive(Debug)]
 pub struct MyMessage {
     pub encoded: Vec<u8>,
-    pub decoded: Option<Rc<Message>>,
+    pub decoded: Option<Arc<Message>>,
 }
 
 impl PartialEq for MyMessage {
@@ -144,7 +144,7 @@ impl MyMessage {
             Ok(decoded) => (
                 MyMessage {
                     encoded,
-                    decoded: Some(Rc::new(decoded)),
+                    decoded: Some(Arc::new(decoded)),
                 },
                 None,
             ),
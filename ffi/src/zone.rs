@@ -0,0 +1,463 @@
+//! Synthetic responses from zone files.
+//!
+//! Zones in master file format are assigned to virtual server addresses, which then answer
+//! questions the way an authoritative server would. This is meant for writing cache fixtures by
+//! hand rather than recording them.
+use crate::client::Cache;
+use crate::client::Protocol;
+use crate::client::Question;
+use crate::client::RetriedResponse;
+use crate::store::StoreError;
+use crate::trust_dns_ext::MyMessage;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use trust_dns_client::op::Edns;
+use trust_dns_client::op::Message;
+use trust_dns_client::op::MessageType;
+use trust_dns_client::op::OpCode;
+use trust_dns_client::op::Query;
+use trust_dns_client::op::ResponseCode;
+use trust_dns_client::rr::Name;
+use trust_dns_client::rr::RData;
+use trust_dns_client::rr::Record;
+use trust_dns_client::rr::RecordType;
+use trust_dns_proto::error::ProtoError;
+use trust_dns_proto::serialize::txt::Lexer;
+use trust_dns_proto::serialize::txt::ParseError;
+use trust_dns_proto::serialize::txt::Parser;
+
+/// The longest chain of CNAME records that is followed within a zone.
+const MAX_CNAME_CHAIN: usize = 8;
+/// The UDP payload size advertised in responses with EDNS.
+const EDNS_MAX_PAYLOAD: u16 = 1232;
+
+#[derive(Debug)]
+pub enum ZoneError {
+    Parse(ParseError),
+    /// The zone has no SOA record at its origin
+    MissingSoa(Name),
+    Proto(ProtoError),
+    Store(StoreError),
+}
+
+impl fmt::Display for ZoneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ZoneError::Parse(err) => write!(f, "invalid zone file: {}", err),
+            ZoneError::MissingSoa(origin) => write!(f, "no SOA record at zone origin {}", origin),
+            ZoneError::Proto(err) => write!(f, "dns error: {}", err),
+            ZoneError::Store(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ZoneError {}
+
+impl From<ParseError> for ZoneError {
+    fn from(err: ParseError) -> Self {
+        ZoneError::Parse(err)
+    }
+}
+
+impl From<ProtoError> for ZoneError {
+    fn from(err: ProtoError) -> Self {
+        ZoneError::Proto(err)
+    }
+}
+
+impl From<StoreError> for ZoneError {
+    fn from(err: StoreError) -> Self {
+        ZoneError::Store(err)
+    }
+}
+
+pub struct Zone {
+    origin: Name,
+    records: Vec<Record>,
+}
+
+impl Zone {
+    /// Parses a zone in master file format.
+    ///
+    /// The origin is taken from a `$ORIGIN` directive if `origin` is `None`.
+    pub fn parse(text: &str, origin: Option<Name>) -> Result<Zone, ZoneError> {
+        let (origin, rrsets) = Parser::new().parse(Lexer::new(text), origin)?;
+        let records: Vec<Record> = rrsets
+            .into_values()
+            .flat_map(|rrset| rrset.records_without_rrsigs().cloned().collect::<Vec<_>>())
+            .collect();
+        let zone = Zone { origin, records };
+        if zone.soa().is_none() {
+            return Err(ZoneError::MissingSoa(zone.origin));
+        }
+        Ok(zone)
+    }
+
+    fn soa(&self) -> Option<&Record> {
+        self.records
+            .iter()
+            .find(|record| record.record_type() == RecordType::SOA && *record.name() == self.origin)
+    }
+
+    fn records_at(&self, name: &Name) -> Vec<&Record> {
+        self.records
+            .iter()
+            .filter(|record| record.name() == name)
+            .collect()
+    }
+
+    fn rrset(&self, name: &Name, rtype: RecordType) -> Vec<Record> {
+        self.records_at(name)
+            .into_iter()
+            .filter(|record| record.record_type() == rtype)
+            .cloned()
+            .collect()
+    }
+
+    /// Whether a name owns any records, or is an empty non-terminal.
+    fn exists(&self, name: &Name) -> bool {
+        self.records
+            .iter()
+            .any(|record| name.zone_of(record.name()))
+    }
+
+    /// The topmost delegation between the origin (exclusive) and `qname` (inclusive).
+    ///
+    /// A DS query for the delegated name itself is answered by the parent side of the cut.
+    fn cut(&self, qname: &Name, qtype: RecordType) -> Option<Name> {
+        let first = self.origin.num_labels() + 1;
+        (first..=qname.num_labels())
+            .map(|labels| qname.trim_to(labels as usize))
+            .filter(|name| !(qtype == RecordType::DS && name == qname))
+            .find(|name| !self.rrset(name, RecordType::NS).is_empty())
+    }
+
+    /// The records owned by `qname`, expanded from a wildcard if `qname` doesn't exist.
+    ///
+    /// Returns `None` if `qname` doesn't exist and no wildcard covers it.
+    fn owned_records(&self, qname: &Name) -> Option<Vec<Record>> {
+        if self.exists(qname) {
+            return Some(self.records_at(qname).into_iter().cloned().collect());
+        }
+        let encloser = (self.origin.num_labels()..qname.num_labels())
+            .rev()
+            .map(|labels| qname.trim_to(labels as usize))
+            .find(|name| self.exists(name))?;
+        let wildcard = Name::from_ascii("*").ok()?.append_domain(&encloser).ok()?;
+        let records = self.records_at(&wildcard);
+        if records.is_empty() {
+            return None;
+        }
+        Some(
+            records
+                .into_iter()
+                .map(|record| {
+                    let mut record = record.clone();
+                    record.set_name(qname.clone());
+                    record
+                })
+                .collect(),
+        )
+    }
+
+    /// Fills in the sections and the response code of an authoritative response.
+    fn answer(&self, qname: &Name, qtype: RecordType, message: &mut Message) {
+        let mut qname = qname.clone();
+        for _ in 0..MAX_CNAME_CHAIN {
+            if let Some(cut) = self.cut(&qname, qtype) {
+                if message.answers().is_empty() {
+                    message.set_authoritative(false);
+                }
+                let ns = self.rrset(&cut, RecordType::NS);
+                for record in &ns {
+                    if let Some(RData::NS(target)) = record.data() {
+                        message.add_additionals(self.rrset(&target.0, RecordType::A));
+                        message.add_additionals(self.rrset(&target.0, RecordType::AAAA));
+                    }
+                }
+                message.add_name_servers(ns);
+                return;
+            }
+
+            let Some(records) = self.owned_records(&qname) else {
+                message.set_response_code(ResponseCode::NXDomain);
+                message.add_name_servers(self.soa().cloned());
+                return;
+            };
+            let matching: Vec<_> = records
+                .iter()
+                .filter(|record| qtype == RecordType::ANY || record.record_type() == qtype)
+                .cloned()
+                .collect();
+            if !matching.is_empty() {
+                message.add_answers(matching);
+                return;
+            }
+            let cname = records
+                .into_iter()
+                .find(|record| record.record_type() == RecordType::CNAME);
+            let Some(cname) = cname else {
+                message.add_name_servers(self.soa().cloned());
+                return;
+            };
+            let target = match cname.data() {
+                Some(RData::CNAME(target)) => target.0.clone(),
+                _ => return,
+            };
+            message.add_answer(cname);
+            if !self.origin.zone_of(&target) {
+                return;
+            }
+            qname = target;
+        }
+    }
+}
+
+/// Zones assigned to virtual server addresses.
+#[derive(Default)]
+pub struct Zones {
+    servers: HashMap<IpAddr, Vec<Zone>>,
+}
+
+impl Zones {
+    pub fn add(&mut self, server: IpAddr, zone: Zone) {
+        self.servers.entry(server).or_default().push(zone);
+    }
+
+    /// The most specific zone of a server containing `qname`.
+    ///
+    /// DS queries for the origin of a zone go to its parent zone if the server has that too.
+    fn zone(&self, server: &IpAddr, qname: &Name, qtype: RecordType) -> Option<&Zone> {
+        let zones = self.servers.get(server)?;
+        let closest = |parent_side: bool| {
+            zones
+                .iter()
+                .filter(|zone| zone.origin.zone_of(qname))
+                .filter(|zone| !(parent_side && zone.origin == *qname))
+                .max_by_key(|zone| zone.origin.num_labels())
+        };
+        match qtype {
+            RecordType::DS => closest(true).or_else(|| closest(false)),
+            _ => closest(false),
+        }
+    }
+
+    /// The response a server would give to a question.
+    ///
+    /// Servers refuse questions outside of their zones. EDNS queries get EDNS responses with the
+    /// DO flag echoed. Responses too large for UDP are truncated to just the header and question.
+    pub fn respond(&self, question: &Question, server: &IpAddr) -> Result<Vec<u8>, ProtoError> {
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Response)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(question.recursion_desired)
+            .add_query(Query::query(question.qname.clone(), question.qtype));
+        if let Some(edns_config) = &question.edns_config {
+            let mut edns = Edns::new();
+            edns.set_max_payload(EDNS_MAX_PAYLOAD)
+                .set_dnssec_ok(edns_config.dnssec_ok);
+            message.set_edns(edns);
+        }
+
+        match self.zone(server, &question.qname, question.qtype) {
+            Some(zone) => {
+                message.set_authoritative(true);
+                zone.answer(&question.qname, question.qtype, &mut message);
+            }
+            None => {
+                message.set_response_code(ResponseCode::Refused);
+            }
+        }
+
+        let bytes = message.to_vec()?;
+        let limit = match (&question.proto, &question.edns_config) {
+            (Protocol::Tcp, _) => u16::MAX,
+            (Protocol::Udp, None) => 512,
+            (Protocol::Udp, Some(edns_config)) => edns_config.max_payload.max(512),
+        };
+        if bytes.len() <= limit as usize {
+            return Ok(bytes);
+        }
+        message.take_answers();
+        message.take_name_servers();
+        message.take_additionals();
+        message.set_truncated(true);
+        message.to_vec()
+    }
+
+    /// Inserts the responses of some servers to a question into a cache.
+    ///
+    /// The responses replace any earlier entries. They are timestamped at the Unix epoch and take
+    /// no time, so the fixtures are the same every time.
+    pub fn synthesize(
+        &self,
+        cache: &Cache,
+        question: &Question,
+        servers: &[IpAddr],
+    ) -> Result<(), ZoneError> {
+        for server in servers {
+            let bytes = self.respond(question, server)?;
+            let response = Arc::new(RetriedResponse {
+                failures: vec![],
+                started: 0,
+                duration: 0,
+                outcome: Ok(MyMessage::from_vec(bytes).0),
+                connect_micros: 0,
+                query_micros: 0,
+                wire: None,
+            });
+            cache.insert(question.clone(), *server, response)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::EdnsConfig;
+    use crate::fixtures::question;
+
+    const PARENT: &str = "
+$ORIGIN example.
+$TTL 3600
+@        SOA   ns1 hostmaster 1 7200 3600 1209600 300
+         NS    ns1
+ns1      A     192.0.2.1
+www      A     192.0.2.10
+         AAAA  2001:db8::10
+alias    CNAME www
+outside  CNAME www.example.net.
+*.wild   TXT   \"wildcard\"
+a.b.c    A     192.0.2.20
+child    NS    ns.child
+         DS    12345 13 2 0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef
+ns.child A     192.0.2.30
+";
+
+    const CHILD: &str = "
+$ORIGIN child.example.
+@   3600 SOA ns hostmaster 1 7200 3600 1209600 300
+    3600 NS  ns
+ns  3600 A   192.0.2.30
+";
+
+    fn zones() -> Zones {
+        let mut zones = Zones::default();
+        let server = "192.0.2.1".parse().unwrap();
+        zones.add(server, Zone::parse(PARENT, None).unwrap());
+        zones.add(server, Zone::parse(CHILD, None).unwrap());
+        zones
+    }
+
+    fn ask(qname: &str, qtype: RecordType) -> Message {
+        let question = Question {
+            qtype,
+            ..question(qname)
+        };
+        let bytes = zones()
+            .respond(&question, &"192.0.2.1".parse().unwrap())
+            .unwrap();
+        Message::from_vec(&bytes).unwrap()
+    }
+
+    fn types(records: &[Record]) -> Vec<RecordType> {
+        records.iter().map(Record::record_type).collect()
+    }
+
+    #[test]
+    fn authoritative_answers() {
+        let answer = ask("www.example.", RecordType::A);
+        assert!(answer.authoritative());
+        assert_eq!(types(answer.answers()), vec![RecordType::A]);
+
+        let nodata = ask("www.example.", RecordType::MX);
+        assert_eq!(nodata.response_code(), ResponseCode::NoError);
+        assert!(nodata.answers().is_empty());
+        assert_eq!(types(nodata.name_servers()), vec![RecordType::SOA]);
+
+        let nxdomain = ask("nope.example.", RecordType::A);
+        assert_eq!(nxdomain.response_code(), ResponseCode::NXDomain);
+        assert_eq!(types(nxdomain.name_servers()), vec![RecordType::SOA]);
+
+        let empty_non_terminal = ask("b.c.example.", RecordType::A);
+        assert_eq!(empty_non_terminal.response_code(), ResponseCode::NoError);
+        assert!(empty_non_terminal.answers().is_empty());
+
+        let cname = ask("alias.example.", RecordType::A);
+        assert_eq!(
+            types(cname.answers()),
+            vec![RecordType::CNAME, RecordType::A]
+        );
+
+        let outside = ask("outside.example.", RecordType::A);
+        assert_eq!(types(outside.answers()), vec![RecordType::CNAME]);
+
+        let wildcard = ask("anything.wild.example.", RecordType::TXT);
+        assert_eq!(types(wildcard.answers()), vec![RecordType::TXT]);
+        assert_eq!(
+            *wildcard.answers()[0].name(),
+            "anything.wild.example.".parse::<Name>().unwrap()
+        );
+
+        let refused = ask("example.net.", RecordType::A);
+        assert_eq!(refused.response_code(), ResponseCode::Refused);
+        assert!(!refused.authoritative());
+    }
+
+    #[test]
+    fn delegations() {
+        let mut zones = Zones::default();
+        let server = "192.0.2.2".parse().unwrap();
+        zones.add(server, Zone::parse(PARENT, None).unwrap());
+        let question = question("www.child.example.");
+        let referral = Message::from_vec(&zones.respond(&question, &server).unwrap()).unwrap();
+        assert!(!referral.authoritative());
+        assert!(referral.answers().is_empty());
+        assert_eq!(types(referral.name_servers()), vec![RecordType::NS]);
+        assert_eq!(types(referral.additionals()), vec![RecordType::A]);
+
+        // The child zone is served too
+        let answer = ask("ns.child.example.", RecordType::A);
+        assert!(answer.authoritative());
+        assert_eq!(types(answer.answers()), vec![RecordType::A]);
+
+        let ds = ask("child.example.", RecordType::DS);
+        assert!(ds.authoritative());
+        assert_eq!(types(ds.answers()), vec![RecordType::DS]);
+    }
+
+    #[test]
+    fn synthesize_fixtures() {
+        let server = "192.0.2.1".parse().unwrap();
+        let question = Question {
+            qtype: RecordType::AAAA,
+            recursion_desired: true,
+            edns_config: Some(EdnsConfig {
+                version: 0,
+                dnssec_ok: true,
+                max_payload: 1232,
+                option_code: 0,
+                option_value: vec![],
+            }),
+            ..question("www.example.")
+        };
+        let cache = Cache::new();
+        zones().synthesize(&cache, &question, &[server]).unwrap();
+
+        let response = cache.latest(&question, &server).unwrap().unwrap();
+        let message = response.message().unwrap();
+        assert!(message.recursion_desired());
+        assert!(message.extensions().as_ref().unwrap().dnssec_ok());
+        assert_eq!(types(message.answers()), vec![RecordType::AAAA]);
+    }
+
+    #[test]
+    fn missing_soa() {
+        let err = Zone::parse("$ORIGIN example.\nwww 3600 A 192.0.2.1\n", None);
+        assert!(matches!(err, Err(ZoneError::MissingSoa(_))));
+    }
+}
@@ -9,9 +9,11 @@ use 5.014;
 
 our $VERSION = '0.01';
 
+use Carp qw( croak );
 use Const::Fast;
 use Exporter qw( import );
 use FFI::Platypus 1.00;
+use FFI::Platypus::Buffer qw( grow scalar_to_pointer );
 use Scalar::Util qw( dualvar isdual looks_like_number );
 
 our @EXPORT_OK = qw(
@@ -22,6 +24,9 @@ our @EXPORT_OK = qw(
 my %NAME2RRTYPE;
 my %NUM2RRTYPE;
 our %NUM2ERROR;
+our %NAME2ERROR;
+our %NAME2RCODE;
+our %NUM2RCODE;
 my %NAME2PROTO;
 my %NUM2PROTO;
 
@@ -29,20 +34,30 @@ our $ffi = FFI::Platypus->new( api => 1, lang => 'Rust' );
 
 $ffi->load_custom_type( '::PointerSizeBuffer' => 'buffer' );
 
-$ffi->type( 'object(Netbase::Cache)'    => 'cache_t' );
-$ffi->type( 'object(Netbase::Net)'      => 'net_t' );
-$ffi->type( 'object(Netbase::IP)'       => 'ip_t' );
-$ffi->type( 'object(Netbase::Name)'     => 'name_t' );
-$ffi->type( 'object(Netbase::Question)' => 'question_t' );
-$ffi->type( 'object(Netbase::Message)'  => 'message_t' );
-$ffi->type( 'u16'                       => 'rrtype_t' );
-$ffi->type( 'u8'                        => 'proto_t' );
-
-$ffi->attach_cast( 'ip_to_opaque',       'ip_t',   'opaque' );
-$ffi->attach_cast( 'net_to_opaque',      'net_t',  'opaque' );
-$ffi->attach_cast( 'opaque_to_ip',       'opaque', 'ip_t' );
-$ffi->attach_cast( 'opaque_to_message',  'opaque', 'message_t' );
-$ffi->attach_cast( 'opaque_to_question', 'opaque', 'question_t' );
+$ffi->type( 'object(Netbase::Cache)'             => 'cache_t' );
+$ffi->type( 'object(Netbase::CancelToken)'       => 'cancel_token_t' );
+$ffi->type( 'object(Netbase::Filter)'            => 'filter_t' );
+$ffi->type( 'object(Netbase::Net)'               => 'net_t' );
+$ffi->type( 'object(Netbase::IP)'                => 'ip_t' );
+$ffi->type( 'object(Netbase::Name)'              => 'name_t' );
+$ffi->type( 'object(Netbase::Question)'          => 'question_t' );
+$ffi->type( 'object(Netbase::Queue)'             => 'queue_t' );
+$ffi->type( 'object(Netbase::Message)'           => 'message_t' );
+$ffi->type( 'object(Netbase::ScriptedTransport)' => 'scripted_transport_t' );
+$ffi->type( 'object(Netbase::Zones)'             => 'zones_t' );
+$ffi->type( 'u16'                                => 'rrtype_t' );
+$ffi->type( 'u8'                                 => 'proto_t' );
+
+$ffi->attach_cast( 'cancel_token_to_opaque',       'cancel_token_t',       'opaque' );
+$ffi->attach_cast( 'ip_to_opaque',                 'ip_t',                 'opaque' );
+$ffi->attach_cast( 'name_to_opaque',               'name_t',               'opaque' );
+$ffi->attach_cast( 'net_to_opaque',                'net_t',                'opaque' );
+$ffi->attach_cast( 'opaque_to_ip',                 'opaque',               'ip_t' );
+$ffi->attach_cast( 'opaque_to_message',            'opaque',               'message_t' );
+$ffi->attach_cast( 'opaque_to_name',               'opaque',               'name_t' );
+$ffi->attach_cast( 'opaque_to_question',           'opaque',               'question_t' );
+$ffi->attach_cast( 'question_to_opaque',           'question_t',           'opaque' );
+$ffi->attach_cast( 'scripted_transport_to_opaque', 'scripted_transport_t', 'opaque' );
 
 $ffi->bundle;
 
@@ -79,11 +94,27 @@ const our $RRTYPE_TSIG       => dualvar 250, "TSIG";
 const our $RRTYPE_TXT        => dualvar 16,  "TXT";
 const our $RRTYPE_ZERO       => dualvar 0,   "ZERO";
 
-const our $E_INTERNAL => dualvar 1, "INTERNAL_ERROR";
-const our $E_IO       => dualvar 2, "IO_ERROR";
-const our $E_PROTOCOL => dualvar 3, "PROTOCOL_ERROR";
-const our $E_TIMEOUT  => dualvar 4, "TIMEOUT_ERROR";
-const our $E_LOCK     => dualvar 5, "LOCK_ERROR";
+const our $E_INTERNAL     => dualvar 1, "INTERNAL_ERROR";
+const our $E_IO           => dualvar 2, "IO_ERROR";
+const our $E_PROTOCOL     => dualvar 3, "PROTOCOL_ERROR";
+const our $E_TIMEOUT      => dualvar 4, "TIMEOUT_ERROR";
+const our $E_NOT_IN_CACHE => dualvar 6, "NOT_IN_CACHE_ERROR";
+const our $E_CANCELLED    => dualvar 7, "CANCELLED_ERROR";
+
+%NAME2RCODE = (
+    NOERROR  => 0,
+    FORMERR  => 1,
+    SERVFAIL => 2,
+    NXDOMAIN => 3,
+    NOTIMP   => 4,
+    REFUSED  => 5,
+    YXDOMAIN => 6,
+    YXRRSET  => 7,
+    NXRRSET  => 8,
+    NOTAUTH  => 9,
+    NOTZONE  => 10,
+    BADVERS  => 16,
+);
 
 const our $PROTO_UDP => dualvar 1, "UDP";
 const our $PROTO_TCP => dualvar 2, "TCP";
@@ -105,14 +136,18 @@ const our $PROTO_TCP => dualvar 2, "TCP";
         $E_PROTOCOL,
         $E_IO,
         $E_TIMEOUT,
-        $E_LOCK,
+        $E_NOT_IN_CACHE,
+        $E_CANCELLED,
     );
     for my $error ( @all_errors ) {
         $NUM2ERROR{ 0 + $error } = $error;
+        $NAME2ERROR{"$error"} = $error;
         my $name = "$error" =~ s/(.*)_ERROR/\$E_$1/mr;
         push @EXPORT_OK, $name;
     }
 
+    %NUM2RCODE = reverse %NAME2RCODE;
+
     my @all_rrtypes = (    #
         $RRTYPE_A,
         $RRTYPE_AAAA,
@@ -184,4 +219,35 @@ sub rrtype {
     return;
 }
 
+# Calls foreign code that reports errors through a get_buffer callback.
+#
+# The given sub makes the call, passing on the callback it is given as the
+# get_buffer argument.
+# Returns the value of the call if it is true, and croaks with the error message
+# otherwise.
+sub call_or_croak {
+    my ( $call ) = @_;
+
+    my $err_msg    = "";
+    my $get_buffer = $ffi->closure(
+        sub {
+            my ( $size ) = @_;
+            grow( $err_msg, $size );
+            return scalar_to_pointer $err_msg;
+        }
+    );
+
+    my $result = $call->( $get_buffer );
+    if ( !$result ) {
+        if ( $err_msg eq "" ) {
+            croak "panic in foreign code\n";
+        }
+        else {
+            croak "$err_msg\n";
+        }
+    }
+
+    return $result;
+}
+
 1;
@@ -21,11 +21,41 @@ distinct representations.
 When a request is made that has a cached response, that response is returned and
 no network request.
 
-When there is no cached response Netbase has two strategies for you to choose
-from.
-Either it gives an error response indicating that the request is not in the
-cache, or it transparently sends a network request and records the response in
-the cache before returning it.
+When there is no cached response Netbase has several strategies for you to
+choose from.
+It can give an error response indicating that the request is not in the cache,
+fail the whole lookup, or transparently send a network request and record the
+response in the cache before returning it.
+Cached responses can also be refreshed, always or once they reach a given age,
+keeping a history of responses for every request.
+See L</lookup>.
+
+=head2 Storage
+
+By default a cache is kept in memory and can be saved to and restored from a
+msgpack byte string.
+For large recordings a cache can instead be backed by an SQLite database file.
+Entries are then written to the file incrementally and looked up through
+indexes, without loading the whole file into memory.
+
+=head2 Layered caches
+
+A cache can be layered over one or more read-only base caches, e.g. to let
+every test in a suite add entries on top of a large shared recording without
+changing it.
+Lookups consult the cache itself first and then its bases.
+New responses only ever go into the cache itself, and only its own entries are
+serialized.
+See L</add_base>.
+
+=head2 Concurrent lookups
+
+Many lookups can be made at once, with their outcomes reported as they
+complete.
+See L</lookup_batch>.
+Lookups can also be started without waiting for them, and their outcomes
+collected once an event loop reports them complete.
+See L</submit> and L<Netbase::Queue>.
 
 =cut
 
@@ -37,7 +67,13 @@ use utf8;
 use Carp qw( croak );
 use FFI::Platypus::Buffer qw( grow scalar_to_pointer );
 use Netbase;
+use Netbase::CancelToken;
+use Netbase::Filter;
+use Netbase::IP qw( ip );
 use Netbase::Message;
+use Netbase::Queue;
+use Netbase::Zones;
+use Scalar::Util qw( blessed );
 
 $Netbase::ffi->mangler( sub { "netbase_cache_" . shift } );
 
@@ -80,58 +116,789 @@ $Netbase::ffi->attach(
     sub {
         my ( $xsub, $class, $buffer ) = @_;
 
-        my $err_msg  = "";
-        my $closure = $Netbase::ffi->closure(
+        return Netbase::call_or_croak( sub { $xsub->( $buffer, shift ) } );
+    },
+);
+
+=head2 from_json
+
+Construct a new cache populated with the entries of a JSON document as
+produced by L</to_json>.
+
+    my $cache = Netbase::Cache->from_json( $json );
+
+The document must be UTF-8 encoded.
+
+=cut
+
+$Netbase::ffi->attach(
+    from_json => [ 'buffer', '(usize)->opaque' ] => 'cache_t',
+    sub {
+        my ( $xsub, $class, $json ) = @_;
+
+        return Netbase::call_or_croak( sub { $xsub->( $json, shift ) } );
+    },
+);
+
+=head2 from_pcap
+
+Construct a new cache from the DNS exchanges in a pcap or pcapng capture.
+
+    my $cache = Netbase::Cache->from_pcap( $capture );
+    my $cache = Netbase::Cache->from_pcap( $capture, port => 5353 );
+
+Only UDP and TCP traffic to or from the given port (default 53) is imported.
+TCP streams are reassembled and queries are paired with responses by
+addresses, ports and message ID.
+Each query becomes an attempt of a request to its destination address.
+Queries that got no response are recorded as timeouts, and consecutive attempts
+for the same question and server make up a single request, unless an unanswered
+attempt isn't retried within ten seconds.
+
+=cut
+
+$Netbase::ffi->attach(
+    from_pcap => [ 'buffer', 'u16', '(usize)->opaque' ] => 'cache_t',
+    sub {
+        my ( $xsub, $class, $capture, %args ) = @_;
+        my $port = $args{port} // 53;
+
+        return Netbase::call_or_croak( sub { $xsub->( $capture, $port, shift ) } );
+    },
+);
+
+=head2 from_dnstap
+
+Construct a new cache from the DNS exchanges in a dnstap log in the Frame
+Streams format.
+
+    my $cache = Netbase::Cache->from_dnstap( $log );
+
+Query and response messages of any type are imported and paired up into
+requests like the packets of a capture, see L</from_pcap>.
+Response messages that carry the time of their query are imported even if the
+query itself was not logged.
+
+=cut
+
+$Netbase::ffi->attach(
+    from_dnstap => [ 'buffer', '(usize)->opaque' ] => 'cache_t',
+    sub {
+        my ( $xsub, $class, $log ) = @_;
+
+        return Netbase::call_or_croak( sub { $xsub->( $log, shift ) } );
+    },
+);
+
+=head2 open_sqlite
+
+Construct a cache backed by an SQLite database file.
+The file is created if it does not exist.
+Entries are written to the file as they are added to the cache.
+
+    my $cache = Netbase::Cache->open_sqlite( $path );
+
+=cut
+
+$Netbase::ffi->attach(
+    open_sqlite => [ 'string', '(usize)->opaque' ] => 'cache_t',
+    sub {
+        my ( $xsub, $class, $path ) = @_;
+
+        return Netbase::call_or_croak( sub { $xsub->( $path, shift ) } );
+    },
+);
+
+=head1 METHODS
+
+=head2 to_bytes
+
+Serialize the contents into a byte string.
+
+    my $bytes = $cache->to_bytes();
+
+Entries only found in the bases of the cache are left out, see L</add_base>.
+Croaks if the entries can't be read, e.g. from an SQLite database.
+
+=cut
+
+$Netbase::ffi->attach(
+    to_bytes => [ 'cache_t', '(usize)->opaque', '(usize)->opaque' ] => 'u8',
+    sub {
+        my ( $xsub, $cache ) = @_;
+
+        my $bytes         = "";
+        my $bytes_closure = $Netbase::ffi->closure(
             sub {
                 my ( $size ) = @_;
-                grow( $err_msg, $size );
-                return scalar_to_pointer $err_msg;
+                grow( $bytes, $size );
+                return scalar_to_pointer $bytes;
             }
         );
 
-        my $cache = $xsub->( $buffer, $closure );
-        if ( !defined $cache ) {
-            if ( $err_msg eq "" ) {
-                croak "panic in foreign code\n";
+        Netbase::call_or_croak( sub { $xsub->( $cache, $bytes_closure, shift ) } );
+
+        return $bytes;
+    }
+);
+
+=head2 to_json
+
+Serialize the contents into a UTF-8 encoded JSON document.
+
+    my $json = $cache->to_json();
+
+Entries are ordered by qname, qtype and server, each with its question, server
+and history of responses.
+Response messages are represented as described in RFC 8427 together with their
+wire format in base64.
+When a message has been edited so that it no longer matches its wire format,
+L</from_json> re-encodes it from the edited representation.
+Entries only found in the bases of the cache are left out, see L</add_base>.
+
+=cut
+
+$Netbase::ffi->attach(
+    to_json => [ 'cache_t', '(usize)->opaque', '(usize)->opaque' ] => 'u8',
+    sub {
+        my ( $xsub, $cache ) = @_;
+
+        my $json         = "";
+        my $json_closure = $Netbase::ffi->closure(
+            sub {
+                my ( $size ) = @_;
+                grow( $json, $size );
+                return scalar_to_pointer $json;
+            }
+        );
+
+        Netbase::call_or_croak( sub { $xsub->( $cache, $json_closure, shift ) } );
+
+        return $json;
+    }
+);
+
+=head2 to_pcap
+
+Export all recorded exchanges as a pcap capture, e.g. for viewing in Wireshark.
+
+    my $pcap = $cache->to_pcap( Netbase::IP->new( '192.0.2.1' ) );
+
+Each attempt becomes a query packet from the given client address to port 53
+of the server, followed by a response packet if a response was received.
+Failed attempts and requests that got no response appear as unanswered queries.
+Attempts recorded with their wire details keep the query as sent and the
+addresses it was sent from and answered from.
+Queries to servers of the other address family are sent from the unspecified
+address of that family.
+
+=cut
+
+$Netbase::ffi->attach(
+    to_pcap => [ 'cache_t', 'ip_t', '(usize)->opaque', '(usize)->opaque' ] => 'u8',
+    sub {
+        my ( $xsub, $cache, $client ) = @_;
+
+        my $pcap         = "";
+        my $pcap_closure = $Netbase::ffi->closure(
+            sub {
+                my ( $size ) = @_;
+                grow( $pcap, $size );
+                return scalar_to_pointer $pcap;
             }
-            else {
-                $err_msg .= "\n";
-                croak $err_msg;
+        );
+
+        Netbase::call_or_croak( sub { $xsub->( $cache, $client, $pcap_closure, shift ) } );
+
+        return $pcap;
+    }
+);
+
+=head2 to_dnstap
+
+Export all recorded exchanges as a dnstap log in the Frame Streams format.
+
+    my $dnstap = $cache->to_dnstap( Netbase::IP->new( '192.0.2.1' ) );
+    my $dnstap = $cache->to_dnstap( Netbase::IP->new( '192.0.2.1' ), type => 'client' );
+
+Each attempt is logged as a query message from the given client address to port
+53 of the server.
+Requests that got a response are also logged with a response message.
+Attempts recorded with their wire details keep the query as sent and the
+addresses it was sent from and answered from.
+The type (default C<resolver>) selects between RESOLVER_QUERY/RESOLVER_RESPONSE
+and CLIENT_QUERY/CLIENT_RESPONSE messages.
+Queries to servers of the other address family are sent from the unspecified
+address of that family.
+
+=cut
+
+my %DNSTAP_TYPES = (
+    client   => 1,
+    resolver => 2,
+);
+
+$Netbase::ffi->attach(
+    to_dnstap => [ 'cache_t', 'ip_t', 'u8', '(usize)->opaque', '(usize)->opaque' ] => 'u8',
+    sub {
+        my ( $xsub, $cache, $client, %args ) = @_;
+        my $type = $args{type} // 'resolver';
+
+        my $type_num = $DNSTAP_TYPES{ lc( $type ) }
+          // croak "unrecognized dnstap type: " . $type;
+
+        my $dnstap         = "";
+        my $dnstap_closure = $Netbase::ffi->closure(
+            sub {
+                my ( $size ) = @_;
+                grow( $dnstap, $size );
+                return scalar_to_pointer $dnstap;
             }
+        );
+
+        Netbase::call_or_croak( sub { $xsub->( $cache, $client, $type_num, $dnstap_closure, shift ) } );
+
+        return $dnstap;
+    }
+);
+
+=head2 copy_to
+
+Copy all entries into another cache.
+Entries in the other cache with the same question and server are replaced.
+
+Together with L</open_sqlite>, L</from_bytes>, L</to_bytes>, L</from_json>
+and L</to_json> this converts
+between the storage formats.
+
+    $cache->copy_to( Netbase::Cache->open_sqlite( $path ) );
+
+=cut
+
+$Netbase::ffi->attach(
+    copy_to => [ 'cache_t', 'cache_t', '(usize)->opaque' ] => 'u8',
+    sub {
+        my ( $xsub, $cache, $other ) = @_;
+
+        Netbase::call_or_croak( sub { $xsub->( $cache, $other, shift ) } );
+
+        return;
+    }
+);
+
+=head2 merge
+
+Merge the entries of another cache into this one.
+
+    $cache->merge( $other, 'newer' );
+
+The policy decides what happens when both caches have an entry for the same
+question and server:
+
+=over 4
+
+=item older
+
+Keep the entry whose latest response started first.
+
+=item newer
+
+Keep the entry whose latest response started last.
+
+=item both
+
+Keep the responses from both entries.
+The latest response is the one returned by L</lookup>.
+
+=back
+
+=cut
+
+my %MERGE_POLICIES = (
+    older => 1,
+    newer => 2,
+    both  => 3,
+);
+
+$Netbase::ffi->attach(
+    merge => [ 'cache_t', 'cache_t', 'u8', '(usize)->opaque' ] => 'u8',
+    sub {
+        my ( $xsub, $cache, $other, $policy ) = @_;
+
+        my $policy_num = $MERGE_POLICIES{ lc( $policy // '' ) }
+          // croak "unrecognized merge policy: " . ( $policy // 'undef' );
+
+        Netbase::call_or_croak( sub { $xsub->( $cache, $other, $policy_num, shift ) } );
+
+        return;
+    }
+);
+
+=head2 add_base
+
+Layer this cache over another one.
+
+    my $shared = Netbase::Cache->open_sqlite( 'recording.sqlite' );
+    my $cache  = Netbase::Cache->new();
+    $cache->add_base( $shared );
+    $cache->lookup( $net, $question, @ips );    # only adds entries to $cache
+
+Entries this cache doesn't have are looked up in its bases, in the order they
+were added, and then in the bases of those.
+A base is read through rather than copied, so entries added to it later show
+up here too, but it is never written to.
+Refreshing an entry from a base copies its history into this cache before
+appending to it.
+Removing an entry with L</prune> only removes it from this cache, uncovering
+the entry of a base if there is one.
+
+L</to_bytes> and L</to_json> only serialize the entries of this cache itself.
+Other methods, e.g. L</copy_to>, see the entries of the bases as well.
+
+Dies if this cache would become a base of itself.
+
+=cut
+
+$Netbase::ffi->attach(
+    add_base => [ 'cache_t', 'cache_t', '(usize)->opaque' ] => 'u8',
+    sub {
+        my ( $xsub, $cache, $base ) = @_;
+
+        Netbase::call_or_croak( sub { $xsub->( $cache, $base, shift ) } );
+
+        return;
+    }
+);
+
+=head2 query
+
+List the requests matching a L<Netbase::Filter> along with their latest
+outcomes, without making any network requests.
+
+    for my $match ( $cache->query( Netbase::Filter->new( qname_suffix => 'example.se', outcome => 'TIMEOUT_ERROR' ) ) ) {
+        my ( $question, $ip, $outcome ) = @$match;
+        my ( $started, $duration, $msg_size, $error, $message, $connect_us, $query_us ) = @$outcome;
+    }
+
+The outcomes have the same form as the values returned by L</lookup>.
+The requests are ordered by qname in canonical DNS order, then by qtype and
+server.
+Selections by qname, qname suffix, qtype, server, outcome and rcode are served
+by indexes rather than by scanning the whole cache.
+
+=cut
+
+$Netbase::ffi->attach(
+    query => [ 'cache_t', 'filter_t', '(opaque,opaque,u64,u32,u32,u32,u16,u16,opaque)->void', '(usize)->opaque' ] => 'u8',
+    sub {
+        my ( $xsub, $cache, $filter ) = @_;
+
+        my @matches;
+        my $closure = $Netbase::ffi->closure(
+            sub {
+                my ( $question, $ip, $start, $duration, $connect_us, $query_us, $err_kind, $msg_size, $message ) = @_;
+                $question = Netbase::opaque_to_question $question;
+                $ip       = Netbase::opaque_to_ip $ip;
+                if ( defined $message ) {
+                    $message = Netbase::opaque_to_message $message;
+                }
+                if ( $err_kind ) {
+                    $err_kind = $Netbase::NUM2ERROR{$err_kind} // $Netbase::E_INTERNAL;
+                }
+                push @matches, [ $question, $ip, [ $start, $duration, $msg_size, $err_kind, $message, $connect_us, $query_us ] ];
+            }
+        );
+
+        Netbase::call_or_croak( sub { $xsub->( $cache, $filter, $closure, shift ) } );
+
+        return @matches;
+    }
+);
+
+=head2 filter
+
+Construct a new in-memory cache with the entries matching a
+L<Netbase::Filter>.
+
+    my $subset = $cache->filter( Netbase::Filter->new( qname_suffix => 'example.se' ) );
+
+=cut
+
+$Netbase::ffi->attach(
+    filter => [ 'cache_t', 'filter_t', '(usize)->opaque' ] => 'cache_t',
+    sub {
+        my ( $xsub, $cache, $filter ) = @_;
+
+        return Netbase::call_or_croak( sub { $xsub->( $cache, $filter, shift ) } );
+    }
+);
+
+=head2 prune
+
+Remove the entries matching a L<Netbase::Filter>.
+Returns the number of removed entries.
+Only entries of the cache itself are removed, not those of its bases.
+
+    my $count = $cache->prune( Netbase::Filter->new( outcome => $Netbase::E_TIMEOUT ) );
+
+=cut
+
+$Netbase::ffi->attach(
+    prune => [ 'cache_t', 'filter_t', 'usize*', '(usize)->opaque' ] => 'u8',
+    sub {
+        my ( $xsub, $cache, $filter ) = @_;
+
+        my $removed = 0;
+        Netbase::call_or_croak( sub { $xsub->( $cache, $filter, \$removed, shift ) } );
+
+        return $removed;
+    }
+);
+
+=head2 diff
+
+Compare the latest responses of this cache (old) with those of another cache
+(new).
+Message IDs are never compared.
+
+    my @differences = $cache->diff( $other, ignore_ttl => 1 );
+    for my $difference ( @differences ) {
+        my $kind     = $difference->{kind};
+        my $question = $difference->{question};
+        my $server   = $difference->{server};
+        my $old      = $difference->{old};
+        my $new      = $difference->{new};
+    }
+
+Differences are returned ordered by qname, qtype and server.
+Each difference is a hashref with the keys C<question>, C<server> and C<kind>.
+The C<kind> is one of:
+
+=over 4
+
+=item added
+
+The request only exists in the new cache.
+
+=item removed
+
+The request only exists in the old cache.
+
+=item outcome
+
+The outcome kinds differ.
+C<old> and C<new> are either C<RESPONSE> or an error kind.
+
+=item rcode
+
+The response codes differ.
+
+=item flags
+
+The header flags differ.
+C<old> and C<new> are space separated lists of flags.
+
+=item rrset
+
+The records of an RRset differ.
+C<section> is one of C<answer>, C<authority> and C<additional>, C<owner> is the
+owner name and C<rrtype> is the type of the RRset.
+C<old> and C<new> are the records of the RRset, one per line, or the empty
+string if the RRset is missing.
+
+=back
+
+The accepted options are:
+
+=over 4
+
+=item ignore_ttl
+
+Ignore differences in record TTLs.
+
+=back
+
+=cut
+
+my @DIFF_KINDS    = ( undef, qw( added removed outcome rcode flags rrset ) );
+my @DIFF_SECTIONS = ( undef, qw( answer authority additional ) );
+
+$Netbase::ffi->attach(
+    diff => [ 'cache_t', 'cache_t', 'u8', '(opaque,opaque,u8,u8,opaque,u16,string,string)->void', '(usize)->opaque' ] => 'u8',
+    sub {
+        my ( $xsub, $cache, $other, %opts ) = @_;
+
+        my $ignore_ttl = delete $opts{ignore_ttl};
+        if ( %opts ) {
+            croak "unrecognized arguments: " . join( ' ', sort keys %opts );
         }
 
-        return $cache;
-    },
+        my @differences;
+        my $closure = $Netbase::ffi->closure(
+            sub {
+                my ( $question, $ip, $kind, $section, $owner, $rrtype, $old, $new ) = @_;
+                my %difference = (
+                    question => Netbase::opaque_to_question( $question ),
+                    server   => Netbase::opaque_to_ip( $ip ),
+                    kind     => $DIFF_KINDS[$kind],
+                );
+                if ( defined $old ) {
+                    $difference{old} = $old;
+                    $difference{new} = $new;
+                }
+                if ( $section ) {
+                    $difference{section} = $DIFF_SECTIONS[$section];
+                    $difference{owner}   = Netbase::opaque_to_name( $owner );
+                    $difference{rrtype}  = Netbase::rrtype( $rrtype );
+                }
+                push @differences, \%difference;
+            }
+        );
+
+        Netbase::call_or_croak( sub { $xsub->( $cache, $other, $ignore_ttl ? 1 : 0, $closure, shift ) } );
+
+        return @differences;
+    }
 );
 
-=head1 METHODS
+=head2 stats
 
-=head2 to_bytes
+Summarize every recorded response of this cache, including earlier ones in
+histories.
 
-Serialize the contents into a byte string.
+    my $stats = $cache->stats();
+    for my $ip ( sort keys %{ $stats->{servers} } ) {
+        my $server = $stats->{servers}{$ip};
+        printf "%s %d/%d answered, p90 %s ms\n", $ip, $server->{answered}, $server->{responses}, $server->{latency}{p90} // '-';
+    }
 
-    my $bytes = $cache->to_bytes();
+Returns a hashref with these keys:
+
+=over 4
+
+=item entries
+
+The number of (question, server) pairs.
+
+=item responses, retries, bytes
+
+The totals of the per server counts below.
+
+=item qtypes, protocols, rcodes, errors
+
+Hashrefs from qtype names, protocol names, rcode names and error kinds (e.g.
+C<TIMEOUT_ERROR>) to the number of responses.
+Rcodes are only counted for decodable response messages, which in turn are
+not counted as errors.
+Undecodable response messages count as C<PROTOCOL_ERROR>.
+
+=item servers
+
+A hashref from server addresses to hashrefs with the keys:
+
+=over 4
+
+=item responses
+
+The number of responses from the server.
+
+=item answered
+
+The number of those with a decodable response message.
+
+=item errors
+
+The number of those that are errors.
+
+=item retries
+
+The number of failed attempts that were retried.
+
+=item bytes
+
+The total size of the received response messages.
+
+=item latency
+
+A hashref with the keys C<min>, C<p50>, C<p90>, C<p99> and C<max> giving
+nearest-rank percentiles of the durations of the answered responses in
+milliseconds, or undef if none were answered.
+
+=back
+
+=back
 
 =cut
 
+my @STATS_CATEGORIES = qw( entries qtypes protocols rcodes errors );
+
 $Netbase::ffi->attach(
-    to_bytes => [ 'cache_t', '(usize)->opaque' ] => 'u8',
+    stats => [ 'cache_t', '(u8,u16,u64)->void', '(opaque,u64,u64,u64,u64,u64,u32,u32,u32,u32,u32)->void', '(usize)->opaque' ] => 'u8',
     sub {
         my ( $xsub, $cache ) = @_;
 
-        my $buffer  = "";
-        my $closure = $Netbase::ffi->closure(
+        my %stats = (
+            entries   => 0,
+            responses => 0,
+            retries   => 0,
+            bytes     => 0,
+            qtypes    => {},
+            protocols => {},
+            rcodes    => {},
+            errors    => {},
+            servers   => {},
+        );
+        my $handle_count = $Netbase::ffi->closure(
             sub {
-                my ( $size ) = @_;
-                grow( $buffer, $size );
-                return scalar_to_pointer $buffer;
+                my ( $category, $key, $count ) = @_;
+                my $name = $STATS_CATEGORIES[$category];
+                if ( $name eq 'entries' ) {
+                    $stats{entries} = $count;
+                    return;
+                }
+                $key =
+                    $name eq 'qtypes'    ? Netbase::rrtype( $key )
+                  : $name eq 'protocols' ? Netbase::proto( $key )
+                  : $name eq 'rcodes'    ? $Netbase::NUM2RCODE{$key} // $key
+                  :                        $Netbase::NUM2ERROR{$key} // $Netbase::E_INTERNAL;
+                $stats{$name}{"$key"} += $count;
+            }
+        );
+        my $handle_server = $Netbase::ffi->closure(
+            sub {
+                my ( $ip, $responses, $answered, $errors, $retries, $bytes, @latency ) = @_;
+                $ip = Netbase::opaque_to_ip $ip;
+                my %server = (
+                    responses => $responses,
+                    answered  => $answered,
+                    errors    => $errors,
+                    retries   => $retries,
+                    bytes     => $bytes,
+                    latency   => undef,
+                );
+                if ( $answered ) {
+                    my %latency;
+                    @latency{qw( min p50 p90 p99 max )} = @latency;
+                    $server{latency} = \%latency;
+                }
+                $stats{servers}{"$ip"} = \%server;
+                $stats{$_} += $server{$_} for qw( responses retries bytes );
             }
         );
 
-        $xsub->( $cache, $closure )
-          or croak "panic in foreign code\n";
+        Netbase::call_or_croak( sub { $xsub->( $cache, $handle_count, $handle_server, shift ) } );
 
-        return $buffer;
+        return \%stats;
+    }
+);
+
+=head2 synthesize
+
+Insert the responses that servers of a L<Netbase::Zones> give to a question,
+e.g. for writing fixtures without recording them.
+
+    my $zones = Netbase::Zones->new;
+    $zones->add( '192.0.2.1', $zone_text );
+    $cache->synthesize( $zones, $question, '192.0.2.1' );
+
+The responses replace any earlier entries for the question and the servers.
+They are time stamped at the Unix epoch and take no time.
+
+=cut
+
+$Netbase::ffi->attach(
+    synthesize => [ 'cache_t', 'zones_t', 'question_t', 'opaque[]', 'usize', '(usize)->opaque' ] => 'u8',
+    sub {
+        my ( $xsub, $cache, $zones, $question, @servers ) = @_;
+
+        my @server_ptrs = map { Netbase::ip_to_opaque( ip( $_ ) // croak "invalid server: $_" ) } @servers;
+
+        Netbase::call_or_croak( sub { $xsub->( $cache, $zones, $question, \@server_ptrs, scalar @server_ptrs, shift ) } );
+
+        return;
+    }
+);
+
+=head2 replay
+
+Answer DNS queries with the recorded responses, e.g. for running other tools
+against a recording.
+
+    $cache->replay( 5353, $listen_ip => $server_ip, ... );
+
+Listens for UDP and TCP queries on the given port of each listen address, playing
+the role of the recorded server paired with it.
+Queries are answered with the latest recorded response to the same question,
+using the message ID of the query and after waiting for as long as the recorded
+request took.
+Recorded failures are replayed by leaving as many attempts unanswered, counting
+attempts for as long as each comes within ten seconds of the previous one, and
+questions that aren't in the cache are refused.
+
+Doesn't return unless listening fails.
+
+=cut
+
+$Netbase::ffi->attach(
+    replay => [ 'cache_t', 'opaque[]', 'opaque[]', 'usize', 'u16', '(usize)->opaque' ] => 'u8',
+    sub {
+        my ( $xsub, $cache, $port, @pairs ) = @_;
+
+        croak "odd number of addresses\n"
+          if @pairs % 2;
+
+        my @listen_ptrs;
+        my @server_ptrs;
+        while ( my ( $listen, $server ) = splice @pairs, 0, 2 ) {
+            push @listen_ptrs, Netbase::ip_to_opaque $listen;
+            push @server_ptrs, Netbase::ip_to_opaque $server;
+        }
+
+        Netbase::call_or_croak( sub { $xsub->( $cache, \@listen_ptrs, \@server_ptrs, scalar @listen_ptrs, $port, shift ) } );
+
+        return;
+    }
+);
+
+=head2 proxy
+
+Forward DNS queries to real servers and record the exchanges, e.g. for
+capturing the traffic of other tools.
+
+    $cache->proxy( $net, 5353, $listen_ip => $server_ip, ... );
+
+Listens for UDP and TCP queries on the given port of each listen address, and
+looks up the question of each query from the server paired with it, just like
+L</lookup> would on a cache miss.
+The responses are returned to the clients and appended to the histories of the
+questions in the cache.
+Queries whose lookups fail are left unanswered.
+
+Entries are only written to SQLite caches as they are recorded, so other caches
+are of little use afterwards.
+
+Doesn't return unless listening fails.
+
+=cut
+
+$Netbase::ffi->attach(
+    proxy => [ 'cache_t', 'net_t', 'opaque[]', 'opaque[]', 'usize', 'u16', '(usize)->opaque' ] => 'u8',
+    sub {
+        my ( $xsub, $cache, $net, $port, @pairs ) = @_;
+
+        croak "odd number of addresses\n"
+          if @pairs % 2;
+
+        my @listen_ptrs;
+        my @server_ptrs;
+        while ( my ( $listen, $server ) = splice @pairs, 0, 2 ) {
+            push @listen_ptrs, Netbase::ip_to_opaque $listen;
+            push @server_ptrs, Netbase::ip_to_opaque $server;
+        }
+
+        Netbase::call_or_croak( sub { $xsub->( $cache, $net, \@listen_ptrs, \@server_ptrs, scalar @listen_ptrs, $port, shift ) } );
+
+        return;
     }
 );
 
@@ -141,20 +908,214 @@ Look up responses to a question from a set of server addresses.
 
     my $href = $cache->lookup( $net, $question, @ips );
     for my $ip ( keys %$href ) {
-        my ( $started, $duration, $msg_size, $error, $message ) = @{ $href->{$ip} };
+        my ( $started, $duration, $msg_size, $error, $message, $connect_us, $query_us ) = @{ $href->{$ip} };
+    }
+
+The start time is in milliseconds since the Unix epoch and the duration is in
+milliseconds.
+The connect and query times are in microseconds, measured monotonically, and are
+zero when unknown, e.g. for responses recorded before they were measured.
+
+With a L<Netbase::Net> servers without a cached response are queried and the
+responses are recorded in the cache.
+Without one (undef) they are reported with the error C<$E_NOT_IN_CACHE>.
+
+Instead of a net, a hash reference can be given to choose a cache miss strategy:
+
+    my $href = $cache->lookup( { strategy => 'refresh_if_older_than', net => $net, max_age => 3600 }, $question, @ips );
+
+=over 4
+
+=item cache_only
+
+Report servers without a cached response with the error C<$E_NOT_IN_CACHE>.
+
+=item fill_on_miss
+
+Query servers without a cached response.
+
+=item refresh_always
+
+Query every server, even those with a cached response.
+
+=item refresh_if_older_than
+
+Query servers without a cached response, as well as servers whose latest
+response started more than C<max_age> seconds ago.
+
+=item fail_on_miss
+
+Croak if any server is without a cached response.
+
+=back
+
+The strategies that make queries require a net.
+A L<Netbase::ScriptedTransport> can be given in place of the net to make up the
+outcomes of the queries instead.
+Responses of queries are appended to the histories of their entries, so earlier
+responses remain available through L</history> and L</closest>.
+
+The hash reference can also say when to give up on the queries:
+
+    my $href = $cache->lookup( { strategy => 'fill_on_miss', net => $net, cancel => $token, deadline => 2.5 }, $question, @ips );
+
+=over 4
+
+=item cancel
+
+A L<Netbase::CancelToken> that stops the lookup once it is cancelled.
+
+=item deadline
+
+How long the queries may take in all, in seconds of real time, even if the net
+has a manual clock.
+
+=back
+
+Queries given up on are recorded with the error C<$E_CANCELLED> rather than
+waiting for their timeouts and retries.
+The attempts that failed before are recorded with them.
+Such responses don't count as cached, so later lookups query those servers
+again.
+
+=head2 refresh
+
+Same as L</lookup> with the refresh_always strategy.
+
+    my $href = $cache->refresh( $net, $question, @ips );
+
+=cut
+
+my %STRATEGIES = (
+    cache_only            => 1,
+    fill_on_miss          => 2,
+    refresh_always        => 3,
+    refresh_if_older_than => 4,
+    fail_on_miss          => 5,
+);
+
+# Maps the net argument of lookup, lookup_batch and submit to the strategy
+# number, the transport pointer (a net or a scripted transport), the max age in
+# milliseconds, the cancel token pointer and the deadline in milliseconds.
+my $strategy_args = sub {
+    my ( $net ) = @_;
+
+    my %args =
+        ref $net eq 'HASH' ? %$net
+      : defined $net       ? ( strategy => 'fill_on_miss', net => $net )
+      :                      ( strategy => 'cache_only' );
+    my $strategy = delete $args{strategy} // 'cache_only';
+    my $client   = delete $args{net};
+    my $max_age  = delete $args{max_age} // 0;
+    my $token    = delete $args{cancel};
+    my $deadline = delete $args{deadline} // 0;
+    if ( %args ) {
+        croak "unrecognized arguments: " . join( ' ', sort keys %args );
+    }
+    my $strategy_num = $STRATEGIES{ lc $strategy }
+      // croak "unrecognized lookup strategy: $strategy";
+    if ( blessed $client && $client->isa( 'Netbase::ScriptedTransport' ) ) {
+        $client = Netbase::scripted_transport_to_opaque $client;
+    }
+    elsif ( defined $client ) {
+        $client = Netbase::net_to_opaque $client;
+    }
+    if ( defined $token ) {
+        $token = Netbase::cancel_token_to_opaque $token;
     }
 
+    return $strategy_num, $client, int( $max_age * 1000 ), $token, int( $deadline * 1000 );
+};
+
+my $lookup = sub {
+    my ( $xsub, $cache, $net, $question, @ips ) = @_;
+
+    my ( $strategy_num, $client, $max_age, $token, $deadline ) = $strategy_args->( $net );
+
+    my %results;
+    my $closure = $Netbase::ffi->closure(
+        sub {
+            my ( $ip, $start, $duration, $connect_us, $query_us, $err_kind, $msg_size, $message ) = @_;
+            $ip = Netbase::opaque_to_ip $ip;
+            if ( defined $message ) {
+                $message = Netbase::opaque_to_message $message;
+            }
+            if ( $err_kind ) {
+                $err_kind = $Netbase::NUM2ERROR{$err_kind} // $Netbase::E_INTERNAL;
+            }
+            $results{$ip} = [ $start, $duration, $msg_size, $err_kind, $message, $connect_us, $query_us ];
+        }
+    );
+
+    my @ip_ptrs = map { Netbase::ip_to_opaque $_ } @ips;
+
+    Netbase::call_or_croak( sub { $xsub->( $cache, $strategy_num, $client, $max_age, $token, $deadline, $question, \@ip_ptrs, scalar @ips, $closure, shift ) } );
+
+    return \%results;
+};
+
+my @lookup_type = ( [ 'cache_t', 'u8', 'opaque', 'u64', 'opaque', 'u64', 'question_t', 'opaque[]', 'usize', '(opaque,u64,u32,u32,u32,u16,u16,opaque)->void', '(usize)->opaque' ] => 'u8' );
+
+$Netbase::ffi->attach(
+    lookup => @lookup_type,
+    $lookup
+);
+
+$Netbase::ffi->attach(
+    [ lookup => 'refresh' ] => @lookup_type,
+    sub {
+        my ( $xsub, $cache, $net, @args ) = @_;
+        return $lookup->( $xsub, $cache, { strategy => 'refresh_always', net => $net }, @args );
+    }
+);
+
+=head2 lookup_batch
+
+Look up responses to many questions at once, each from a set of server
+addresses of its own.
+
+    my @requests = ( [ $question1, @ips1 ], [ $question2, @ips2 ] );
+    $cache->lookup_batch(
+        $net,
+        \@requests,
+        sub {
+            my ( $index, $responses, $failure ) = @_;
+            my ( $question, @ips ) = @{ $requests[$index] };
+        }
+    );
+
+The net argument chooses a cache miss strategy just like for L</lookup>, and
+every request is handled as L</lookup> would.
+A deadline applies to the batch as a whole.
+But the queries of all the requests are made concurrently, and the callback is
+called for each request as soon as it is complete rather than once they all
+are.
+Requests that need no queries come first, in order, and the others follow as
+their queries finish.
+
+The callback is given the index of the request in the list, the responses in
+the same form as those returned by L</lookup>, and a failure message.
+If the request failed as a whole, i.e. with the fail_on_miss strategy, the
+responses are empty and the failure holds the message that L</lookup> would
+have croaked with.
+Otherwise the failure is undef.
+The callback may do lookups of its own, but it must not die.
+
+Croaks for invalid strategy arguments, before any callback is called.
+
 =cut
 
 $Netbase::ffi->attach(
-    lookup => [ 'cache_t', 'opaque', 'question_t', 'opaque[]', 'usize', '(opaque,u64,u32,u16,u16,opaque)->void' ] => 'u8',
+    lookup_batch => [ 'cache_t', 'u8', 'opaque', 'u64', 'opaque', 'u64', 'opaque[]', 'usize', 'opaque[]', 'usize[]', '(usize,opaque,u64,u32,u32,u32,u16,u16,opaque)->void', '(usize,string)->void', '(usize)->opaque' ] => 'u8',
     sub {
-        my ( $xsub, $cache, $client, $question, @ips ) = @_;
+        my ( $xsub, $cache, $net, $requests, $callback ) = @_;
 
-        my %results;
-        my $closure = $Netbase::ffi->closure(
+        my ( $strategy_num, $client, $max_age, $token, $deadline ) = $strategy_args->( $net );
+
+        my %responses;
+        my $handle_outcome = $Netbase::ffi->closure(
             sub {
-                my ( $ip, $start, $duration, $err_kind, $msg_size, $message ) = @_;
+                my ( $index, $ip, $start, $duration, $connect_us, $query_us, $err_kind, $msg_size, $message ) = @_;
                 $ip = Netbase::opaque_to_ip $ip;
                 if ( defined $message ) {
                     $message = Netbase::opaque_to_message $message;
@@ -162,20 +1123,118 @@ $Netbase::ffi->attach(
                 if ( $err_kind ) {
                     $err_kind = $Netbase::NUM2ERROR{$err_kind} // $Netbase::E_INTERNAL;
                 }
-                $results{$ip} = [ $start, $duration, $msg_size, $err_kind, $message ];
+                $responses{$index}{$ip} = [ $start, $duration, $msg_size, $err_kind, $message, $connect_us, $query_us ];
+            }
+        );
+        my $handle_completion = $Netbase::ffi->closure(
+            sub {
+                my ( $index, $failure ) = @_;
+                $callback->( $index, delete $responses{$index} // {}, $failure );
             }
         );
 
-        if ( defined $client ) {
-            $client = Netbase::net_to_opaque $client;
-        }
+        my @question_ptrs = map { Netbase::question_to_opaque $_->[0] } @$requests;
+        my @ip_ptrs       = map { my ( undef, @ips ) = @$_; map { Netbase::ip_to_opaque $_ } @ips } @$requests;
+        my @ip_counts     = map { $#$_ } @$requests;
+
+        Netbase::call_or_croak( sub { $xsub->( $cache, $strategy_num, $client, $max_age, $token, $deadline, \@question_ptrs, scalar @question_ptrs, \@ip_ptrs, \@ip_counts, $handle_outcome, $handle_completion, shift ) } );
+
+        return;
+    }
+);
+
+=head2 submit
+
+Start a lookup in the background and return its ID right away.
+
+    my $queue = Netbase::Queue->new();
+    my $id    = $cache->submit( $queue, $net, $question, @ips );
+
+The net argument chooses a cache miss strategy just like for L</lookup>.
+Lookups that make queries run as tasks on the runtime of the net and their
+responses are recorded in the cache as usual, while the others complete right
+away.
+Its outcome is collected from the L<Netbase::Queue> once the file descriptor of
+the queue becomes readable.
+
+Croaks right away for invalid strategy arguments, but a fail_on_miss lookup
+that misses is reported by L<Netbase::Queue/drain>.
+
+=cut
+
+$Netbase::ffi->attach(
+    submit => [ 'cache_t', 'queue_t', 'u8', 'opaque', 'u64', 'opaque', 'u64', 'question_t', 'opaque[]', 'usize', '(usize)->opaque' ] => 'u64',
+    sub {
+        my ( $xsub, $cache, $queue, $net, $question, @ips ) = @_;
+
+        my ( $strategy_num, $client, $max_age, $token, $deadline ) = $strategy_args->( $net );
 
         my @ip_ptrs = map { Netbase::ip_to_opaque $_ } @ips;
 
-        $xsub->( $cache, $client, $question, \@ip_ptrs, scalar @ips, $closure )
-          or croak "panic in foreign code\n";
+        return Netbase::call_or_croak( sub { $xsub->( $cache, $queue, $strategy_num, $client, $max_age, $token, $deadline, $question, \@ip_ptrs, scalar @ips, shift ) } );
+    }
+);
+
+=head2 history
+
+List all responses to a question from a server address, oldest first, without
+making any network requests.
 
-        return \%results;
+    for my $response ( $cache->history( $question, $ip ) ) {
+        my ( $started, $duration, $msg_size, $error, $message, $connect_us, $query_us ) = @$response;
+    }
+
+The responses have the same form as the values returned by L</lookup>.
+
+=head2 closest
+
+Get the response to a question from a server address that started closest to a
+given time (milliseconds since the Unix epoch), without making any network
+requests.
+
+    my $response = $cache->closest( $question, $ip, $time );
+
+Returns undef if there is no entry.
+Of two responses equally close the older one is returned.
+
+=cut
+
+my $responses = sub {
+    my ( $xsub, @args ) = @_;
+
+    my @responses;
+    my $closure = $Netbase::ffi->closure(
+        sub {
+            my ( $start, $duration, $connect_us, $query_us, $err_kind, $msg_size, $message ) = @_;
+            if ( defined $message ) {
+                $message = Netbase::opaque_to_message $message;
+            }
+            if ( $err_kind ) {
+                $err_kind = $Netbase::NUM2ERROR{$err_kind} // $Netbase::E_INTERNAL;
+            }
+            push @responses, [ $start, $duration, $msg_size, $err_kind, $message, $connect_us, $query_us ];
+        }
+    );
+
+    Netbase::call_or_croak( sub { $xsub->( @args, $closure, shift ) } );
+
+    return @responses;
+};
+
+$Netbase::ffi->attach(
+    history => [ 'cache_t', 'question_t', 'ip_t', '(u64,u32,u32,u32,u16,u16,opaque)->void', '(usize)->opaque' ] => 'u8',
+    sub {
+        my ( $xsub, $cache, $question, $server ) = @_;
+        return $responses->( $xsub, $cache, $question, $server );
+    }
+);
+
+$Netbase::ffi->attach(
+    closest => [ 'cache_t', 'question_t', 'ip_t', 'u64', '(u64,u32,u32,u32,u16,u16,opaque)->void', '(usize)->opaque' ] => 'u8',
+    sub {
+        my ( $xsub, $cache, $question, $server, $time ) = @_;
+        my ( $response ) = $responses->( $xsub, $cache, $question, $server, $time );
+        return $response;
     }
 );
 
@@ -185,16 +1244,44 @@ Traverse all cached requests.
 
     $cache->for_each_request(
         sub {
-            my ( $ip, $question ) = @_;
+            my ( $question, $ip ) = @_;
         }
     );
 
+The requests are collected before the first call, so the callback is free to
+make lookups in the same cache, e.g. to refresh every request:
+
+    $cache->for_each_request(
+        sub {
+            my ( $question, $ip ) = @_;
+            $cache->refresh( $net, $question, $ip );
+        }
+    );
+
+The requests are traversed by qname in canonical DNS order, then by qtype and
+server.
+To traverse them by the start of the first attempt of their oldest response
+instead, give the order argument:
+
+    $cache->for_each_request( $callback, order => 'first_attempt' );
+
 =cut
 
+my %REQUEST_ORDERS = (
+    name          => 1,
+    first_attempt => 2,
+);
+
 $Netbase::ffi->attach(
-    for_each_request => [ 'cache_t', '(opaque, opaque)->void' ] => 'u8',
+    for_each_request => [ 'cache_t', 'u8', '(opaque, opaque)->void' ] => 'u8',
     sub {
-        my ( $xsub, $cache, $callback ) = @_;
+        my ( $xsub, $cache, $callback, %args ) = @_;
+        my $order = delete $args{order} // 'name';
+        if ( %args ) {
+            croak "unrecognized arguments: " . join( ' ', sort keys %args );
+        }
+        my $order_num = $REQUEST_ORDERS{ lc $order }
+          // croak "unrecognized request order: $order";
 
         my $closure = $Netbase::ffi->closure(
             sub {
@@ -205,7 +1292,7 @@ $Netbase::ffi->attach(
             }
         );
 
-        $xsub->( $cache, $closure )
+        $xsub->( $cache, $order_num, $closure )
           or croak "panic in foreign code\n";
 
         return;
@@ -220,22 +1307,24 @@ Traverse all cached failed queries for a given request.
         $question,
         $ip,
         sub {
-            my ( $start, $duration, $error ) = @_;
+            my ( $start, $duration, $error, $query_us ) = @_;
         }
     );
 
+The query time is in microseconds, or zero when unknown.
+
 =cut
 
 $Netbase::ffi->attach(
-    for_each_retry => [ 'cache_t', 'question_t', 'ip_t', '(u64, u32, u32)->void' ] => 'u8',
+    for_each_retry => [ 'cache_t', 'question_t', 'ip_t', '(u64, u32, u32, u16)->void' ] => 'u8',
     sub {
         my ( $xsub, $cache, $question, $server, $callback ) = @_;
 
         my $closure = $Netbase::ffi->closure(
             sub {
-                my ( $start, $duration, $error ) = @_;
+                my ( $start, $duration, $query_us, $error ) = @_;
                 $error = $Netbase::NUM2ERROR{$error} // $Netbase::E_INTERNAL;
-                $callback->( $start, $duration, $error );
+                $callback->( $start, $duration, $error, $query_us );
             }
         );
 
@@ -0,0 +1,106 @@
+=head1 NAME
+
+Netbase::CancelToken - a way to stop lookups in flight
+
+=head1 DESCRIPTION
+
+A B<Netbase::CancelToken> is given to L<Netbase::Cache/lookup> and its
+relatives along with a net.
+Once the token is cancelled, lookups using it give up on the queries they are
+waiting for and return promptly.
+The queries given up on are recorded in the cache with the error
+C<$E_CANCELLED>.
+
+A token can be cancelled from another thread, or when the process receives a
+signal.
+
+    my $token = Netbase::CancelToken->new();
+    $token->cancel_on_signal( 'INT' );
+    my $href = $cache->lookup( { strategy => 'fill_on_miss', net => $net, cancel => $token }, $question, @ips );
+    exit 130 if $token->is_cancelled;
+
+=cut
+
+package Netbase::CancelToken;
+use strict;
+use warnings;
+use utf8;
+
+use Carp qw( croak );
+use Config;
+use Netbase;
+
+$Netbase::ffi->mangler( sub { "netbase_cancel_token_" . shift } );
+
+my %SIGNALS;
+@SIGNALS{ split ' ', $Config{sig_name} } = split ' ', $Config{sig_num};
+
+=head1 CONSTRUCTORS
+
+=head2 new
+
+Construct a new token that isn't cancelled.
+
+    my $token = Netbase::CancelToken->new();
+
+=cut
+
+$Netbase::ffi->attach( new => ['string'] => 'cancel_token_t' );
+
+=head1 METHODS
+
+=head2 cancel
+
+Cancel the token.
+It stays cancelled, so later lookups using it don't make any queries.
+
+    $token->cancel();
+
+=head2 is_cancelled
+
+Check whether the token has been cancelled.
+
+    if ( $token->is_cancelled ) { ... }
+
+=cut
+
+$Netbase::ffi->attach( cancel       => ['cancel_token_t'] );
+$Netbase::ffi->attach( is_cancelled => ['cancel_token_t'] => 'u8' );
+
+=head2 cancel_on_signal
+
+Cancel the token whenever the process receives a signal, given by name or by
+number.
+
+    $token->cancel_on_signal( 'INT' );
+
+Perl only runs its own signal handlers in between calls to foreign code, so a
+C<$SIG{INT}> handler can't stop a lookup that is already running.
+This method handles the signal in foreign code instead.
+For the rest of the life of the process, the signal then no longer has its
+default action, e.g. terminating the process, and C<%SIG> handlers for it are
+no longer called.
+
+Croaks for unknown signals and for signals that can't be handled, like C<KILL>.
+
+=cut
+
+$Netbase::ffi->attach(
+    cancel_on_signal => [ 'cancel_token_t', 'i32', '(usize)->opaque' ] => 'u8',
+    sub {
+        my ( $xsub, $token, $signal ) = @_;
+
+        my $signum = $signal =~ /^[0-9]+$/ ? $signal : $SIGNALS{ uc( $signal ) =~ s/^SIG//r };
+        if ( !$signum ) {
+            croak "unrecognized signal: $signal";
+        }
+
+        Netbase::call_or_croak( sub { $xsub->( $token, $signum, shift ) } );
+
+        return;
+    }
+);
+
+$Netbase::ffi->attach( DESTROY => ['cancel_token_t'] );
+
+1;
@@ -0,0 +1,156 @@
+=head1 NAME
+
+Netbase::Filter - criteria for selecting cache entries
+
+=head1 DESCRIPTION
+
+A B<Netbase::Filter> is a conjunction of criteria that are matched against
+the question, the server and the latest response of each cache entry.
+Unset criteria match every entry.
+
+=cut
+
+package Netbase::Filter;
+use strict;
+use warnings;
+use utf8;
+
+use Carp qw( croak );
+use Netbase qw( proto rrtype );
+use Netbase::IP qw( ip );
+use Netbase::Name qw( name );
+
+$Netbase::ffi->mangler( sub { "netbase_filter_" . shift } );
+
+=head1 CONSTRUCTORS
+
+=head2 new
+
+Construct a new filter.
+
+    my $filter = Netbase::Filter->new(
+        qname_suffix  => 'example.se',
+        qtype         => 'NS',
+        proto         => 'UDP',
+        server        => '192.0.2.1',
+        outcome       => $Netbase::E_TIMEOUT,
+        rcode         => 'NXDOMAIN',
+        started_from  => 1640995200000,
+        started_until => 1641081600000,
+    );
+
+The accepted arguments are:
+
+=over 4
+
+=item qname
+
+Match entries with exactly this qname.
+
+=item qname_suffix
+
+Match entries with this qname or any name below it.
+
+=item qtype
+
+Match entries with this qtype.
+
+=item proto
+
+Match entries with this transport protocol.
+
+=item server
+
+Match entries for this server address.
+
+=item outcome
+
+Match entries whose latest outcome has this error kind.
+The value C<RESPONSE> (or zero) matches entries whose latest outcome is a
+response.
+
+=item rcode
+
+Match entries whose latest outcome is a response with this response code,
+given by number or by one of the names C<NOERROR>, C<FORMERR>, C<SERVFAIL>,
+C<NXDOMAIN>, C<NOTIMP>, C<REFUSED>, C<YXDOMAIN>, C<YXRRSET>, C<NXRRSET>,
+C<NOTAUTH>, C<NOTZONE> and C<BADVERS>.
+
+=item started_from
+
+Match entries whose latest request started at or after this time
+(milliseconds since the Unix epoch).
+
+=item started_until
+
+Match entries whose latest request started before this time
+(milliseconds since the Unix epoch).
+
+=back
+
+=cut
+
+$Netbase::ffi->attach(
+    new => ['string'] => 'filter_t',
+    sub {
+        my ( $xsub, $class, %args ) = @_;
+
+        my $this = $xsub->( $class );
+
+        if ( defined( my $qname = delete $args{qname} ) ) {
+            $this->set_qname( name( $qname ) // croak "invalid qname: $qname" );
+        }
+        if ( defined( my $suffix = delete $args{qname_suffix} ) ) {
+            $this->set_qname_suffix( name( $suffix ) // croak "invalid qname_suffix: $suffix" );
+        }
+        if ( defined( my $qtype = delete $args{qtype} ) ) {
+            $this->set_qtype( rrtype( $qtype ) // croak "invalid qtype: $qtype" );
+        }
+        if ( defined( my $proto = delete $args{proto} ) ) {
+            $this->set_proto( proto( $proto ) // croak "invalid proto: $proto" )
+              or croak "invalid proto: $proto";
+        }
+        if ( defined( my $server = delete $args{server} ) ) {
+            $this->set_server( ip( $server ) // croak "invalid server: $server" );
+        }
+        if ( defined( my $outcome = delete $args{outcome} ) ) {
+            my $value =
+                uc $outcome eq 'RESPONSE' ? 0
+              : exists $Netbase::NAME2ERROR{ uc $outcome } ? $Netbase::NAME2ERROR{ uc $outcome }
+              :                                               $outcome;
+            $this->set_outcome( $value )
+              or croak "invalid outcome: $outcome";
+        }
+        if ( defined( my $rcode = delete $args{rcode} ) ) {
+            my $value = $Netbase::NAME2RCODE{ uc $rcode } // $rcode;
+            $value =~ /^\d+$/ && $value < 4096
+              or croak "invalid rcode: $rcode";
+            $this->set_rcode( $value );
+        }
+        if ( defined( my $from = delete $args{started_from} ) ) {
+            $this->set_started_from( $from );
+        }
+        if ( defined( my $until = delete $args{started_until} ) ) {
+            $this->set_started_until( $until );
+        }
+        if ( %args ) {
+            croak "unrecognized arguments: " . join( ' ', sort keys %args );
+        }
+
+        return $this;
+    }
+);
+
+$Netbase::ffi->attach( set_qname         => [ 'filter_t', 'name_t' ] );
+$Netbase::ffi->attach( set_qname_suffix  => [ 'filter_t', 'name_t' ] );
+$Netbase::ffi->attach( set_qtype         => [ 'filter_t', 'rrtype_t' ] );
+$Netbase::ffi->attach( set_proto         => [ 'filter_t', 'proto_t' ] => 'u8' );
+$Netbase::ffi->attach( set_server        => [ 'filter_t', 'ip_t' ] );
+$Netbase::ffi->attach( set_outcome       => [ 'filter_t', 'u16' ] => 'u8' );
+$Netbase::ffi->attach( set_rcode         => [ 'filter_t', 'u16' ] );
+$Netbase::ffi->attach( set_started_from  => [ 'filter_t', 'u64' ] );
+$Netbase::ffi->attach( set_started_until => [ 'filter_t', 'u64' ] );
+
+$Netbase::ffi->attach( DESTROY => ['filter_t'] );
+
+1;
@@ -10,23 +10,40 @@ use Netbase::IP qw( ip );
 
 $Netbase::ffi->mangler( sub { "netbase_net_" . shift } );
 
+# Clocks for timestamps and retry sleeps. The fixed and manual clocks start at
+# the time given by the now argument (milliseconds since the Unix epoch) and
+# never actually sleep. A manual clock is moved forward by advance_clock, and
+# up to the deadline of each query that times out.
+my %CLOCKS = (
+    real   => 1,
+    fixed  => 2,
+    manual => 3,
+);
+
 $Netbase::ffi->attach(
-    new => [ 'string', 'ip_t', 'u32', 'u16', 'u32' ] => 'net_t',
+    new => [ 'string', 'ip_t', 'u32', 'u16', 'u32', 'u32', 'u8', 'u64' ] => 'net_t',
     sub {
         my ( $xsub, $class, %args ) = @_;
-        my $bind_addr = delete $args{bind_addr} // '0.0.0.0';
-        my $timeout   = delete $args{timeout}   // 30;
-        my $retry     = delete $args{retry}     // 3;
-        my $retrans   = delete $args{retrans}   // 1;
+        my $bind_addr     = delete $args{bind_addr}     // '0.0.0.0';
+        my $timeout       = delete $args{timeout}       // 30;
+        my $retry         = delete $args{retry}         // 3;
+        my $retrans       = delete $args{retrans}       // 1;
+        my $max_in_flight = delete $args{max_in_flight} // 0;
+        my $clock         = delete $args{clock}         // 'real';
+        my $now           = delete $args{now}           // 0;
         if ( %args ) {
             croak "unrecognized arguments: " . join( ' ', sort keys %args );
         }
+        my $clock_num = $CLOCKS{ lc $clock }
+          // croak "unrecognized clock: $clock";
         $timeout = int( $timeout * 1000 );
         $retrans = int( $retrans * 1000 );
-        return $xsub->( $class, ip( $bind_addr ), $timeout, $retry, $retrans );
+        return $xsub->( $class, ip( $bind_addr ), $timeout, $retry, $retrans, $max_in_flight, $clock_num, $now );
     }
 );
 
+$Netbase::ffi->attach( advance_clock => [ 'net_t', 'u64' ] );
+
 $Netbase::ffi->attach(
     lookup => [ 'net_t', 'question_t', 'ip_t', 'u64*', 'u32*', '(usize)->opaque' ] => 'u32',
     sub {
@@ -0,0 +1,155 @@
+=head1 NAME
+
+Netbase::Queue - lookups running in the background
+
+=head1 DESCRIPTION
+
+A B<Netbase::Queue> collects the outcomes of lookups started with
+L<Netbase::Cache/submit>.
+Submitted lookups run as tasks on the runtime of their net, so submitting
+returns right away and any number of lookups can be in flight at once.
+
+The queue has a file descriptor that becomes readable when lookups complete,
+so that an event loop can wait for them along with its other file descriptors.
+Once it is readable, L</drain> collects the completed lookups without blocking.
+
+A queue may be destroyed while lookups are still running.
+Their responses are still recorded in their caches, but their completions are
+discarded.
+
+    my $queue = Netbase::Queue->new();
+    my %pending;
+    for my $ip ( @ips ) {
+        $pending{ $cache->submit( $queue, $net, $question, $ip ) } = $ip;
+    }
+    while ( %pending ) {
+        vec( my $rin = '', $queue->fd, 1 ) = 1;
+        select( $rin, undef, undef, undef );
+        for my $completion ( $queue->drain() ) {
+            my ( $id, $responses, $failure ) = @$completion;
+            delete $pending{$id};
+        }
+    }
+
+=cut
+
+package Netbase::Queue;
+use strict;
+use warnings;
+use utf8;
+
+use Netbase;
+
+$Netbase::ffi->mangler( sub { "netbase_queue_" . shift } );
+
+=head1 ERRORS
+
+All these subroutines call foreign code to achieve their task.
+In case the foreign code panics (without terminating the process) the native
+subroutine reacts by calling C<croak>.
+
+=head1 CONSTRUCTORS
+
+=head2 new
+
+Construct a new empty queue.
+
+    my $queue = Netbase::Queue->new();
+
+=cut
+
+$Netbase::ffi->attach(
+    new => [ 'string', '(usize)->opaque' ] => 'queue_t',
+    sub {
+        my ( $xsub, $class ) = @_;
+
+        return Netbase::call_or_croak( sub { $xsub->( $class, shift ) } );
+    }
+);
+
+=head1 METHODS
+
+=head2 fd
+
+Get the file descriptor that is readable while there are completed lookups to
+drain.
+
+    my $fd = $queue->fd();
+
+The file descriptor belongs to the queue.
+Only wait for it to become readable; never read from it or close it.
+
+=head2 pending
+
+Get the number of lookups submitted and not yet drained.
+
+    my $count = $queue->pending();
+
+=cut
+
+$Netbase::ffi->attach( fd      => ['queue_t'] => 'i32' );
+$Netbase::ffi->attach( pending => ['queue_t'] => 'u64' );
+
+=head2 drain
+
+Collect the completed lookups, in the order they completed, without waiting
+for more.
+
+    for my $completion ( $queue->drain() ) {
+        my ( $id, $responses, $failure ) = @$completion;
+        for my $ip ( keys %$responses ) {
+            my ( $started, $duration, $msg_size, $error, $message, $connect_us, $query_us ) = @{ $responses->{$ip} };
+        }
+    }
+
+The ID is the one returned by L<Netbase::Cache/submit>.
+The responses have the same form as those returned by
+L<Netbase::Cache/lookup>.
+If the lookup failed as a whole, e.g. with the fail_on_miss strategy, the
+responses are empty and the failure holds the message that
+L<Netbase::Cache/lookup> would have croaked with.
+Otherwise the failure is undef.
+
+Returns an empty list if no lookups have completed since the last call.
+Croaks if signalling a completed lookup on the file descriptor failed since the
+last call, leaving the completed lookups for the next call.
+
+=cut
+
+$Netbase::ffi->attach(
+    drain => [ 'queue_t', '(u64,opaque,u64,u32,u32,u32,u16,u16,opaque)->void', '(u64,string)->void', '(usize)->opaque' ] => 'u8',
+    sub {
+        my ( $xsub, $queue ) = @_;
+
+        my %responses;
+        my $handle_outcome = $Netbase::ffi->closure(
+            sub {
+                my ( $id, $ip, $start, $duration, $connect_us, $query_us, $err_kind, $msg_size, $message ) = @_;
+                $ip = Netbase::opaque_to_ip $ip;
+                if ( defined $message ) {
+                    $message = Netbase::opaque_to_message $message;
+                }
+                if ( $err_kind ) {
+                    $err_kind = $Netbase::NUM2ERROR{$err_kind} // $Netbase::E_INTERNAL;
+                }
+                $responses{$id}{$ip} = [ $start, $duration, $msg_size, $err_kind, $message, $connect_us, $query_us ];
+            }
+        );
+
+        my @completions;
+        my $handle_completion = $Netbase::ffi->closure(
+            sub {
+                my ( $id, $error ) = @_;
+                push @completions, [ $id, delete $responses{$id} // {}, $error ];
+            }
+        );
+
+        Netbase::call_or_croak( sub { $xsub->( $queue, $handle_outcome, $handle_completion, shift ) } );
+
+        return @completions;
+    }
+);
+
+$Netbase::ffi->attach( DESTROY => ['queue_t'] );
+
+1;
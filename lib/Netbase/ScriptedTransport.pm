@@ -0,0 +1,127 @@
+=head1 NAME
+
+Netbase::ScriptedTransport - made up query outcomes for testing lookups
+
+=head1 DESCRIPTION
+
+A B<Netbase::ScriptedTransport> is given to L<Netbase::Cache/lookup> and its
+relatives in place of a L<Netbase::Net>.
+Instead of sending anything, it plays out the attempts scripted for each
+question and server address, retrying failed attempts just like a net does.
+Attempts that aren't scripted time out.
+
+    my $transport = Netbase::ScriptedTransport->new( retry => 2 );
+    $transport->add( $question, '192.0.2.1', 'timeout', delay => 1 );
+    $transport->add( $question, '192.0.2.1', 'respond', message => $bytes, delay => 0.012 );
+    my $href = $cache->lookup( $transport, $question, ip( '192.0.2.1' ) );
+
+The transport has a manual clock that is moved forward by the delays of the
+attempts and by the time between retries, so lookups return right away.
+
+=cut
+
+package Netbase::ScriptedTransport;
+use strict;
+use warnings;
+use utf8;
+
+use Carp qw( croak );
+use Netbase;
+use Netbase::IP qw( ip );
+
+$Netbase::ffi->mangler( sub { "netbase_scripted_transport_" . shift } );
+
+my %KINDS = (
+    respond   => 1,
+    truncate  => 2,
+    malformed => 3,
+    timeout   => 4,
+    refuse    => 5,
+);
+
+=head1 CONSTRUCTORS
+
+=head2 new
+
+Construct a new transport without any scripted attempts.
+
+    my $transport = Netbase::ScriptedTransport->new( retry => 3, retrans => 1, now => 1_600_000_000_000 );
+
+The retry and retrans arguments are the same as those of L<Netbase::Net/new>.
+The clock starts at now, in milliseconds since the Unix epoch, and defaults to
+the epoch.
+
+=cut
+
+$Netbase::ffi->attach(
+    new => [ 'string', 'u16', 'u32', 'u64', '(usize)->opaque' ] => 'scripted_transport_t',
+    sub {
+        my ( $xsub, $class, %args ) = @_;
+        my $retry   = delete $args{retry}   // 3;
+        my $retrans = delete $args{retrans} // 1;
+        my $now     = delete $args{now}     // 0;
+        if ( %args ) {
+            croak "unrecognized arguments: " . join( ' ', sort keys %args );
+        }
+        return Netbase::call_or_croak( sub { $xsub->( $class, $retry, int( $retrans * 1000 ), $now, shift ) } );
+    }
+);
+
+=head1 METHODS
+
+=head2 add
+
+Append an attempt to the script for a question and server address.
+Attempts are used up in order.
+
+    $transport->add( $question, '192.0.2.1', 'respond', message => $bytes, delay => 0.012 );
+
+The kind of attempt is one of:
+
+=over 4
+
+=item respond
+
+The server responds with the message.
+
+=item truncate
+
+The server responds with the message with the TC flag set.
+
+=item malformed
+
+The server responds with the message, which doesn't need to be valid.
+
+=item timeout
+
+The attempt times out.
+
+=item refuse
+
+The server refuses the connection.
+
+=back
+
+The delay is how long the attempt takes, in seconds, and defaults to zero.
+
+=cut
+
+$Netbase::ffi->attach(
+    [ push => 'add' ] => [ 'scripted_transport_t', 'question_t', 'ip_t', 'u64', 'u8', 'buffer' ] => 'u8',
+    sub {
+        my ( $xsub, $transport, $question, $server, $kind, %args ) = @_;
+        my $message = delete $args{message} // '';
+        my $delay   = delete $args{delay}   // 0;
+        if ( %args ) {
+            croak "unrecognized arguments: " . join( ' ', sort keys %args );
+        }
+        my $server_ip = ip( $server ) // croak "invalid server: $server";
+        my $kind_num  = $KINDS{ lc $kind } // croak "unrecognized attempt: $kind";
+        $xsub->( $transport, $question, $server_ip, int( $delay * 1000 ), $kind_num, $message );
+        return;
+    }
+);
+
+$Netbase::ffi->attach( DESTROY => ['scripted_transport_t'] );
+
+1;
@@ -0,0 +1,82 @@
+=head1 NAME
+
+Netbase::Zones - zone files served by virtual servers
+
+=head1 DESCRIPTION
+
+A B<Netbase::Zones> assigns zones in master file format to server addresses.
+The servers answer questions the way authoritative servers would, which is used
+for synthesizing cache fixtures from readable zone text instead of recording
+them.
+
+Servers give referrals with glue below delegations, NXDOMAIN and NODATA
+responses with the SOA record of the zone, follow CNAME records within the zone
+and expand wildcards.
+Questions outside of the zones of a server are refused.
+
+=cut
+
+package Netbase::Zones;
+use strict;
+use warnings;
+use utf8;
+
+use Carp qw( croak );
+use Netbase;
+use Netbase::IP qw( ip );
+use Netbase::Name qw( name );
+
+$Netbase::ffi->mangler( sub { "netbase_zones_" . shift } );
+
+=head1 CONSTRUCTORS
+
+=head2 new
+
+Construct a new set of zones without any servers.
+
+    my $zones = Netbase::Zones->new;
+
+=cut
+
+$Netbase::ffi->attach( new => ['string'] => 'zones_t' );
+
+=head1 METHODS
+
+=head2 add
+
+Add a zone to those served by a server.
+
+    $zones->add( '192.0.2.1', $zone_text );
+    $zones->add( '192.0.2.1', $zone_text, origin => 'example.se' );
+
+The origin is taken from an C<$ORIGIN> directive in the zone text unless it is
+given.
+Croaks if the zone text can't be parsed or has no SOA record at its origin.
+
+=cut
+
+$Netbase::ffi->attach(
+    add => [ 'zones_t', 'ip_t', 'buffer', 'opaque', '(usize)->opaque' ] => 'u8',
+    sub {
+        my ( $xsub, $zones, $server, $text, %args ) = @_;
+
+        my $server_ip = ip( $server ) // croak "invalid server: $server";
+
+        my $origin;
+        if ( defined( my $value = delete $args{origin} ) ) {
+            $origin = name( $value ) // croak "invalid origin: $value";
+        }
+        if ( %args ) {
+            croak "unrecognized arguments: " . join( ' ', sort keys %args );
+        }
+
+        my $origin_ptr = defined $origin ? Netbase::name_to_opaque $origin : undef;
+        Netbase::call_or_croak( sub { $xsub->( $zones, $server_ip, $text, $origin_ptr, shift ) } );
+
+        return;
+    }
+);
+
+$Netbase::ffi->attach( DESTROY => ['zones_t'] );
+
+1;
@@ -1,12 +1,17 @@
 use Test2::V0;
 use Test2::Tools::Class;
 
-use Netbase qw( proto rrtype $RRTYPE_A $RRTYPE_AAAA $RRTYPE_NS $RRTYPE_SOA );
+use File::Temp;
+
+use Netbase qw( proto rrtype $E_CANCELLED $E_NOT_IN_CACHE $E_TIMEOUT $RRTYPE_A $RRTYPE_AAAA $RRTYPE_NS $RRTYPE_SOA );
 use Netbase::Cache;
+use Netbase::CancelToken;
 use Netbase::IP qw( ip );
 use Netbase::Name qw( name );
 use Netbase::Net;
 use Netbase::Question qw( question );
+use Netbase::Queue;
+use Netbase::ScriptedTransport;
 use Scalar::Util qw( dualvar );
 
 subtest 'Netbase' => sub {
@@ -117,7 +122,80 @@ subtest 'Netbase::Cache' => sub {
     subtest 'lookup()' => sub {
         my $cache = Netbase::Cache->new();
         my $responses = $cache->lookup( undef, question('example.com', 'A'), ip( '192.0.2.1' ) );
-        is $responses, { '192.0.2.1' => [0, 0, 0, 0, undef] };
+        is $responses, { '192.0.2.1' => [0, 0, 0, $E_NOT_IN_CACHE, undef, 0, 0] };
+    };
+
+    subtest 'lookup() with a cancelled token' => sub {
+        my $cache = Netbase::Cache->new();
+        my $token = Netbase::CancelToken->new();
+        ok !$token->is_cancelled, 'not cancelled at first';
+        $token->cancel();
+        ok $token->is_cancelled, 'cancelled';
+        my $strategy  = { strategy => 'fill_on_miss', net => Netbase::Net->new(), cancel => $token };
+        my $responses = $cache->lookup( $strategy, question('example.com', 'A'), ip( '192.0.2.1' ) );
+        is $responses->{'192.0.2.1'}[3], $E_CANCELLED;
+    };
+
+    subtest 'lookup() with a scripted transport' => sub {
+        my $cache     = Netbase::Cache->new();
+        my $transport = Netbase::ScriptedTransport->new( retry => 2, now => 1_000 );
+        $transport->add( question('example.com', 'A'), '192.0.2.1', 'timeout', delay => 1 );
+        my $responses = $cache->lookup( $transport, question('example.com', 'A'), ip( '192.0.2.1' ) );
+        is $responses, { '192.0.2.1' => [3_000, 0, 0, $E_TIMEOUT, undef, 0, 0] };
+        like dies { $transport->add( question('example.com', 'A'), '192.0.2.1', 'vanish' ) }, qr/unrecognized attempt/;
+    };
+
+    subtest 'lookup_batch()' => sub {
+        my $cache    = Netbase::Cache->new();
+        my @requests = ( [ question('example.com', 'A'), ip( '192.0.2.1' ) ], [ question('example.com', 'NS') ] );
+        my @completions;
+        $cache->lookup_batch( undef, \@requests, sub { push @completions, [@_] } );
+        is \@completions, [ [ 0, { '192.0.2.1' => [0, 0, 0, $E_NOT_IN_CACHE, undef, 0, 0] }, undef ], [ 1, {}, undef ] ];
+    };
+
+    subtest 'submit()' => sub {
+        my $cache = Netbase::Cache->new();
+        my $queue = Netbase::Queue->new();
+        my $id1   = $cache->submit( $queue, undef, question('example.com', 'A'), ip( '192.0.2.1' ) );
+        my $id2   = $cache->submit( $queue, { strategy => 'fail_on_miss' }, question('example.com', 'A'), ip( '192.0.2.1' ) );
+        my %completions;
+        while ( $queue->pending ) {
+            vec( my $rin = '', $queue->fd, 1 ) = 1;
+            select( $rin, undef, undef, undef );
+            $completions{ $_->[0] } = $_ for $queue->drain();
+        }
+        is $completions{$id1}, [ $id1, { '192.0.2.1' => [0, 0, 0, $E_NOT_IN_CACHE, undef, 0, 0] }, undef ];
+        is $completions{$id2}, [ $id2, {}, match qr/not in cache/ ];
+        is [ $queue->drain() ], [], 'nothing left to drain';
+    };
+
+    subtest 'history() and closest()' => sub {
+        my $cache = Netbase::Cache->new();
+        is [ $cache->history( question('example.com', 'A'), ip( '192.0.2.1' ) ) ], [];
+        is $cache->closest( question('example.com', 'A'), ip( '192.0.2.1' ), 0 ), undef;
+    };
+
+    subtest 'stats()' => sub {
+        my $stats = Netbase::Cache->new()->stats();
+        is $stats, {
+            entries   => 0,
+            responses => 0,
+            retries   => 0,
+            bytes     => 0,
+            qtypes    => {},
+            protocols => {},
+            rcodes    => {},
+            errors    => {},
+            servers   => {},
+        };
+    };
+
+    subtest 'add_base()' => sub {
+        my $base  = Netbase::Cache->new();
+        my $cache = Netbase::Cache->new();
+        $cache->add_base( $base );
+        is $cache->to_bytes(), $base->to_bytes();
+        like dies { $base->add_base( $cache ) }, qr/base of itself/, 'rejects cycles';
     };
 
     subtest '{from,to}_bytes()' => sub {
@@ -133,6 +211,23 @@ subtest 'Netbase::Cache' => sub {
         my $buffer3 = $cache3->to_bytes();
         is $buffer3, $buffer2;
     };
+
+    subtest 'open_sqlite() and copy_to()' => sub {
+        my $dir = File::Temp->newdir();
+        my $cache1 = Netbase::Cache->open_sqlite( "$dir/cache.sqlite" );
+        isa_ok $cache1, ['Netbase::Cache'], 'returns an instance';
+        my $cache2 = Netbase::Cache->new();
+        $cache1->copy_to( $cache2 );
+        is $cache2->to_bytes(), Netbase::Cache->new()->to_bytes();
+    };
+
+    subtest 'to_json() and from_json()' => sub {
+        my $json = Netbase::Cache->new()->to_json();
+        like $json, qr/"entries"/, 'returns a json document';
+        my $cache = Netbase::Cache->from_json( $json );
+        isa_ok $cache, ['Netbase::Cache'], 'returns an instance';
+        like dies { Netbase::Cache->from_json( '{' ) }, qr/^json error/, 'rejects invalid json';
+    };
 };
 
 done_testing;